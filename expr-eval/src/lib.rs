@@ -0,0 +1,4019 @@
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    str::Chars,
+    sync::Mutex,
+};
+
+// 自定义 Result 类型
+pub type Result<T> = std::result::Result<T, ExprError>;
+
+// 自定义错误类型，调用方可以通过匹配区分错误的类型
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExprError {
+    // 其他计算错误，例如类型不匹配、参数超出定义域、超出计算预算
+    Parse(String),
+    // 词法或者语法错误，包括表达式的长度和嵌套层数超出限制
+    Syntax(String),
+    // 除数为 0
+    DivByZero,
+    // 整数或者分数溢出
+    Overflow(String),
+    // 未定义的变量
+    UndefinedVariable(String),
+    // 未定义的函数
+    UnknownFunction(String),
+    // eval_program 中某一行的错误，包括行号（从 1 开始）和这一行的错误
+    Line(usize, Box<ExprError>),
+}
+
+impl std::error::Error for ExprError {}
+
+impl Display for ExprError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Parse(s) | Self::Syntax(s) | Self::Overflow(s) => write!(f, "{}", s),
+            Self::DivByZero => write!(f, "Division by zero"),
+            Self::UndefinedVariable(name) => write!(f, "Unknown identifier {}", name),
+            Self::UnknownFunction(name) => write!(f, "Unknown function {}", name),
+            Self::Line(line, err) => write!(f, "Line {}: {}", line, err),
+        }
+    }
+}
+
+// 计算结果，整数、浮点数、分数、比较运算得到的布尔值、字符串或者列表
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Rational(Rational),
+    Str(String),
+    // 列表字面量的值，元素可以是任意类型，包括列表
+    List(Vec<Value>),
+}
+
+impl Value {
+    // 转换为浮点数，true 为 1，false 为 0，字符串和列表不是数字，为 NaN
+    pub fn as_f64(&self) -> f64 {
+        match self {
+            Value::Int(n) => *n as f64,
+            Value::Float(f) => *f,
+            Value::Bool(b) => *b as i32 as f64,
+            Value::Rational(r) => r.to_f64(),
+            Value::Str(_) | Value::List(_) => f64::NAN,
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Float(v) => write!(f, "{}", v),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Rational(r) => write!(f, "{}", r),
+            // 和字符串字面量的写法一致，加上引号并转义，可以重新作为表达式解析
+            Value::Str(s) => write!(f, "{}", quote(s)),
+            Value::List(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+        }
+    }
+}
+
+// 格式化计算结果，浮点数保留 precision 位小数并去掉末尾的 0，例如 0.1 + 0.2 输出 0.3
+// 只影响输出，计算结果本身的精度不变
+pub fn format_result(value: Value, precision: usize) -> String {
+    match value {
+        Value::Float(f) if f.is_finite() => {
+            let s = format!("{:.*}", precision, f);
+            let s = if s.contains('.') {
+                s.trim_end_matches('0').trim_end_matches('.')
+            } else {
+                &s
+            };
+            // 舍入之后为 0 的负数，例如 -0.0001 保留 2 位小数
+            match s {
+                "-0" => "0".to_string(),
+                s => s.to_string(),
+            }
+        }
+        // 列表中的每个元素分别格式化
+        Value::List(items) => {
+            let items: Vec<_> = items
+                .into_iter()
+                .map(|item| format_result(item, precision))
+                .collect();
+            format!("[{}]", items.join(", "))
+        }
+        value => value.to_string(),
+    }
+}
+
+// 按照指定的进制格式化整数结果，支持 2、8、10、16 进制，例如 255 的十六进制是 0xff
+// 负数在前缀之前加负号，例如 -0xff，其他类型的结果和 Display 的输出相同
+pub fn format_result_radix(value: Value, radix: u32) -> Result<String> {
+    let n = match value {
+        Value::Int(n) => n,
+        value => return Ok(value.to_string()),
+    };
+    // i64::MIN 的绝对值超出 i64 的范围，使用无符号数格式化
+    let abs = n.unsigned_abs();
+    let digits = match radix {
+        2 => format!("0b{:b}", abs),
+        8 => format!("0o{:o}", abs),
+        10 => abs.to_string(),
+        16 => format!("0x{:x}", abs),
+        _ => return Err(ExprError::Parse(format!("Unsupported radix {}", radix))),
+    };
+    Ok(if n < 0 {
+        format!("-{}", digits)
+    } else {
+        digits
+    })
+}
+
+// 每三位插入一个分隔符格式化整数结果，例如 1000000 输出 1,000,000，分隔符可以自定义
+// 其他类型的结果和 Display 的输出相同
+pub fn format_grouped(value: Value, separator: char) -> String {
+    let n = match value {
+        Value::Int(n) => n,
+        value => return value.to_string(),
+    };
+    let digits = n.unsigned_abs().to_string();
+    let mut s = String::with_capacity(digits.len() * 4 / 3 + 1);
+    if n < 0 {
+        s.push('-');
+    }
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i) % 3 == 0 {
+            s.push(separator);
+        }
+        s.push(c);
+    }
+    s
+}
+
+// 分数，创建和计算之后都会约分为最简形式，分母总是正数
+// 中间结果使用 i128 计算，约分之后超出 i64 范围时返回错误
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rational {
+    num: i64,
+    den: i64,
+}
+
+impl Rational {
+    pub fn new(num: i64, den: i64) -> Result<Self> {
+        Self::reduce(num as i128, den as i128)
+    }
+
+    // 分子
+    pub fn num(&self) -> i64 {
+        self.num
+    }
+
+    // 分母
+    pub fn den(&self) -> i64 {
+        self.den
+    }
+
+    // 转换为浮点数
+    pub fn to_f64(&self) -> f64 {
+        self.num as f64 / self.den as f64
+    }
+
+    pub fn checked_add(self, other: Self) -> Result<Self> {
+        let (a, b) = (self.wide(), other.wide());
+        Self::reduce(a.0 * b.1 + b.0 * a.1, a.1 * b.1)
+    }
+
+    pub fn checked_sub(self, other: Self) -> Result<Self> {
+        let (a, b) = (self.wide(), other.wide());
+        Self::reduce(a.0 * b.1 - b.0 * a.1, a.1 * b.1)
+    }
+
+    pub fn checked_mul(self, other: Self) -> Result<Self> {
+        let (a, b) = (self.wide(), other.wide());
+        Self::reduce(a.0 * b.0, a.1 * b.1)
+    }
+
+    pub fn checked_div(self, other: Self) -> Result<Self> {
+        let (a, b) = (self.wide(), other.wide());
+        Self::reduce(a.0 * b.1, a.1 * b.0)
+    }
+
+    // 取负数，分子是 i64::MIN 时溢出
+    pub fn checked_neg(self) -> Result<Self> {
+        let (num, den) = self.wide();
+        Self::reduce(-num, den)
+    }
+
+    // 绝对值，分子是 i64::MIN 时溢出
+    pub fn checked_abs(self) -> Result<Self> {
+        let (num, den) = self.wide();
+        Self::reduce(num.abs(), den)
+    }
+
+    // 整数次幂，负数次幂先取倒数
+    pub fn checked_pow(self, exp: i64) -> Result<Self> {
+        let base = if exp < 0 {
+            Self::reduce(self.den as i128, self.num as i128)?
+        } else {
+            self
+        };
+        let exp = u32::try_from(exp.unsigned_abs()).map_err(|_| Self::overflow())?;
+        let (num, den) = base.wide();
+        match (num.checked_pow(exp), den.checked_pow(exp)) {
+            (Some(num), Some(den)) => Self::reduce(num, den),
+            _ => Err(Self::overflow()),
+        }
+    }
+
+    // 向下取整
+    pub fn floor(self) -> Self {
+        Self {
+            num: self.num.div_euclid(self.den),
+            den: 1,
+        }
+    }
+
+    fn wide(&self) -> (i128, i128) {
+        (self.num as i128, self.den as i128)
+    }
+
+    // 约分，并保证分母为正数
+    fn reduce(num: i128, den: i128) -> Result<Self> {
+        if den == 0 {
+            return Err(ExprError::DivByZero);
+        }
+        let g = gcd(num, den);
+        let (num, den) = if den < 0 {
+            (-num / g, -den / g)
+        } else {
+            (num / g, den / g)
+        };
+        match (i64::try_from(num), i64::try_from(den)) {
+            (Ok(num), Ok(den)) => Ok(Self { num, den }),
+            _ => Err(Self::overflow()),
+        }
+    }
+
+    fn overflow() -> ExprError {
+        ExprError::Overflow("Rational overflow".into())
+    }
+}
+
+// 最大公约数，b 不为 0 时结果总是正数
+fn gcd(mut a: i128, mut b: i128) -> i128 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a.abs()
+}
+
+impl From<i64> for Rational {
+    fn from(n: i64) -> Self {
+        Self { num: n, den: 1 }
+    }
+}
+
+impl PartialOrd for Rational {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Rational {
+    // 分母都是正数，交叉相乘后比较
+    fn cmp(&self, other: &Self) -> Ordering {
+        let (a, b) = (self.wide(), other.wide());
+        (a.0 * b.1).cmp(&(b.0 * a.1))
+    }
+}
+
+impl Display for Rational {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.den == 1 {
+            write!(f, "{}", self.num)
+        } else {
+            write!(f, "{}/{}", self.num, self.den)
+        }
+    }
+}
+
+// 角度单位，决定三角函数参数和反三角函数返回值的单位
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AngleMode {
+    #[default]
+    Radians, // 弧度
+    Degrees, // 角度
+}
+
+// 整数运算溢出时的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Overflow {
+    #[default]
+    Checked, // 返回错误
+    Wrap, // 按照 64 位补码回绕，例如 i64::MAX + 1 = i64::MIN，用于模拟固定位宽的机器运算
+}
+
+// 函数调用的语法
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CallSyntax {
+    #[default]
+    Parens, // 圆括号，例如 sqrt(16)
+    Brackets, // 方括号，例如 sqrt[16]，圆括号只用于分组
+}
+
+// Token 表示，数字、标识符、运算符号、括号
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Float(f64),      // 带小数点的数字
+    Ident(String),   // 标识符，函数名或者常量
+    Str(String),     // 双引号括起来的字符串
+    Let,             // 关键字 let，局部变量
+    In,              // 关键字 in，局部变量的作用范围
+    Plus,            // 加
+    Minus,           // 减
+    Multiply,        // 乘
+    Divide,          // 除
+    FloorDiv,        // 向下取整除
+    Power,           // 幂
+    Bang,            // 感叹号，阶乘
+    LeftParen,       // 左括号
+    RightParen,      // 右括号
+    LeftBracket,     // 左方括号，列表或者下标
+    RightBracket,    // 右方括号
+    Bar,             // 竖线，绝对值
+    Comma,           // 逗号，分隔函数参数
+    Range,           // 范围，不包括结尾，例如 1..5
+    RangeInclusive,  // 范围，包括结尾，例如 1..=5
+    Assign,          // 等号，变量赋值
+    Question,        // 问号，条件表达式
+    Colon,           // 冒号，条件表达式
+    Less,            // 小于
+    LessEqual,       // 小于等于
+    Greater,         // 大于
+    GreaterEqual,    // 大于等于
+    Equal,           // 等于
+    NotEqual,        // 不等于
+    Invalid(String), // 无法识别的字符，保存错误信息
+}
+
+// 左结合
+pub const ASSOC_LEFT: i32 = 0;
+// 右结合
+pub const ASSOC_RIGHT: i32 = 1;
+
+impl Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Token::Number(n) => n.to_string(),
+                Token::Float(f) => f.to_string(),
+                Token::Ident(name) => name.clone(),
+                Token::Str(s) => quote(s),
+                Token::Let => "let".to_string(),
+                Token::In => "in".to_string(),
+                Token::Plus => "+".to_string(),
+                Token::Minus => "-".to_string(),
+                Token::Multiply => "*".to_string(),
+                Token::Divide => "/".to_string(),
+                Token::FloorDiv => "//".to_string(),
+                Token::Power => "^".to_string(),
+                Token::Bang => "!".to_string(),
+                Token::LeftParen => "(".to_string(),
+                Token::RightParen => ")".to_string(),
+                Token::LeftBracket => "[".to_string(),
+                Token::RightBracket => "]".to_string(),
+                Token::Bar => "|".to_string(),
+                Token::Comma => ",".to_string(),
+                Token::Range => "..".to_string(),
+                Token::RangeInclusive => "..=".to_string(),
+                Token::Assign => "=".to_string(),
+                Token::Question => "?".to_string(),
+                Token::Colon => ":".to_string(),
+                Token::Less => "<".to_string(),
+                Token::LessEqual => "<=".to_string(),
+                Token::Greater => ">".to_string(),
+                Token::GreaterEqual => ">=".to_string(),
+                Token::Equal => "==".to_string(),
+                Token::NotEqual => "!=".to_string(),
+                Token::Invalid(msg) => msg.clone(),
+            }
+        )
+    }
+}
+
+impl Token {
+    // 判断是不是运算符号
+    fn is_operator(&self) -> bool {
+        matches!(
+            self,
+            Token::Plus
+                | Token::Minus
+                | Token::Multiply
+                | Token::Divide
+                | Token::FloorDiv
+                | Token::Power
+        ) || self.is_comparison()
+    }
+
+    // 判断是不是比较运算符
+    fn is_comparison(&self) -> bool {
+        matches!(
+            self,
+            Token::Less
+                | Token::LessEqual
+                | Token::Greater
+                | Token::GreaterEqual
+                | Token::Equal
+                | Token::NotEqual
+        )
+    }
+
+    // 获取运算符的优先级
+    fn precedence(&self) -> i32 {
+        match self {
+            t if t.is_comparison() => 1,
+            Token::Plus | Token::Minus => 2,
+            Token::Multiply | Token::Divide | Token::FloorDiv => 3,
+            Token::Power => 4,
+            _ => 0,
+        }
+    }
+
+    // 获取运算符的结合性
+    fn assoc(&self) -> i32 {
+        match self {
+            Token::Power => ASSOC_RIGHT,
+            _ => ASSOC_LEFT,
+        }
+    }
+
+    // 根据当前运算符进行计算
+    // 两边都是整数时按整数计算，整数和分数之间按分数计算，否则转换为浮点数计算
+    // strict_division 为 true 时，整数除法不能整除会返回错误，而不是截断
+    // rational 为 true 时，整数除法的结果是约分后的分数
+    // 向下取整除（//）不受这两个选项的影响，结果总是向负无穷取整
+    // 整数运算溢出时按照 overflow 处理，指数为负数的整数幂运算按浮点数计算
+    // 两个字符串相加是字符串拼接，字符串和其他类型不能一起计算
+    fn compute(
+        &self,
+        l: Value,
+        r: Value,
+        strict_division: bool,
+        rational: bool,
+        overflow: Overflow,
+    ) -> Result<Value> {
+        match (l, r) {
+            (Value::Str(mut l), Value::Str(r)) if *self == Token::Plus => {
+                l.push_str(&r);
+                Ok(Value::Str(l))
+            }
+            (Value::Str(_), Value::Str(_)) => Err(ExprError::Parse(format!(
+                "Invalid string operand for {}",
+                self
+            ))),
+            (Value::Str(_), _) | (_, Value::Str(_)) => Err(mismatched_types()),
+            (Value::Int(_), Value::Int(0)) if matches!(self, Token::Divide | Token::FloorDiv) => {
+                Err(ExprError::DivByZero)
+            }
+            (Value::Int(l), Value::Int(r)) if *self == Token::Divide && rational => {
+                Rational::new(l, r).map(Value::Rational)
+            }
+            (Value::Rational(l), Value::Rational(r)) => self.compute_rational(l, r),
+            (Value::Rational(l), Value::Int(r)) => self.compute_rational(l, Rational::from(r)),
+            (Value::Int(l), Value::Rational(r)) => self.compute_rational(Rational::from(l), r),
+            (Value::Int(l), Value::Int(r)) if *self == Token::Divide && strict_division => {
+                // i64::MIN % -1 溢出，但是可以整除
+                if l.checked_rem(r).is_some_and(|m| m != 0) {
+                    return Err(ExprError::Parse("non-exact integer division".into()));
+                }
+                self.compute_int(l, r, overflow)
+            }
+            (Value::Int(l), Value::Int(r)) if !(*self == Token::Power && r < 0) => {
+                self.compute_int(l, r, overflow)
+            }
+            (Value::Bool(_), _) | (_, Value::Bool(_)) => Err(ExprError::Parse(format!(
+                "Invalid boolean operand for {}",
+                self
+            ))),
+            (Value::List(_), _) | (_, Value::List(_)) => Err(ExprError::Parse(format!(
+                "Invalid list operand for {}",
+                self
+            ))),
+            (_, r) if *self == Token::FloorDiv && r.as_f64() == 0.0 => Err(ExprError::DivByZero),
+            (l, r) => {
+                let (l, r) = (l.as_f64(), r.as_f64());
+                match self {
+                    Token::Plus => Some(l + r),
+                    Token::Minus => Some(l - r),
+                    Token::Multiply => Some(l * r),
+                    Token::Divide => Some(l / r),
+                    Token::FloorDiv => Some((l / r).floor()),
+                    Token::Power => Some(l.powf(r)),
+                    _ => None,
+                }
+                .map(Value::Float)
+                .ok_or(ExprError::Parse("Unexpected expr".into()))
+            }
+        }
+    }
+
+    // 整数之间的计算，除数不为 0，幂运算的指数不是负数
+    fn compute_int(&self, l: i64, r: i64, overflow: Overflow) -> Result<Value> {
+        let result = match (self, overflow) {
+            (Token::Plus, Overflow::Checked) => l.checked_add(r),
+            (Token::Plus, Overflow::Wrap) => Some(l.wrapping_add(r)),
+            (Token::Minus, Overflow::Checked) => l.checked_sub(r),
+            (Token::Minus, Overflow::Wrap) => Some(l.wrapping_sub(r)),
+            (Token::Multiply, Overflow::Checked) => l.checked_mul(r),
+            (Token::Multiply, Overflow::Wrap) => Some(l.wrapping_mul(r)),
+            (Token::Divide, Overflow::Checked) => l.checked_div(r),
+            (Token::Divide, Overflow::Wrap) => Some(l.wrapping_div(r)),
+            // 只有 i64::MIN // -1 会溢出
+            (Token::FloorDiv, Overflow::Checked) => l.checked_div(r).map(|_| floor_div(l, r)),
+            (Token::FloorDiv, Overflow::Wrap) if r == -1 => Some(l.wrapping_neg()),
+            (Token::FloorDiv, Overflow::Wrap) => Some(floor_div(l, r)),
+            (Token::Power, Overflow::Checked) => {
+                u32::try_from(r).ok().and_then(|r| l.checked_pow(r))
+            }
+            (Token::Power, Overflow::Wrap) => Some(wrapping_pow(l, r as u64)),
+            _ => return Err(ExprError::Parse("Unexpected expr".into())),
+        };
+        result
+            .map(Value::Int)
+            .ok_or(ExprError::Overflow("Integer overflow".into()))
+    }
+
+    // 分数之间的计算，指数不是整数时转换为浮点数计算
+    fn compute_rational(&self, l: Rational, r: Rational) -> Result<Value> {
+        match self {
+            Token::Plus => l.checked_add(r),
+            Token::Minus => l.checked_sub(r),
+            Token::Multiply => l.checked_mul(r),
+            Token::Divide => l.checked_div(r),
+            Token::FloorDiv => l.checked_div(r).map(|q| q.floor()),
+            Token::Power if r.den() == 1 => l.checked_pow(r.num()),
+            Token::Power => return Ok(Value::Float(l.to_f64().powf(r.to_f64()))),
+            _ => Err(ExprError::Parse("Unexpected expr".into())),
+        }
+        .map(Value::Rational)
+    }
+
+    // 根据当前的比较运算符进行比较
+    // 数字之间按照数值比较，布尔值之间只能判断是否相等，字符串之间按照字典序（Unicode 码点）比较
+    fn compare(&self, l: &Value, r: &Value) -> Result<bool> {
+        let ordering = match (l, r) {
+            (Value::Str(l), Value::Str(r)) => Some(l.cmp(r)),
+            (Value::Str(_), _) | (_, Value::Str(_)) => return Err(mismatched_types()),
+            (Value::Int(l), Value::Int(r)) => l.partial_cmp(r),
+            (Value::Rational(l), Value::Rational(r)) => l.partial_cmp(r),
+            (Value::Rational(l), Value::Int(r)) => l.partial_cmp(&Rational::from(*r)),
+            (Value::Int(l), Value::Rational(r)) => Rational::from(*l).partial_cmp(r),
+            (Value::Bool(l), Value::Bool(r)) if matches!(self, Token::Equal | Token::NotEqual) => {
+                l.partial_cmp(r)
+            }
+            (Value::Bool(_), _) | (_, Value::Bool(_)) => {
+                return Err(ExprError::Parse(format!(
+                    "Invalid boolean operand for {}",
+                    self
+                )))
+            }
+            (Value::List(_), _) | (_, Value::List(_)) => {
+                return Err(ExprError::Parse(format!(
+                    "Invalid list operand for {}",
+                    self
+                )))
+            }
+            (l, r) => l.as_f64().partial_cmp(&r.as_f64()),
+        };
+
+        // NaN 和任何值都不相等
+        let ordering = match ordering {
+            Some(ordering) => ordering,
+            None => return Ok(*self == Token::NotEqual),
+        };
+        Ok(self.holds(ordering))
+    }
+
+    // 比较的结果是否满足比较运算符
+    fn holds(&self, ordering: Ordering) -> bool {
+        match self {
+            Token::Less => ordering.is_lt(),
+            Token::LessEqual => ordering.is_le(),
+            Token::Greater => ordering.is_gt(),
+            Token::GreaterEqual => ordering.is_ge(),
+            Token::Equal => ordering.is_eq(),
+            Token::NotEqual => ordering.is_ne(),
+            _ => unreachable!(),
+        }
+    }
+}
+
+// 字符串和其他类型一起计算或者比较时的错误
+fn mismatched_types() -> ExprError {
+    ExprError::Parse("Mismatched types, expecting a string operand".into())
+}
+
+// 整数的向下取整除法，例如 -7 // 2 = -4，而 -7 / 2 = -3
+fn floor_div(l: i64, r: i64) -> i64 {
+    let q = l / r;
+    if l % r != 0 && (l < 0) != (r < 0) {
+        q - 1
+    } else {
+        q
+    }
+}
+
+// 回绕的整数幂运算，指数可能超出 u32 的范围，所以不能直接使用 i64::wrapping_pow
+fn wrapping_pow(mut base: i64, mut exp: u64) -> i64 {
+    let mut acc: i64 = 1;
+    while exp > 0 {
+        if exp & 1 == 1 {
+            acc = acc.wrapping_mul(base);
+        }
+        base = base.wrapping_mul(base);
+        exp >>= 1;
+    }
+    acc
+}
+
+// 内置常量
+fn constant(name: &str) -> Option<Value> {
+    match name {
+        "pi" => Some(Value::Float(std::f64::consts::PI)),
+        "e" => Some(Value::Float(std::f64::consts::E)),
+        _ => None,
+    }
+}
+
+// 内置函数表，返回函数的参数个数
+fn function_arity(name: &str) -> Option<usize> {
+    match name {
+        "sin" | "cos" | "tan" => Some(1),
+        "asin" | "acos" | "atan" => Some(1),
+        "atan2" | "gcd" | "lcm" => Some(2),
+        "deg" | "rad" | "sqrt" => Some(1),
+        "round" | "floor" | "ceil" | "trunc" => Some(1),
+        "clamp" | "powmod" => Some(3),
+        _ => None,
+    }
+}
+
+// 取整函数，整数保持不变，round 在正好一半时远离 0 取整，例如 round(-2.5) = -3
+fn round(name: &str, value: &Value) -> Value {
+    if let Value::Int(_) = value {
+        return value.clone();
+    }
+    let x = value.as_f64();
+    Value::Float(match name {
+        "round" => x.round(),
+        "floor" => x.floor(),
+        "ceil" => x.ceil(),
+        "trunc" => x.trunc(),
+        _ => unreachable!(),
+    })
+}
+
+// 将 x 限制在 [lo, hi] 之间，参数都是整数时结果也是整数
+fn clamp(args: &[Value]) -> Result<Value> {
+    let (lo, hi) = (args[1].as_f64(), args[2].as_f64());
+    if lo.is_nan() || hi.is_nan() || lo > hi {
+        return Err(ExprError::Parse(format!(
+            "Function clamp expects lo <= hi, got {} and {}",
+            args[1], args[2]
+        )));
+    }
+    match (&args[0], &args[1], &args[2]) {
+        (Value::Int(x), Value::Int(lo), Value::Int(hi)) => Ok(Value::Int((*x).clamp(*lo, *hi))),
+        (x, _, _) => Ok(Value::Float(x.as_f64().clamp(lo, hi))),
+    }
+}
+
+// 模幂运算 base ^ exp mod modulus，使用平方乘算法，中间结果不会溢出
+// 参数都必须是整数，exp 不能为负数，modulus 必须大于 0，结果在 [0, modulus) 之间
+fn powmod(args: &[Value]) -> Result<Value> {
+    let (base, mut exp, modulus) = match (&args[0], &args[1], &args[2]) {
+        (Value::Int(base), Value::Int(exp), Value::Int(modulus)) => (*base, *exp, *modulus),
+        _ => {
+            return Err(ExprError::Parse(
+                "Function powmod expects integer arguments".into(),
+            ))
+        }
+    };
+    if modulus <= 0 {
+        return Err(ExprError::Parse(format!(
+            "Function powmod expects a positive modulus, got {}",
+            modulus
+        )));
+    }
+    if exp < 0 {
+        return Err(ExprError::Parse(format!(
+            "Function powmod expects a non-negative exponent, got {}",
+            exp
+        )));
+    }
+
+    let modulus = modulus as i128;
+    let mut base = (base as i128).rem_euclid(modulus);
+    let mut result = 1 % modulus;
+    while exp > 0 {
+        if exp & 1 == 1 {
+            result = result * base % modulus;
+        }
+        base = base * base % modulus;
+        exp >>= 1;
+    }
+    Ok(Value::Int(result as i64))
+}
+
+// 最大公约数和最小公倍数，负数按照绝对值计算，gcd(0, 0) = 0，lcm(0, x) = 0
+fn gcd_lcm(name: &str, args: &[Value]) -> Result<Value> {
+    let (a, b) = match (&args[0], &args[1]) {
+        (Value::Int(a), Value::Int(b)) => (*a as i128, *b as i128),
+        _ => {
+            return Err(ExprError::Parse(format!(
+                "Function {} expects integer arguments",
+                name
+            )))
+        }
+    };
+    let g = gcd(a, b);
+    let result = match name {
+        "gcd" => g,
+        "lcm" if g == 0 => 0,
+        "lcm" => a.abs() / g * b.abs(),
+        _ => unreachable!(),
+    };
+    // 结果是正数，例如 gcd(-2^63, 0) = 2^63 超出 i64 的范围
+    i64::try_from(result)
+        .map(Value::Int)
+        .map_err(|_| ExprError::Overflow(format!("Integer overflow in {}", name)))
+}
+
+// 范围上的聚合函数
+fn is_aggregate(name: &str) -> bool {
+    matches!(name, "sum" | "product")
+}
+
+// 参数是字符串的函数，len 的参数也可以是列表
+fn is_string_function(name: &str) -> bool {
+    matches!(name, "len" | "ord")
+}
+
+// 内置函数，包括聚合函数和字符串函数
+fn is_builtin_function(name: &str) -> bool {
+    function_arity(name).is_some() || is_aggregate(name) || is_string_function(name)
+}
+
+// 使用双引号括起字符串，并转义其中的特殊字符，和解析字符串时的转义规则一致
+fn quote(s: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// 消耗一步计算预算，预算用完时返回错误
+fn consume_step(budget: &mut Option<usize>) -> Result<()> {
+    match budget {
+        Some(0) => Err(ExprError::Parse("evaluation step budget exceeded".into())),
+        Some(steps) => {
+            *steps -= 1;
+            Ok(())
+        }
+        None => Ok(()),
+    }
+}
+
+// 遇到不符合预期的 Token 时返回的错误，无法识别的字符使用 Tokenizer 给出的错误信息
+fn unexpected(token: &Token, msg: &str) -> ExprError {
+    match token {
+        Token::Invalid(invalid) => ExprError::Syntax(invalid.clone()),
+        _ => ExprError::Syntax(msg.into()),
+    }
+}
+
+// 逐个读取字符，和 Peekable<Chars> 一样可以向前看一个字符，同时记录已经读取的字符数
+#[derive(Clone)]
+struct CharCursor<'a> {
+    chars: Chars<'a>,
+    peeked: Option<Option<char>>,
+    // 已经读取的字符数，也就是下一个字符的位置
+    pos: usize,
+}
+
+impl CharCursor<'_> {
+    fn peek(&mut self) -> Option<&char> {
+        let chars = &mut self.chars;
+        self.peeked.get_or_insert_with(|| chars.next()).as_ref()
+    }
+}
+
+impl Iterator for CharCursor<'_> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        let c = match self.peeked.take() {
+            Some(c) => c,
+            None => self.chars.next(),
+        };
+        if c.is_some() {
+            self.pos += 1;
+        }
+        c
+    }
+}
+
+// 将一个算术表达式解析成连续的 Token
+// 并通过 Iterator 返回，解析时通过 TokenStream 向前看一个 Token
+struct Tokenizer<'a> {
+    tokens: CharCursor<'a>,
+    // 宽松模式，跳过无法识别的 Token
+    lenient: bool,
+    // 当前 Token 的开始位置（从 0 开始的字符位置）
+    start: usize,
+}
+
+impl<'a> Tokenizer<'a> {
+    fn new(expr: &'a str) -> Self {
+        Self {
+            tokens: CharCursor {
+                chars: expr.chars(),
+                peeked: None,
+                pos: 0,
+            },
+            lenient: false,
+            start: 0,
+        }
+    }
+
+    // 设置宽松模式，无法识别的 Token 不会返回给调用方
+    fn lenient(mut self, lenient: bool) -> Self {
+        self.lenient = lenient;
+        self
+    }
+
+    // 解析下一个 Token
+    fn scan(&mut self) -> Option<Token> {
+        // 消除前面的空格
+        self.consume_whitespace();
+        self.start = self.tokens.pos;
+        // 解析当前位置的 Token 类型
+        match self.tokens.peek() {
+            Some(c) if c.is_ascii_digit() => self.scan_number(),
+            // 只支持 ASCII 数字，其他的 Unicode 数字（例如阿拉伯-印度数字）直接报错
+            Some(&c) if c.is_numeric() => {
+                self.tokens.next();
+                Some(Token::Invalid(format!(
+                    "Unsupported non-ASCII digit '{}'",
+                    c
+                )))
+            }
+            Some(c) if c.is_alphabetic() || *c == '_' => self.scan_identifier(),
+            Some('"') => self.scan_string(),
+            Some(_) => self.scan_operator(),
+            None => None,
+        }
+    }
+
+    // 消除空白字符
+    fn consume_whitespace(&mut self) {
+        while let Some(&c) = self.tokens.peek() {
+            if c.is_whitespace() {
+                self.tokens.next();
+            } else {
+                break;
+            }
+        }
+    }
+
+    // 扫描数字，小数点后面跟着数字时是浮点数，例如 2.5，否则是整数，0x 开头的是十六进制数字
+    fn scan_number(&mut self) -> Option<Token> {
+        let mut num = self.scan_digits();
+        if num == "0" && matches!(self.tokens.peek(), Some('x' | 'X')) {
+            self.tokens.next();
+            return Some(self.scan_hex());
+        }
+
+        // 向后多看一个字符，判断小数点后面是不是数字
+        if self.tokens.peek() == Some(&'.')
+            && self.peek_second().is_some_and(|c| c.is_ascii_digit())
+        {
+            self.tokens.next();
+            num.push('.');
+            num.push_str(&self.scan_digits());
+            return match num.parse() {
+                Ok(f) => Some(Token::Float(f)),
+                Err(_) => Some(Token::Invalid(format!("Invalid number {}", num))),
+            };
+        }
+
+        match num.parse() {
+            Ok(n) => Some(Token::Number(n)),
+            Err(_) => Some(Token::Invalid(format!("Invalid number {}", num))),
+        }
+    }
+
+    // 扫描十六进制数字，0x 已经被消费，例如 0xff
+    // 带有小数部分或者二进制指数 p 的是十六进制浮点数，例如 0x1.8p3 = 1.5 * 2^3 = 12，
+    // 和 C、Rust 的语法一样，有小数部分时必须有指数部分，指数是十进制的，可以带符号
+    fn scan_hex(&mut self) -> Token {
+        let mantissa = self.scan_while(|c| c.is_ascii_hexdigit());
+        let mut literal = format!("0x{}", mantissa);
+
+        // 小数部分，两个点号是范围，例如 0x1..0xf
+        let fraction = if self.tokens.peek() == Some(&'.') && self.peek_second() != Some('.') {
+            self.tokens.next();
+            let fraction = self.scan_while(|c| c.is_ascii_hexdigit());
+            literal.push('.');
+            literal.push_str(&fraction);
+            Some(fraction)
+        } else {
+            None
+        };
+
+        // 二进制指数部分
+        let exponent = if matches!(self.tokens.peek(), Some('p' | 'P')) {
+            self.tokens.next();
+            let mut exponent = String::new();
+            if let Some(&sign @ ('+' | '-')) = self.tokens.peek() {
+                self.tokens.next();
+                exponent.push(sign);
+            }
+            exponent.push_str(&self.scan_digits());
+            literal.push('p');
+            literal.push_str(&exponent);
+            match exponent.parse::<i32>() {
+                Ok(exponent) => Some(exponent),
+                Err(_) => {
+                    return Token::Invalid(format!(
+                        "Malformed hex float {}: invalid exponent",
+                        literal
+                    ))
+                }
+            }
+        } else {
+            None
+        };
+
+        let digits = format!("{}{}", mantissa, fraction.as_deref().unwrap_or(""));
+        if digits.is_empty() {
+            return Token::Invalid(format!("Invalid number {}", literal));
+        }
+        let exponent = match (fraction.is_some(), exponent) {
+            (_, Some(exponent)) => exponent,
+            (true, None) => {
+                return Token::Invalid(format!("Malformed hex float {}: missing exponent", literal))
+            }
+            (false, None) => {
+                return match i64::from_str_radix(&mantissa, 16) {
+                    Ok(n) => Token::Number(n),
+                    Err(_) => Token::Invalid(format!("Invalid number {}", literal)),
+                }
+            }
+        };
+
+        // 每一位小数相当于指数减 4
+        let mut value = 0.0;
+        for c in digits.chars() {
+            value = value * 16.0 + c.to_digit(16).unwrap_or(0) as f64;
+        }
+        let shift = fraction.map_or(0, |f| f.len() as i32 * 4);
+        let value = value * 2f64.powi(exponent.saturating_sub(shift));
+        if value.is_finite() {
+            Token::Float(value)
+        } else {
+            Token::Invalid(format!("Hex float {} is out of range", literal))
+        }
+    }
+
+    // 获取下一个字符之后的字符
+    fn peek_second(&self) -> Option<char> {
+        let mut ahead = self.tokens.clone();
+        ahead.next();
+        ahead.next()
+    }
+
+    // 扫描连续的 ASCII 数字
+    fn scan_digits(&mut self) -> String {
+        self.scan_while(|c| c.is_ascii_digit())
+    }
+
+    // 扫描连续的满足条件的字符
+    fn scan_while(&mut self, pred: impl Fn(char) -> bool) -> String {
+        let mut digits = String::new();
+        while let Some(&c) = self.tokens.peek() {
+            if pred(c) {
+                digits.push(c);
+                self.tokens.next();
+            } else {
+                break;
+            }
+        }
+        digits
+    }
+
+    // 扫描标识符，字母或下划线开头，后面可以跟字母、数字、下划线
+    // 中间可以包含点号表示命名空间，例如 db.timeout，点号不能在结尾
+    // 连续的两个点号是范围，不属于标识符，例如 a..b
+    fn scan_identifier(&mut self) -> Option<Token> {
+        let mut name = String::new();
+        while let Some(&c) = self.tokens.peek() {
+            if c == '.' && self.peek_second() == Some('.') {
+                break;
+            }
+            if c.is_alphanumeric() || c == '_' || c == '.' {
+                name.push(c);
+                self.tokens.next();
+            } else {
+                break;
+            }
+        }
+
+        if name.ends_with('.') {
+            return Some(Token::Invalid(format!("Malformed identifier {}", name)));
+        }
+        // 关键字不能作为变量名或者函数名
+        match name.as_str() {
+            "let" => Some(Token::Let),
+            "in" => Some(Token::In),
+            _ => Some(Token::Ident(name)),
+        }
+    }
+
+    // 扫描双引号括起来的字符串，支持的转义字符有 \" \\ \n \t
+    fn scan_string(&mut self) -> Option<Token> {
+        self.tokens.next();
+        let mut s = String::new();
+        loop {
+            match self.tokens.next() {
+                Some('"') => return Some(Token::Str(s)),
+                Some('\\') => match self.tokens.next() {
+                    Some('"') => s.push('"'),
+                    Some('\\') => s.push('\\'),
+                    Some('n') => s.push('\n'),
+                    Some('t') => s.push('\t'),
+                    Some(c) => {
+                        return Some(Token::Invalid(format!("Unknown escape sequence \\{}", c)))
+                    }
+                    None => return Some(Token::Invalid("Unterminated string".into())),
+                },
+                Some(c) => s.push(c),
+                None => return Some(Token::Invalid("Unterminated string".into())),
+            }
+        }
+    }
+
+    // 扫描运算符号
+    fn scan_operator(&mut self) -> Option<Token> {
+        match self.tokens.next() {
+            Some('+') => Some(Token::Plus),
+            // 同时支持 Unicode 的减号 U+2212
+            Some('-' | '\u{2212}') => Some(Token::Minus),
+            Some('*') => Some(Token::Multiply),
+            // 两个斜杠是向下取整除，注释如果以后要支持的话需要使用其他的符号，例如 #
+            Some('/') => Some(self.scan_with('/', Token::FloorDiv, Token::Divide)),
+            Some('^') => Some(Token::Power),
+            Some('(') => Some(Token::LeftParen),
+            Some(')') => Some(Token::RightParen),
+            Some('[') => Some(Token::LeftBracket),
+            Some(']') => Some(Token::RightBracket),
+            Some('|') => Some(Token::Bar),
+            Some(',') => Some(Token::Comma),
+            Some('?') => Some(Token::Question),
+            Some(':') => Some(Token::Colon),
+            Some('.') if self.tokens.peek() == Some(&'.') => {
+                self.tokens.next();
+                Some(self.scan_with('=', Token::RangeInclusive, Token::Range))
+            }
+            Some('=') => Some(self.scan_with('=', Token::Equal, Token::Assign)),
+            Some('<') => Some(self.scan_with('=', Token::LessEqual, Token::Less)),
+            Some('>') => Some(self.scan_with('=', Token::GreaterEqual, Token::Greater)),
+            Some('!') => Some(self.scan_with('=', Token::NotEqual, Token::Bang)),
+            Some(c) => Some(Token::Invalid(format!("Unexpected character '{}'", c))),
+            None => None,
+        }
+    }
+
+    // 运算符后面紧跟 next 时是双字符的运算符，例如 <=、//，否则是单字符的运算符
+    fn scan_with(&mut self, next: char, double: Token, single: Token) -> Token {
+        if self.tokens.peek() == Some(&next) {
+            self.tokens.next();
+            return double;
+        }
+        single
+    }
+}
+
+// 实现 Iterator 接口，使 Tokenizer 可以通过 for 循环遍历
+impl Tokenizer<'_> {
+    // 解析下一个 Token，同时返回它的开始位置
+    fn next_with_pos(&mut self) -> Option<(Token, usize)> {
+        loop {
+            match self.scan() {
+                Some(Token::Invalid(_)) if self.lenient => continue,
+                token => return token.map(|token| (token, self.start)),
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for Tokenizer<'a> {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_with_pos().map(|(token, _)| token)
+    }
+}
+
+// 解析时使用的 Token 流，和 Peekable 一样可以向前看一个 Token，同时记录最近取出的 Token 的位置
+struct TokenStream<'a> {
+    tokenizer: Tokenizer<'a>,
+    peeked: Option<Option<(Token, usize)>>,
+    // 最近一次 next 返回的 Token 的开始位置（从 0 开始的字符位置）
+    pos: usize,
+}
+
+impl<'a> TokenStream<'a> {
+    fn new(tokenizer: Tokenizer<'a>) -> Self {
+        Self {
+            tokenizer,
+            peeked: None,
+            pos: 0,
+        }
+    }
+
+    fn peek(&mut self) -> Option<&Token> {
+        let tokenizer = &mut self.tokenizer;
+        self.peeked
+            .get_or_insert_with(|| tokenizer.next_with_pos())
+            .as_ref()
+            .map(|(token, _)| token)
+    }
+}
+
+impl Iterator for TokenStream<'_> {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Token> {
+        let next = match self.peeked.take() {
+            Some(next) => next,
+            None => self.tokenizer.next_with_pos(),
+        };
+        next.map(|(token, pos)| {
+            self.pos = pos;
+            token
+        })
+    }
+}
+
+// 统一表达式的空白，不改变表达式的含义，括号保持原样
+// 二元运算符和比较运算符两边各一个空格，逗号后面一个空格，
+// 一元负号后面、函数名和括号之间、括号和绝对值符号的内侧、范围符号两边都没有空格
+pub fn normalize(src: &str) -> Result<String> {
+    // 先完整解析一次，保证表达式合法
+    Expr::new(src).parse()?;
+
+    let mut out = String::new();
+    // 下一个 Token 是否应该是操作数，用来区分一元负号和减号
+    let mut expect_operand = true;
+    for token in Tokenizer::new(src) {
+        match token {
+            Token::Number(_) | Token::Ident(_) | Token::Str(_) => {
+                out.push_str(&token.to_string());
+                expect_operand = false;
+            }
+            // 整数值的浮点数需要保留小数点，否则会变成整数
+            Token::Float(f) => {
+                if f.fract() == 0.0 {
+                    out.push_str(&format!("{:.1}", f));
+                } else {
+                    out.push_str(&f.to_string());
+                }
+                expect_operand = false;
+            }
+            Token::Minus if expect_operand => out.push('-'),
+            Token::Bang => {
+                out.push('!');
+                expect_operand = false;
+            }
+            Token::Let => {
+                out.push_str("let ");
+                expect_operand = false;
+            }
+            // 需要操作数时是左边的绝对值符号，否则是右边的，两种情况下 expect_operand 都不变
+            Token::Bar => out.push('|'),
+            Token::LeftParen => {
+                out.push('(');
+                expect_operand = true;
+            }
+            Token::RightParen => {
+                out.push(')');
+                expect_operand = false;
+            }
+            Token::LeftBracket => {
+                out.push('[');
+                expect_operand = true;
+            }
+            Token::RightBracket => {
+                out.push(']');
+                expect_operand = false;
+            }
+            Token::Comma => {
+                out.push_str(", ");
+                expect_operand = true;
+            }
+            Token::Range | Token::RangeInclusive => {
+                out.push_str(&token.to_string());
+                expect_operand = true;
+            }
+            _ => {
+                out.push_str(&format!(" {} ", token));
+                expect_operand = true;
+            }
+        }
+    }
+    Ok(out)
+}
+
+// 计算选项
+#[derive(Debug, Clone, Copy, Default)]
+struct Options {
+    // 三角函数使用的角度单位
+    angle_mode: AngleMode,
+    // 严格整数除法，默认关闭
+    strict_division: bool,
+    // 分数模式，默认关闭
+    rational: bool,
+    // 整数溢出的处理方式，默认返回错误
+    overflow: Overflow,
+    // 安全模式，默认关闭，开启后拒绝可能消耗大量资源的运算
+    safe: bool,
+}
+
+// 安全模式下幂运算指数的绝对值上限
+const SAFE_MAX_EXPONENT: f64 = 64.0;
+// 安全模式下阶乘的参数上限
+const SAFE_MAX_FACTORIAL: i64 = 10;
+// 安全模式下列表的元素个数上限
+const SAFE_MAX_LIST_LEN: usize = 1000;
+// 安全模式下拼接得到的字符串的长度（字节数）上限
+const SAFE_MAX_STRING_LEN: usize = 4096;
+// 安全模式下 sum、product 的范围长度上限
+const SAFE_MAX_RANGE_LEN: i128 = 10000;
+
+fn not_allowed_in_safe_mode() -> ExprError {
+    ExprError::Parse("operation not allowed in safe mode".into())
+}
+
+// 语法树节点
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Number(i64),
+    Float(f64),
+    Ident(String),                                // 常量或者变量
+    Assign(String, Box<Node>),                    // 变量赋值
+    Call(String, Vec<Node>),                      // 函数调用
+    Negate(Box<Node>),                            // 取负数
+    Factorial(Box<Node>),                         // 阶乘 n!
+    Abs(Box<Node>),                               // 绝对值
+    Binary(Token, Box<Node>, Box<Node>),          // 二元运算
+    Comparison(Box<Node>, Vec<(Token, Node)>),    // 连续的比较运算
+    Range(Box<Node>, Box<Node>, bool), // 范围，只能作为 sum、product 的参数，bool 表示是否包括结尾
+    Conditional(Box<Node>, Box<Node>, Box<Node>), // 条件表达式 cond ? a : b
+    Str(String),                       // 字符串，值是 Value::Str
+    Let(String, Box<Node>, Box<Node>), // 局部变量 let name = value in body
+    Cached(usize, Box<Node>),          // 编译时标记的不依赖变量的子树，结果缓存在第 usize 个位置
+    List(Vec<Node>),                   // 列表，值是 Value::List
+    Index(Box<Node>, Box<Node>),       // 下标访问 list[i]，从 0 开始
+}
+
+// 变量的来源，例如数据库中的一行数据，或者按需计算的值
+// 没有这个变量时返回 None，计算时会报错 Unknown identifier
+pub trait VarSource {
+    fn get(&self, name: &str) -> Option<Value>;
+}
+
+impl VarSource for HashMap<String, Value> {
+    fn get(&self, name: &str) -> Option<Value> {
+        HashMap::get(self, name).cloned()
+    }
+}
+
+pub struct Expr<'a> {
+    src: &'a str,
+    iter: TokenStream<'a>,
+    options: Options,
+    // 变量环境
+    vars: HashMap<String, Value>,
+    // 剩余的计算步数，None 表示不限制
+    budget: Option<usize>,
+    // 自定义的运算符优先级和结合性，key 是运算符的符号，没有设置的运算符使用默认值
+    precedences: HashMap<String, (i32, i32)>,
+    // 宽松模式
+    lenient: bool,
+    // 表达式的最大长度（字节数），None 表示不限制
+    max_length: Option<usize>,
+    // 括号、函数调用、绝对值的最大嵌套层数，None 表示不限制
+    max_depth: Option<usize>,
+    // 解析时当前的嵌套层数
+    depth: usize,
+    // 函数调用的语法
+    call_syntax: CallSyntax,
+}
+
+impl<'a> Expr<'a> {
+    pub fn new(src: &'a str) -> Self {
+        Self {
+            src,
+            iter: TokenStream::new(Tokenizer::new(src)),
+            options: Options::default(),
+            vars: HashMap::new(),
+            budget: None,
+            precedences: HashMap::new(),
+            lenient: false,
+            max_length: None,
+            max_depth: None,
+            depth: 0,
+            call_syntax: CallSyntax::default(),
+        }
+    }
+
+    // 设置运算符的优先级和结合性，覆盖默认值，例如让 + 的优先级高于 *
+    // op 是运算符的符号，例如 "+"、"//"、"<="；优先级必须大于 0，越大越先计算，默认值为：
+    // 比较运算 1，+ - 为 2，* / // 为 3，^ 为 4
+    pub fn operator_precedence(mut self, op: &str, precedence: i32, assoc: i32) -> Result<Self> {
+        let mut tokens = Tokenizer::new(op);
+        let token = match (tokens.next(), tokens.next()) {
+            (Some(token), None) if token.is_operator() => token,
+            _ => return Err(ExprError::Syntax(format!("Unknown operator {}", op))),
+        };
+        if precedence < 1 {
+            return Err(ExprError::Syntax(format!(
+                "Invalid precedence {} for {}",
+                precedence, op
+            )));
+        }
+        if assoc != ASSOC_LEFT && assoc != ASSOC_RIGHT {
+            return Err(ExprError::Syntax(format!(
+                "Invalid associativity {} for {}",
+                assoc, op
+            )));
+        }
+        self.precedences
+            .insert(token.to_string(), (precedence, assoc));
+        Ok(self)
+    }
+
+    // 限制表达式的长度（字节数），超出时在词法分析之前返回错误，用于计算不受信任的输入
+    pub fn max_length(mut self, max: usize) -> Self {
+        self.max_length = Some(max);
+        self
+    }
+
+    // 限制括号、函数调用和绝对值的嵌套层数，超出时返回错误，例如 ((1)) 的嵌套层数为 2
+    pub fn max_depth(mut self, max: usize) -> Self {
+        self.max_depth = Some(max);
+        self
+    }
+
+    // 宽松模式，跳过无法识别的字符或者 Token 继续解析，例如 2 + @ 3 按照 2 + 3 计算，默认关闭
+    // 跳过的内容作为警告通过 eval_with_warnings 返回
+    pub fn lenient(mut self, lenient: bool) -> Self {
+        self.iter = TokenStream::new(Tokenizer::new(self.src).lenient(lenient));
+        self.lenient = lenient;
+        self
+    }
+
+    // 设置角度单位
+    pub fn angle_mode(mut self, mode: AngleMode) -> Self {
+        self.options.angle_mode = mode;
+        self
+    }
+
+    // 设置函数调用的语法，默认使用圆括号，使用方括号时 f(x) 是语法错误，反之亦然
+    // 使用方括号时标识符后面的方括号总是函数调用，列表和下标的语法不变，例如 [1, 2][0]
+    pub fn call_syntax(mut self, syntax: CallSyntax) -> Self {
+        self.call_syntax = syntax;
+        self
+    }
+
+    // 设置严格整数除法，开启后不能整除的整数除法会返回错误
+    pub fn strict_division(mut self, strict: bool) -> Self {
+        self.options.strict_division = strict;
+        self
+    }
+
+    // 设置分数模式，开启后整数除法得到约分后的分数，例如 1/3 + 1/6 的结果是 1/2
+    pub fn rational(mut self, rational: bool) -> Self {
+        self.options.rational = rational;
+        self
+    }
+
+    // 设置整数溢出的处理方式，默认返回错误，Overflow::Wrap 按照 64 位补码回绕
+    pub fn overflow(mut self, overflow: Overflow) -> Self {
+        self.options.overflow = overflow;
+        self
+    }
+
+    // 设置安全模式，用于计算不受信任的输入，和 max_length、eval_with_budget 一起使用
+    // 开启后拒绝指数过大的幂运算、参数过大的阶乘、元素过多的列表和过长的字符串拼接
+    pub fn safe_mode(mut self, safe: bool) -> Self {
+        self.options.safe = safe;
+        self
+    }
+
+    // 编译表达式，得到的语法树可以使用不同的变量重复计算
+    // 词法分析和语法分析只在编译时进行一次
+    pub fn compile(src: &str) -> Result<Compiled> {
+        let mut node = Expr::new(src).parse()?;
+        let mut slots = 0;
+        mark_cached(&mut node, &mut slots);
+        Ok(Compiled {
+            node,
+            options: Options::default(),
+            functions: HashMap::new(),
+            cache: Mutex::new(vec![None; slots]),
+        })
+    }
+
+    // 表达式中引用的变量，不包括函数名、常量和 let 定义的局部变量
+    // 可以在计算之前检查需要的变量是否都已经提供
+    pub fn free_variables(src: &str) -> Result<HashSet<String>> {
+        let node = Expr::new(src).parse()?;
+        let mut vars = HashSet::new();
+        collect_free_variables(&node, &mut vec![], &mut vars);
+        Ok(vars)
+    }
+
+    // 计算表达式，获取结果
+    pub fn eval(&mut self) -> Result<Value> {
+        let node = self.parse()?;
+        let mut evaluator = Evaluator::new(self.options, &self.vars);
+        evaluator.budget = self.budget;
+        let result = evaluator.evaluate(&node)?;
+        let assigned = evaluator.assigned;
+        self.vars.extend(assigned);
+        Ok(result)
+    }
+
+    // 计算表达式，同时返回宽松模式下跳过的内容，严格模式下没有警告
+    pub fn eval_with_warnings(&mut self) -> Result<(Value, Vec<String>)> {
+        let value = self.eval()?;
+        Ok((value, self.warnings()))
+    }
+
+    // 宽松模式下跳过的内容，每一项是对应的错误信息
+    fn warnings(&self) -> Vec<String> {
+        if !self.lenient {
+            return vec![];
+        }
+        Tokenizer::new(self.src)
+            .filter_map(|token| match token {
+                Token::Invalid(msg) => Some(msg),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // 限制计算的步数，解析和计算时每处理一个节点消耗一步，超出时返回错误
+    // 用于计算不受信任的表达式，防止过深的嵌套或者过大的表达式耗尽资源
+    pub fn eval_with_budget(&mut self, max_steps: usize) -> Result<Value> {
+        self.budget = Some(max_steps);
+        self.eval()
+    }
+
+    // 使用给定的变量计算表达式，表达式中的赋值不会影响传入的变量
+    pub fn eval_with_vars(&mut self, vars: &HashMap<String, Value>) -> Result<Value> {
+        self.eval_with_source(vars)
+    }
+
+    // 使用自定义的变量来源计算表达式，表达式中的赋值只在这次计算中有效
+    pub fn eval_with_source(&mut self, source: &dyn VarSource) -> Result<Value> {
+        let node = self.parse()?;
+        Evaluator::new(self.options, source).evaluate(&node)
+    }
+
+    // 解析完整的表达式，得到语法树
+    fn parse(&mut self) -> Result<Node> {
+        if let Some(max) = self.max_length {
+            if self.src.len() > max {
+                return Err(ExprError::Syntax(format!(
+                    "Expression is longer than {} bytes",
+                    max
+                )));
+            }
+        }
+        let node = self.parse_ternary()?;
+        // 如果还有 Token 没有处理，说明表达式存在错误
+        if let Some(token) = self.iter.peek() {
+            return Err(unexpected(token, "Unexpected end of expr"));
+        }
+        Ok(node)
+    }
+
+    // 解析单个 Token或者子表达式
+    // 后面可以跟着任意个下标，例如 xs[0]、(xs)[0]、f(x)[0]、[[1, 2]][0][1]
+    // 使用方括号调用函数时，标识符后面的左方括号仍然是函数调用
+    fn parse_atom(&mut self) -> Result<Node> {
+        let mut result = self.parse_primary()?;
+        while self.iter.peek() == Some(&Token::LeftBracket) {
+            self.iter.next();
+            self.enter_nested()?;
+            let index = self.parse_ternary()?;
+            self.depth -= 1;
+            match self.iter.next() {
+                Some(Token::RightBracket) => (),
+                Some(token) => return Err(unexpected(&token, "Expecting ]")),
+                None => return Err(ExprError::Syntax("Expecting ]".into())),
+            }
+            result = Node::Index(Box::new(result), Box::new(index));
+        }
+        Ok(result)
+    }
+
+    // 解析下标之前的部分
+    fn parse_primary(&mut self) -> Result<Node> {
+        consume_step(&mut self.budget)?;
+        match self.iter.peek() {
+            // 如果是数字的话，直接返回
+            Some(Token::Number(n)) => {
+                let val = *n;
+                self.iter.next();
+                Ok(Node::Number(val))
+            }
+            Some(Token::Float(f)) => {
+                let val = *f;
+                self.iter.next();
+                Ok(Node::Float(val))
+            }
+            Some(Token::Str(s)) => {
+                let val = s.clone();
+                self.iter.next();
+                Ok(Node::Str(val))
+            }
+            // 如果是标识符：
+            // 1. 后面跟着左括号（使用方括号调用时是左方括号）的是函数调用
+            // 2. 后面跟着等号的是变量赋值，等号右边是完整的表达式
+            // 3. 否则是常量或者变量，后面的左方括号是下标，内置函数名后面的左方括号提示使用圆括号调用
+            Some(Token::Ident(name)) => {
+                let name = name.clone();
+                self.iter.next();
+                let (open, close) = match self.call_syntax {
+                    CallSyntax::Parens => (Token::LeftParen, Token::RightParen),
+                    CallSyntax::Brackets => (Token::LeftBracket, Token::RightBracket),
+                };
+                match self.iter.peek() {
+                    Some(token) if *token == open => {
+                        self.iter.next();
+                        self.enter_nested()?;
+                        let args = self.parse_args(&close)?;
+                        self.depth -= 1;
+                        Ok(Node::Call(name, args))
+                    }
+                    Some(Token::LeftParen) => Err(ExprError::Syntax(format!(
+                        "Function calls use {}{}{}",
+                        name, open, close
+                    ))),
+                    Some(Token::LeftBracket) if is_builtin_function(&name) => Err(
+                        ExprError::Syntax(format!("Function calls use {}{}{}", name, open, close)),
+                    ),
+                    Some(Token::Assign) => {
+                        self.iter.next();
+                        Ok(Node::Assign(name, Box::new(self.parse_ternary()?)))
+                    }
+                    _ => Ok(Node::Ident(name)),
+                }
+            }
+            // 如果是 let 的话，解析局部变量 let name = value in body，body 尽可能向后延伸，
+            // 例如 let a = 2 in a * a + 1 等价于 let a = 2 in (a * a + 1)
+            Some(Token::Let) => {
+                self.iter.next();
+                let name = match self.iter.next() {
+                    Some(Token::Ident(name)) => name,
+                    Some(token) => return Err(unexpected(&token, "Expecting a variable name")),
+                    None => return Err(ExprError::Syntax("Expecting a variable name".into())),
+                };
+                match self.iter.next() {
+                    Some(Token::Assign) => (),
+                    Some(token) => return Err(unexpected(&token, "Unexpected character")),
+                    None => return Err(ExprError::Syntax("Expecting =".into())),
+                }
+                let value = self.parse_ternary()?;
+                match self.iter.next() {
+                    Some(Token::In) => (),
+                    Some(token) => return Err(unexpected(&token, "Unexpected character")),
+                    None => return Err(ExprError::Syntax("Expecting in".into())),
+                }
+                let body = self.parse_ternary()?;
+                Ok(Node::Let(name, Box::new(value), Box::new(body)))
+            }
+            // 如果是负号的话，解析后面的操作数，负号的优先级比幂运算低，例如 -2^2 = -4
+            // 连续的负号每个都是一元负号，例如 3 - -4 和 3 -- 4 都是 7
+            Some(Token::Minus) => {
+                self.iter.next();
+                let pos = self.iter.pos;
+                // 负号后面不是操作数时，报告这个负号的位置（从 0 开始的字符位置），例如 3 -- 和 (3 - -)
+                // 无法识别的 Token 由解析操作数时报告具体的错误
+                if !matches!(
+                    self.iter.peek(),
+                    Some(
+                        Token::Number(_)
+                            | Token::Float(_)
+                            | Token::Str(_)
+                            | Token::Ident(_)
+                            | Token::Let
+                            | Token::Minus
+                            | Token::Bar
+                            | Token::LeftBracket
+                            | Token::LeftParen
+                            | Token::Invalid(_)
+                    )
+                ) {
+                    return Err(ExprError::Syntax(format!(
+                        "Expecting an operand after '-' at position {}",
+                        pos
+                    )));
+                }
+                let (power_prec, _) = self.precedence_of(&Token::Power);
+                let operand = self.parse_expr(power_prec)?;
+                Ok(Node::Negate(Box::new(operand)))
+            }
+            // 如果是竖线的话，解析绝对值 |x|
+            // 竖线出现在操作数的位置时表示绝对值的开始，出现在运算符的位置时表示最内层绝对值的结束，
+            // 例如 ||x| - 1|，以后如果支持按位或，只能在运算符的位置并且没有未结束的绝对值时使用
+            Some(Token::Bar) => {
+                self.iter.next();
+                self.enter_nested()?;
+                let result = self.parse_ternary()?;
+                self.depth -= 1;
+                match self.iter.next() {
+                    Some(Token::Bar) => (),
+                    Some(token) => return Err(unexpected(&token, "Unexpected character")),
+                    None => return Err(ExprError::Syntax("Expecting closing |".into())),
+                }
+                Ok(Node::Abs(Box::new(result)))
+            }
+            // 如果是左方括号的话，解析列表 [a, b, c]
+            Some(Token::LeftBracket) => {
+                self.iter.next();
+                self.enter_nested()?;
+                let mut items = Vec::new();
+                if self.iter.peek() == Some(&Token::RightBracket) {
+                    self.iter.next();
+                } else {
+                    loop {
+                        items.push(self.parse_ternary()?);
+                        match self.iter.next() {
+                            Some(Token::Comma) => (),
+                            Some(Token::RightBracket) => break,
+                            Some(token) => return Err(unexpected(&token, "Expecting , or ]")),
+                            None => return Err(ExprError::Syntax("Expecting ]".into())),
+                        }
+                    }
+                }
+                self.depth -= 1;
+                Ok(Node::List(items))
+            }
+            // 如果是左括号的话，递归解析括号内的表达式
+            Some(Token::LeftParen) => {
+                self.iter.next();
+                self.enter_nested()?;
+                let result = self.parse_ternary()?;
+                self.depth -= 1;
+                match self.iter.next() {
+                    Some(Token::RightParen) => (),
+                    Some(token) => return Err(unexpected(&token, "Unexpected character")),
+                    None => return Err(ExprError::Syntax("Unexpected character".into())),
+                }
+                Ok(result)
+            }
+            Some(token) => Err(unexpected(token, "Expecting a number or left parenthesis")),
+            None => Err(ExprError::Syntax(
+                "Expecting a number or left parenthesis".into(),
+            )),
+        }
+    }
+
+    // 进入一层嵌套，超出最大嵌套层数时返回错误
+    fn enter_nested(&mut self) -> Result<()> {
+        self.depth += 1;
+        match self.max_depth {
+            Some(max) if self.depth > max => Err(ExprError::Syntax(format!(
+                "Expression is nested deeper than {} levels",
+                max
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    // 解析函数的参数列表，左括号已经被消费，以右括号结束
+    // 解析函数的参数，直到 close 为止
+    fn parse_args(&mut self, close: &Token) -> Result<Vec<Node>> {
+        let mut args = Vec::new();
+        if self.iter.peek() == Some(close) {
+            self.iter.next();
+            return Ok(args);
+        }
+
+        loop {
+            let arg = self.parse_ternary()?;
+            // 参数可以是一个范围，例如 sum(1..5)
+            let arg = match self.iter.peek() {
+                Some(Token::Range | Token::RangeInclusive) => {
+                    let inclusive = self.iter.next() == Some(Token::RangeInclusive);
+                    let end = self.parse_ternary()?;
+                    Node::Range(Box::new(arg), Box::new(end), inclusive)
+                }
+                _ => arg,
+            };
+            args.push(arg);
+            match self.iter.next() {
+                Some(Token::Comma) => continue,
+                Some(token) if token == *close => break,
+                Some(token) => return Err(unexpected(&token, "Unexpected character")),
+                None => return Err(ExprError::Syntax("Unexpected character".into())),
+            }
+        }
+        Ok(args)
+    }
+
+    // 解析条件表达式 cond ? a : b，优先级比所有的运算符都低，比赋值高，右结合
+    // 例如 x = a > 0 ? 1 : -1 等价于 x = ((a > 0) ? 1 : -1)，
+    // a ? b : c ? d : e 等价于 a ? b : (c ? d : e)
+    fn parse_ternary(&mut self) -> Result<Node> {
+        let cond = self.parse_expr(1)?;
+        if self.iter.peek() != Some(&Token::Question) {
+            return Ok(cond);
+        }
+        self.iter.next();
+        let then = self.parse_ternary()?;
+        match self.iter.next() {
+            Some(Token::Colon) => (),
+            Some(token) => return Err(unexpected(&token, "Unexpected character")),
+            None => return Err(ExprError::Syntax("Expecting :".into())),
+        }
+        let otherwise = self.parse_ternary()?;
+        Ok(Node::Conditional(
+            Box::new(cond),
+            Box::new(then),
+            Box::new(otherwise),
+        ))
+    }
+
+    fn parse_expr(&mut self, min_prec: i32) -> Result<Node> {
+        // 解析第一个 Token，后面可以跟着任意个阶乘，例如 3!!，阶乘的优先级比幂运算高，2^3! = 2^6
+        let mut atom_lhs = self.parse_atom()?;
+        while self.iter.peek() == Some(&Token::Bang) {
+            self.iter.next();
+            atom_lhs = Node::Factorial(Box::new(atom_lhs));
+        }
+
+        loop {
+            let cur_token = self.iter.peek();
+            if cur_token.is_none() {
+                break;
+            }
+            let token = cur_token.unwrap().clone();
+
+            // 1. Token 一定是运算符
+            // 2. Token 的优先级必须大于等于 min_prec
+            let (prec, assoc) = self.precedence_of(&token);
+            if !token.is_operator() || prec < min_prec {
+                break;
+            }
+
+            let mut next_prec = prec;
+            if assoc == ASSOC_LEFT {
+                next_prec += 1;
+            }
+
+            self.iter.next();
+
+            // 连续的比较运算，例如 1 < x < 10，等价于 1 < x && x < 10
+            if token.is_comparison() {
+                atom_lhs = self.parse_comparison(token, atom_lhs, next_prec)?;
+                continue;
+            }
+
+            // 递归解析右边的表达式
+            let atom_rhs = self.parse_expr(next_prec)?;
+
+            // 得到了两边的表达式，组成二元运算
+            atom_lhs = Node::Binary(token, Box::new(atom_lhs), Box::new(atom_rhs));
+        }
+        Ok(atom_lhs)
+    }
+
+    // 获取运算符的优先级和结合性，优先使用自定义的值
+    fn precedence_of(&self, token: &Token) -> (i32, i32) {
+        self.precedences
+            .get(&token.to_string())
+            .copied()
+            .unwrap_or((token.precedence(), token.assoc()))
+    }
+
+    // 解析一串连续的比较运算
+    // 括号内的比较是一个单独的表达式，例如 (1 < 2) < 3，不会被当作连续比较
+    fn parse_comparison(&mut self, token: Token, lhs: Node, next_prec: i32) -> Result<Node> {
+        let mut comparisons = vec![(token, self.parse_expr(next_prec)?)];
+        while let Some(token) = self.iter.peek() {
+            if !token.is_comparison() {
+                break;
+            }
+            let op = token.clone();
+            self.iter.next();
+            comparisons.push((op, self.parse_expr(next_prec)?));
+        }
+        Ok(Node::Comparison(Box::new(lhs), comparisons))
+    }
+}
+
+// 收集语法树中引用的变量，bound 是当前作用范围内 let 定义的局部变量
+// 二元运算沿着左子树循环处理
+fn collect_free_variables(mut node: &Node, bound: &mut Vec<String>, vars: &mut HashSet<String>) {
+    while let Node::Binary(_, l, r) = node {
+        collect_free_variables(r, bound, vars);
+        node = l;
+    }
+    match node {
+        Node::Number(_) | Node::Float(_) | Node::Str(_) => (),
+        Node::Ident(name) => {
+            if constant(name).is_none() && !bound.contains(name) {
+                vars.insert(name.clone());
+            }
+        }
+        // 赋值的变量不需要提供，只收集右边引用的变量
+        Node::Assign(_, value) => collect_free_variables(value, bound, vars),
+        Node::Call(_, args) => args
+            .iter()
+            .for_each(|arg| collect_free_variables(arg, bound, vars)),
+        Node::Negate(n) | Node::Abs(n) | Node::Factorial(n) => {
+            collect_free_variables(n, bound, vars)
+        }
+        Node::Binary(_, l, r) | Node::Range(l, r, _) => {
+            collect_free_variables(l, bound, vars);
+            collect_free_variables(r, bound, vars);
+        }
+        Node::Comparison(first, rest) => {
+            collect_free_variables(first, bound, vars);
+            rest.iter()
+                .for_each(|(_, n)| collect_free_variables(n, bound, vars));
+        }
+        Node::Conditional(cond, a, b) => {
+            collect_free_variables(cond, bound, vars);
+            collect_free_variables(a, bound, vars);
+            collect_free_variables(b, bound, vars);
+        }
+        Node::Let(name, value, body) => {
+            collect_free_variables(value, bound, vars);
+            bound.push(name.clone());
+            collect_free_variables(body, bound, vars);
+            bound.pop();
+        }
+        Node::Cached(_, n) => collect_free_variables(n, bound, vars),
+        Node::List(items) => items
+            .iter()
+            .for_each(|item| collect_free_variables(item, bound, vars)),
+        Node::Index(list, index) => {
+            collect_free_variables(list, bound, vars);
+            collect_free_variables(index, bound, vars);
+        }
+    }
+}
+
+// 子树是否不依赖任何变量，也没有赋值，这样的子树每次计算的结果都相同
+// 保守地把 let 定义的局部变量也当作变量，例如 let a = 1 in a 不会被缓存
+// 二元运算沿着左子树循环处理，和计算时一样不会因为很长的表达式递归过深
+fn is_pure(mut node: &Node) -> bool {
+    while let Node::Binary(_, l, r) = node {
+        if !is_pure(r) {
+            return false;
+        }
+        node = l;
+    }
+    match node {
+        Node::Number(_) | Node::Float(_) | Node::Str(_) => true,
+        Node::Ident(name) => constant(name).is_some(),
+        Node::Assign(..) | Node::Let(..) => false,
+        Node::Call(_, args) | Node::List(args) => args.iter().all(is_pure),
+        Node::Negate(n) | Node::Abs(n) | Node::Factorial(n) | Node::Cached(_, n) => is_pure(n),
+        Node::Binary(_, l, r) | Node::Range(l, r, _) | Node::Index(l, r) => {
+            is_pure(l) && is_pure(r)
+        }
+        Node::Comparison(first, rest) => is_pure(first) && rest.iter().all(|(_, n)| is_pure(n)),
+        Node::Conditional(cond, a, b) => is_pure(cond) && is_pure(a) && is_pure(b),
+    }
+}
+
+// 左子树链从上往下连续有多少层二元运算依赖变量，不是二元运算时返回 0
+fn impure_levels(mut node: &Node) -> usize {
+    let mut pure_rhs = Vec::new();
+    while let Node::Binary(_, l, r) = node {
+        pure_rhs.push(is_pure(r));
+        node = l;
+    }
+    if !is_pure(node) {
+        return pure_rhs.len();
+    }
+    pure_rhs.iter().rposition(|pure| !pure).map_or(0, |i| i + 1)
+}
+
+// 将语法树中最大的不依赖变量的子树包装成 Node::Cached，slots 是已经分配的缓存位置数量
+// 数字、字符串这样的叶子节点不需要缓存，范围本身不是一个值，列表字面量取下标时只计算选中的元素，
+// 这两种节点都在使用它们的函数调用或者下标这一层缓存
+// 二元运算沿着左子树循环处理，先一次算出左子树链上有多少层依赖变量，避免每一层都重新检查整个子树
+fn mark_cached(mut node: &mut Node, slots: &mut usize) {
+    for _ in 0..impure_levels(node) {
+        let Node::Binary(_, l, r) = node else { break };
+        mark_cached(r, slots);
+        node = l;
+    }
+    match node {
+        Node::Number(_) | Node::Float(_) | Node::Str(_) | Node::Ident(_) => return,
+        Node::Range(..) | Node::List(_) => (),
+        _ if is_pure(node) => {
+            let inner = std::mem::replace(node, Node::Number(0));
+            *node = Node::Cached(*slots, Box::new(inner));
+            *slots += 1;
+            return;
+        }
+        _ => (),
+    }
+    match node {
+        Node::Assign(_, n)
+        | Node::Negate(n)
+        | Node::Abs(n)
+        | Node::Factorial(n)
+        | Node::Cached(_, n) => mark_cached(n, slots),
+        Node::Call(_, args) | Node::List(args) => {
+            args.iter_mut().for_each(|arg| mark_cached(arg, slots))
+        }
+        Node::Binary(_, l, r) | Node::Range(l, r, _) | Node::Let(_, l, r) | Node::Index(l, r) => {
+            mark_cached(l, slots);
+            mark_cached(r, slots);
+        }
+        Node::Comparison(first, rest) => {
+            mark_cached(first, slots);
+            rest.iter_mut().for_each(|(_, n)| mark_cached(n, slots));
+        }
+        Node::Conditional(cond, a, b) => {
+            mark_cached(cond, slots);
+            mark_cached(a, slots);
+            mark_cached(b, slots);
+        }
+        Node::Number(_) | Node::Float(_) | Node::Str(_) | Node::Ident(_) => (),
+    }
+}
+
+// 自定义函数，参数个数由函数自己检查
+type CustomFunction = dyn Fn(&[Value]) -> Result<Value> + Send + Sync;
+
+// 编译后的表达式，保存解析得到的语法树，适用于使用不同的变量重复计算同一个表达式
+// 不依赖变量的子树只在第一次计算成功时计算一次，之后的计算直接使用缓存的结果
+pub struct Compiled {
+    node: Node,
+    options: Options,
+    // 自定义函数
+    functions: HashMap<String, Box<CustomFunction>>,
+    // 不依赖变量的子树的计算结果，下标是 Node::Cached 中的位置
+    cache: Mutex<Vec<Option<Value>>>,
+}
+
+impl Compiled {
+    // 设置角度单位
+    pub fn angle_mode(mut self, mode: AngleMode) -> Self {
+        self.options.angle_mode = mode;
+        self.clear_cache();
+        self
+    }
+
+    // 设置严格整数除法
+    pub fn strict_division(mut self, strict: bool) -> Self {
+        self.options.strict_division = strict;
+        self.clear_cache();
+        self
+    }
+
+    // 设置分数模式
+    pub fn rational(mut self, rational: bool) -> Self {
+        self.options.rational = rational;
+        self.clear_cache();
+        self
+    }
+
+    // 设置整数溢出的处理方式
+    pub fn overflow(mut self, overflow: Overflow) -> Self {
+        self.options.overflow = overflow;
+        self.clear_cache();
+        self
+    }
+
+    // 设置安全模式
+    pub fn safe_mode(mut self, safe: bool) -> Self {
+        self.options.safe = safe;
+        self.clear_cache();
+        self
+    }
+
+    // 注册自定义函数，内置函数优先，参数都是常量的调用结果会被缓存，所以函数必须是纯函数
+    pub fn function(
+        mut self,
+        name: &str,
+        f: impl Fn(&[Value]) -> Result<Value> + Send + Sync + 'static,
+    ) -> Self {
+        self.functions.insert(name.to_string(), Box::new(f));
+        self.clear_cache();
+        self
+    }
+
+    // 设置改变之后，之前缓存的结果不再有效
+    fn clear_cache(&mut self) {
+        self.cache.get_mut().unwrap().fill(None);
+    }
+
+    // 使用给定的变量计算表达式，表达式中的赋值不会影响传入的变量
+    pub fn eval(&self, vars: &HashMap<String, Value>) -> Result<Value> {
+        self.eval_with_source(vars)
+    }
+
+    // 使用自定义的变量来源计算表达式
+    pub fn eval_with_source(&self, source: &dyn VarSource) -> Result<Value> {
+        let mut evaluator = Evaluator::new(self.options, source);
+        evaluator.functions = Some(&self.functions);
+        evaluator.cache = Some(&self.cache);
+        evaluator.evaluate(&self.node)
+    }
+}
+
+// 计算语法树
+// 表达式中赋值的变量保存在 assigned 中，不会修改传入的变量
+struct Evaluator<'v> {
+    options: Options,
+    vars: &'v dyn VarSource,
+    assigned: HashMap<String, Value>,
+    // let 定义的局部变量，后定义的在后面，离开作用范围时移除
+    scopes: Vec<(String, Value)>,
+    // 剩余的计算步数，None 表示不限制
+    budget: Option<usize>,
+    // 自定义函数，只有编译后的表达式才有
+    functions: Option<&'v HashMap<String, Box<CustomFunction>>>,
+    // 不依赖变量的子树的计算结果，只有编译后的表达式才有
+    cache: Option<&'v Mutex<Vec<Option<Value>>>>,
+}
+
+impl<'v> Evaluator<'v> {
+    fn new(options: Options, vars: &'v dyn VarSource) -> Self {
+        Self {
+            options,
+            vars,
+            assigned: HashMap::new(),
+            scopes: Vec::new(),
+            budget: None,
+            functions: None,
+            cache: None,
+        }
+    }
+
+    fn evaluate(&mut self, node: &Node) -> Result<Value> {
+        consume_step(&mut self.budget)?;
+        match node {
+            Node::Number(n) => Ok(Value::Int(*n)),
+            Node::Float(f) => Ok(Value::Float(*f)),
+            Node::Ident(name) => self.lookup(name),
+            Node::Assign(name, value) => self.assign(name, value),
+            Node::Let(name, value, body) => self.let_in(name, value, body),
+            Node::Cached(slot, inner) => {
+                if let Some(value) = self
+                    .cache
+                    .and_then(|cache| cache.lock().unwrap()[*slot].clone())
+                {
+                    return Ok(value);
+                }
+                // 计算出错时不缓存，下次计算时会重新计算
+                let value = self.evaluate(inner)?;
+                if let Some(cache) = self.cache {
+                    cache.lock().unwrap()[*slot] = Some(value.clone());
+                }
+                Ok(value)
+            }
+            Node::Call(name, args) if is_aggregate(name) => self.aggregate(name, args),
+            Node::Call(name, args) if is_string_function(name) => self.string_function(name, args),
+            Node::Call(name, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| self.evaluate(arg))
+                    .collect::<Result<Vec<_>>>()?;
+                self.call_function(name, args)
+            }
+            Node::Negate(operand) => match self.evaluate(operand)? {
+                Value::Int(n) => match self.options.overflow {
+                    Overflow::Checked => n
+                        .checked_neg()
+                        .map(Value::Int)
+                        .ok_or(ExprError::Overflow("Integer overflow".into())),
+                    Overflow::Wrap => Ok(Value::Int(n.wrapping_neg())),
+                },
+                Value::Float(f) => Ok(Value::Float(-f)),
+                Value::Rational(r) => r.checked_neg().map(Value::Rational),
+                Value::Bool(_) => Err(ExprError::Parse("Invalid boolean operand for -".into())),
+                Value::Str(_) => Err(ExprError::Parse("Invalid string operand for -".into())),
+                Value::List(_) => Err(ExprError::Parse("Invalid list operand for -".into())),
+            },
+            Node::Abs(operand) => match self.evaluate(operand)? {
+                Value::Int(n) => match self.options.overflow {
+                    Overflow::Checked => n
+                        .checked_abs()
+                        .map(Value::Int)
+                        .ok_or(ExprError::Overflow("Integer overflow".into())),
+                    Overflow::Wrap => Ok(Value::Int(n.wrapping_abs())),
+                },
+                Value::Float(f) => Ok(Value::Float(f.abs())),
+                Value::Rational(r) => r.checked_abs().map(Value::Rational),
+                Value::Bool(_) => Err(ExprError::Parse("Invalid boolean operand for |".into())),
+                Value::Str(_) => Err(ExprError::Parse("Invalid string operand for |".into())),
+                Value::List(_) => Err(ExprError::Parse("Invalid list operand for |".into())),
+            },
+            Node::Factorial(operand) => {
+                let value = self.evaluate(operand)?;
+                self.factorial(value)
+            }
+            Node::Binary(..) => self.binary_chain(node),
+            Node::Comparison(lhs, comparisons) => self.compare_chain(lhs, comparisons),
+            // 只计算选中的分支
+            Node::Conditional(cond, then, otherwise) => match self.evaluate(cond)? {
+                Value::Bool(true) => self.evaluate(then),
+                Value::Bool(false) => self.evaluate(otherwise),
+                _ => Err(ExprError::Parse("Condition must be a boolean".into())),
+            },
+            Node::Str(s) => Ok(Value::Str(s.clone())),
+            Node::Range(..) => Err(ExprError::Parse(
+                "Range is only allowed in sum and product".into(),
+            )),
+            Node::List(items) if self.options.safe && items.len() > SAFE_MAX_LIST_LEN => {
+                Err(not_allowed_in_safe_mode())
+            }
+            Node::List(items) => items
+                .iter()
+                .map(|item| self.evaluate(item))
+                .collect::<Result<Vec<_>>>()
+                .map(Value::List),
+            Node::Index(list, index) => match &**list {
+                // 直接对列表字面量取下标时只计算选中的元素
+                Node::List(items) if !(self.options.safe && items.len() > SAFE_MAX_LIST_LEN) => {
+                    let i = self.list_index(index, items.len())?;
+                    self.evaluate(&items[i])
+                }
+                list => match self.evaluate(list)? {
+                    Value::List(mut items) => {
+                        let i = self.list_index(index, items.len())?;
+                        Ok(items.swap_remove(i))
+                    }
+                    _ => Err(ExprError::Parse("Only lists can be indexed".into())),
+                },
+            },
+        }
+    }
+
+    // 计算一串左结合的二元运算，例如 1 + 2 - 3 的语法树是 ((1 + 2) - 3)
+    // 沿着左子树循环展开，很长的表达式也不会因为递归过深耗尽栈空间
+    fn binary_chain(&mut self, mut node: &Node) -> Result<Value> {
+        let mut operands = Vec::new();
+        while let Node::Binary(op, lhs, rhs) = node {
+            // 最外层的节点已经消耗过一步
+            if !operands.is_empty() {
+                consume_step(&mut self.budget)?;
+            }
+            operands.push((op, rhs));
+            node = lhs;
+        }
+        let mut value = self.evaluate(node)?;
+        for (op, rhs) in operands.into_iter().rev() {
+            let rhs = self.evaluate(rhs)?;
+            value = self.binary(op, value, rhs)?;
+        }
+        Ok(value)
+    }
+
+    fn binary(&mut self, op: &Token, lhs: Value, rhs: Value) -> Result<Value> {
+        if self.options.safe && *op == Token::Power && rhs.as_f64().abs() > SAFE_MAX_EXPONENT {
+            return Err(not_allowed_in_safe_mode());
+        }
+        let value = op.compute(
+            lhs,
+            rhs,
+            self.options.strict_division,
+            self.options.rational,
+            self.options.overflow,
+        )?;
+        match value {
+            Value::Str(s) if self.options.safe && s.len() > SAFE_MAX_STRING_LEN => {
+                Err(not_allowed_in_safe_mode())
+            }
+            value => Ok(value),
+        }
+    }
+
+    // 计算下标，必须是整数，并且在 [0, len) 之间
+    fn list_index(&mut self, index: &Node, len: usize) -> Result<usize> {
+        let i = match self.evaluate(index)? {
+            Value::Int(i) => i,
+            _ => return Err(ExprError::Parse("List index must be an integer".into())),
+        };
+        match usize::try_from(i) {
+            Ok(i) if i < len => Ok(i),
+            _ => Err(ExprError::Parse(format!(
+                "List index {} out of bounds for length {}",
+                i, len
+            ))),
+        }
+    }
+
+    // 变量赋值，赋值表达式的值就是变量的新值
+    // 已经存在的变量会被覆盖，常量不能被赋值
+    fn assign(&mut self, name: &str, value: &Node) -> Result<Value> {
+        if constant(name).is_some() {
+            return Err(ExprError::Parse(format!(
+                "Cannot assign to constant {}",
+                name
+            )));
+        }
+        let value = self.evaluate(value)?;
+        self.assigned.insert(name.to_string(), value.clone());
+        Ok(value)
+    }
+
+    // 在局部变量的作用范围内计算 body，内层的同名变量会覆盖外层的变量
+    fn let_in(&mut self, name: &str, value: &Node, body: &Node) -> Result<Value> {
+        if constant(name).is_some() {
+            return Err(ExprError::Parse(format!(
+                "Cannot assign to constant {}",
+                name
+            )));
+        }
+        let value = self.evaluate(value)?;
+        self.scopes.push((name.to_string(), value));
+        let result = self.evaluate(body);
+        self.scopes.pop();
+        result
+    }
+
+    // 获取常量或者变量的值，局部变量优先
+    fn lookup(&self, name: &str) -> Result<Value> {
+        constant(name)
+            .or_else(|| {
+                self.scopes
+                    .iter()
+                    .rev()
+                    .find(|(n, _)| n == name)
+                    .map(|(_, v)| v.clone())
+            })
+            .or_else(|| self.assigned.get(name).cloned())
+            .or_else(|| self.vars.get(name))
+            .ok_or(ExprError::UndefinedVariable(name.to_string()))
+    }
+
+    // 计算一串连续的比较运算，每个比较的右边同时作为下一个比较的左边，只会计算一次
+    // 所有的比较都成立时结果为 true，方向可以不同，例如 1 < x > 0 等价于 1 < x && x > 0
+    fn compare_chain(&mut self, lhs: &Node, comparisons: &[(Token, Node)]) -> Result<Value> {
+        let mut lhs = self.evaluate(lhs)?;
+        let mut result = true;
+        for (op, rhs) in comparisons {
+            let rhs = self.evaluate(rhs)?;
+            result &= op.compare(&lhs, &rhs)?;
+            lhs = rhs;
+        }
+        Ok(Value::Bool(result))
+    }
+
+    // 计算字符串函数，len 返回字符的个数（参数是列表时返回元素的个数），ord 返回单个字符的 Unicode 码点
+    fn string_function(&mut self, name: &str, args: &[Node]) -> Result<Value> {
+        consume_step(&mut self.budget)?;
+        let value = match args {
+            [arg] => self.evaluate(arg)?,
+            _ => {
+                return Err(ExprError::Parse(format!(
+                    "Function {} expects a string argument",
+                    name
+                )))
+            }
+        };
+        let s = match (name, value) {
+            (_, Value::Str(s)) => s,
+            ("len", Value::List(items)) => return Ok(Value::Int(items.len() as i64)),
+            ("len", _) => {
+                return Err(ExprError::Parse(
+                    "Function len expects a string or list argument".into(),
+                ))
+            }
+            _ => {
+                return Err(ExprError::Parse(format!(
+                    "Function {} expects a string argument",
+                    name
+                )))
+            }
+        };
+        match name {
+            "len" => Ok(Value::Int(s.chars().count() as i64)),
+            "ord" => {
+                let mut chars = s.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => Ok(Value::Int(c as i64)),
+                    _ => Err(ExprError::Parse(
+                        "Function ord expects a single character".into(),
+                    )),
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    // 计算阶乘，参数必须是非负整数，0! = 1
+    fn factorial(&mut self, value: Value) -> Result<Value> {
+        let n = match value {
+            Value::Int(n) if n >= 0 => n,
+            _ => {
+                return Err(ExprError::Parse(
+                    "Factorial expects a non-negative integer".into(),
+                ))
+            }
+        };
+        if self.options.safe && n > SAFE_MAX_FACTORIAL {
+            return Err(not_allowed_in_safe_mode());
+        }
+        let mut acc: i64 = 1;
+        for i in 2..=n {
+            // 每次乘法消耗一步计算预算
+            consume_step(&mut self.budget)?;
+            acc = acc
+                .checked_mul(i)
+                .ok_or(ExprError::Overflow("Integer overflow in factorial".into()))?;
+        }
+        Ok(Value::Int(acc))
+    }
+
+    // 计算范围上的聚合函数，sum 求和，product 求积
+    // a..b 不包括结尾，a..=b 包括结尾，范围的起止必须是整数
+    // 空的范围返回单位元，sum 为 0，product 为 1
+    fn aggregate(&mut self, name: &str, args: &[Node]) -> Result<Value> {
+        let (start, end, inclusive) = match args {
+            [Node::Range(start, end, inclusive)] => (start, end, *inclusive),
+            _ => {
+                return Err(ExprError::Parse(format!(
+                    "Function {} expects a range argument",
+                    name
+                )))
+            }
+        };
+        let (start, end) = match (self.evaluate(start)?, self.evaluate(end)?) {
+            (Value::Int(start), Value::Int(end)) => (start, end),
+            _ => return Err(ExprError::Parse("Range bounds must be integers".into())),
+        };
+        // 用 i128 计算结尾，避免 i64::MIN 减一溢出
+        let end = if inclusive {
+            end as i128
+        } else {
+            end as i128 - 1
+        };
+        if self.options.safe && end - start as i128 + 1 > SAFE_MAX_RANGE_LEN {
+            return Err(not_allowed_in_safe_mode());
+        }
+
+        let sum = name == "sum";
+        let mut acc: i64 = if sum { 0 } else { 1 };
+        for n in start as i128..=end {
+            let n = n as i64;
+            // 每个元素消耗一步计算预算，避免过大的范围
+            consume_step(&mut self.budget)?;
+            let next = if sum {
+                acc.checked_add(n)
+            } else {
+                acc.checked_mul(n)
+            };
+            acc = next.ok_or(ExprError::Overflow(format!("Integer overflow in {}", name)))?;
+        }
+        Ok(Value::Int(acc))
+    }
+
+    // 调用内置函数
+    fn call_function(&self, name: &str, args: Vec<Value>) -> Result<Value> {
+        let arity = match function_arity(name) {
+            Some(arity) => arity,
+            None => {
+                return match self.functions.and_then(|functions| functions.get(name)) {
+                    Some(f) => f(&args),
+                    None => Err(ExprError::UnknownFunction(name.to_string())),
+                }
+            }
+        };
+        if args.len() != arity {
+            return Err(ExprError::Parse(format!(
+                "Function {} expects {} arguments, got {}",
+                name,
+                arity,
+                args.len()
+            )));
+        }
+
+        if args
+            .iter()
+            .any(|arg| matches!(arg, Value::Bool(_) | Value::Str(_) | Value::List(_)))
+        {
+            return Err(ExprError::Parse(format!(
+                "Function {} expects numeric arguments",
+                name
+            )));
+        }
+
+        match name {
+            "round" | "floor" | "ceil" | "trunc" => return Ok(round(name, &args[0])),
+            "clamp" => return clamp(&args),
+            "powmod" => return powmod(&args),
+            "gcd" | "lcm" => return gcd_lcm(name, &args),
+            _ => (),
+        }
+
+        let x = args[0].as_f64();
+        let result = match name {
+            "sin" => self.input_angle(x).sin(),
+            "cos" => self.input_angle(x).cos(),
+            "tan" => self.input_angle(x).tan(),
+            "asin" => self.output_angle(x.asin()),
+            "acos" => self.output_angle(x.acos()),
+            "atan" => self.output_angle(x.atan()),
+            "atan2" => self.output_angle(x.atan2(args[1].as_f64())),
+            "deg" => x.to_degrees(),
+            "rad" => x.to_radians(),
+            "sqrt" => x.sqrt(),
+            _ => unreachable!(),
+        };
+
+        // 参数超出定义域时结果为 NaN，例如 asin(2)
+        if result.is_nan() {
+            return Err(ExprError::Parse(format!(
+                "Argument out of domain for {}",
+                name
+            )));
+        }
+        Ok(Value::Float(result))
+    }
+
+    // 将当前角度单位的值转换为弧度
+    fn input_angle(&self, x: f64) -> f64 {
+        match self.options.angle_mode {
+            AngleMode::Radians => x,
+            AngleMode::Degrees => x.to_radians(),
+        }
+    }
+
+    // 将弧度转换为当前角度单位的值
+    fn output_angle(&self, x: f64) -> f64 {
+        match self.options.angle_mode {
+            AngleMode::Radians => x,
+            AngleMode::Degrees => x.to_degrees(),
+        }
+    }
+}
+
+// 会话，在多次计算之间保存变量，适用于 REPL 或者脚本
+// 每次 eval 计算一条语句，例如先执行 x = 5，之后的语句中就可以使用 x
+// 1. 使用未定义的变量会返回错误，会话中已有的变量不受影响
+// 2. 对已定义的变量重新赋值会覆盖原来的值
+// 3. 常量（例如 pi）不能被赋值
+#[derive(Default)]
+pub struct Session {
+    vars: HashMap<String, Value>,
+    angle_mode: AngleMode,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // 设置角度单位
+    pub fn angle_mode(mut self, mode: AngleMode) -> Self {
+        self.angle_mode = mode;
+        self
+    }
+
+    // 计算一条语句，语句中的赋值会保存到会话中
+    pub fn eval(&mut self, line: &str) -> Result<Value> {
+        let mut expr = Expr::new(line).angle_mode(self.angle_mode);
+        expr.vars = std::mem::take(&mut self.vars);
+        let result = expr.eval();
+        self.vars = expr.vars;
+        result
+    }
+
+    // 获取变量的值
+    pub fn get(&self, name: &str) -> Option<Value> {
+        self.vars.get(name).cloned()
+    }
+
+    // 获取所有的变量
+    pub fn vars(&self) -> &HashMap<String, Value> {
+        &self.vars
+    }
+}
+
+// 计算多行定义，每行是一个 name = expr，按照顺序计算，后面的定义可以使用前面定义的变量，返回所有的变量
+// 字符串之外 # 之后的内容是注释，空行会被忽略；使用之后才定义的变量（包括循环引用）会返回错误
+// 每一行的错误都通过 ExprError::Line 返回，包括出错的行号
+pub fn eval_program(src: &str) -> Result<HashMap<String, Value>> {
+    let mut lines = Vec::new();
+    for (i, line) in src.lines().enumerate() {
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        let name = match line.split_once('=') {
+            Some((name, rest)) if is_identifier(name.trim()) && !rest.starts_with('=') => {
+                name.trim()
+            }
+            _ => {
+                return Err(ExprError::Line(
+                    i + 1,
+                    Box::new(ExprError::Syntax(
+                        "Expecting a definition name = expr".into(),
+                    )),
+                ))
+            }
+        };
+        lines.push((i + 1, name, line));
+    }
+
+    let mut session = Session::new();
+    for (pos, (lineno, _, line)) in lines.iter().enumerate() {
+        let err = match session.eval(line) {
+            Ok(_) => continue,
+            // 变量在之后的行中才定义
+            Err(ExprError::UndefinedVariable(name))
+                if lines[pos..].iter().any(|(_, defined, _)| *defined == name) =>
+            {
+                ExprError::Parse(format!("{} is used before it is defined", name))
+            }
+            Err(err) => err,
+        };
+        return Err(ExprError::Line(*lineno, Box::new(err)));
+    }
+    Ok(session.vars)
+}
+
+// 去掉一行中 # 之后的注释，字符串中的 # 不是注释
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => (),
+        }
+    }
+    line
+}
+
+// 是否是合法的变量名，和解析标识符的规则相同
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_alphanumeric() || c == '_' || c == '.')
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        eval_program, format_grouped, format_result, format_result_radix, normalize, AngleMode,
+        CallSyntax, Expr, ExprError, Overflow, Rational, Result, Session, Value, VarSource,
+        ASSOC_LEFT, ASSOC_RIGHT,
+    };
+    use std::{
+        collections::{HashMap, HashSet},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    fn assert_float(value: Value, expected: f64) {
+        match value {
+            Value::Float(v) => assert!((v - expected).abs() < 1e-9, "{} != {}", v, expected),
+            _ => panic!("expected float, got {:?}", value),
+        }
+    }
+
+    #[test]
+    fn test_inverse_trig() -> Result<()> {
+        assert_float(Expr::new("asin(1)").eval()?, std::f64::consts::FRAC_PI_2);
+        assert_float(Expr::new("acos(1)").eval()?, 0.0);
+        assert_float(Expr::new("atan(1)").eval()?, std::f64::consts::FRAC_PI_4);
+        assert_float(
+            Expr::new("atan2(1, 1)").eval()?,
+            std::f64::consts::FRAC_PI_4,
+        );
+        assert_float(Expr::new("atan2(0, 0 - 1)").eval()?, std::f64::consts::PI);
+        Ok(())
+    }
+
+    #[test]
+    fn test_inverse_trig_degrees() -> Result<()> {
+        let eval = |src| Expr::new(src).angle_mode(AngleMode::Degrees).eval();
+        assert_float(eval("asin(1)")?, 90.0);
+        assert_float(eval("acos(0)")?, 90.0);
+        assert_float(eval("atan(1)")?, 45.0);
+        assert_float(eval("atan2(1, 1)")?, 45.0);
+        assert_float(eval("sin(30)")?, 0.5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_deg_rad() -> Result<()> {
+        assert_float(Expr::new("deg(atan2(1, 1))").eval()?, 45.0);
+        assert_float(Expr::new("rad(180)").eval()?, std::f64::consts::PI);
+        assert_float(Expr::new("deg(pi) / 2").eval()?, 90.0);
+        Ok(())
+    }
+
+    // 超出定义域、参数个数错误、未知函数
+    #[test]
+    fn test_function_errors() {
+        assert!(Expr::new("asin(2)").eval().is_err());
+        assert!(Expr::new("acos(0 - 2)").eval().is_err());
+        assert!(Expr::new("atan2(1)").eval().is_err());
+        assert!(Expr::new("asin(1, 2)").eval().is_err());
+        assert!(Expr::new("foo(1)").eval().is_err());
+        assert!(Expr::new("foo").eval().is_err());
+    }
+
+    #[test]
+    fn test_integer_expr() -> Result<()> {
+        let src = "92 + 5 + 5 * 27 - (92 - 12) / 4 + 26";
+        assert_eq!(Expr::new(src).eval()?, Value::Int(238));
+        Ok(())
+    }
+
+    #[test]
+    fn test_eval_with_vars() -> Result<()> {
+        let vars = HashMap::from([("x".to_string(), Value::Int(3))]);
+        assert_eq!(
+            Expr::new("x * x + 1").eval_with_vars(&vars)?,
+            Value::Int(10)
+        );
+        assert!(Expr::new("y + 1").eval_with_vars(&vars).is_err());
+        Ok(())
+    }
+
+    // 多条语句共享同一个会话的变量
+    #[test]
+    fn test_session() -> Result<()> {
+        let mut session = Session::new();
+        assert_eq!(session.eval("x = 5")?, Value::Int(5));
+        assert_eq!(session.eval("y = x * 2")?, Value::Int(10));
+        assert_eq!(session.eval("x + y")?, Value::Int(15));
+
+        // 重新赋值会覆盖原来的值
+        session.eval("x = x + 1")?;
+        assert_eq!(session.get("x"), Some(Value::Int(6)));
+
+        // 未定义的变量返回错误，已有的变量不受影响
+        assert!(session.eval("z + 1").is_err());
+        assert!(session.eval("pi = 3").is_err());
+        assert_eq!(session.vars().len(), 2);
+        assert_eq!(session.get("y"), Some(Value::Int(10)));
+        Ok(())
+    }
+
+    // 使用方括号调用函数时，圆括号只用于分组
+    #[test]
+    fn test_call_syntax() -> Result<()> {
+        let brackets = |src| Expr::new(src).call_syntax(CallSyntax::Brackets).eval();
+        assert_eq!(brackets("sqrt[16]")?, Value::Float(4.0));
+        assert_eq!(brackets("atan2[0, 1] + (1 + 2) * 2")?, Value::Float(6.0));
+        assert_eq!(brackets("sqrt[sqrt[16]]")?, Value::Float(2.0));
+        assert_eq!(brackets("[1, 2, 3][1]")?, Value::Int(2));
+        assert!(matches!(brackets("sqrt(16)"), Err(ExprError::Syntax(_))));
+        assert!(brackets("sqrt[16)").is_err());
+
+        let parens = |src| Expr::new(src).eval();
+        assert_eq!(parens("sqrt(16)")?, Value::Float(4.0));
+        assert_eq!(
+            parens("sqrt[16]").unwrap_err().to_string(),
+            "Function calls use sqrt()"
+        );
+        assert!(parens("sqrt(0 - 1)").is_err());
+        Ok(())
+    }
+
+    // 多行定义，后面的定义使用前面定义的变量
+    #[test]
+    fn test_eval_program() -> Result<()> {
+        let vars = eval_program(
+            "# 基础配置
+            base = 10
+
+            rate = 0.5 # 比例
+            total = base * 2 + 1
+            scaled = total * rate
+            base = base + 1
+            ",
+        )?;
+        assert_eq!(vars.len(), 4);
+        assert_eq!(vars["base"], Value::Int(11));
+        assert_eq!(vars["rate"], Value::Float(0.5));
+        assert_eq!(vars["total"], Value::Int(21));
+        assert_eq!(vars["scaled"], Value::Float(10.5));
+        assert!(eval_program("")?.is_empty());
+
+        // 字符串中的 # 不是注释
+        let vars = eval_program(
+            "n = len(\"a#b\") # 3 个字符
+            m = len(\"\\\"#\") + n # 转义的引号不会结束字符串",
+        )?;
+        assert_eq!(vars["n"], Value::Int(3));
+        assert_eq!(vars["m"], Value::Int(5));
+        Ok(())
+    }
+
+    // 使用之后才定义的变量、循环引用以及不是定义的行都返回错误
+    #[test]
+    fn test_eval_program_errors() {
+        let err = |src| eval_program(src).unwrap_err().to_string();
+        assert_eq!(
+            err("a = b + 1\nb = 2"),
+            "Line 1: b is used before it is defined"
+        );
+        assert_eq!(
+            err("x = 1\na = b\nb = a"),
+            "Line 2: b is used before it is defined"
+        );
+        assert_eq!(err("a = a + 1"), "Line 1: a is used before it is defined");
+        assert_eq!(err("a = 1\n\na = c"), "Line 3: Unknown identifier c");
+        assert_eq!(err("a = 1 / 0"), "Line 1: Division by zero");
+        assert_eq!(err("a = 1\nb = (2"), "Line 2: Unexpected character");
+        assert_eq!(
+            err("a = 1\na == 1"),
+            "Line 2: Expecting a definition name = expr"
+        );
+        assert_eq!(err("1 + 2"), "Line 1: Expecting a definition name = expr");
+        assert!(eval_program("pi = 3").is_err());
+        assert_eq!(
+            eval_program("a = 1\nb = a / 0"),
+            Err(ExprError::Line(2, Box::new(ExprError::DivByZero)))
+        );
+    }
+
+    // 严格整数除法
+    #[test]
+    fn test_strict_division() -> Result<()> {
+        assert_eq!(Expr::new("7 / 2").eval()?, Value::Int(3));
+        assert!(Expr::new("7 / 2").strict_division(true).eval().is_err());
+        assert_eq!(
+            Expr::new("6 / 2").strict_division(true).eval()?,
+            Value::Int(3)
+        );
+        // 浮点数除法不受影响
+        assert_eq!(
+            Expr::new("rad(180) / pi").strict_division(true).eval()?,
+            Value::Float(1.0)
+        );
+        assert!(Expr::new("1 / 0").eval().is_err());
+        Ok(())
+    }
+
+    // 带命名空间的变量
+    #[test]
+    fn test_namespaced_identifier() -> Result<()> {
+        let vars = HashMap::from([
+            ("a.b".to_string(), Value::Int(2)),
+            ("c".to_string(), Value::Int(3)),
+            ("db.conn.timeout".to_string(), Value::Int(30)),
+        ]);
+        assert_eq!(Expr::new("a.b + c").eval_with_vars(&vars)?, Value::Int(5));
+        assert_eq!(
+            Expr::new("db.conn.timeout * 2").eval_with_vars(&vars)?,
+            Value::Int(60)
+        );
+
+        // 点号在结尾或者连续出现
+        assert!(Expr::new("a.").eval_with_vars(&vars).is_err());
+        assert!(Expr::new("a. + 1").eval_with_vars(&vars).is_err());
+        assert!(Expr::new("a..b").eval_with_vars(&vars).is_err());
+        Ok(())
+    }
+
+    // 非 ASCII 的数字字符
+    #[test]
+    fn test_unicode_digit() {
+        let err = Expr::new("1 + \u{0663}").eval().unwrap_err();
+        assert_eq!(err.to_string(), "Unsupported non-ASCII digit '\u{0663}'");
+        let err = Expr::new("12\u{0663} + 1").eval().unwrap_err();
+        assert_eq!(err.to_string(), "Unsupported non-ASCII digit '\u{0663}'");
+        let err = Expr::new("(1 + 2) @").eval().unwrap_err();
+        assert_eq!(err.to_string(), "Unexpected character '@'");
+    }
+
+    // 比较运算
+    #[test]
+    fn test_comparison() -> Result<()> {
+        assert_eq!(Expr::new("1 + 1 == 2").eval()?, Value::Bool(true));
+        assert_eq!(Expr::new("3 != 3").eval()?, Value::Bool(false));
+        assert_eq!(Expr::new("2 * 3 >= 6").eval()?, Value::Bool(true));
+        assert_eq!(Expr::new("pi < 3").eval()?, Value::Bool(false));
+        assert_eq!(Expr::new("(1 < 2) == (3 > 2)").eval()?, Value::Bool(true));
+        assert!(Expr::new("(1 < 2) + 1").eval().is_err());
+        assert!(Expr::new("(1 < 2) < 3").eval().is_err());
+        assert!(Expr::new("1 ! 2").eval().is_err());
+        Ok(())
+    }
+
+    // 连续的比较运算
+    #[test]
+    fn test_chained_comparison() -> Result<()> {
+        assert_eq!(Expr::new("1 < 5 < 10").eval()?, Value::Bool(true));
+        assert_eq!(Expr::new("1 < 0 < 10").eval()?, Value::Bool(false));
+        assert_eq!(Expr::new("1 < 5 > 0").eval()?, Value::Bool(true));
+        assert_eq!(Expr::new("1 <= 1 < 2 == 2").eval()?, Value::Bool(true));
+
+        // 中间的表达式只计算一次
+        let mut session = Session::new();
+        session.eval("n = 0")?;
+        assert_eq!(session.eval("1 < (n = n + 5) < 10")?, Value::Bool(true));
+        assert_eq!(session.get("n"), Some(Value::Int(5)));
+        Ok(())
+    }
+
+    // 分数模式下的精确计算
+    #[test]
+    fn test_rational() -> Result<()> {
+        let eval = |src| Expr::new(src).rational(true).eval();
+        assert_eq!(eval("1/3 + 1/6")?, Value::Rational(Rational::new(1, 2)?));
+        assert_eq!(eval("6/8")?, Value::Rational(Rational::new(3, 4)?));
+        assert_eq!(eval("2/(0-4)")?.to_string(), "-1/2");
+        assert_eq!(eval("1/3 * 3")?.to_string(), "1");
+        assert_eq!(eval("(2/3)^(0-2)")?, Value::Rational(Rational::new(9, 4)?));
+        assert_eq!(eval("1/3 < 1/2")?, Value::Bool(true));
+        assert_eq!(eval("2/4 == 1/2")?, Value::Bool(true));
+
+        // 整数、分数、浮点数混合计算
+        assert_eq!(
+            eval("(1/2 + 1/3) * 6 - 2^2")?,
+            Value::Rational(Rational::new(1, 1)?)
+        );
+        match eval("1/4 + 1/4")? {
+            Value::Rational(r) => assert_eq!(r.to_f64(), 0.5),
+            value => panic!("expected rational, got {:?}", value),
+        }
+        assert_float(eval("1/2 * pi")?, std::f64::consts::FRAC_PI_2);
+
+        // 默认关闭时仍然是整数除法
+        assert_eq!(Expr::new("1/3 + 1/6").eval()?, Value::Int(0));
+
+        // 除数为 0
+        assert!(eval("1/0").is_err());
+        assert!(eval("1/(1/2 - 1/2)").is_err());
+        assert!(Rational::new(1, 0).is_err());
+        Ok(())
+    }
+
+    // 编译一次，使用不同的变量多次计算
+    #[test]
+    fn test_compile() -> Result<()> {
+        // 编译之后不再依赖源字符串，计算时不会再次进行词法分析
+        let src = String::from("x * x");
+        let compiled = Expr::compile(&src)?;
+        drop(src);
+
+        for x in [0, 3, -4, 12] {
+            let vars = HashMap::from([("x".to_string(), Value::Int(x))]);
+            assert_eq!(compiled.eval(&vars)?, Value::Int(x * x));
+        }
+        assert!(compiled.eval(&HashMap::new()).is_err());
+
+        // 表达式中的赋值不会保留到下一次计算
+        let compiled = Expr::compile("y = x + 1")?;
+        let vars = HashMap::from([("x".to_string(), Value::Int(1))]);
+        assert_eq!(compiled.eval(&vars)?, Value::Int(2));
+        assert_eq!(compiled.eval(&vars)?, Value::Int(2));
+
+        // 语法错误在编译时返回
+        assert!(Expr::compile("x * ").is_err());
+        let compiled = Expr::compile("sin(x)")?.angle_mode(AngleMode::Degrees);
+        let vars = HashMap::from([("x".to_string(), Value::Int(90))]);
+        assert_float(compiled.eval(&vars)?, 1.0);
+        Ok(())
+    }
+
+    // 向下取整除
+    #[test]
+    fn test_floor_division() -> Result<()> {
+        assert_eq!(Expr::new("7 // 2").eval()?, Value::Int(3));
+        assert_eq!(Expr::new("-7 // 2").eval()?, Value::Int(-4));
+        assert_eq!(Expr::new("7 // -2").eval()?, Value::Int(-4));
+        assert_eq!(Expr::new("-8 // 2").eval()?, Value::Int(-4));
+        assert_eq!(Expr::new("-7 / 2").eval()?, Value::Int(-3));
+        assert_eq!(Expr::new("2 * 7 // 2 * 3").eval()?, Value::Int(21));
+        assert_eq!(Expr::new("pi // 1").eval()?, Value::Float(3.0));
+        assert_eq!(
+            Expr::new("-7 // 2").strict_division(true).eval()?,
+            Value::Int(-4)
+        );
+        assert_eq!(
+            Expr::new("-(7/2) // 1").rational(true).eval()?,
+            Value::Rational(Rational::new(-4, 1)?)
+        );
+
+        // 除数为 0
+        assert!(Expr::new("7 // 0").eval().is_err());
+        assert!(Expr::new("pi // 0").eval().is_err());
+        assert!(Expr::new("7 // (1/2 - 1/2)").rational(true).eval().is_err());
+        assert!(Expr::new("7 /// 2").eval().is_err());
+        Ok(())
+    }
+
+    // 负号
+    #[test]
+    fn test_negate() -> Result<()> {
+        assert_eq!(Expr::new("-2^2").eval()?, Value::Int(-4));
+        assert_eq!(Expr::new("2 * -3").eval()?, Value::Int(-6));
+        assert_eq!(Expr::new("--1").eval()?, Value::Int(1));
+        assert_eq!(Expr::new("-(1 + 2) - -3").eval()?, Value::Int(0));
+        assert!(Expr::new("-(1 < 2)").eval().is_err());
+        assert_eq!(
+            Expr::new("-(1 / 3)").rational(true).eval()?,
+            Value::Rational(Rational::new(-1, 3)?)
+        );
+        assert!(matches!(
+            Expr::new("-((-9223372036854775807 - 1) / 1)")
+                .rational(true)
+                .eval(),
+            Err(ExprError::Overflow(_))
+        ));
+        Ok(())
+    }
+
+    // 格式化计算结果
+    #[test]
+    fn test_format_result() -> Result<()> {
+        assert_eq!((0.1 + 0.2).to_string(), "0.30000000000000004");
+        assert_eq!(format_result(Value::Float(0.1 + 0.2), 10), "0.3");
+        let vars = HashMap::from([
+            ("a".to_string(), Value::Float(0.1)),
+            ("b".to_string(), Value::Float(0.2)),
+        ]);
+        let value = Expr::new("a + b").eval_with_vars(&vars)?;
+        assert_eq!(format_result(value.clone(), 15), "0.3");
+        assert_eq!(format_result(value, 17), "0.30000000000000004");
+
+        assert_eq!(format_result(Value::Float(std::f64::consts::PI), 0), "3");
+        assert_eq!(format_result(Value::Float(std::f64::consts::PI), 2), "3.14");
+        assert_eq!(
+            format_result(Value::Float(std::f64::consts::PI), 4),
+            "3.1416"
+        );
+        assert_eq!(format_result(Value::Float(2.5), 4), "2.5");
+        assert_eq!(format_result(Value::Float(100.0), 3), "100");
+        assert_eq!(format_result(Value::Float(-0.0001), 2), "0");
+        assert_eq!(format_result(Value::Float(f64::INFINITY), 2), "inf");
+
+        // 其他类型的值不受影响
+        assert_eq!(format_result(Value::Int(100), 2), "100");
+        assert_eq!(format_result(Value::Bool(true), 2), "true");
+        Ok(())
+    }
+
+    // 绝对值
+    #[test]
+    fn test_abs_bars() -> Result<()> {
+        assert_eq!(Expr::new("|-5|").eval()?, Value::Int(5));
+        assert_eq!(Expr::new("|\u{2212}5|").eval()?, Value::Int(5));
+        assert_eq!(Expr::new("|3 - 7| + 1").eval()?, Value::Int(5));
+        assert_eq!(Expr::new("2 * |1 - 4|^2").eval()?, Value::Int(18));
+        assert_eq!(Expr::new("||-3| - 5|").eval()?, Value::Int(2));
+        assert_eq!(Expr::new("|1 - |2 - 6||").eval()?, Value::Int(3));
+        assert_eq!(
+            Expr::new("|-pi|").eval()?,
+            Value::Float(std::f64::consts::PI)
+        );
+        assert_eq!(
+            Expr::new("|1/3 - 1/2|").rational(true).eval()?,
+            Value::Rational(Rational::new(1, 6)?)
+        );
+        assert!(matches!(
+            Expr::new("|(-9223372036854775807 - 1) / 1|")
+                .rational(true)
+                .eval(),
+            Err(ExprError::Overflow(_))
+        ));
+
+        assert!(Expr::new("|3 - 7").eval().is_err());
+        assert!(Expr::new("||").eval().is_err());
+        assert!(Expr::new("|1 < 2|").eval().is_err());
+        Ok(())
+    }
+
+    // 限制计算步数
+    #[test]
+    fn test_eval_with_budget() -> Result<()> {
+        let src = "(1 + 2) * (3 + 4) - |5 - 6| + sin(0)";
+        let err = Expr::new(src).eval_with_budget(5).unwrap_err();
+        assert_eq!(err.to_string(), "evaluation step budget exceeded");
+        assert_eq!(Expr::new(src).eval_with_budget(100)?, Value::Float(20.0));
+
+        // 解析时同样会消耗步数
+        let nested = format!("{}1{}", "(".repeat(50), ")".repeat(50));
+        assert!(Expr::new(&nested).eval_with_budget(20).is_err());
+        assert_eq!(Expr::new(&nested).eval()?, Value::Int(1));
+
+        // 刚好用完预算：解析两个操作数 2 步，计算三个节点 3 步
+        assert_eq!(Expr::new("1 + 2").eval_with_budget(5)?, Value::Int(3));
+        assert!(Expr::new("1 + 2").eval_with_budget(4).is_err());
+        Ok(())
+    }
+
+    // 自定义运算符优先级
+    #[test]
+    fn test_operator_precedence() -> Result<()> {
+        assert_eq!(Expr::new("2 + 3 * 4").eval()?, Value::Int(14));
+
+        // 交换 + 和 * 的优先级
+        let eval = |src| {
+            Expr::new(src)
+                .operator_precedence("+", 3, ASSOC_LEFT)?
+                .operator_precedence("*", 2, ASSOC_LEFT)?
+                .eval()
+        };
+        assert_eq!(eval("2 + 3 * 4")?, Value::Int(20));
+        assert_eq!(eval("2 * 3 + 4")?, Value::Int(14));
+        assert_eq!(eval("2 - 1 * 3")?, Value::Int(3));
+
+        // 改变结合性
+        let mut expr = Expr::new("10 - 4 - 3").operator_precedence("-", 2, ASSOC_RIGHT)?;
+        assert_eq!(expr.eval()?, Value::Int(9));
+        let mut expr = Expr::new("2 ^ 3 ^ 2").operator_precedence("^", 4, ASSOC_LEFT)?;
+        assert_eq!(expr.eval()?, Value::Int(64));
+
+        assert!(Expr::new("1")
+            .operator_precedence("(", 1, ASSOC_LEFT)
+            .is_err());
+        assert!(Expr::new("1")
+            .operator_precedence("++", 1, ASSOC_LEFT)
+            .is_err());
+        assert!(Expr::new("1")
+            .operator_precedence("+", 0, ASSOC_LEFT)
+            .is_err());
+        assert!(Expr::new("1").operator_precedence("+", 1, 2).is_err());
+        Ok(())
+    }
+
+    // 整数和浮点数
+    #[test]
+    fn test_int_and_float() -> Result<()> {
+        // 整数
+        assert_eq!(Expr::new("2 + 2").eval()?, Value::Int(4));
+        assert_eq!(Expr::new("7 / 2").eval()?, Value::Int(3));
+        assert_eq!(
+            Expr::new("3000000000 * 3").eval()?,
+            Value::Int(9_000_000_000)
+        );
+
+        // 浮点数
+        assert_eq!(Expr::new("1.5 * 2.5").eval()?, Value::Float(3.75));
+        assert_eq!(Expr::new("2.0 + 2.0").eval()?, Value::Float(4.0));
+        assert_eq!(format_result(Expr::new("0.1 + 0.2").eval()?, 10), "0.3");
+
+        // 混合计算时转换为浮点数
+        assert_eq!(Expr::new("2.0 + 2").eval()?, Value::Float(4.0));
+        assert_eq!(Expr::new("7.0 / 2").eval()?, Value::Float(3.5));
+        assert_eq!(Expr::new("-1.5 // 1").eval()?, Value::Float(-2.0));
+        assert_eq!(Expr::new("2 ^ 0.5 < 1.5").eval()?, Value::Bool(true));
+        assert_eq!(Expr::new("|2 - 3.5|").eval()?, Value::Float(1.5));
+
+        // 小数点前后都需要有数字
+        assert!(Expr::new("1.").eval().is_err());
+        assert!(Expr::new(".5").eval().is_err());
+        assert!(Expr::new("1.2.3").eval().is_err());
+        Ok(())
+    }
+
+    // 范围上的求和与求积
+    #[test]
+    fn test_range_aggregate() -> Result<()> {
+        assert_eq!(Expr::new("sum(1..5)").eval()?, Value::Int(10));
+        assert_eq!(Expr::new("sum(1..=5)").eval()?, Value::Int(15));
+        assert_eq!(Expr::new("product(1..4)").eval()?, Value::Int(6));
+        assert_eq!(Expr::new("product(1..=4)").eval()?, Value::Int(24));
+        assert_eq!(
+            Expr::new("sum(-2..=2) + product(2..=3)").eval()?,
+            Value::Int(6)
+        );
+
+        // 空的范围
+        assert_eq!(Expr::new("sum(5..1)").eval()?, Value::Int(0));
+        assert_eq!(Expr::new("sum(3..3)").eval()?, Value::Int(0));
+        assert_eq!(Expr::new("product(3..3)").eval()?, Value::Int(1));
+
+        // 范围的起止可以是表达式和变量
+        let vars = HashMap::from([("n".to_string(), Value::Int(10))]);
+        assert_eq!(
+            Expr::new("sum(1..=n)").eval_with_vars(&vars)?,
+            Value::Int(55)
+        );
+        assert_eq!(
+            Expr::new("sum(n - 1..n * 2)").eval_with_vars(&vars)?,
+            Value::Int(154)
+        );
+
+        assert!(Expr::new("sum(1)").eval().is_err());
+        assert!(Expr::new("sum(1..2, 3)").eval().is_err());
+        assert!(Expr::new("sum(1.5..3)").eval().is_err());
+        assert!(Expr::new("sin(1..2)").eval().is_err());
+        assert!(Expr::new("1..2").eval().is_err());
+        assert!(Expr::new("product(1..=100)").eval().is_err());
+        assert!(Expr::new("sum(1..1000000)").eval_with_budget(1000).is_err());
+        Ok(())
+    }
+
+    // 统一表达式的空白
+    #[test]
+    fn test_normalize() -> Result<()> {
+        let cases = [
+            ("1+2*3", "1 + 2 * 3"),
+            ("  1 +   2 *3 ", "1 + 2 * 3"),
+            ("-x^2", "-x ^ 2"),
+            ("- x ^ 2", "-x ^ 2"),
+            ("2*-3", "2 * -3"),
+            ("2 - - 3", "2 - -3"),
+            ("--1", "--1"),
+            ("( 1+2 ) * ( 3 )", "(1 + 2) * (3)"),
+            ("atan2 ( 1 ,2 )", "atan2(1, 2)"),
+            ("| a-b |-|c|", "|a - b| - |c|"),
+            ("x=1<2<=y", "x = 1 < 2 <= y"),
+            (
+                "sum( 1 ..= n )+product(1 .. 3)",
+                "sum(1..=n) + product(1..3)",
+            ),
+            ("7//2 != 2.0", "7 // 2 != 2.0"),
+            ("1.50 \u{2212} 1", "1.5 - 1"),
+        ];
+        for (src, expected) in cases {
+            let normalized = normalize(src)?;
+            assert_eq!(normalized, expected, "{}", src);
+            // 重复调用结果不变
+            assert_eq!(normalize(&normalized)?, normalized);
+        }
+
+        // 空白不同的等价表达式得到相同的结果，计算结果也不变
+        let vars = HashMap::from([("x".to_string(), Value::Int(3))]);
+        for src in ["2*(x-1)^2", " 2 * ( x - 1 ) ^ 2", "2 *(x- 1)^ 2"] {
+            assert_eq!(normalize(src)?, "2 * (x - 1) ^ 2");
+            assert_eq!(
+                Expr::new(&normalize(src)?).eval_with_vars(&vars)?,
+                Expr::new(src).eval_with_vars(&vars)?
+            );
+        }
+
+        assert!(normalize("1 +").is_err());
+        assert!(normalize("(1").is_err());
+        Ok(())
+    }
+
+    // 条件表达式和赋值的优先级
+    #[test]
+    fn test_ternary() -> Result<()> {
+        let mut session = Session::new();
+        session.eval("a = 5")?;
+        assert_eq!(session.eval("x = a > 0 ? 1 : -1")?, Value::Int(1));
+        assert_eq!(session.get("x"), Some(Value::Int(1)));
+        session.eval("a = -5")?;
+        assert_eq!(session.eval("x = a > 0 ? 1 : -1")?, Value::Int(-1));
+        assert_eq!(session.get("x"), Some(Value::Int(-1)));
+
+        // 赋值右结合
+        assert_eq!(session.eval("y = z = a < 0 ? 2 : 3")?, Value::Int(2));
+        assert_eq!(session.get("y"), Some(Value::Int(2)));
+        assert_eq!(session.get("z"), Some(Value::Int(2)));
+
+        // 条件表达式右结合，a ? b : (c ? d : f)，e 是常量，这里使用 f
+        let nested = |a: bool, c: bool| -> Result<Value> {
+            let vars = HashMap::from([
+                ("a".to_string(), Value::Bool(a)),
+                ("b".to_string(), Value::Int(1)),
+                ("c".to_string(), Value::Bool(c)),
+                ("d".to_string(), Value::Int(2)),
+                ("f".to_string(), Value::Int(3)),
+            ]);
+            Expr::new("a ? b : c ? d : f").eval_with_vars(&vars)
+        };
+        assert_eq!(nested(true, true)?, Value::Int(1));
+        assert_eq!(nested(true, false)?, Value::Int(1));
+        assert_eq!(nested(false, true)?, Value::Int(2));
+        assert_eq!(nested(false, false)?, Value::Int(3));
+        assert_eq!(
+            Expr::new("1 < 2 ? 3 < 4 ? 5 : 6 : 7").eval()?,
+            Value::Int(5)
+        );
+
+        // 运算符的优先级都比条件表达式高
+        assert_eq!(Expr::new("1 + 1 == 2 ? 10 * 2 : 0").eval()?, Value::Int(20));
+        assert_eq!(Expr::new("(1 > 2 ? 1 : 2) * 3").eval()?, Value::Int(6));
+        assert_eq!(
+            Expr::new("atan2(0, 1 > 0 ? 1 : -1)").eval()?,
+            Value::Float(0.0)
+        );
+
+        // 只计算选中的分支
+        assert_eq!(Expr::new("1 > 0 ? 1 : 1 / 0").eval()?, Value::Int(1));
+
+        assert!(Expr::new("1 ? 2 : 3").eval().is_err());
+        assert!(Expr::new("1 > 0 ? 2").eval().is_err());
+        assert!(Expr::new("1 > 0 ? 2 , 3").eval().is_err());
+        assert_eq!(normalize("x=a>0?1:-1")?, "x = a > 0 ? 1 : -1");
+        Ok(())
+    }
+
+    // 十六进制整数和浮点数
+    #[test]
+    fn test_hex_literal() -> Result<()> {
+        assert_eq!(Expr::new("0xff").eval()?, Value::Int(255));
+        assert_eq!(Expr::new("0X1F + 1").eval()?, Value::Int(32));
+        assert_eq!(Expr::new("0x1.8p3").eval()?, Value::Float(12.0));
+        assert_eq!(Expr::new("0x1P-2").eval()?, Value::Float(0.25));
+        assert_eq!(Expr::new("0x.8p+1").eval()?, Value::Float(1.0));
+        assert_eq!(Expr::new("0xA.Cp0").eval()?, Value::Float(10.75));
+        assert_eq!(Expr::new("0x1.p4 - 1").eval()?, Value::Float(15.0));
+        assert_eq!(Expr::new("sum(0x1..=0xa)").eval()?, Value::Int(55));
+
+        assert!(Expr::new("0x1.8").eval().is_err());
+        assert!(Expr::new("0x1.8p").eval().is_err());
+        assert!(Expr::new("0x1p-").eval().is_err());
+        assert!(Expr::new("0x").eval().is_err());
+        assert!(Expr::new("0x.p1").eval().is_err());
+        assert!(Expr::new("0x1p99999").eval().is_err());
+        assert!(Expr::new("0x8000000000000000").eval().is_err());
+        Ok(())
+    }
+
+    // 宽松模式跳过无法识别的字符
+    #[test]
+    fn test_lenient() -> Result<()> {
+        let mut expr = Expr::new("2 + @ 3").lenient(true);
+        let (value, warnings) = expr.eval_with_warnings()?;
+        assert_eq!(value, Value::Int(5));
+        assert_eq!(warnings, vec!["Unexpected character '@'".to_string()]);
+
+        let (value, warnings) = Expr::new("#2 * $3 ~").lenient(true).eval_with_warnings()?;
+        assert_eq!(value, Value::Int(6));
+        assert_eq!(warnings.len(), 3);
+
+        // 没有需要跳过的内容
+        let (value, warnings) = Expr::new("1 + 2").lenient(true).eval_with_warnings()?;
+        assert_eq!(value, Value::Int(3));
+        assert!(warnings.is_empty());
+
+        // 跳过之后仍然不合法
+        assert!(Expr::new("2 + @").lenient(true).eval().is_err());
+
+        // 默认是严格模式
+        assert!(Expr::new("2 + @ 3").eval().is_err());
+        assert!(Expr::new("2 + @ 3").eval_with_warnings().is_err());
+        assert!(Expr::new("2 + @ 3").lenient(false).eval().is_err());
+        Ok(())
+    }
+
+    // 字符串函数
+    #[test]
+    fn test_string_functions() -> Result<()> {
+        assert_eq!(Expr::new(r#"len("abc")"#).eval()?, Value::Int(3));
+        assert_eq!(Expr::new(r#"len("")"#).eval()?, Value::Int(0));
+        assert_eq!(Expr::new(r#"len("héllo, 世界")"#).eval()?, Value::Int(9));
+        assert_eq!(Expr::new(r#"len("a\"b\\c\nd\te")"#).eval()?, Value::Int(9));
+        assert_eq!(Expr::new(r#"ord("A")"#).eval()?, Value::Int(65));
+        assert_eq!(Expr::new(r#"ord("\n")"#).eval()?, Value::Int(10));
+        assert_eq!(Expr::new(r#"ord("\"")"#).eval()?, Value::Int(34));
+        assert_eq!(
+            Expr::new(r#"len("ab") * 2 + ord("a")"#).eval()?,
+            Value::Int(101)
+        );
+
+        // 字符串不能参与数值运算
+        assert!(Expr::new(r#""abc" + 1"#).eval().is_err());
+        assert!(Expr::new(r#"1 + "abc""#).eval().is_err());
+        assert!(Expr::new(r#"-"abc""#).eval().is_err());
+        assert_eq!(Expr::new(r#""abc""#).eval()?, Value::Str("abc".into()));
+        assert!(Expr::new(r#"sin("abc")"#).eval().is_err());
+        assert!(Expr::new("len(1)").eval().is_err());
+        assert!(Expr::new(r#"len("a", "b")"#).eval().is_err());
+        assert!(Expr::new(r#"ord("AB")"#).eval().is_err());
+        assert!(Expr::new(r#"ord("")"#).eval().is_err());
+
+        // 不完整的字符串和未知的转义字符
+        assert!(Expr::new(r#"len("abc)"#).eval().is_err());
+        assert!(Expr::new(r#"len("a\qb")"#).eval().is_err());
+
+        assert_eq!(normalize(r#"len( "a\"b\n" )+1"#)?, r#"len("a\"b\n") + 1"#);
+        Ok(())
+    }
+
+    // 限制表达式的长度和嵌套层数
+    #[test]
+    fn test_limits() -> Result<()> {
+        assert_eq!(Expr::new("1 + 2").max_length(5).eval()?, Value::Int(3));
+        assert!(Expr::new("1 + 23").max_length(5).eval().is_err());
+        // 超出长度时不会进行词法分析，非法的字符也只返回长度错误
+        assert_eq!(
+            Expr::new("@@@@@@")
+                .max_length(5)
+                .eval()
+                .unwrap_err()
+                .to_string(),
+            Expr::new("123456")
+                .max_length(5)
+                .eval()
+                .unwrap_err()
+                .to_string()
+        );
+
+        assert_eq!(Expr::new("((1 + 2))").max_depth(2).eval()?, Value::Int(3));
+        assert!(Expr::new("(((1 + 2)))").max_depth(2).eval().is_err());
+        assert_eq!(
+            Expr::new("(1) + (2) + ((3))").max_depth(2).eval()?,
+            Value::Int(6)
+        );
+        assert_eq!(
+            Expr::new("|sin(0)|").max_depth(2).eval()?,
+            Value::Float(0.0)
+        );
+        assert!(Expr::new("|sin((0))|").max_depth(2).eval().is_err());
+        assert!(Expr::new("atan2(1, (((0))))").max_depth(3).eval().is_err());
+
+        // 深层嵌套的输入在限制内报错，不会耗尽栈
+        let deep = format!("{}1{}", "(".repeat(10000), ")".repeat(10000));
+        assert!(Expr::new(&deep).max_depth(100).eval().is_err());
+
+        // 默认不限制
+        let nested = format!("{}1{}", "(".repeat(50), ")".repeat(50));
+        assert_eq!(Expr::new(&nested).eval()?, Value::Int(1));
+        Ok(())
+    }
+
+    // 很长的左结合表达式不会因为递归过深耗尽栈
+    #[test]
+    fn test_long_chain() -> Result<()> {
+        let long = vec!["1"; 20001].join("+");
+        assert_eq!(Expr::new(&long).eval()?, Value::Int(20001));
+
+        let vars = format!("x{}", "-1".repeat(20000));
+        assert_eq!(
+            Expr::free_variables(&vars)?,
+            HashSet::from(["x".to_string()])
+        );
+        let compiled = Expr::compile(&vars)?;
+        let env = HashMap::from([("x".to_string(), Value::Int(20000))]);
+        assert_eq!(compiled.eval(&env)?, Value::Int(0));
+        Ok(())
+    }
+
+    // let 定义的局部变量
+    #[test]
+    fn test_let_in() -> Result<()> {
+        assert_eq!(Expr::new("let a = 2 + 3 in a * a").eval()?, Value::Int(25));
+        assert_eq!(Expr::new("1 + (let a = 2 in a * 3)").eval()?, Value::Int(7));
+
+        // 嵌套的 let，内层覆盖外层，离开作用范围之后恢复
+        assert_eq!(
+            Expr::new("let a = 2 in let b = a + 1 in a * b").eval()?,
+            Value::Int(6)
+        );
+        assert_eq!(
+            Expr::new("let a = 2 in (let a = 10 in a) + a").eval()?,
+            Value::Int(12)
+        );
+        assert_eq!(
+            Expr::new("let a = 1 in let a = a + 1 in let a = a * 10 in a").eval()?,
+            Value::Int(20)
+        );
+
+        // 局部变量覆盖外部的变量，不会修改外部的变量
+        let mut session = Session::new();
+        session.eval("x = 1")?;
+        assert_eq!(session.eval("let x = 5 in x * 2")?, Value::Int(10));
+        assert_eq!(session.eval("x")?, Value::Int(1));
+        assert!(session.eval("(let y = 1 in y) + y").is_err());
+
+        assert!(Expr::new("let pi = 3 in pi").eval().is_err());
+        assert!(Expr::new("let a = 1 a").eval().is_err());
+        assert!(Expr::new("let 1 = 1 in 1").eval().is_err());
+        assert!(Expr::new("let in = 1 in 1").eval().is_err());
+        assert_eq!(normalize("let a=2+3 in a*a")?, "let a = 2 + 3 in a * a");
+        Ok(())
+    }
+
+    // 一行数据，列名作为变量名
+    struct Row {
+        columns: Vec<&'static str>,
+        values: Vec<Value>,
+    }
+
+    impl VarSource for Row {
+        fn get(&self, name: &str) -> Option<Value> {
+            let i = self.columns.iter().position(|c| *c == name)?;
+            self.values.get(i).cloned()
+        }
+    }
+
+    // 使用自定义的变量来源计算表达式，例如过滤数据
+    #[test]
+    fn test_eval_with_source() -> Result<()> {
+        let columns = vec!["age", "score"];
+        let rows: Vec<_> = [(17, 90), (30, 55), (42, 80)]
+            .iter()
+            .map(|&(age, score)| Row {
+                columns: columns.clone(),
+                values: vec![Value::Int(age), Value::Int(score)],
+            })
+            .collect();
+
+        let filter = "age >= 18 ? score > 60 : age < 0";
+        let mut matched = vec![];
+        for row in &rows {
+            if Expr::new(filter).eval_with_source(row)? == Value::Bool(true) {
+                matched.push(row.values[0].clone());
+            }
+        }
+        assert_eq!(matched, vec![Value::Int(42)]);
+
+        // 编译一次，对每一行计算
+        let compiled = Expr::compile("score * 2 + age")?;
+        assert_eq!(compiled.eval_with_source(&rows[1])?, Value::Int(140));
+
+        // 局部变量优先
+        assert_eq!(
+            Expr::new("let score = 2 in age + score").eval_with_source(&rows[0])?,
+            Value::Int(19)
+        );
+        assert!(Expr::new("name == 1").eval_with_source(&rows[0]).is_err());
+        Ok(())
+    }
+
+    // 表达式中引用的变量
+    #[test]
+    fn test_free_variables() -> Result<()> {
+        let names = |names: &[&str]| -> HashSet<String> {
+            names.iter().map(|name| name.to_string()).collect()
+        };
+        assert_eq!(
+            Expr::free_variables("a + b * sqrt(c) + pi")?,
+            names(&["a", "b", "c"])
+        );
+        assert_eq!(
+            Expr::free_variables("x > 0 ? max(x, y) : |z| + e")?,
+            names(&["x", "y", "z"])
+        );
+        assert_eq!(Expr::free_variables("a * a + a")?, names(&["a"]));
+        assert_eq!(Expr::free_variables("1 + 2")?, names(&[]));
+        assert_eq!(Expr::free_variables("sum(1..=n)")?, names(&["n"]));
+
+        // 局部变量只在作用范围内不需要提供
+        assert_eq!(
+            Expr::free_variables("let a = b in a + c")?,
+            names(&["b", "c"])
+        );
+        assert_eq!(Expr::free_variables("(let a = 1 in a) + a")?, names(&["a"]));
+        assert_eq!(Expr::free_variables("x = y + 1")?, names(&["y"]));
+        assert!(Expr::free_variables("a +").is_err());
+        Ok(())
+    }
+
+    // 取整和限制范围的函数
+    #[test]
+    fn test_rounding_functions() -> Result<()> {
+        let eval = |src: &str| Expr::new(src).eval();
+        assert_eq!(eval("round(2.5)")?, Value::Float(3.0));
+        assert_eq!(eval("round(-2.5)")?, Value::Float(-3.0));
+        assert_eq!(eval("round(2.49)")?, Value::Float(2.0));
+        assert_eq!(eval("floor(2.5)")?, Value::Float(2.0));
+        assert_eq!(eval("floor(-2.5)")?, Value::Float(-3.0));
+        assert_eq!(eval("ceil(2.5)")?, Value::Float(3.0));
+        assert_eq!(eval("ceil(-2.5)")?, Value::Float(-2.0));
+        assert_eq!(eval("trunc(2.5)")?, Value::Float(2.0));
+        assert_eq!(eval("trunc(-2.5)")?, Value::Float(-2.0));
+        // 整数保持不变
+        assert_eq!(eval("floor(7)")?, Value::Int(7));
+        assert_eq!(eval("round(7 // 2)")?, Value::Int(3));
+
+        assert_eq!(eval("clamp(15, 0, 10)")?, Value::Int(10));
+        assert_eq!(eval("clamp(-5, 0, 10)")?, Value::Int(0));
+        assert_eq!(eval("clamp(5, 5, 5)")?, Value::Int(5));
+        assert_eq!(eval("clamp(2.5, 0, 1.5)")?, Value::Float(1.5));
+        assert_eq!(eval("clamp(0.5, 0, 1)")?, Value::Float(0.5));
+        assert!(eval("clamp(5, 10, 0)").is_err());
+        assert!(eval("clamp(5, 1)").is_err());
+        assert!(eval("round(1 < 2)").is_err());
+        Ok(())
+    }
+
+    // 模幂运算
+    #[test]
+    fn test_powmod() -> Result<()> {
+        let eval = |src: &str| Expr::new(src).eval();
+        // 3^5 = 243 = 18 * 13 + 9
+        assert_eq!(eval("powmod(3, 5, 13)")?, Value::Int(9));
+        assert_eq!(eval("powmod(2, 10, 1000)")?, Value::Int(24));
+        assert_eq!(eval("powmod(7, 0, 5)")?, Value::Int(1));
+        assert_eq!(eval("powmod(7, 0, 1)")?, Value::Int(0));
+        assert_eq!(eval("powmod(-2, 3, 5)")?, Value::Int(2));
+
+        // 直接计算 2^(p-1) 会溢出，费马小定理：2^(p-1) mod p = 1
+        assert_eq!(eval("powmod(2, 1000000006, 1000000007)")?, Value::Int(1));
+        assert_eq!(
+            eval("powmod(123456789, 987654321, 9223372036854775807)")?,
+            Value::Int(powmod_naive(123456789, 987654321, i64::MAX))
+        );
+
+        assert!(eval("powmod(2, 3, 0)").is_err());
+        assert!(eval("powmod(2, 3, -5)").is_err());
+        assert!(eval("powmod(2, -1, 5)").is_err());
+        assert!(eval("powmod(2.5, 3, 5)").is_err());
+        assert!(eval("powmod(2, 3)").is_err());
+        Ok(())
+    }
+
+    // 最大公约数和最小公倍数
+    #[test]
+    fn test_gcd_lcm() -> Result<()> {
+        let eval = |src: &str| Expr::new(src).eval();
+        assert_eq!(eval("gcd(12, 18)")?, Value::Int(6));
+        assert_eq!(eval("lcm(4, 6)")?, Value::Int(12));
+        assert_eq!(eval("gcd(-12, 18)")?, Value::Int(6));
+        assert_eq!(eval("lcm(-4, -6)")?, Value::Int(12));
+        assert_eq!(eval("gcd(7, 0)")?, Value::Int(7));
+        assert_eq!(eval("gcd(0, 0)")?, Value::Int(0));
+        assert_eq!(eval("lcm(0, 5)")?, Value::Int(0));
+        assert_eq!(eval("lcm(0, 0)")?, Value::Int(0));
+
+        assert!(matches!(
+            eval("lcm(9223372036854775807, 9223372036854775806)"),
+            Err(ExprError::Overflow(_))
+        ));
+        assert!(eval("gcd(2.5, 5)").is_err());
+        assert!(eval("gcd(2)").is_err());
+        Ok(())
+    }
+
+    // 逐位计算的模幂运算，用于验证结果
+    fn powmod_naive(base: i64, exp: i64, modulus: i64) -> i64 {
+        let (base, modulus) = (base as u128, modulus as u128);
+        let mut result = 1;
+        for bit in (0..64).rev() {
+            result = result * result % modulus;
+            if exp >> bit & 1 == 1 {
+                result = result * base % modulus;
+            }
+        }
+        result as i64
+    }
+
+    // 编译后的表达式中，不依赖变量的子树只计算一次
+    #[test]
+    fn test_compiled_cache() -> Result<()> {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let compiled = Expr::compile("expensive(10, 2) * 2 + x + expensive(x, 1)")?.function(
+            "expensive",
+            move |args| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                match args {
+                    [Value::Int(n), Value::Int(m)] => Ok(Value::Int(n * m)),
+                    _ => Err(ExprError::Parse("expensive expects 2 integers".into())),
+                }
+            },
+        );
+
+        for x in 0..5 {
+            let vars = HashMap::from([("x".to_string(), Value::Int(x))]);
+            assert_eq!(compiled.eval(&vars)?, Value::Int(40 + 2 * x));
+        }
+        // 常量参数的调用只计算了一次，依赖变量的调用每次都计算
+        assert_eq!(calls.load(Ordering::SeqCst), 1 + 5);
+
+        // 计算出错时不缓存
+        calls.store(0, Ordering::SeqCst);
+        let compiled = Expr::compile("expensive(1, 2) + x")?.function("expensive", {
+            let counter = calls.clone();
+            move |_| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                Ok(Value::Int(1))
+            }
+        });
+        assert!(compiled.eval(&HashMap::new()).is_err());
+        let vars = HashMap::from([("x".to_string(), Value::Int(1))]);
+        assert_eq!(compiled.eval(&vars)?, Value::Int(2));
+        assert_eq!(compiled.eval(&vars)?, Value::Int(2));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // 修改设置之后重新计算
+        let compiled = Expr::compile("sin(90) + x")?;
+        let vars = HashMap::from([("x".to_string(), Value::Int(0))]);
+        assert_float(compiled.eval(&vars)?, 90f64.sin());
+        let compiled = compiled.angle_mode(AngleMode::Degrees);
+        assert_float(compiled.eval(&vars)?, 1.0);
+
+        // 局部变量不会被缓存，未知的函数在计算时返回错误
+        let compiled = Expr::compile("let a = 2 * 3 in a + x")?;
+        assert_eq!(compiled.eval(&vars)?, Value::Int(6));
+        assert!(Expr::compile("foo(1)")?.eval(&vars).is_err());
+        Ok(())
+    }
+
+    // 列表和下标访问
+    #[test]
+    fn test_list() -> Result<()> {
+        let eval = |src: &str| Expr::new(src).eval();
+        assert_eq!(eval("[1, 2, 3][1]")?, Value::Int(2));
+        assert_eq!(eval("[1 + 1, 2 * 3, 7][1] + 1")?, Value::Int(7));
+        assert_eq!(eval("[1, 2, 3][1 + 1]")?, Value::Int(3));
+        assert_eq!(eval("[[1, 2], [3, 4, 5]][1][2]")?, Value::Int(5));
+        assert_eq!(eval("[1.5, 1 < 2][1]")?, Value::Bool(true));
+        // 只计算选中的元素
+        assert_eq!(eval("[1, 1 // 0][0]")?, Value::Int(1));
+
+        let mut session = Session::new();
+        session.eval("i = 2")?;
+        assert_eq!(session.eval("[10, 20, 30][i] * 2")?, Value::Int(60));
+
+        // 越界和非法的下标
+        assert!(eval("[1, 2, 3][3]").is_err());
+        assert!(eval("[1, 2, 3][-1]").is_err());
+        assert!(eval("[][0]").is_err());
+        assert!(eval("[1, 2][0.5]").is_err());
+        assert!(eval("[1, 2").is_err());
+        assert!(eval("[1 2]").is_err());
+
+        assert_eq!(eval("len([1, 2, 3])")?, Value::Int(3));
+        assert_eq!(eval("len([])")?, Value::Int(0));
+        assert_eq!(eval("len([[1], [2, 3]][1])")?, Value::Int(2));
+
+        // 列表字面量的值是列表
+        let list = Value::List(vec![Value::Int(1), Value::Int(2)]);
+        assert_eq!(eval("[1, 2]")?, list);
+        assert_eq!(eval("[[1, 2]][0]")?, list);
+        assert_eq!(eval("[]")?, Value::List(vec![]));
+        assert_eq!(
+            eval("[1 + 1, [2 < 3], 1 / 2.0]")?,
+            Value::List(vec![
+                Value::Int(2),
+                Value::List(vec![Value::Bool(true)]),
+                Value::Float(0.5)
+            ])
+        );
+        assert_eq!(eval("[1, [2, 3]]")?.to_string(), "[1, [2, 3]]");
+        assert_eq!(format_result(eval("[0.1 + 0.2, 3]")?, 15), "[0.3, 3]");
+        session.eval("xs = [1, 2, 3]")?;
+        assert_eq!(session.eval("len(xs)")?, Value::Int(3));
+        // 变量、括号内的表达式也可以取下标
+        assert_eq!(session.eval("xs[1]")?, Value::Int(2));
+        assert_eq!(session.eval("xs[len(xs) - 1] * 2")?, Value::Int(6));
+        session.eval("m = [xs, [4]]")?;
+        assert_eq!(session.eval("m[0][2] + m[1][0]")?, Value::Int(7));
+        assert_eq!(session.eval("-xs[0]")?, Value::Int(-1));
+        assert_eq!(eval("([1, 2, 3])[1]")?, Value::Int(2));
+        assert_eq!(eval("(let a = [1, 2] in a)[1]")?, Value::Int(2));
+        assert!(session.eval("len(xs)[0]").is_err());
+        assert!(session.eval("xs[3]").is_err());
+        // 使用方括号调用函数时，标识符后面的方括号是函数调用，括号内的表达式仍然可以取下标
+        let brackets = |src| {
+            Expr::new(src)
+                .call_syntax(CallSyntax::Brackets)
+                .eval_with_vars(session.vars())
+        };
+        assert_eq!(brackets("(xs)[1] + sqrt[4]")?, Value::Float(4.0));
+        assert_eq!(
+            brackets("xs[1]").unwrap_err(),
+            ExprError::UnknownFunction("xs".into())
+        );
+        assert_eq!(
+            session.get("xs"),
+            Some(Value::List(vec![
+                Value::Int(1),
+                Value::Int(2),
+                Value::Int(3)
+            ]))
+        );
+        // 列表不能参与数值运算和比较
+        assert!(eval("[1] + 1").is_err());
+        assert!(eval("-[1]").is_err());
+        assert!(eval("[1] < [2]").is_err());
+        assert!(eval("sqrt([4])").is_err());
+        assert!(eval("[1, 1 // 0]").is_err());
+        assert_eq!(eval("len(\"abc\")")?, Value::Int(3));
+        assert!(eval("len(3)").is_err());
+        assert!(eval("ord([1])").is_err());
+
+        assert_eq!(normalize("[1,2 ,3][ 1 ]")?, "[1, 2, 3][1]");
+        assert_eq!(Expr::free_variables("[a, 1][b]")?.len(), 2);
+        let compiled = Expr::compile("[1, 2, 3][x] + [4, 5][1]")?;
+        let vars = HashMap::from([("x".to_string(), Value::Int(2))]);
+        assert_eq!(compiled.eval(&vars)?, Value::Int(8));
+        Ok(())
+    }
+
+    // 字符串拼接和比较
+    #[test]
+    fn test_string_concat_compare() -> Result<()> {
+        let eval = |src: &str| Expr::new(src).eval();
+        assert_eq!(eval("\"a\" + \"b\" == \"ab\"")?, Value::Bool(true));
+        assert_eq!(eval("\"a\" + \"b\" != \"ab\"")?, Value::Bool(false));
+        assert_eq!(eval("len(\"ab\" + \"cde\" + \"\")")?, Value::Int(5));
+        assert_eq!(eval("ord(\"\" + \"x\")")?, Value::Int(120));
+
+        // 字典序比较
+        assert_eq!(eval("\"abc\" < \"abd\"")?, Value::Bool(true));
+        assert_eq!(eval("\"b\" > \"abc\"")?, Value::Bool(true));
+        assert_eq!(eval("\"a\" <= \"a\" < \"a\" + \"a\"")?, Value::Bool(true));
+        assert_eq!(eval("\"B\" >= \"a\"")?, Value::Bool(false));
+        assert_eq!(eval("\"x\" + \"y\" == \"xy\" ? 1 : 2")?, Value::Int(1));
+        // 数字的加法不受影响
+        assert_eq!(eval("1 + 2 == 3")?, Value::Bool(true));
+
+        // 类型不一致
+        assert!(eval("\"a\" + 1").is_err());
+        assert!(eval("1 + \"a\" == \"1a\"").is_err());
+        assert!(eval("\"1\" == 1").is_err());
+        assert!(eval("1 < \"a\"").is_err());
+        assert!(eval("\"a\" * 2 == \"aa\"").is_err());
+        assert!(eval("\"a\" - \"a\"").is_err());
+        assert!(eval("\"a\" == true").is_err());
+        assert!(eval("[\"a\"] + \"b\"").is_err());
+
+        // 字符串是计算结果的一种，可以保存在变量和列表中
+        assert_eq!(eval("\"a\" + \"b\"")?, Value::Str("ab".into()));
+        assert_eq!(eval("1 < 2 ? \"x\" : \"y\"")?, Value::Str("x".into()));
+        assert_eq!(eval("[\"a\", \"b\" + \"c\"][1]")?, Value::Str("bc".into()));
+        assert_eq!(eval("[\"a\", 1]")?.to_string(), "[\"a\", 1]");
+        assert_eq!(eval("\"a\\\"b\"")?.to_string(), "\"a\\\"b\"");
+        let mut session = Session::new();
+        session.eval("s = \"ab\"")?;
+        assert_eq!(session.eval("s + \"c\" == \"abc\"")?, Value::Bool(true));
+        assert_eq!(session.eval("len(s + s)")?, Value::Int(4));
+        assert!(session.eval("s + 1").is_err());
+        let long = format!("\"{}\"", "a".repeat(3000));
+        let concat = format!("{} + {}", long, long);
+        assert!(Expr::new(&concat).eval().is_ok());
+        assert!(Expr::new(&concat).safe_mode(true).eval().is_err());
+
+        let compiled = Expr::compile("\"a\" + \"b\" == \"ab\" ? x : 0")?;
+        let vars = HashMap::from([("x".to_string(), Value::Int(7))]);
+        assert_eq!(compiled.eval(&vars)?, Value::Int(7));
+        Ok(())
+    }
+
+    #[test]
+    fn test_factorial() -> Result<()> {
+        assert_eq!(Expr::new("5!").eval()?, Value::Int(120));
+        assert_eq!(Expr::new("0!").eval()?, Value::Int(1));
+        assert_eq!(Expr::new("3!!").eval()?, Value::Int(720));
+        assert_eq!(Expr::new("2^3!").eval()?, Value::Int(64));
+        assert_eq!(Expr::new("-3! + 1").eval()?, Value::Int(-5));
+        assert_eq!(Expr::new("3 != 4").eval()?, Value::Bool(true));
+        assert!(Expr::new("(-1)!").eval().is_err());
+        assert!(Expr::new("2.5!").eval().is_err());
+        assert!(Expr::new("21!").eval().is_err());
+        assert_eq!(normalize("3!+1")?, "3! + 1");
+        Ok(())
+    }
+
+    #[test]
+    fn test_safe_mode() -> Result<()> {
+        // 默认允许
+        assert_eq!(Expr::new("2 ^ 62").eval()?, Value::Int(1 << 62));
+        assert_eq!(Expr::new("20!").eval()?, Value::Int(2432902008176640000));
+
+        let err = Expr::new("2 ^ 100").safe_mode(true).eval().unwrap_err();
+        assert_eq!(err.to_string(), "operation not allowed in safe mode");
+        let err = Expr::new("1.5 ^ -100.5")
+            .safe_mode(true)
+            .eval()
+            .unwrap_err();
+        assert_eq!(err.to_string(), "operation not allowed in safe mode");
+        let err = Expr::new("20!").safe_mode(true).eval().unwrap_err();
+        assert_eq!(err.to_string(), "operation not allowed in safe mode");
+
+        // 上限以内的运算不受影响
+        assert_eq!(
+            Expr::new("2 ^ 10").safe_mode(true).eval()?,
+            Value::Int(1024)
+        );
+        assert_eq!(
+            Expr::new("10!").safe_mode(true).eval()?,
+            Value::Int(3628800)
+        );
+        assert_eq!(
+            Expr::new("[1, 2, 3][1]").safe_mode(true).eval()?,
+            Value::Int(2)
+        );
+
+        let big = format!("len([{}])", vec!["1"; 1001].join(", "));
+        assert_eq!(Expr::new(&big).eval()?, Value::Int(1001));
+        assert!(Expr::new(&big).safe_mode(true).eval().is_err());
+
+        assert_eq!(
+            Expr::new("sum(1..=10000)").safe_mode(true).eval()?,
+            Value::Int(50005000)
+        );
+        assert_eq!(Expr::new("sum(1..=10001)").eval()?, Value::Int(50015001));
+        let err = Expr::new("sum(1..=10001)")
+            .safe_mode(true)
+            .eval()
+            .unwrap_err();
+        assert_eq!(err.to_string(), "operation not allowed in safe mode");
+        assert!(Expr::new("product(0..9223372036854775807)")
+            .safe_mode(true)
+            .eval()
+            .is_err());
+
+        let compiled = Expr::compile("x ^ 100")?.safe_mode(true);
+        let vars = HashMap::from([("x".to_string(), Value::Int(1))]);
+        assert!(compiled.eval(&vars).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_result_radix() -> Result<()> {
+        assert_eq!(format_result_radix(Value::Int(255), 16)?, "0xff");
+        assert_eq!(format_result_radix(Value::Int(255), 10)?, "255");
+        assert_eq!(format_result_radix(Value::Int(255), 8)?, "0o377");
+        assert_eq!(format_result_radix(Value::Int(255), 2)?, "0b11111111");
+        assert_eq!(format_result_radix(Value::Int(0), 2)?, "0b0");
+        assert_eq!(format_result_radix(Value::Int(-10), 2)?, "-0b1010");
+        assert_eq!(format_result_radix(Value::Int(-10), 8)?, "-0o12");
+        assert_eq!(format_result_radix(Value::Int(-10), 10)?, "-10");
+        assert_eq!(format_result_radix(Value::Int(-10), 16)?, "-0xa");
+        assert_eq!(
+            format_result_radix(Value::Int(i64::MIN), 16)?,
+            "-0x8000000000000000"
+        );
+        assert_eq!(format_result_radix(Value::Float(1.5), 16)?, "1.5");
+        assert!(format_result_radix(Value::Int(1), 3).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_grouped() {
+        assert_eq!(format_grouped(Value::Int(0), ','), "0");
+        assert_eq!(format_grouped(Value::Int(999), ','), "999");
+        assert_eq!(format_grouped(Value::Int(1000), ','), "1,000");
+        assert_eq!(format_grouped(Value::Int(123456), ','), "123,456");
+        assert_eq!(format_grouped(Value::Int(1000000), ','), "1,000,000");
+        assert_eq!(format_grouped(Value::Int(-42), ','), "-42");
+        assert_eq!(format_grouped(Value::Int(-1234567), ','), "-1,234,567");
+        assert_eq!(
+            format_grouped(Value::Int(i64::MIN), ','),
+            "-9,223,372,036,854,775,808"
+        );
+        assert_eq!(format_grouped(Value::Int(1000000), '_'), "1_000_000");
+        assert_eq!(format_grouped(Value::Int(1234567), ' '), "1 234 567");
+        assert_eq!(format_grouped(Value::Float(1.5), ','), "1.5");
+    }
+
+    #[test]
+    fn test_unary_minus() -> Result<()> {
+        assert_eq!(Expr::new("3 - -4").eval()?, Value::Int(7));
+        assert_eq!(Expr::new("3 - - 4").eval()?, Value::Int(7));
+        assert_eq!(Expr::new("3 -- 4").eval()?, Value::Int(7));
+        assert_eq!(Expr::new("--4").eval()?, Value::Int(4));
+
+        let err = Expr::new("--").eval().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Expecting an operand after '-' at position 1"
+        );
+        let err = Expr::new("3 -- ").eval().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Expecting an operand after '-' at position 3"
+        );
+        let err = Expr::new("-").eval().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Expecting an operand after '-' at position 0"
+        );
+
+        // 负号后面不是操作数，后面还有其他的 - 或者使用 Unicode 减号
+        let err = Expr::new("(3 - -)").eval().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Expecting an operand after '-' at position 5"
+        );
+        let err = Expr::new("2 * - , 1 - 2").eval().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Expecting an operand after '-' at position 4"
+        );
+        let err = Expr::new("1 - \u{2212}").eval().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Expecting an operand after '-' at position 4"
+        );
+        let err = Expr::new("\"é\" + \u{2212}").eval().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Expecting an operand after '-' at position 6"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_overflow() -> Result<()> {
+        let max = "9223372036854775807";
+        for src in [
+            format!("{} + 1", max),
+            format!("-{} - 2", max),
+            format!("{} * 2", max),
+            "2 ^ 64".to_string(),
+            format!("(-{} - 1) // -1", max),
+            format!("-(-{} - 1)", max),
+        ] {
+            let err = Expr::new(&src).eval().unwrap_err();
+            assert_eq!(err.to_string(), "Integer overflow", "{}", src);
+        }
+
+        let wrap = |src: &str| Expr::new(src).overflow(Overflow::Wrap).eval();
+        assert_eq!(wrap(&format!("{} + 1", max))?, Value::Int(i64::MIN));
+        assert_eq!(wrap(&format!("-{} - 2", max))?, Value::Int(i64::MAX));
+        assert_eq!(wrap(&format!("{} * 2", max))?, Value::Int(-2));
+        assert_eq!(wrap("2 ^ 64")?, Value::Int(0));
+        assert_eq!(wrap("3 ^ 40")?, Value::Int(3i64.wrapping_pow(40)));
+        assert_eq!(
+            wrap(&format!("(-{} - 1) // -1", max))?,
+            Value::Int(i64::MIN)
+        );
+        assert_eq!(wrap(&format!("-(-{} - 1)", max))?, Value::Int(i64::MIN));
+
+        // 没有溢出时两种方式的结果相同，负数指数按浮点数计算
+        assert_eq!(wrap("7 // -2")?, Value::Int(-4));
+        assert_eq!(Expr::new("2 ^ -1").eval()?, Value::Float(0.5));
+        Ok(())
+    }
+
+    #[test]
+    fn test_error_kinds() {
+        let err = |src: &str| Expr::new(src).eval().unwrap_err();
+        assert!(matches!(err("1 +"), ExprError::Syntax(_)));
+        assert!(matches!(err("(1 + 2"), ExprError::Syntax(_)));
+        assert!(matches!(err("2 $ 3"), ExprError::Syntax(_)));
+        assert!(matches!(
+            Expr::new("((1))").max_depth(1).eval().unwrap_err(),
+            ExprError::Syntax(_)
+        ));
+        assert_eq!(err("1 / 0"), ExprError::DivByZero);
+        assert_eq!(err("1.5 // 0"), ExprError::DivByZero);
+        assert_eq!(
+            Expr::new("1 / 0").rational(true).eval().unwrap_err(),
+            ExprError::DivByZero
+        );
+        assert!(matches!(
+            err("9223372036854775807 + 1"),
+            ExprError::Overflow(_)
+        ));
+        assert!(matches!(err("21!"), ExprError::Overflow(_)));
+        assert!(matches!(err("product(1..=30)"), ExprError::Overflow(_)));
+        assert_eq!(err("x + 1"), ExprError::UndefinedVariable("x".into()));
+        assert_eq!(err("foo(1)"), ExprError::UnknownFunction("foo".into()));
+        assert!(matches!(err("1 ? 2 : 3"), ExprError::Parse(_)));
+
+        // 错误信息不变
+        assert_eq!(err("1 / 0").to_string(), "Division by zero");
+        assert_eq!(err("x + 1").to_string(), "Unknown identifier x");
+        assert_eq!(err("foo(1)").to_string(), "Unknown function foo");
+    }
+}
@@ -1,21 +1,301 @@
 use fs4::FileExt;
+use sha2::{Digest, Sha256};
 use std::{
-    collections::btree_map,
-    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    io::{BufReader, Read, Seek, SeekFrom, Write},
     ops::Bound,
-    path::PathBuf,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
 };
 
 const KEY_VAL_HEADER_LEN: u32 = 4;
 const MERGE_FILE_EXT: &str = "merge";
+const REPAIR_FILE_EXT: &str = "repair";
+const BACKUP_FILE_EXT: &str = "bak";
+const EPOCH_FILE_EXT: &str = "epoch";
+const TIMESTAMP_FILE_EXT: &str = "ts";
+// BTreeMap 中每条数据的额外开销的估计值：节点中的父指针、长度、子节点指针以及未使用的槽位，
+// 按每个节点 11 个槽位、平均一半以上被使用来估算
+const KEYDIR_ENTRY_OVERHEAD: usize = 16;
 
-type KeyDir = std::collections::BTreeMap<Vec<u8>, (u64, u32)>;
+// 内存索引，key -> (value 的位置, value 的长度)
+// key 使用 Box<[u8]> 而不是 Vec<u8> 保存：少了一个 capacity 字段，也不会有多余的容量，
+// 每个 key 至少节省 8 个字节，key 数量很多（例如上百万个很短的 key）时可以明显减少内存占用
+type KeyDir = std::collections::BTreeMap<Box<[u8]>, (u64, u32)>;
+
+// 索引中的一条数据
+type KeyDirEntry = (Box<[u8]>, (u64, u32));
+
+// 最后一条记录是删除记录的 key，也就是日志中没有被后续写入覆盖的删除记录
+type Tombstones = HashSet<Box<[u8]>>;
+
+// 重写分段后分段原来和现在的起始位置，以及被重写的分段中 value 原来的位置到现在在分段中的位置的映射
+type SegmentMove = (u64, u64, Option<HashMap<u64, u64>>);
 
 pub type Result<T> = std::result::Result<T, std::io::Error>;
 
+// 日志中的一条变更记录，key 以及写入的 value，删除的记录 value 为 None
+pub type Change = (Vec<u8>, Option<Vec<u8>>);
+
+// 按照 MVCC 的编码格式解码 key，返回原始的 key 和版本号
+// 编码格式为 8 字节大端的 key 长度 + key + 8 字节大端的版本号，长度不匹配时返回 None
+fn decode_mvcc_key(key: &[u8]) -> Option<(&[u8], u64)> {
+    if key.len() < 16 {
+        return None;
+    }
+    let (len, rest) = key.split_at(8);
+    let len = u64::from_be_bytes(len.try_into().ok()?);
+    if len != rest.len() as u64 - 8 {
+        return None;
+    }
+    let (raw_key, version) = rest.split_at(rest.len() - 8);
+    Some((raw_key, u64::from_be_bytes(version.try_into().ok()?)))
+}
+
+// 统计日志中损坏的部分包含的记录数，tail 从第一条损坏的记录开始
+// 损坏的记录之后的记录边界是未知的，从下一个字节开始逐个尝试，第一个能连续解析到末尾的位置之后的记录都被计入，
+// 找不到时只有损坏的这一条记录，例如进程崩溃时没有写完的最后一条记录
+fn count_dropped(tail: &[u8]) -> usize {
+    if tail.is_empty() {
+        return 0;
+    }
+    (1..tail.len())
+        .find_map(|start| count_records(&tail[start..]))
+        .map_or(1, |count| count + 1)
+}
+
+// 从头解析 buf 中的记录，最后一条记录正好在末尾结束时返回记录数
+fn count_records(buf: &[u8]) -> Option<usize> {
+    let mut pos = 0;
+    let mut count = 0;
+    while pos < buf.len() {
+        let header = pos + KEY_VAL_HEADER_LEN as usize * 2;
+        let key_len = u32::from_be_bytes(buf.get(pos..pos + 4)?.try_into().ok()?);
+        let value_len = i32::from_be_bytes(buf.get(pos + 4..header)?.try_into().ok()?);
+        if value_len < -1 {
+            return None;
+        }
+        pos = header + key_len as usize + value_len.max(0) as usize;
+        count += 1;
+    }
+    (pos == buf.len()).then_some(count)
+}
+
 pub struct MiniBitcask {
     log: Log,
     keydir: KeyDir,
+    // 数据变更的订阅者
+    subscribers: Mutex<Vec<Sender<ChangeEvent>>>,
+    // value 的读缓存，默认不开启
+    cache: Option<ValueCache>,
+    // 当前日志中有效的删除记录
+    tombstones: Tombstones,
+    // 自动 merge 的策略
+    merge_policy: MergePolicy,
+    // with_value 读取 value 使用的缓冲区，多次调用之间复用
+    read_buf: Vec<u8>,
+    // 打开数据库时分配的 epoch
+    epoch: u64,
+    // 只读时内存索引和日志不一致，例如不截断日志的 recover_to_timestamp，所有的写入都会被拒绝
+    read_only: bool,
+}
+
+// 使用过期的 epoch 写入时返回的错误，包装在 std::io::Error 中，可以通过 get_ref 和 downcast_ref 获取
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FencedOut {
+    // 写入时使用的 epoch
+    pub epoch: u64,
+    // 当前最新的 epoch
+    pub current: u64,
+}
+
+impl std::fmt::Display for FencedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "fenced out, epoch {} is older than current epoch {}",
+            self.epoch, self.current
+        )
+    }
+}
+
+impl std::error::Error for FencedOut {}
+
+// 自动 merge 的策略，默认不会自动 merge
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MergePolicy {
+    // 删除记录的数量超过这个值时，在 delete 之后自动 merge
+    pub max_tombstones: Option<usize>,
+}
+
+// 分段保存日志的配置，通过 MiniBitcask::open_segmented 使用
+// 分段保存在同一个目录中，文件名为 prefix + 6 位序号 + suffix，默认是 data-000001.log，序号从 1 开始递增
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentOptions {
+    pub prefix: String,
+    pub suffix: String,
+    // 当前分段的大小达到这个值之后，下一次写入会创建新的分段
+    // 一次写入的记录总是在同一个分段中，所以分段的大小可能超过这个值
+    pub max_size: u64,
+}
+
+impl Default for SegmentOptions {
+    fn default() -> Self {
+        Self {
+            prefix: "data-".to_string(),
+            suffix: ".log".to_string(),
+            max_size: 64 * 1024 * 1024,
+        }
+    }
+}
+
+impl SegmentOptions {
+    fn file_name(&self, id: u64) -> String {
+        format!("{}{:06}{}", self.prefix, id, self.suffix)
+    }
+
+    // 从文件名中解析分段的序号，不符合格式的文件返回 None
+    fn parse_id(&self, name: &str) -> Option<u64> {
+        let id = name
+            .strip_prefix(&self.prefix)?
+            .strip_suffix(&self.suffix)?;
+        if id.len() < 6 || !id.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        id.parse().ok()
+    }
+}
+
+// value 的编码方式，写入日志之前调用 encode，从日志读取之后调用 decode，例如压缩、加密或者校验
+// 删除记录没有 value，不会经过编码；日志中的 value 长度是编码之后的长度
+// 重新打开数据库时需要使用相同的编码方式，否则读取时会返回错误或者错误的数据
+pub trait ValueCodec: Send + Sync {
+    fn encode(&self, v: &[u8]) -> Vec<u8>;
+    fn decode(&self, v: &[u8]) -> Result<Vec<u8>>;
+}
+
+// 数据库的统计信息
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    // 有效的 key 数量
+    pub keys: usize,
+    // 日志中没有被覆盖的删除记录数量，merge 之后清零
+    pub tombstones: usize,
+}
+
+// 日志修复的结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RepairReport {
+    // 保留的有效记录数
+    pub kept: usize,
+    // 丢弃的记录数，包括第一条损坏的记录以及之后能识别出的记录
+    pub dropped: usize,
+    // 第一条损坏的记录及其之后被丢弃的字节数
+    pub dropped_bytes: u64,
+}
+
+// 索引检查的结果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditReport {
+    // 检查的 key 数量
+    pub checked: usize,
+    // 索引和日志不一致的 key
+    pub mismatches: Vec<Vec<u8>>,
+}
+
+// 批量写入中的一个操作
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    Set(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+}
+
+// 合并两个数据库时，key 在两边都存在的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    // 保留当前数据库的值
+    KeepSelf,
+    // 使用另一个数据库的值覆盖
+    TakeOther,
+}
+
+// 数据变更的类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    Set,
+    Delete,
+}
+
+// 数据变更事件，每次 set、delete 之后发送给订阅者
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeEvent {
+    pub key: Vec<u8>,
+    pub kind: ChangeKind,
+}
+
+// 分多次写入 value，通过 MiniBitcask::set_streaming 创建
+pub struct ValueWriter<'a> {
+    db: &'a mut MiniBitcask,
+    key: Vec<u8>,
+    // 记录在日志中的起始位置
+    offset: u64,
+    total_len: u32,
+    written: u32,
+}
+
+impl ValueWriter<'_> {
+    // 还需要写入的字节数
+    pub fn remaining(&self) -> u32 {
+        self.total_len - self.written
+    }
+
+    // value 写完之后更新内存索引
+    fn finish(&mut self) {
+        let len = KEY_VAL_HEADER_LEN * 2 + self.key.len() as u32 + self.total_len;
+        let key = std::mem::take(&mut self.key);
+        self.db.apply_entry(&key, true, self.offset, len);
+    }
+}
+
+impl Write for ValueWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let n = buf.len().min(self.remaining() as usize);
+        if n == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("value is longer than {} bytes", self.total_len),
+            ));
+        }
+        self.db.log.file.seek(SeekFrom::End(0))?;
+        self.db.log.file.write_all(&buf[..n])?;
+        self.written += n as u32;
+        if self.remaining() == 0 {
+            self.finish();
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.db.log.file.flush()
+    }
+}
+
+impl Drop for ValueWriter<'_> {
+    fn drop(&mut self) {
+        // 没有写完的记录，截断到记录开始的位置，之后的写入不会受到影响
+        if self.remaining() > 0 {
+            let local = self.offset - self.db.log.base();
+            if let Err(error) = self.db.log.file.set_len(local) {
+                log::error!("failed to truncate incomplete value: {}", error);
+            }
+        }
+    }
 }
 
 impl Drop for MiniBitcask {
@@ -28,40 +308,658 @@ impl Drop for MiniBitcask {
 
 impl MiniBitcask {
     pub fn new(path: PathBuf) -> Result<Self> {
-        let mut log = Log::new(path)?;
-        let keydir = log.load_index()?;
-        Ok(Self { log, keydir })
+        Self::open_log(Log::new(path)?)
+    }
+
+    // 打开分段保存的数据库，dir 是保存分段的目录，epoch 等附属文件保存为 dir 中的 log.epoch 等文件
+    // 日志中的位置是所有分段按顺序拼接之后的位置，changes_since、truncate_to 等的使用方式和不分段时一样
+    // merge 把有效的数据写入一个新的分段，然后删除之前所有的分段；repair 和 recover_to_timestamp 只支持不分段的日志
+    pub fn open_segmented(dir: PathBuf, options: SegmentOptions) -> Result<Self> {
+        Self::open_log(Log::open_segmented(dir, options)?)
+    }
+
+    fn open_log(mut log: Log) -> Result<Self> {
+        let (keydir, tombstones) = log.load_index()?;
+        let epoch = log.read_epoch()? + 1;
+        log.write_epoch(epoch)?;
+        Ok(Self {
+            log,
+            keydir,
+            subscribers: Mutex::new(Vec::new()),
+            cache: None,
+            tombstones,
+            merge_policy: MergePolicy::default(),
+            read_buf: Vec::new(),
+            epoch,
+            read_only: false,
+        })
+    }
+
+    // 打开数据库时分配的 epoch，每次打开都会加一并保存在日志旁边的 .epoch 文件中
+    // 配合 set_fenced、delete_fenced 使用，新的实例打开之后，旧实例使用之前的 epoch 写入会被拒绝，
+    // 例如故障切换之后仍然在运行的旧进程；正常情况下文件锁已经保证同时只有一个实例，
+    // epoch 用于文件锁失效的情况（例如锁的租约过期），检查和写入之间不是原子的，只能尽量阻止旧实例的写入
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    // 带 epoch 检查的写入，epoch 不是最新的时返回 FencedOut 错误
+    pub fn set_fenced(&mut self, key: &[u8], value: Vec<u8>, epoch: u64) -> Result<()> {
+        self.check_epoch(epoch)?;
+        self.set(key, value)
+    }
+
+    // 带 epoch 检查的删除，epoch 不是最新的时返回 FencedOut 错误
+    pub fn delete_fenced(&mut self, key: &[u8], epoch: u64) -> Result<()> {
+        self.check_epoch(epoch)?;
+        self.delete(key)
+    }
+
+    // 只读时返回错误
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "database is read-only",
+            ));
+        }
+        Ok(())
+    }
+
+    // 每次都读取文件中最新的 epoch，其他实例打开之后这里就能看到
+    fn check_epoch(&self, epoch: u64) -> Result<()> {
+        let current = self.log.read_epoch()?;
+        if epoch != current {
+            return Err(std::io::Error::other(FencedOut { epoch, current }));
+        }
+        Ok(())
+    }
+
+    // 开启 value 的读缓存，最多缓存 capacity 个 key，超出时淘汰最久没有访问的 key
+    pub fn with_cache(mut self, capacity: usize) -> Self {
+        self.cache = Some(ValueCache::new(capacity));
+        self
+    }
+
+    // 开启写缓冲区，多条记录先在内存中累积，超过 capacity 字节时再一起写入文件，适用于大量很小的写入
+    // 内存索引仍然在每次写入时立即更新，读取日志之前会先写入缓冲区中的数据，所以总是能读到之前的写入
+    // 缓冲区中的数据在 sync、读取日志以及关闭数据库时写入文件，sync 之后才保证持久化：
+    // 不使用缓冲区时，进程崩溃不会丢失已经返回的写入，使用缓冲区时，进程崩溃会丢失上一次写入文件之后的写入
+    pub fn with_write_buffer(mut self, capacity: usize) -> Self {
+        self.log.write_buf_capacity = capacity;
+        self
+    }
+
+    // 设置 value 的编码方式，需要在打开之后、读写数据之前设置
+    // merge、export 等重写的日志使用同样的编码方式，之后打开时也需要设置相同的编码方式
+    pub fn with_codec(mut self, codec: impl ValueCodec + 'static) -> Self {
+        self.log.codec = Some(Arc::new(codec));
+        self
+    }
+
+    // 设置自动 merge 的策略
+    pub fn with_merge_policy(mut self, policy: MergePolicy) -> Self {
+        self.merge_policy = policy;
+        self
+    }
+
+    // 按顺序返回数据文件的路径，最后一个是当前写入的分段，不分段时只有日志文件本身
+    pub fn list_segments(&self) -> Vec<PathBuf> {
+        match &self.log.segments {
+            Some(segments) => segments.ids().map(|id| segments.path(id)).collect(),
+            None => vec![self.log.path.clone()],
+        }
+    }
+
+    // 获取统计信息
+    pub fn stats(&self) -> Stats {
+        Stats {
+            keys: self.keydir.len(),
+            tombstones: self.tombstones.len(),
+        }
+    }
+
+    // 内存索引占用的内存的估计值（字节），不包括 tombstones 和读写缓冲区
+    // 每条数据按 key 的长度 + 数据本身的大小 + 固定的 BTreeMap 节点开销计算，只是一个近似值，
+    // 实际的占用还和内存分配器、节点的填充程度有关
+    pub fn keydir_memory_bytes(&self) -> usize {
+        let fixed = std::mem::size_of::<KeyDirEntry>() + KEYDIR_ENTRY_OVERHEAD;
+        self.keydir.keys().map(|key| key.len() + fixed).sum()
+    }
+
+    // 计算所有有效数据的 SHA-256 摘要，按 key 的顺序依次加入每个 key 和 value
+    // 只和逻辑上的数据有关，和日志的布局无关，merge 前后、或者写入顺序不同的两个数据库，
+    // 只要数据相同，摘要就相同，可以用来校验数据或者比较副本
+    pub fn digest(&mut self) -> Result<[u8; 32]> {
+        let mut hasher = Sha256::new();
+        let mut value = Vec::new();
+        for (key, (value_pos, value_len)) in self.keydir.iter() {
+            self.log
+                .read_value_into(*value_pos, *value_len, &mut value)?;
+            // 加上长度，避免 key 和 value 的边界不同但拼接起来相同的情况
+            hasher.update((key.len() as u32).to_be_bytes());
+            hasher.update(key);
+            hasher.update((value.len() as u32).to_be_bytes());
+            hasher.update(&value);
+        }
+        Ok(hasher.finalize().into())
+    }
+
+    // value 最大的 n 个 key 及其 value 的长度，按长度从大到小排列，长度相同时按 key 排列
+    // 只使用内存索引中的长度，不需要读取日志，可以用来找出占用空间最多的 key
+    pub fn top_keys_by_size(&self, n: usize) -> Vec<(Vec<u8>, u32)> {
+        let mut sizes: Vec<_> = self
+            .keydir
+            .iter()
+            .map(|(key, (_, value_len))| (key, *value_len))
+            .collect();
+        // keydir 已经按照 key 排好序，稳定排序之后长度相同的 key 仍然保持原来的顺序
+        sizes.sort_by_key(|&(_, len)| std::cmp::Reverse(len));
+        sizes
+            .into_iter()
+            .take(n)
+            .map(|(key, len)| (key.to_vec(), len))
+            .collect()
+    }
+
+    // 日志中记录的总数，包括被覆盖的记录和删除记录，需要读取整个日志
+    // 和有效的 key 的数量（stats().keys）相比，可以看出日志的碎片程度，判断是否需要 merge
+    pub fn physical_record_count(&mut self) -> Result<usize> {
+        let file_len = self.log.len()?;
+        let mut count = 0;
+        let mut pos = 0;
+        while pos < file_len {
+            let (_, _, len) = self.log.read_entry(pos, file_len)?;
+            pos += len;
+            count += 1;
+        }
+        Ok(count)
     }
 
     pub fn merge(&mut self) -> Result<()> {
+        self.check_writable()?;
         // 创建一个新的临时用于用于写入
         let mut merge_path = self.log.path.clone();
         merge_path.set_extension(MERGE_FILE_EXT);
 
+        let (new_log, new_keydir) = self.rewrite(merge_path)?;
+        self.replace_log(new_log, new_keydir)?;
+        // 重写后的日志中没有删除记录
+        self.tombstones.clear();
+
+        Ok(())
+    }
+
+    // 用于保存 MVCC 数据的合并：每个 key 的多个版本分别以不同的编码 key 保存，
+    // 普通的 merge 无法回收旧版本，这里每个 key 只保留最新的 min_versions_to_keep 个版本，
+    // 同时保留活跃事务可能读到的版本：不小于 watermark（最早的活跃事务的版本）的版本，
+    // 以及小于 watermark 的最新一个版本，没有活跃事务时 watermark 传 u64::MAX
+    // 无法按 MVCC 格式解码的 key 原样保留，返回回收的版本数量
+    pub fn merge_mvcc(&mut self, min_versions_to_keep: usize, watermark: u64) -> Result<usize> {
+        self.check_writable()?;
+        // keydir 按字节排序，同一个 key 的版本连续排列，并且按版本从小到大排列
+        let mut dropped = HashSet::new();
+        let mut versions: Vec<(&[u8], u64)> = Vec::new();
+        let mut keys = self.keydir.keys().peekable();
+        while let Some(key) = keys.next() {
+            let Some((raw_key, version)) = decode_mvcc_key(key) else {
+                continue;
+            };
+            versions.push((key, version));
+            let last = keys
+                .peek()
+                .and_then(|next| decode_mvcc_key(next))
+                .is_none_or(|(next, _)| next != raw_key);
+            if !last {
+                continue;
+            }
+            // 小于 watermark 的最新一个版本是活跃事务能读到的版本，需要保留
+            let visible = versions.iter().rposition(|(_, v)| *v < watermark);
+            let n = versions.len();
+            for (i, (key, version)) in versions.drain(..).enumerate() {
+                let keep =
+                    i + min_versions_to_keep >= n || version >= watermark || Some(i) == visible;
+                if !keep {
+                    dropped.insert(key);
+                }
+            }
+        }
+        if dropped.is_empty() {
+            return Ok(0);
+        }
+        let dropped: HashSet<Box<[u8]>> = dropped.into_iter().map(Box::from).collect();
+
+        let mut merge_path = self.log.path.clone();
+        merge_path.set_extension(MERGE_FILE_EXT);
+        let (new_log, new_keydir) =
+            self.rewrite_filtered(merge_path, |key| !dropped.contains(key))?;
+        self.replace_log(new_log, new_keydir)?;
+        self.tombstones.clear();
+
+        Ok(dropped.len())
+    }
+
+    // 只合并一个范围内的 key，适用于只有部分 key 频繁更新的情况
+    // 范围内的 key 只保留有效的记录，删除记录也会被清除；范围外的记录原样保留，包括历史版本和删除记录
+    // 分段时先把范围内有效的记录追加到新的分段中，再只重写包含范围内记录的分段，其他分段不会被读取或者修改；
+    // 不分段时日志只有一个文件，这个文件就是受影响的分段，会被整个重写
+    pub fn merge_range(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<()> {
+        self.check_writable()?;
+        if self.log.segments.is_some() {
+            return self.merge_range_segments(range);
+        }
+        let mut merge_path = self.log.path.clone();
+        merge_path.set_extension(MERGE_FILE_EXT);
         let mut new_log = Log::new(merge_path)?;
+        new_log.file.set_len(0)?;
+        new_log.codec = self.log.codec.clone();
         let mut new_keydir = KeyDir::new();
 
-        // 重写数据
-        for (key, (value_pos, value_len)) in self.keydir.iter() {
-            let value = self.log.read_value(*value_pos, *value_len)?;
-            let (offset, len) = new_log.write_entry(key, Some(&value))?;
-            new_keydir.insert(
-                key.clone(),
-                (offset + len as u64 - *value_len as u64, *value_len),
-            );
+        let file_len = self.log.len()?;
+        let mut pos = 0;
+        while pos < file_len {
+            let (key, value, len) = self.log.read_entry(pos, file_len)?;
+            // 范围内的 key 只保留索引中指向的那一条记录
+            let keep = if range.contains(&key) {
+                let value_pos = pos + KEY_VAL_HEADER_LEN as u64 * 2 + key.len() as u64;
+                value.is_some()
+                    && self.keydir.get(key.as_slice()).map(|(p, _)| *p) == Some(value_pos)
+            } else {
+                true
+            };
+            pos += len;
+            if !keep {
+                continue;
+            }
+
+            let (offset, new_len) = new_log.write_entry(&key, value.as_deref())?;
+            match value {
+                Some(_) => {
+                    // 编码之后的长度可能和读到的 value 不同，根据记录的长度计算
+                    let value_len = new_len - KEY_VAL_HEADER_LEN * 2 - key.len() as u32;
+                    new_keydir.insert(
+                        key.into_boxed_slice(),
+                        (offset + new_len as u64 - value_len as u64, value_len),
+                    );
+                }
+                None => {
+                    new_keydir.remove(key.as_slice());
+                }
+            }
+        }
+
+        self.replace_log(new_log, new_keydir)?;
+        self.tombstones.retain(|key| !range.contains(&key.to_vec()));
+        Ok(())
+    }
+
+    fn merge_range_segments(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<()> {
+        // 找到包含范围内记录的分段，没有时不需要合并
+        let file_len = self.log.len()?;
+        let mut affected = HashSet::new();
+        self.log.for_each_record(file_len, |start, key, _, _| {
+            if range.contains(&key) {
+                affected.insert(start);
+            }
+        })?;
+        if affected.is_empty() {
+            return Ok(());
+        }
+
+        // 结束当前的分段，之前所有的记录都在已经写满的分段中，范围内有效的记录追加到之后的分段中
+        self.log.roll()?;
+        let keys: Vec<Box<[u8]>> = self
+            .keydir
+            .keys()
+            .filter(|key| range.contains(&key.to_vec()))
+            .cloned()
+            .collect();
+        for key in keys {
+            let (value_pos, value_len) = self.keydir[&key];
+            let value = self.log.read_value(value_pos, value_len)?;
+            let (offset, len) = self.log.write_entry(&key, Some(&value))?;
+            let value_len = len - KEY_VAL_HEADER_LEN * 2 - key.len() as u32;
+            self.keydir
+                .insert(key, (offset + len as u64 - value_len as u64, value_len));
+        }
+
+        // 受影响的分段中范围内的记录都已经失效，包括删除记录，只保留范围外的记录
+        let moves = self
+            .log
+            .retain_segments(&affected, |key| !range.contains(&key.to_vec()))?;
+        for (value_pos, _) in self.keydir.values_mut() {
+            let i = moves.partition_point(|(start, _, _)| *start <= *value_pos) - 1;
+            let (start, new_start, moved) = &moves[i];
+            *value_pos = match moved {
+                Some(moved) => new_start + moved[value_pos],
+                None => *value_pos - start + new_start,
+            };
+        }
+        self.tombstones.retain(|key| !range.contains(&key.to_vec()));
+        // 记录的位置发生了变化，之前的时间点不再有效
+        self.log.write_timestamps(&[])?;
+        if let Some(cache) = self.cache.as_mut() {
+            cache.clear();
         }
+        Ok(())
+    }
 
-        // 重写完成，重命名文件
-        std::fs::rename(new_log.path, self.log.path.clone())?;
+    // 使用重写完成的日志替换现在的日志
+    fn replace_log(&mut self, mut new_log: Log, new_keydir: KeyDir) -> Result<()> {
+        match &self.log.segments {
+            // 分段时重写完成的日志作为一个新的分段，然后删除之前所有的分段
+            Some(segments) => {
+                let id = segments.active_id + 1;
+                std::fs::rename(&new_log.path, segments.path(id))?;
+                for old in segments.ids() {
+                    std::fs::remove_file(segments.path(old))?;
+                }
+                new_log.segments = Some(Segments {
+                    options: segments.options.clone(),
+                    dir: segments.dir.clone(),
+                    sealed: Vec::new(),
+                    active_id: id,
+                    base: 0,
+                });
+            }
+            // 重写完成，重命名文件
+            None => std::fs::rename(&new_log.path, &self.log.path)?,
+        }
 
         new_log.path = self.log.path.clone();
+        new_log.write_buf_capacity = self.log.write_buf_capacity;
         // 替换现在的
         self.log = new_log;
         self.keydir = new_keydir;
+        // 记录的位置发生了变化，之前的时间点不再有效
+        self.log.write_timestamps(&[])?;
+        // 数据的位置发生了变化，清空缓存
+        if let Some(cache) = self.cache.as_mut() {
+            cache.clear();
+        }
+        Ok(())
+    }
+
+    // 修复损坏的日志文件，例如进程崩溃后留下的不完整记录
+    // 从头读取日志，直到第一条无法完整读取的记录为止，将之前的记录写入一个新的日志文件并替换原文件
+    // 原文件会先备份为 .bak 文件，修复期间会持有文件锁
+    pub fn repair(path: PathBuf) -> Result<RepairReport> {
+        let mut log = Log::new(path.clone())?;
+        let file_len = log.file.metadata()?.len();
+
+        let mut entries = Vec::new();
+        let mut pos = 0;
+        while pos < file_len {
+            match log.read_entry(pos, file_len) {
+                Ok((key, value, len)) => {
+                    entries.push((key, value));
+                    pos += len;
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err),
+            }
+        }
+        let mut tail = Vec::new();
+        log.file.seek(SeekFrom::Start(pos))?;
+        log.file.read_to_end(&mut tail)?;
+
+        // 备份原文件
+        let mut backup_path = path.clone();
+        backup_path.set_extension(BACKUP_FILE_EXT);
+        std::fs::copy(&path, backup_path)?;
+
+        // 写入有效的记录，完成后重命名文件
+        let mut repair_path = path.clone();
+        repair_path.set_extension(REPAIR_FILE_EXT);
+        let mut new_log = Log::new(repair_path)?;
+        new_log.file.set_len(0)?;
+        for (key, value) in entries.iter() {
+            new_log.write_entry(key, value.as_deref())?;
+        }
+        new_log.file.sync_all()?;
+        std::fs::rename(&new_log.path, &path)?;
+
+        Ok(RepairReport {
+            kept: entries.len(),
+            dropped: count_dropped(&tail),
+            dropped_bytes: file_len - pos,
+        })
+    }
+
+    // 记录一个时间点：到目前为止写入的所有记录的时间都不晚于 ts，保存在日志旁边的 .ts 文件中
+    // ts 的单位由调用方决定，例如毫秒时间戳，不能比上一次记录的小；配合 recover_to_timestamp 使用
+    // merge 会改变记录的位置，之前记录的时间点全部失效并被清除
+    pub fn mark_timestamp(&mut self, ts: u64) -> Result<()> {
+        self.check_writable()?;
+        let offset = self.log.len()?;
+        if let Some(&(_, last)) = self.log.read_timestamps()?.last() {
+            if ts < last {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("timestamp {} is earlier than the last one {}", ts, last),
+                ));
+            }
+        }
+        self.log.append_timestamp(offset, ts)
+    }
+
+    // 将数据库恢复到 ts 时的状态，只使用最后一个不晚于 ts 的时间点之前写入的记录构建索引，用于撤销最近错误的写入
+    // 两个时间点之间写入的记录不知道具体的时间，所以不晚于 ts 的时间点之后的记录都会被忽略
+    // 没有不晚于 ts 的时间点时返回错误，例如从来没有调用过 mark_timestamp，或者 merge 之后时间点已经被清除
+    // truncate 为 false 时只在内存中按照 ts 时的状态构建索引，日志不变，之后的记录在重新打开时仍然可见，
+    // 这时索引和日志不一致，返回的数据库是只读的，只能用来查看或者通过 export 导出 ts 时的数据；
+    // truncate 为 true 时截断之后的记录，恢复之前先把原来的日志备份到 .bak 文件中
+    pub fn recover_to_timestamp(path: PathBuf, ts: u64, truncate: bool) -> Result<MiniBitcask> {
+        let mut db = MiniBitcask::new(path.clone())?;
+        let file_len = db.log.len()?;
+        let offset = db
+            .log
+            .read_timestamps()?
+            .into_iter()
+            .take_while(|(offset, t)| *t <= ts && *offset <= file_len)
+            .last()
+            .map(|(offset, _)| offset)
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("no timestamp mark at or before {}", ts),
+                )
+            })?;
+        if offset == file_len {
+            return Ok(db);
+        }
+        if truncate {
+            let mut backup_path = path;
+            backup_path.set_extension(BACKUP_FILE_EXT);
+            std::fs::copy(&db.log.path, backup_path)?;
+            db.truncate_to(offset, true)?;
+            return Ok(db);
+        }
+
+        let (keydir, tombstones, end) = db.log.load_index_to(offset)?;
+        if end != offset {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "timestamp mark is not at a record boundary",
+            ));
+        }
+        db.keydir = keydir;
+        db.tombstones = tombstones;
+        db.read_only = true;
+        Ok(db)
+    }
+
+    // 将数据库回滚到 offset 时的状态，丢弃 offset 之后写入的所有记录，并用保留的记录重建索引
+    // offset 必须是记录的边界，例如 changes_since 返回的末尾位置；merge 之后之前的 offset 不再有效
+    // 被丢弃的数据无法恢复，confirm 必须为 true，防止误操作
+    pub fn truncate_to(&mut self, offset: u64, confirm: bool) -> Result<()> {
+        self.check_writable()?;
+        if !confirm {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "truncate requires confirmation",
+            ));
+        }
+        let file_len = self.log.len()?;
+        if offset > file_len {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "offset beyond end of log",
+            ));
+        }
+
+        let (keydir, tombstones, end) = self.log.load_index_to(offset)?;
+        if end != offset {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "offset is not at a record boundary",
+            ));
+        }
+
+        self.log.truncate(offset)?;
+        // 被丢弃的记录之后的时间点也不再有效
+        let mut timestamps = self.log.read_timestamps()?;
+        timestamps.retain(|(pos, _)| *pos <= offset);
+        self.log.write_timestamps(&timestamps)?;
+        self.keydir = keydir;
+        self.tombstones = tombstones;
+        if let Some(cache) = self.cache.as_mut() {
+            cache.clear();
+        }
+        Ok(())
+    }
 
+    // 重新读取日志构建内存索引，替换当前的索引，不需要关闭再重新打开数据库，文件锁一直保持
+    // 适用于直接修改了日志文件之后，让内存索引和日志重新保持一致
+    pub fn reindex(&mut self) -> Result<()> {
+        self.check_writable()?;
+        let (keydir, tombstones) = self.log.load_index()?;
+        self.keydir = keydir;
+        self.tombstones = tombstones;
+        if let Some(cache) = self.cache.as_mut() {
+            cache.clear();
+        }
         Ok(())
     }
 
+    // 检查内存索引和日志是否一致，用于排查位置计算的问题
+    // 对于索引中的每个 key，读取它指向的记录，检查记录中的 key 和 value 长度是否和索引一致
+    pub fn audit(&mut self) -> Result<AuditReport> {
+        let file_len = self.log.len()?;
+        let mut mismatches = Vec::new();
+        for (key, (value_pos, value_len)) in self.keydir.iter() {
+            let header_len = KEY_VAL_HEADER_LEN as u64 * 2 + key.len() as u64;
+            let consistent = match value_pos.checked_sub(header_len) {
+                Some(pos) => match self.log.read_entry(pos, file_len) {
+                    Ok((k, Some(_), len)) => *k == **key && len == header_len + *value_len as u64,
+                    Ok(_) | Err(_) => false,
+                },
+                None => false,
+            };
+            if !consistent {
+                mismatches.push(key.to_vec());
+            }
+        }
+        Ok(AuditReport {
+            checked: self.keydir.len(),
+            mismatches,
+        })
+    }
+
+    // 检查 merge 之后的日志和索引，返回有问题的 key，按照 key 排序
+    // 1. 在多个分段中都有有效记录的 key，也就是在这些分段中这个 key 的最后一条记录不是删除记录，
+    //    merge 之后每个 key 只应该在一个分段中有效，还没有 merge 时在之后的分段中被覆盖的 key 也会被报告
+    // 2. 按顺序读取所有记录重建的索引和内存索引不一致的 key，包括索引中缺少的 key 和索引中多出的 key
+    pub fn check_duplicates(&mut self) -> Result<Vec<Vec<u8>>> {
+        let file_len = self.log.len()?;
+        // 每个 key 最后一条记录所在的分段以及是否有效，之前的分段中有效的次数
+        let mut last: HashMap<Vec<u8>, (u64, bool)> = HashMap::new();
+        let mut live_segments: HashMap<Vec<u8>, usize> = HashMap::new();
+        self.log
+            .for_each_record(file_len, |segment, key, _, value_len| {
+                if let Some((prev, true)) = last.get(&key) {
+                    if *prev != segment {
+                        *live_segments.entry(key.clone()).or_default() += 1;
+                    }
+                }
+                last.insert(key, (segment, value_len.is_some()));
+            })?;
+        for (key, (_, live)) in last {
+            if live {
+                *live_segments.entry(key).or_default() += 1;
+            }
+        }
+
+        let (keydir, _) = self.log.load_index()?;
+        let mut keys: BTreeSet<Vec<u8>> = live_segments
+            .into_iter()
+            .filter(|(_, count)| *count > 1)
+            .map(|(key, _)| key)
+            .collect();
+        for (key, entry) in self.keydir.iter() {
+            if keydir.get(key) != Some(entry) {
+                keys.insert(key.to_vec());
+            }
+        }
+        for key in keydir.keys() {
+            if !self.keydir.contains_key(key) {
+                keys.insert(key.to_vec());
+            }
+        }
+        Ok(keys.into_iter().collect())
+    }
+
+    // 日志中最后一条记录是删除记录的 key，按照 key 排序，也就是 merge 时可以回收的删除记录
+    // 直接读取日志，不使用内存中的 tombstones，可以和 stats().tombstones 对照排查问题
+    pub fn tombstoned_keys(&mut self) -> Result<Vec<Vec<u8>>> {
+        let (_, tombstones) = self.log.load_index()?;
+        let mut keys: Vec<_> = tombstones.into_iter().map(|key| key.into_vec()).collect();
+        keys.sort();
+        Ok(keys)
+    }
+
+    // 将有效的数据导出到一个新的路径，用于备份
+    // 导出的文件可以直接通过 MiniBitcask::new 打开（设置了编码方式时需要设置相同的编码方式），当前的数据库不受影响
+    pub fn export(&mut self, dest: PathBuf) -> Result<()> {
+        let (new_log, _) = self.rewrite(dest)?;
+        new_log.file.sync_all()
+    }
+
+    // 将所有有效的数据重写到一个新的日志文件中，返回新的日志和索引
+    fn rewrite(&mut self, path: PathBuf) -> Result<(Log, KeyDir)> {
+        self.rewrite_filtered(path, |_| true)
+    }
+
+    // 重写数据，只保留 keep 返回 true 的 key
+    fn rewrite_filtered(
+        &mut self,
+        path: PathBuf,
+        keep: impl Fn(&[u8]) -> bool,
+    ) -> Result<(Log, KeyDir)> {
+        let mut new_log = Log::new(path)?;
+        // 清除目标文件中可能残留的数据
+        new_log.file.set_len(0)?;
+        new_log.codec = self.log.codec.clone();
+        let mut new_keydir = KeyDir::new();
+
+        // 重写数据
+        for (key, (value_pos, value_len)) in self.keydir.iter() {
+            if !keep(key) {
+                continue;
+            }
+            let value = self.log.read_value(*value_pos, *value_len)?;
+            let (offset, len) = new_log.write_entry(key, Some(&value))?;
+            // 编码之后的长度可能和原来不同，根据记录的长度计算
+            let new_value_len = len - KEY_VAL_HEADER_LEN * 2 - key.len() as u32;
+            new_keydir.insert(
+                key.clone(),
+                (offset + len as u64 - new_value_len as u64, new_value_len),
+            );
+        }
+
+        Ok((new_log, new_keydir))
+    }
+
     //   0-----3------7   8--------------21 22---------------38
     //        4 + 4     +      14 + 17
     // offset 0
@@ -74,42 +972,347 @@ impl MiniBitcask {
     // len 39
     // value_len 17
     pub fn set(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        self.check_writable()?;
         let (offset, len) = self.log.write_entry(key, Some(&value))?;
-        let value_len = value.len() as u32;
-        self.keydir.insert(
-            key.to_vec(),
-            (offset + len as u64 - value_len as u64, value_len),
-        );
+        self.apply_entry(key, true, offset, len);
         Ok(())
     }
 
+    // key 不存在时才写入，返回是否写入，只检查内存索引，不需要读取原来的 value
+    pub fn set_if_absent(&mut self, key: &[u8], value: Vec<u8>) -> Result<bool> {
+        if self.keydir.contains_key(key) {
+            return Ok(false);
+        }
+        self.set(key, value)?;
+        Ok(true)
+    }
+
+    // 将另一个数据库中所有有效的数据写入当前数据库，返回写入的 key 数量
+    // 通过 scan 逐条读取，不会一次性把另一个数据库的数据都加载到内存中
+    pub fn merge_from(
+        &mut self,
+        other: &mut MiniBitcask,
+        on_conflict: ConflictPolicy,
+    ) -> Result<usize> {
+        let mut written = 0;
+        for entry in other.scan(..)? {
+            let (key, value) = entry?;
+            if on_conflict == ConflictPolicy::KeepSelf && self.keydir.contains_key(key.as_slice()) {
+                continue;
+            }
+            self.set(&key, value)?;
+            written += 1;
+        }
+        Ok(written)
+    }
+
+    // 分多次写入一个很大的 value，不需要先在内存中准备好完整的 value
+    // 先在日志末尾写入记录头和 key，之后通过返回的 ValueWriter 依次写入 value 的内容，
+    // 写满 total_len 个字节时才更新内存索引，在此之前读不到新的 value
+    // 没有写完就被 drop 的记录会被截断；进程崩溃时留下的不完整记录可以通过 repair 清除
+    // 设置了编码方式时不支持，编码需要完整的 value
+    pub fn set_streaming(&mut self, key: &[u8], total_len: u32) -> Result<ValueWriter<'_>> {
+        self.check_writable()?;
+        if self.log.codec.is_some() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "streaming writes are not supported with a value codec",
+            ));
+        }
+        if total_len > i32::MAX as u32 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("value of {} bytes is too large", total_len),
+            ));
+        }
+        self.log.flush_buffer()?;
+        self.log.roll_if_full()?;
+        let local = self.log.file.seek(SeekFrom::End(0))?;
+        let mut header = Vec::with_capacity(KEY_VAL_HEADER_LEN as usize * 2 + key.len());
+        header.extend_from_slice(&(key.len() as u32).to_be_bytes());
+        header.extend_from_slice(&(total_len as i32).to_be_bytes());
+        header.extend_from_slice(key);
+        if let Err(err) = self.log.file.write_all(&header) {
+            self.log.file.set_len(local)?;
+            return Err(err);
+        }
+        let offset = self.log.base() + local;
+
+        let mut writer = ValueWriter {
+            db: self,
+            key: key.to_vec(),
+            offset,
+            total_len,
+            written: 0,
+        };
+        if total_len == 0 {
+            writer.finish();
+        }
+        Ok(writer)
+    }
+
     pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        // 先从缓存中获取
+        if let Some(val) = self.cache.as_mut().and_then(|cache| cache.get(key)) {
+            return Ok(Some(val));
+        }
+
         if let Some((value_pos, value_len)) = self.keydir.get(key) {
             let val = self.log.read_value(*value_pos, *value_len)?;
+            if let Some(cache) = self.cache.as_mut() {
+                cache.insert(key.to_vec(), val.clone());
+            }
             Ok(Some(val))
         } else {
             Ok(None)
         }
     }
 
-    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
-        self.log.write_entry(key, None)?;
-        self.keydir.remove(key);
-        Ok(())
+    // 读取 value 到内部复用的缓冲区中，并以切片的形式传给 f，返回 f 的结果
+    // 缓冲区只会按需扩容，适用于只需要查看一次 value 的大 value 读取，避免每次读取都分配内存
+    // 直接从日志中读取，不会读取或者填充读缓存
+    pub fn with_value<R>(&mut self, key: &[u8], f: impl FnOnce(&[u8]) -> R) -> Result<Option<R>> {
+        match self.keydir.get(key) {
+            Some((value_pos, value_len)) => {
+                self.log
+                    .read_value_into(*value_pos, *value_len, &mut self.read_buf)?;
+                Ok(Some(f(&self.read_buf)))
+            }
+            None => Ok(None),
+        }
     }
 
-    fn flush(&mut self) -> Result<()> {
-        Ok(self.log.file.sync_all()?)
+    // 只读取 value 开头的最多 max_len 个字节，适用于只需要 value 前缀的很大的 value
+    // 直接从日志中读取，不会读取或者填充读缓存；设置了编码方式时需要读取完整的 value 解码之后再截取
+    pub fn get_prefix(&mut self, key: &[u8], max_len: u32) -> Result<Option<Vec<u8>>> {
+        let (value_pos, value_len) = match self.keydir.get(key) {
+            Some(entry) => *entry,
+            None => return Ok(None),
+        };
+        if self.log.codec.is_some() {
+            let mut value = self.log.read_value(value_pos, value_len)?;
+            value.truncate(max_len as usize);
+            return Ok(Some(value));
+        }
+        Ok(Some(
+            self.log.read_value(value_pos, value_len.min(max_len))?,
+        ))
     }
 
-    pub fn scan(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> ScanIterator<'_> {
-        ScanIterator {
-            inner: self.keydir.range(range),
-            log: &mut self.log,
+    // 获取 key 当前有效的记录在磁盘上占用的字节数，包括头部、key 和 value
+    pub fn record_size(&self, key: &[u8]) -> Option<u32> {
+        self.keydir
+            .get(key)
+            .map(|(_, value_len)| KEY_VAL_HEADER_LEN * 2 + key.len() as u32 + value_len)
+    }
+
+    // 将 key 的值当作大端序的 i64 加上 delta，写回并返回新的值
+    // key 不存在或者值不是 8 个字节时当作 0 处理，溢出时返回错误
+    pub fn increment(&mut self, key: &[u8], delta: i64) -> Result<i64> {
+        let current = match self.get(key)? {
+            Some(value) => value.try_into().map_or(0, i64::from_be_bytes),
+            None => 0,
+        };
+        let value = current.checked_add(delta).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "increment overflow")
+        })?;
+        self.set(key, value.to_be_bytes().to_vec())?;
+        Ok(value)
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.check_writable()?;
+        let (offset, len) = self.log.write_entry(key, None)?;
+        self.apply_entry(key, false, offset, len);
+        self.maybe_merge()
+    }
+
+    // 交换两个 key 的值，不存在的 key 交换后另一个 key 也不存在
+    // 两条记录通过一次写入追加到日志中，避免中间插入其他的写入
+    // 注意这并不是严格的原子写入：如果进程在写入过程中崩溃，repair 可能只保留第一条记录
+    pub fn swap(&mut self, a: &[u8], b: &[u8]) -> Result<()> {
+        self.check_writable()?;
+        let value_a = self.get(a)?;
+        let value_b = self.get(b)?;
+        if a == b || (value_a.is_none() && value_b.is_none()) {
+            return Ok(());
+        }
+
+        let entries = [(a, value_b.as_deref()), (b, value_a.as_deref())];
+        let positions = self.log.write_entries(&entries)?;
+        for ((key, value), (offset, len)) in entries.iter().zip(positions) {
+            self.apply_entry(key, value.is_some(), offset, len);
+        }
+        self.maybe_merge()
+    }
+
+    // 多个 key 的条件写入，conditions 中每个 key 当前的值都和期望的值相同时（None 表示 key 不存在），
+    // 才会写入 writes 中所有的操作，返回是否写入；有任何一个条件不满足时不会写入任何数据
+    // 和 swap 一样，所有的记录通过一次写入追加到日志中，同样不是严格的原子写入
+    pub fn conditional_batch(
+        &mut self,
+        conditions: &[(Vec<u8>, Option<Vec<u8>>)],
+        writes: &[Op],
+    ) -> Result<bool> {
+        self.check_writable()?;
+        for (key, expected) in conditions {
+            if self.get(key)? != *expected {
+                return Ok(false);
+            }
+        }
+        if writes.is_empty() {
+            return Ok(true);
+        }
+
+        let entries: Vec<(&[u8], Option<&[u8]>)> = writes
+            .iter()
+            .map(|op| match op {
+                Op::Set(key, value) => (key.as_slice(), Some(value.as_slice())),
+                Op::Delete(key) => (key.as_slice(), None),
+            })
+            .collect();
+        let positions = self.log.write_entries(&entries)?;
+        for ((key, value), (offset, len)) in entries.iter().zip(positions) {
+            self.apply_entry(key, value.is_some(), offset, len);
+        }
+        self.maybe_merge()?;
+        Ok(true)
+    }
+
+    // 写入日志之后更新索引、删除记录和缓存，并通知订阅者
+    // value_len 为 None 表示删除
+    // is_set 为 false 表示删除记录，value 的长度根据记录的长度计算，设置了编码方式时是编码之后的长度
+    fn apply_entry(&mut self, key: &[u8], is_set: bool, offset: u64, len: u32) {
+        match is_set {
+            true => {
+                let value_len = len - KEY_VAL_HEADER_LEN * 2 - key.len() as u32;
+                self.keydir.insert(
+                    key.into(),
+                    (offset + len as u64 - value_len as u64, value_len),
+                );
+                self.tombstones.remove(key);
+            }
+            false => {
+                self.keydir.remove(key);
+                self.tombstones.insert(key.into());
+            }
+        }
+        if let Some(cache) = self.cache.as_mut() {
+            cache.remove(key);
+        }
+        let kind = match is_set {
+            true => ChangeKind::Set,
+            false => ChangeKind::Delete,
+        };
+        self.notify(key, kind);
+    }
+
+    // 删除记录过多时自动 merge
+    fn maybe_merge(&mut self) -> Result<()> {
+        if let Some(max) = self.merge_policy.max_tombstones {
+            if self.tombstones.len() > max {
+                self.merge()?;
+            }
+        }
+        Ok(())
+    }
+
+    // 订阅数据变更，每次 set、delete 都会发送一个 ChangeEvent
+    pub fn subscribe(&self) -> Receiver<ChangeEvent> {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    // 通知所有的订阅者，Receiver 已经被 drop 的订阅者会被移除
+    fn notify(&self, key: &[u8], kind: ChangeKind) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|tx| {
+            tx.send(ChangeEvent {
+                key: key.to_vec(),
+                kind,
+            })
+            .is_ok()
+        });
+    }
+
+    // 获取 offset 之后写入的所有记录，以及当前日志的末尾位置，用于增量同步
+    // 下一次调用时传入返回的末尾位置，就可以只获取这之后的新记录
+    // 直接从日志中读取，同一个 key 的多次写入都会返回；merge 之后日志被重写，之前的 offset 不再有效
+    pub fn changes_since(&mut self, offset: u64) -> Result<(Vec<Change>, u64)> {
+        let file_len = self.log.len()?;
+        if offset > file_len {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "offset beyond end of log",
+            ));
+        }
+
+        let mut changes = Vec::new();
+        let mut pos = offset;
+        while pos < file_len {
+            let (key, value, len) = self.log.read_entry(pos, file_len)?;
+            changes.push((key, value));
+            pos += len;
+        }
+        Ok((changes, pos))
+    }
+
+    // 将写缓冲区中的数据写入文件，并同步到磁盘，返回之后之前的写入都不会因为崩溃丢失
+    pub fn sync(&mut self) -> Result<()> {
+        self.flush()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.log.flush_buffer()?;
+        Ok(self.log.file.sync_all()?)
+    }
+
+    // 扫描一个范围内的数据，创建时复制范围内的索引，并单独打开一个只读的日志文件
+    // 迭代器不借用数据库，之后的写入和 merge 不会影响正在进行的扫描，读到的是创建时的数据：
+    // 写入只会追加到日志末尾，merge 重命名替换日志文件之后，打开的文件仍然指向原来的日志
+    // truncate_to 会截断原来的日志文件，之后读取被截断的数据会返回错误
+    pub fn scan(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<ScanIterator> {
+        // 单独打开的文件读不到写缓冲区中的数据
+        self.log.flush_buffer()?;
+        let start = range.start_bound().map(|key| key.as_slice());
+        let end = range.end_bound().map(|key| key.as_slice());
+        let entries = self
+            .keydir
+            .range::<[u8], _>((start, end))
+            .map(|(key, entry)| (key.clone(), *entry))
+            .collect();
+        let log = self.log.open_reader()?;
+        Ok(ScanIterator { entries, log })
+    }
+
+    // 预先读取一个范围内的 value，使它们进入操作系统的页缓存，读取的数据直接丢弃
+    // 适用于启动之后需要稳定延迟的场景，返回读取的 key 的数量
+    pub fn prefetch(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<usize> {
+        let start = range.start_bound().map(|key| key.as_slice());
+        let end = range.end_bound().map(|key| key.as_slice());
+        let mut count = 0;
+        for (value_pos, value_len) in self.keydir.range::<[u8], _>((start, end)).map(|(_, v)| v) {
+            self.log.read_value(*value_pos, *value_len)?;
+            count += 1;
         }
+        Ok(count)
     }
 
-    pub fn scan_prefix(&mut self, prefix: &[u8]) -> ScanIterator<'_> {
+    // 扫描一个范围内 value 满足条件的数据，只返回满足条件的 key 和 value
+    // 每个 value 只读取一次，读取失败时返回错误
+    pub fn filter_scan(
+        &mut self,
+        range: impl std::ops::RangeBounds<Vec<u8>>,
+        pred: impl Fn(&[u8]) -> bool,
+    ) -> Result<impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>> {
+        Ok(self.scan(range)?.filter(move |item| match item {
+            Ok((_, value)) => pred(value),
+            Err(_) => true,
+        }))
+    }
+
+    pub fn scan_prefix(&mut self, prefix: &[u8]) -> Result<ScanIterator> {
         let start = Bound::Included(prefix.to_vec());
 
         // 最后一位加一，例如原始前缀是 "aaaa"，变为 "aaab"
@@ -121,39 +1324,215 @@ impl MiniBitcask {
 
         self.scan((start, end))
     }
+
+    // 按照日志中的顺序遍历所有有效的数据，每个 key 只返回最后一次写入，位置是最后一次写入在日志中的位置
+    // 直接读取日志，跳过被覆盖的记录和删除记录，可以用于按照写入顺序重放数据
+    pub fn scan_log_order(&mut self) -> Result<LogOrderIterator<'_>> {
+        let file_len = self.log.len()?;
+        Ok(LogOrderIterator {
+            keydir: &self.keydir,
+            log: &mut self.log,
+            pos: 0,
+            file_len,
+        })
+    }
+
+    // 并行遍历所有有效的数据，对每个 key 和 value 调用 f，调用的顺序不确定
+    // 索引按照 key 的范围分成和线程数相同的份数，每个线程单独打开一个只读的日志文件，读取的位置互不影响
+    #[cfg(feature = "rayon")]
+    pub fn par_for_each(&mut self, f: impl Fn(&[u8], &[u8]) + Sync) -> Result<()> {
+        use rayon::prelude::*;
+
+        // 单独打开的文件读不到写缓冲区中的数据
+        self.log.flush_buffer()?;
+        let entries: Vec<_> = self.keydir.iter().collect();
+        let chunk_size = entries.len().div_ceil(rayon::current_num_threads()).max(1);
+        let log = &self.log;
+        entries.par_chunks(chunk_size).try_for_each(|chunk| {
+            let mut reader = log.open_reader()?;
+            let mut value = Vec::new();
+            for (key, (value_pos, value_len)) in chunk {
+                reader.read_value_into(*value_pos, *value_len, &mut value)?;
+                f(key, &value);
+            }
+            Ok(())
+        })
+    }
 }
 
 // 迭代器实现
-pub struct ScanIterator<'a> {
-    inner: btree_map::Range<'a, Vec<u8>, (u64, u32)>,
-    log: &'a mut Log,
+pub struct ScanIterator {
+    // 创建时范围内的索引
+    entries: VecDeque<KeyDirEntry>,
+    // 创建时打开的日志文件
+    log: Log,
 }
 
-impl<'a> ScanIterator<'a> {
-    fn map(&mut self, item: (&Vec<u8>, &(u64, u32))) -> <Self as Iterator>::Item {
+impl ScanIterator {
+    fn map(&mut self, item: KeyDirEntry) -> <Self as Iterator>::Item {
         let (key, (value_pos, value_len)) = item;
-        let value = self.log.read_value(*value_pos, *value_len)?;
-        Ok((key.clone(), value))
+        let value = self.log.read_value(value_pos, value_len)?;
+        Ok((key.into_vec(), value))
     }
 }
 
-impl<'a> Iterator for ScanIterator<'a> {
+impl Iterator for ScanIterator {
     type Item = Result<(Vec<u8>, Vec<u8>)>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.inner.next().map(|item| self.map(item))
+        self.entries.pop_front().map(|item| self.map(item))
     }
 }
 
-impl<'a> DoubleEndedIterator for ScanIterator<'a> {
+impl DoubleEndedIterator for ScanIterator {
     fn next_back(&mut self) -> Option<Self::Item> {
-        self.inner.next_back().map(|item| self.map(item))
+        self.entries.pop_back().map(|item| self.map(item))
+    }
+}
+
+// 按照日志顺序遍历的迭代器
+pub struct LogOrderIterator<'a> {
+    keydir: &'a KeyDir,
+    log: &'a mut Log,
+    // 下一条记录的位置
+    pos: u64,
+    file_len: u64,
+}
+
+impl<'a> Iterator for LogOrderIterator<'a> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pos < self.file_len {
+            let (key, value, len) = match self.log.read_entry(self.pos, self.file_len) {
+                Ok(entry) => entry,
+                Err(err) => {
+                    // 读取失败之后不再继续遍历
+                    self.pos = self.file_len;
+                    return Some(Err(err));
+                }
+            };
+            let value_pos = self.pos + KEY_VAL_HEADER_LEN as u64 * 2 + key.len() as u64;
+            self.pos += len;
+
+            // 只返回索引中指向的记录，也就是这个 key 最后一次写入
+            if let Some(value) = value {
+                if self.keydir.get(key.as_slice()).map(|(p, _)| *p) == Some(value_pos) {
+                    return Some(Ok((key, value)));
+                }
+            }
+        }
+        None
+    }
+}
+
+// LRU 缓存，保存 key 对应的 value
+struct ValueCache {
+    capacity: usize,
+    // key -> (value, 最近一次访问的序号)
+    entries: HashMap<Vec<u8>, (Vec<u8>, u64)>,
+    // 访问序号 -> key，序号最小的是最久没有访问的 key
+    order: BTreeMap<u64, Vec<u8>>,
+    tick: u64,
+}
+
+impl ValueCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    // 获取缓存的 value，并更新访问序号
+    fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        let (value, last) = self.entries.get_mut(key)?;
+        self.order.remove(last);
+        self.tick += 1;
+        *last = self.tick;
+        self.order.insert(self.tick, key.to_vec());
+        Some(value.clone())
+    }
+
+    // 添加缓存，容量满了的时候淘汰最久没有访问的 key
+    fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.remove(&key);
+        if self.entries.len() >= self.capacity {
+            if let Some((_, oldest)) = self.order.pop_first() {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (value, self.tick));
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some((_, last)) = self.entries.remove(key) {
+            self.order.remove(&last);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
     }
 }
 
 struct Log {
+    // 日志文件的路径，分段时不是数据文件，只用来确定 epoch、时间点等附属文件的位置
     path: PathBuf,
+    // 当前写入的文件，分段时是最后一个分段
     file: std::fs::File,
+    // 分段的状态，None 时所有记录都在 path 一个文件中
+    segments: Option<Segments>,
+    // 还没有写入文件的记录
+    write_buf: Vec<u8>,
+    // 写缓冲区的大小，为 0 时不使用缓冲区，每次写入都直接写入文件
+    write_buf_capacity: usize,
+    // value 的编码方式，None 时原样写入
+    codec: Option<Arc<dyn ValueCodec>>,
+}
+
+// 分段日志的状态，日志中的位置是所有分段按顺序拼接之后的位置，一条记录不会跨越两个分段
+struct Segments {
+    options: SegmentOptions,
+    dir: PathBuf,
+    // 已经写满的分段，按顺序保存序号、文件以及在日志中的起始位置
+    sealed: Vec<(u64, std::fs::File, u64)>,
+    // 当前写入的分段的序号以及在日志中的起始位置
+    active_id: u64,
+    base: u64,
+}
+
+impl Segments {
+    fn path(&self, id: u64) -> PathBuf {
+        self.dir.join(self.options.file_name(id))
+    }
+
+    // 所有分段的序号，按顺序排列
+    fn ids(&self) -> impl Iterator<Item = u64> + '_ {
+        self.sealed
+            .iter()
+            .map(|(id, _, _)| *id)
+            .chain(std::iter::once(self.active_id))
+    }
+}
+
+// 以读写方式打开文件，加 exclusive lock 防止并发更新
+fn open_locked(path: &Path) -> Result<std::fs::File> {
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(path)?;
+    file.try_lock_exclusive()?;
+    Ok(file)
 }
 
 impl Log {
@@ -162,288 +1541,2411 @@ impl Log {
             std::fs::create_dir_all(dir)?;
         }
 
-        let file = std::fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(&path)?;
+        let file = open_locked(&path)?;
+
+        Ok(Self {
+            path,
+            file,
+            segments: None,
+            write_buf: Vec::new(),
+            write_buf_capacity: 0,
+            codec: None,
+        })
+    }
+
+    // 打开目录中所有符合格式的分段，按序号排序，最后一个作为当前写入的分段，没有分段时创建第一个
+    fn open_segmented(dir: PathBuf, options: SegmentOptions) -> Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        let mut ids = Vec::new();
+        for entry in std::fs::read_dir(&dir)? {
+            let name = entry?.file_name();
+            if let Some(id) = name.to_str().and_then(|name| options.parse_id(name)) {
+                ids.push(id);
+            }
+        }
+        ids.sort();
+
+        let active_id = ids.pop().unwrap_or(1);
+        let mut segments = Segments {
+            options,
+            dir: dir.clone(),
+            sealed: Vec::new(),
+            active_id,
+            base: 0,
+        };
+        for id in ids {
+            let file = open_locked(&segments.path(id))?;
+            let len = file.metadata()?.len();
+            segments.sealed.push((id, file, segments.base));
+            segments.base += len;
+        }
+        let file = open_locked(&segments.path(active_id))?;
+
+        Ok(Self {
+            path: dir.join("log"),
+            file,
+            segments: Some(segments),
+            write_buf: Vec::new(),
+            write_buf_capacity: 0,
+            codec: None,
+        })
+    }
+
+    // 当前写入的文件在日志中的起始位置，不分段时为 0
+    fn base(&self) -> u64 {
+        self.segments.as_ref().map_or(0, |segments| segments.base)
+    }
+
+    // 找到 pos 所在的文件，返回文件、pos 在文件中的位置以及这个文件在日志中的结束位置
+    // 当前写入的文件还会继续增长，结束位置为 u64::MAX
+    fn locate(&mut self, pos: u64) -> (&mut std::fs::File, u64, u64) {
+        if let Some(segments) = self.segments.as_mut() {
+            if pos < segments.base {
+                // 第一个分段的起始位置是 0，一定能找到
+                let i = segments
+                    .sealed
+                    .partition_point(|(_, _, start)| *start <= pos)
+                    - 1;
+                let end = segments
+                    .sealed
+                    .get(i + 1)
+                    .map_or(segments.base, |(_, _, start)| *start);
+                let (_, file, start) = &mut segments.sealed[i];
+                return (file, pos - *start, end);
+            }
+            return (&mut self.file, pos - segments.base, u64::MAX);
+        }
+        (&mut self.file, pos, u64::MAX)
+    }
+
+    // 当前分段的大小达到上限时，创建下一个分段作为当前写入的文件，之前的分段之后只会被读取
+    fn roll_if_full(&mut self) -> Result<()> {
+        let Some(max_size) = self.segments.as_ref().map(|s| s.options.max_size) else {
+            return Ok(());
+        };
+        let len = self.file.metadata()?.len() + self.write_buf.len() as u64;
+        if len < max_size {
+            return Ok(());
+        }
+        self.roll()
+    }
+
+    // 结束当前的分段，之后的写入都在一个新的分段中，当前分段为空时不需要
+    fn roll(&mut self) -> Result<()> {
+        self.flush_buffer()?;
+        let len = self.file.metadata()?.len();
+        if len == 0 {
+            return Ok(());
+        }
+        self.file.sync_all()?;
+        if let Some(segments) = self.segments.as_mut() {
+            let file = open_locked(&segments.path(segments.active_id + 1))?;
+            let sealed = std::mem::replace(&mut self.file, file);
+            segments
+                .sealed
+                .push((segments.active_id, sealed, segments.base));
+            segments.base += len;
+            segments.active_id += 1;
+        }
+        Ok(())
+    }
+
+    // 截断到 offset，丢弃之后的所有数据，分段时删除 offset 之后的分段
+    fn truncate(&mut self, offset: u64) -> Result<()> {
+        self.flush_buffer()?;
+        if let Some(segments) = self.segments.as_mut() {
+            while offset < segments.base {
+                let Some((id, file, start)) = segments.sealed.pop() else {
+                    break;
+                };
+                std::fs::remove_file(segments.path(segments.active_id))?;
+                self.file = file;
+                segments.active_id = id;
+                segments.base = start;
+            }
+        }
+        self.file.set_len(offset - self.base())?;
+        self.file.sync_all()
+    }
+
+    // 重写起始位置在 starts 中的已经写满的分段，只保留 key 满足 keep 的记录，没有剩下记录的分段直接删除
+    // 之后的分段在日志中的位置会向前移动，返回每个保留的分段（包括当前写入的分段）原来和现在的起始位置，
+    // 被重写的分段还会返回其中每条保留的记录原来 value 在日志中的位置到现在在分段中的位置的映射
+    fn retain_segments(
+        &mut self,
+        starts: &HashSet<u64>,
+        keep: impl Fn(&[u8]) -> bool,
+    ) -> Result<Vec<SegmentMove>> {
+        self.flush_buffer()?;
+        let Some(segments) = self.segments.as_mut() else {
+            return Ok(Vec::new());
+        };
+
+        // 先读取所有需要重写的分段，记录不完整时直接返回错误，所有的分段都保持不变
+        let mut rewritten = HashMap::new();
+        for (_, file, start) in segments.sealed.iter_mut() {
+            if !starts.contains(start) {
+                continue;
+            }
+            let mut bytes = Vec::new();
+            file.seek(SeekFrom::Start(0))?;
+            file.read_to_end(&mut bytes)?;
+            let mut kept = Vec::new();
+            let mut moved = HashMap::new();
+            let mut pos = 0;
+            while pos < bytes.len() {
+                let header = pos + KEY_VAL_HEADER_LEN as usize * 2;
+                let key_len = bytes
+                    .get(pos..pos + 4)
+                    .map_or(0, |b| u32::from_be_bytes(b.try_into().unwrap()) as usize);
+                let value_len = bytes.get(pos + 4..header).map_or(0, |b| {
+                    i32::from_be_bytes(b.try_into().unwrap()).max(0) as usize
+                });
+                let end = header + key_len + value_len;
+                if end > bytes.len() {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        "incomplete log entry",
+                    ));
+                }
+                if keep(&bytes[header..header + key_len]) {
+                    let value_pos = header + key_len;
+                    moved.insert(
+                        *start + value_pos as u64,
+                        (kept.len() + value_pos - pos) as u64,
+                    );
+                    kept.extend_from_slice(&bytes[pos..end]);
+                }
+                pos = end;
+            }
+            rewritten.insert(*start, (kept, moved));
+        }
+
+        let mut moves = Vec::new();
+        let mut base = 0;
+        for (id, file, start) in std::mem::take(&mut segments.sealed) {
+            let Some((kept, moved)) = rewritten.remove(&start) else {
+                let len = file.metadata()?.len();
+                moves.push((start, base, None));
+                segments.sealed.push((id, file, base));
+                base += len;
+                continue;
+            };
+
+            let path = segments.path(id);
+            drop(file);
+            if kept.is_empty() {
+                std::fs::remove_file(&path)?;
+                continue;
+            }
+            // 先写入临时文件再替换，写入过程中出错时原来的分段保持不变
+            let mut merge_path = path.clone().into_os_string();
+            merge_path.push(".");
+            merge_path.push(MERGE_FILE_EXT);
+            let mut new_file = open_locked(Path::new(&merge_path))?;
+            new_file.set_len(0)?;
+            new_file.write_all(&kept)?;
+            new_file.sync_all()?;
+            std::fs::rename(&merge_path, &path)?;
+            moves.push((start, base, Some(moved)));
+            segments.sealed.push((id, new_file, base));
+            base += kept.len() as u64;
+        }
+        moves.push((segments.base, base, None));
+        segments.base = base;
+        Ok(moves)
+    }
+
+    // 读取保存的 epoch，文件不存在时为 0
+    fn read_epoch(&self) -> Result<u64> {
+        let mut path = self.path.clone();
+        path.set_extension(EPOCH_FILE_EXT);
+        match std::fs::read(&path) {
+            Ok(bytes) => {
+                let bytes = bytes.try_into().map_err(|_| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid epoch file")
+                })?;
+                Ok(u64::from_be_bytes(bytes))
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(0),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn write_epoch(&self, epoch: u64) -> Result<()> {
+        let mut path = self.path.clone();
+        path.set_extension(EPOCH_FILE_EXT);
+        let mut file = std::fs::File::create(path)?;
+        file.write_all(&epoch.to_be_bytes())?;
+        file.sync_all()
+    }
+
+    // 读取 mark_timestamp 记录的时间点，每个时间点是日志的位置和时间，文件不存在时为空
+    fn read_timestamps(&self) -> Result<Vec<(u64, u64)>> {
+        let mut path = self.path.clone();
+        path.set_extension(TIMESTAMP_FILE_EXT);
+        let bytes = match std::fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+        // 每个时间点 16 个字节，忽略写入中断留下的不完整的部分
+        Ok(bytes
+            .chunks_exact(16)
+            .map(|chunk| {
+                let (offset, ts) = chunk.split_at(8);
+                (
+                    u64::from_be_bytes(offset.try_into().unwrap()),
+                    u64::from_be_bytes(ts.try_into().unwrap()),
+                )
+            })
+            .collect())
+    }
+
+    fn append_timestamp(&self, offset: u64, ts: u64) -> Result<()> {
+        let mut path = self.path.clone();
+        path.set_extension(TIMESTAMP_FILE_EXT);
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        file.write_all(&offset.to_be_bytes())?;
+        file.write_all(&ts.to_be_bytes())?;
+        file.sync_all()
+    }
+
+    fn write_timestamps(&self, timestamps: &[(u64, u64)]) -> Result<()> {
+        let mut path = self.path.clone();
+        path.set_extension(TIMESTAMP_FILE_EXT);
+        if timestamps.is_empty() {
+            return match std::fs::remove_file(path) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
+                _ => Ok(()),
+            };
+        }
+        let mut file = std::fs::File::create(path)?;
+        for (offset, ts) in timestamps {
+            file.write_all(&offset.to_be_bytes())?;
+            file.write_all(&ts.to_be_bytes())?;
+        }
+        file.sync_all()
+    }
+
+    // 构建内存索引，同时返回没有被覆盖的删除记录
+    fn load_index(&mut self) -> Result<(KeyDir, Tombstones)> {
+        let file_len = self.len()?;
+        let (keydir, tombstones, _) = self.load_index_to(file_len)?;
+        Ok((keydir, tombstones))
+    }
+
+    // 只使用 end 之前的记录构建内存索引，同时返回读取结束的位置
+    // end 不在记录的边界上时，结束的位置会超过 end
+    fn load_index_to(&mut self, end: u64) -> Result<(KeyDir, Tombstones, u64)> {
+        let mut keydir = KeyDir::new();
+        let mut tombstones = Tombstones::new();
+        let pos = self.for_each_record(end, |_, key, value_pos, value_len| match value_len {
+            Some(value_len) => {
+                tombstones.remove(key.as_slice());
+                keydir.insert(key.into_boxed_slice(), (value_pos, value_len));
+            }
+            None => {
+                keydir.remove(key.as_slice());
+                tombstones.insert(key.into_boxed_slice());
+            }
+        })?;
+        Ok((keydir, tombstones, pos))
+    }
+
+    // 按顺序读取 end 之前的每条记录的 key、value 的位置和长度（删除记录为 None），返回读取结束的位置
+    // f 的第一个参数是记录所在文件在日志中的起始位置，不分段时都是 0
+    fn for_each_record(
+        &mut self,
+        end: u64,
+        mut f: impl FnMut(u64, Vec<u8>, u64, Option<u32>),
+    ) -> Result<u64> {
+        let mut len_buf = [0u8; KEY_VAL_HEADER_LEN as usize];
+        let mut pos: u64 = 0;
+
+        // 一条记录只会在一个文件中，分段时依次读取每个文件
+        while pos < end {
+            let (file, local, file_end) = self.locate(pos);
+            let start = pos - local;
+            let mut r = BufReader::new(file);
+            r.seek(SeekFrom::Start(local))?;
+            while pos < end.min(file_end) {
+                let read_one = || -> Result<(Vec<u8>, u64, Option<u32>)> {
+                    // 读取 key 的长度
+                    r.read_exact(&mut len_buf)?;
+                    let key_len = u32::from_be_bytes(len_buf);
+                    // 读取 value 的长度
+                    r.read_exact(&mut len_buf)?;
+                    let value_lent_or_tombstone = match i32::from_be_bytes(len_buf) {
+                        l if l >= 0 => Some(l as u32),
+                        _ => None,
+                    };
+
+                    // value 的位置
+                    let value_pos = pos + KEY_VAL_HEADER_LEN as u64 * 2 + key_len as u64;
+
+                    // 读取 key 的内容
+                    let mut key = vec![0; key_len as usize];
+                    r.read_exact(&mut key)?;
+
+                    // 跳过 value 的长度
+                    if let Some(value_len) = value_lent_or_tombstone {
+                        r.seek_relative(value_len as i64)?;
+                    }
+
+                    Ok((key, value_pos, value_lent_or_tombstone))
+                }();
+
+                match read_one {
+                    Ok((key, value_pos, value_len)) => {
+                        pos = value_pos + value_len.unwrap_or(0) as u64;
+                        f(start, key, value_pos, value_len);
+                    }
+                    Err(err) => return Err(err.into()),
+                }
+            }
+        }
+
+        Ok(pos)
+    }
+
+    // 读取 pos 位置的一条完整记录，返回 key、value（删除的记录为 None）以及记录的长度
+    // 记录的长度超出文件末尾时，说明记录不完整，返回 UnexpectedEof 错误
+    fn read_entry(&mut self, pos: u64, file_len: u64) -> Result<(Vec<u8>, Option<Vec<u8>>, u64)> {
+        let codec = self.codec.clone();
+        let (file, local, end) = self.locate(pos);
+        // 分段时记录不会超出所在的分段
+        let file_len = file_len.min(end);
+        let mut len_buf = [0u8; KEY_VAL_HEADER_LEN as usize];
+        file.seek(SeekFrom::Start(local))?;
+        file.read_exact(&mut len_buf)?;
+        let key_len = u32::from_be_bytes(len_buf);
+        file.read_exact(&mut len_buf)?;
+        let value_len_or_tombstone = match i32::from_be_bytes(len_buf) {
+            l if l >= 0 => Some(l as u32),
+            _ => None,
+        };
+
+        let len = KEY_VAL_HEADER_LEN as u64 * 2
+            + key_len as u64
+            + value_len_or_tombstone.unwrap_or(0) as u64;
+        if pos + len > file_len {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "incomplete log entry",
+            ));
+        }
+
+        let mut key = vec![0; key_len as usize];
+        file.read_exact(&mut key)?;
+        let value = match value_len_or_tombstone {
+            Some(value_len) => {
+                let mut value = vec![0; value_len as usize];
+                file.read_exact(&mut value)?;
+                match &codec {
+                    Some(codec) => Some(codec.decode(&value)?),
+                    None => Some(value),
+                }
+            }
+            None => None,
+        };
+
+        Ok((key, value, len))
+    }
+
+    // 根据 value 的位置和长度获取 value 的值
+    // 单独打开一个只读的日志文件，用于不借用数据库的读取
+    fn open_reader(&self) -> Result<Self> {
+        let (file, segments) = match &self.segments {
+            Some(segments) => {
+                let sealed = segments
+                    .sealed
+                    .iter()
+                    .map(|(id, _, start)| {
+                        Ok((*id, std::fs::File::open(segments.path(*id))?, *start))
+                    })
+                    .collect::<Result<_>>()?;
+                let reader = Segments {
+                    options: segments.options.clone(),
+                    dir: segments.dir.clone(),
+                    sealed,
+                    active_id: segments.active_id,
+                    base: segments.base,
+                };
+                (
+                    std::fs::File::open(segments.path(segments.active_id))?,
+                    Some(reader),
+                )
+            }
+            None => (std::fs::File::open(&self.path)?, None),
+        };
+        Ok(Log {
+            path: self.path.clone(),
+            file,
+            segments,
+            write_buf: Vec::new(),
+            write_buf_capacity: 0,
+            codec: self.codec.clone(),
+        })
+    }
+
+    fn read_value(&mut self, value_pos: u64, value_len: u32) -> Result<Vec<u8>> {
+        let mut value = Vec::new();
+        self.read_value_into(value_pos, value_len, &mut value)?;
+        Ok(value)
+    }
+
+    // 读取 value 到 buf 中，buf 原有的内容会被覆盖，容量足够时不会重新分配内存
+    fn read_value_into(&mut self, value_pos: u64, value_len: u32, buf: &mut Vec<u8>) -> Result<()> {
+        self.flush_buffer()?;
+        buf.clear();
+        buf.resize(value_len as usize, 0);
+        let (file, local, _) = self.locate(value_pos);
+        file.seek(SeekFrom::Start(local))?;
+        file.read_exact(buf)?;
+        if let Some(codec) = &self.codec {
+            *buf = codec.decode(buf)?;
+        }
+        Ok(())
+    }
+
+    // +-------------+-------------+----------------+----------------+
+    // | key len(4)    val len(4)     key(varint)       val(varint)  |
+    // +-------------+-------------+----------------+----------------+
+    fn write_entry(&mut self, key: &[u8], value: Option<&[u8]>) -> Result<(u64, u32)> {
+        Ok(self.write_entries(&[(key, value)])?[0])
+    }
+
+    // 将多条记录一起追加到日志中，返回每条记录的位置和长度
+    // 记录先写入写缓冲区，缓冲区满了之后再写入文件
+    fn write_entries(&mut self, entries: &[(&[u8], Option<&[u8]>)]) -> Result<Vec<(u64, u32)>> {
+        self.roll_if_full()?;
+        let mut offset =
+            self.base() + self.file.seek(SeekFrom::End(0))? + self.write_buf.len() as u64;
+        let mut positions = Vec::with_capacity(entries.len());
+        let buf = &mut self.write_buf;
+        for (key, value) in entries {
+            let encoded;
+            let value = match (value, &self.codec) {
+                (Some(value), Some(codec)) => {
+                    encoded = codec.encode(value);
+                    Some(encoded.as_slice())
+                }
+                (value, _) => *value,
+            };
+            let key_len = key.len() as u32;
+            let value_len = value.map_or(0, |v| v.len() as u32);
+            let value_len_or_tomestone = value.map_or(-1, |v| v.len() as i32);
+
+            // 总共占据的长度
+            let len = KEY_VAL_HEADER_LEN * 2 + key_len + value_len;
+
+            buf.extend_from_slice(&key_len.to_be_bytes());
+            buf.extend_from_slice(&value_len_or_tomestone.to_be_bytes());
+            buf.extend_from_slice(key);
+            if let Some(value) = value {
+                buf.extend_from_slice(value);
+            }
+            positions.push((offset, len));
+            offset += len as u64;
+        }
+
+        if self.write_buf.len() >= self.write_buf_capacity {
+            self.flush_buffer()?;
+        }
+        Ok(positions)
+    }
+
+    // 将写缓冲区中的数据一次写入文件
+    fn flush_buffer(&mut self) -> Result<()> {
+        if self.write_buf.is_empty() {
+            return Ok(());
+        }
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.write_all(&self.write_buf)?;
+        self.write_buf.clear();
+        Ok(())
+    }
+
+    // 日志的长度，包括写缓冲区中的数据，缓冲区中的数据会先写入文件
+    fn len(&mut self) -> Result<u64> {
+        self.flush_buffer()?;
+        Ok(self.base() + self.file.metadata()?.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Change, ChangeEvent, ChangeKind, ConflictPolicy, FencedOut, Log, MergePolicy, MiniBitcask,
+        Op, Result, SegmentOptions, Stats, ValueCodec,
+    };
+    use std::{
+        io::{Seek, SeekFrom, Write},
+        ops::Bound,
+    };
+
+    #[test]
+    fn test_log_read_write() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join("sqldb-disk-engine-log-test1")
+            .join("log");
+
+        let mut log = Log::new(path.clone())?;
+        log.write_entry(b"a", Some(b"val1"))?;
+        log.write_entry(b"b", Some(b"val2"))?;
+        log.write_entry(b"c", Some(b"val3"))?;
+
+        // rewrite
+        log.write_entry(b"a", Some(b"val5"))?;
+        // delete
+        log.write_entry(b"c", None)?;
+
+        let (keydir, tombstones) = log.load_index()?;
+        assert_eq!(2, keydir.len());
+        assert_eq!(1, tombstones.len());
+
+        path.parent().map(|p| std::fs::remove_dir_all(p));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_log_reopen() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join("sqldb-disk-engine-log-test2")
+            .join("log");
+
+        let mut log = Log::new(path.clone())?;
+        log.write_entry(b"a", Some(b"val1"))?;
+        log.write_entry(b"b", Some(b"val2"))?;
+        log.write_entry(b"c", Some(b"val3"))?;
+        log.write_entry(b"d", Some(b"val4"))?;
+        log.write_entry(b"d", None)?;
+
+        drop(log);
+
+        let mut log = Log::new(path.clone())?;
+        let (keydir, _) = log.load_index()?;
+        assert_eq!(3, keydir.len());
+
+        path.parent().map(|p| std::fs::remove_dir_all(p));
+
+        Ok(())
+    }
+
+    // 测试点读的情况
+    #[test]
+    fn test_point_opt() -> Result<()> {
+        let path = std::env::temp_dir().join("minibitcask-test").join("log");
+        let mut eng = MiniBitcask::new(path.clone())?;
+
+        // 测试获取一个不存在的 key
+        assert_eq!(eng.get(b"not exist")?, None);
+
+        // 获取一个存在的 key
+        eng.set(b"aa", vec![1, 2, 3, 4])?;
+        assert_eq!(eng.get(b"aa")?, Some(vec![1, 2, 3, 4]));
+
+        // 重复 put，将会覆盖前一个值
+        eng.set(b"aa", vec![5, 6, 7, 8])?;
+        assert_eq!(eng.get(b"aa")?, Some(vec![5, 6, 7, 8]));
+
+        // 删除之后再读取
+        eng.delete(b"aa")?;
+        assert_eq!(eng.get(b"aa")?, None);
+
+        // key、value 为空的情况
+        assert_eq!(eng.get(b"")?, None);
+        eng.set(b"", vec![])?;
+        assert_eq!(eng.get(b"")?, Some(vec![]));
+
+        eng.set(b"cc", vec![5, 6, 7, 8])?;
+        assert_eq!(eng.get(b"cc")?, Some(vec![5, 6, 7, 8]));
+
+        path.parent().map(|p| std::fs::remove_dir_all(p));
+        Ok(())
+    }
+
+    // 测试扫描
+    #[test]
+    fn test_scan() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join("minibitcask-scan-test")
+            .join("log");
+        let mut eng = MiniBitcask::new(path.clone())?;
+
+        eng.set(b"nnaes", b"value1".to_vec())?;
+        eng.set(b"amhue", b"value2".to_vec())?;
+        eng.set(b"meeae", b"value3".to_vec())?;
+        eng.set(b"uujeh", b"value4".to_vec())?;
+        eng.set(b"anehe", b"value5".to_vec())?;
+
+        let start = Bound::Included(b"a".to_vec());
+        let end = Bound::Excluded(b"e".to_vec());
+
+        let mut iter = eng.scan((start.clone(), end.clone()))?;
+        let (key1, _) = iter.next().expect("no value founded")?;
+        assert_eq!(key1, b"amhue".to_vec());
+
+        let (key2, _) = iter.next().expect("no value founded")?;
+        assert_eq!(key2, b"anehe".to_vec());
+        drop(iter);
+
+        let start = Bound::Included(b"b".to_vec());
+        let end = Bound::Excluded(b"z".to_vec());
+        let mut iter2 = eng.scan((start, end))?;
+
+        let (key3, _) = iter2.next_back().expect("no value founded")?;
+        assert_eq!(key3, b"uujeh".to_vec());
+
+        let (key4, _) = iter2.next_back().expect("no value founded")?;
+        assert_eq!(key4, b"nnaes".to_vec());
+
+        let (key5, _) = iter2.next_back().expect("no value founded")?;
+        assert_eq!(key5, b"meeae".to_vec());
+
+        path.parent().map(|p| std::fs::remove_dir_all(p));
+        Ok(())
+    }
+
+    // 测试前缀扫描
+    #[test]
+    fn test_scan_prefix() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join("minibitcask-scan-prefix-test")
+            .join("log");
+        let mut eng = MiniBitcask::new(path.clone())?;
+
+        eng.set(b"ccnaes", b"value1".to_vec())?;
+        eng.set(b"camhue", b"value2".to_vec())?;
+        eng.set(b"deeae", b"value3".to_vec())?;
+        eng.set(b"eeujeh", b"value4".to_vec())?;
+        eng.set(b"canehe", b"value5".to_vec())?;
+        eng.set(b"aanehe", b"value6".to_vec())?;
+
+        let prefix = b"ca";
+        let mut iter = eng.scan_prefix(prefix)?;
+        let (key1, _) = iter.next().transpose()?.unwrap();
+        assert_eq!(key1, b"camhue".to_vec());
+        let (key2, _) = iter.next().transpose()?.unwrap();
+        assert_eq!(key2, b"canehe".to_vec());
+
+        println!("{:?}", path.clone());
+        path.parent().map(|p| std::fs::remove_dir_all(p));
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join("minibitcask-merge-test")
+            .join("log");
+
+        let mut eng = MiniBitcask::new(path.clone())?;
+
+        eng.set(b"a", b"value1".to_vec())?;
+        eng.set(b"b", b"value2".to_vec())?;
+        eng.set(b"c", b"value3".to_vec())?;
+        eng.delete(b"a")?;
+        eng.delete(b"b")?;
+        eng.delete(b"c")?;
+
+        eng.merge()?;
+
+        eng.set(b"a", b"value1".to_vec())?;
+        eng.set(b"b", b"value2".to_vec())?;
+        eng.set(b"c", b"value3".to_vec())?;
+
+        let val = eng.get(b"a")?;
+        assert_eq!(b"value1".to_vec(), val.unwrap());
+
+        let val = eng.get(b"b")?;
+        assert_eq!(b"value2".to_vec(), val.unwrap());
+
+        let val = eng.get(b"c")?;
+        assert_eq!(b"value3".to_vec(), val.unwrap());
+
+        path.parent().map(|p| std::fs::remove_dir_all(p));
+        Ok(())
+    }
+
+    // 测试导出数据
+    #[test]
+    fn test_export() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join("minibitcask-export-test")
+            .join("log");
+        let export_path = std::env::temp_dir()
+            .join("minibitcask-export-test")
+            .join("backup");
+
+        let mut eng = MiniBitcask::new(path.clone())?;
+        eng.set(b"a", b"value1".to_vec())?;
+        eng.set(b"b", b"value2".to_vec())?;
+        eng.set(b"c", b"value3".to_vec())?;
+        eng.set(b"a", b"value4".to_vec())?;
+        eng.delete(b"b")?;
+
+        eng.export(export_path.clone())?;
+
+        // 导出的数据库和原来的数据库数据一致
+        let mut backup = MiniBitcask::new(export_path.clone())?;
+        assert_eq!(backup.keydir.len(), eng.keydir.len());
+        let expected = eng.scan(..)?.collect::<Result<Vec<_>>>()?;
+        let actual = backup.scan(..)?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(expected, actual);
+
+        // 原来的数据库仍然可以使用
+        eng.set(b"d", b"value5".to_vec())?;
+        assert_eq!(eng.get(b"d")?, Some(b"value5".to_vec()));
+        assert_eq!(backup.get(b"d")?, None);
+
+        path.parent().map(|p| std::fs::remove_dir_all(p));
+        Ok(())
+    }
+
+    // 测试计数器自增
+    #[test]
+    fn test_increment() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join("minibitcask-increment-test")
+            .join("log");
+        let mut eng = MiniBitcask::new(path.clone())?;
+
+        // 不存在的 key 从 0 开始
+        assert_eq!(eng.increment(b"counter", 5)?, 5);
+        assert_eq!(eng.increment(b"counter", -2)?, 3);
+        assert_eq!(eng.get(b"counter")?, Some(3i64.to_be_bytes().to_vec()));
+
+        // 值不是 8 个字节时当作 0
+        eng.set(b"other", b"abc".to_vec())?;
+        assert_eq!(eng.increment(b"other", 1)?, 1);
+
+        // 溢出返回错误，原来的值不变
+        eng.set(b"max", i64::MAX.to_be_bytes().to_vec())?;
+        assert!(eng.increment(b"max", 1).is_err());
+        assert_eq!(eng.get(b"max")?, Some(i64::MAX.to_be_bytes().to_vec()));
+
+        path.parent().map(|p| std::fs::remove_dir_all(p));
+        Ok(())
+    }
+
+    // 测试订阅数据变更
+    #[test]
+    fn test_subscribe() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join("minibitcask-subscribe-test")
+            .join("log");
+        let mut eng = MiniBitcask::new(path.clone())?;
+
+        let rx = eng.subscribe();
+        eng.set(b"a", b"value1".to_vec())?;
+        eng.delete(b"a")?;
+
+        let events = rx.try_iter().collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            vec![
+                ChangeEvent {
+                    key: b"a".to_vec(),
+                    kind: ChangeKind::Set,
+                },
+                ChangeEvent {
+                    key: b"a".to_vec(),
+                    kind: ChangeKind::Delete,
+                },
+            ]
+        );
+
+        // Receiver 被 drop 之后，订阅者会被移除
+        drop(rx);
+        eng.set(b"b", b"value2".to_vec())?;
+        assert!(eng.subscribers.lock().unwrap().is_empty());
+
+        path.parent().map(|p| std::fs::remove_dir_all(p));
+        Ok(())
+    }
+
+    // 测试读缓存
+    #[test]
+    fn test_cache() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join("minibitcask-cache-test")
+            .join("log");
+        let mut eng = MiniBitcask::new(path.clone())?.with_cache(2);
+
+        eng.set(b"a", b"value1".to_vec())?;
+        eng.set(b"b", b"value2".to_vec())?;
+        assert_eq!(eng.get(b"a")?, Some(b"value1".to_vec()));
+
+        // 替换成一个只写的文件，之后从磁盘读取数据都会失败
+        let write_only = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path.with_extension("write-only"))?;
+        let file = std::mem::replace(&mut eng.log.file, write_only);
+
+        // 缓存命中，不会读取文件
+        assert_eq!(eng.get(b"a")?, Some(b"value1".to_vec()));
+        assert!(eng.get(b"b").is_err());
+        eng.log.file = file;
+
+        // 写入之后缓存失效
+        eng.set(b"a", b"value3".to_vec())?;
+        assert_eq!(eng.get(b"a")?, Some(b"value3".to_vec()));
+
+        // 容量满了之后淘汰最久没有访问的 key
+        eng.set(b"c", b"value4".to_vec())?;
+        eng.get(b"b")?;
+        eng.get(b"c")?;
+        assert!(!eng
+            .cache
+            .as_ref()
+            .unwrap()
+            .entries
+            .contains_key(b"a".as_slice()));
+
+        // merge 之后仍然能读到正确的数据
+        eng.merge()?;
+        assert_eq!(eng.get(b"a")?, Some(b"value3".to_vec()));
+        assert_eq!(eng.get(b"c")?, Some(b"value4".to_vec()));
+
+        path.parent().map(|p| std::fs::remove_dir_all(p));
+        Ok(())
+    }
+
+    // 测试修复损坏的日志
+    #[test]
+    fn test_repair() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join("minibitcask-repair-test")
+            .join("log");
+
+        let mut log = Log::new(path.clone())?;
+        log.write_entry(b"a", Some(b"val1"))?;
+        log.write_entry(b"b", Some(b"val2"))?;
+        let (offset, _) = log.write_entry(b"c", Some(b"val3"))?;
+        log.write_entry(b"d", Some(b"val4"))?;
+        let file_len = log.file.metadata()?.len();
+
+        // 破坏 c 的 key 长度
+        log.file.seek(SeekFrom::Start(offset))?;
+        log.file.write_all(&u32::MAX.to_be_bytes())?;
+        drop(log);
+
+        let report = MiniBitcask::repair(path.clone())?;
+        // c 和 d 都被丢弃
+        assert_eq!(report.kept, 2);
+        assert_eq!(report.dropped, 2);
+        assert_eq!(report.dropped_bytes, file_len - offset);
+        assert!(path.with_extension("bak").exists());
+
+        let mut eng = MiniBitcask::new(path.clone())?;
+        assert_eq!(eng.get(b"a")?, Some(b"val1".to_vec()));
+        assert_eq!(eng.get(b"b")?, Some(b"val2".to_vec()));
+        assert_eq!(eng.get(b"c")?, None);
+        assert_eq!(eng.get(b"d")?, None);
+
+        path.parent().map(|p| std::fs::remove_dir_all(p));
+        Ok(())
+    }
+
+    // 测试写入过程中维护的索引和重新打开时加载的索引一致
+    #[test]
+    fn test_keydir_reload() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join("minibitcask-keydir-test")
+            .join("log");
+        let mut eng = MiniBitcask::new(path.clone())?;
+
+        for i in 0..1000u32 {
+            eng.set(&i.to_be_bytes(), i.to_string().into_bytes())?;
+        }
+        for i in (0..1000u32).step_by(3) {
+            eng.delete(&i.to_be_bytes())?;
+        }
+        for i in (0..1000u32).step_by(7) {
+            eng.set(&i.to_be_bytes(), b"rewrite".to_vec())?;
+        }
+        let keydir = eng.keydir.clone();
+        drop(eng);
+
+        let mut eng = MiniBitcask::new(path.clone())?;
+        assert_eq!(eng.keydir, keydir);
+        assert_eq!(eng.get(&7u32.to_be_bytes())?, Some(b"rewrite".to_vec()));
+        assert_eq!(eng.get(&3u32.to_be_bytes())?, None);
+        assert_eq!(eng.get(&4u32.to_be_bytes())?, Some(b"4".to_vec()));
+
+        // 每个 key 比 Vec<u8> 少占用一个 usize
+        assert_eq!(
+            std::mem::size_of::<Vec<u8>>() - std::mem::size_of::<Box<[u8]>>(),
+            std::mem::size_of::<usize>()
+        );
+
+        path.parent().map(|p| std::fs::remove_dir_all(p));
+        Ok(())
+    }
+
+    // 测试获取某个位置之后的变更
+    #[test]
+    fn test_changes_since() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join("minibitcask-changes-test")
+            .join("log");
+        let mut eng = MiniBitcask::new(path.clone())?;
+        eng.set(b"a", b"val1".to_vec())?;
+        eng.set(b"b", b"val2".to_vec())?;
+
+        let (changes, offset) = eng.changes_since(0)?;
+        assert_eq!(changes.len(), 2);
+
+        eng.set(b"c", b"val3".to_vec())?;
+        eng.delete(b"a")?;
+        eng.set(b"c", b"val4".to_vec())?;
+
+        let (changes, end) = eng.changes_since(offset)?;
+        let expected: Vec<Change> = vec![
+            (b"c".to_vec(), Some(b"val3".to_vec())),
+            (b"a".to_vec(), None),
+            (b"c".to_vec(), Some(b"val4".to_vec())),
+        ];
+        assert_eq!(changes, expected);
+
+        // 没有新的写入
+        assert_eq!(eng.changes_since(end)?, (vec![], end));
+        assert!(eng.changes_since(end + 1).is_err());
+
+        path.parent().map(|p| std::fs::remove_dir_all(p));
+        Ok(())
+    }
+
+    // 测试删除记录过多时自动 merge
+    #[test]
+    fn test_tombstone_merge_policy() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join("minibitcask-tombstone-test")
+            .join("log");
+        let policy = MergePolicy {
+            max_tombstones: Some(3),
+        };
+        let mut eng = MiniBitcask::new(path.clone())?.with_merge_policy(policy);
+
+        for key in [b"a", b"b", b"c", b"d", b"e"] {
+            eng.set(key, b"value".to_vec())?;
+        }
+        eng.delete(b"a")?;
+        eng.delete(b"b")?;
+        // 重复删除和重新写入的 key 不会重复计数
+        eng.delete(b"b")?;
+        eng.delete(b"c")?;
+        eng.set(b"c", b"value".to_vec())?;
+        eng.delete(b"d")?;
+        assert_eq!(eng.stats().tombstones, 3);
+
+        // 超过阈值，自动 merge 清除所有的删除记录
+        let len = eng.log.file.metadata()?.len();
+        eng.delete(b"c")?;
+        assert_eq!(eng.stats().tombstones, 0);
+        assert_eq!(eng.stats().keys, 1);
+        assert!(eng.log.file.metadata()?.len() < len);
+        assert_eq!(eng.get(b"e")?, Some(b"value".to_vec()));
+        assert_eq!(eng.get(b"c")?, None);
+
+        // 重新打开时从日志中恢复删除记录的数量
+        eng.delete(b"e")?;
+        drop(eng);
+        let eng = MiniBitcask::new(path.clone())?;
+        assert_eq!(eng.stats().tombstones, 1);
+        assert_eq!(eng.stats().keys, 0);
+
+        path.parent().map(|p| std::fs::remove_dir_all(p));
+        Ok(())
+    }
+
+    // 测试交换两个 key 的值
+    #[test]
+    fn test_swap() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join("minibitcask-swap-test")
+            .join("log");
+        let mut eng = MiniBitcask::new(path.clone())?.with_cache(10);
+        eng.set(b"a", b"val1".to_vec())?;
+        eng.set(b"b", b"value2".to_vec())?;
+        assert_eq!(eng.get(b"a")?, Some(b"val1".to_vec()));
+
+        // 两个 key 都存在
+        eng.swap(b"a", b"b")?;
+        assert_eq!(eng.get(b"a")?, Some(b"value2".to_vec()));
+        assert_eq!(eng.get(b"b")?, Some(b"val1".to_vec()));
+
+        // 一个 key 不存在
+        eng.swap(b"a", b"c")?;
+        assert_eq!(eng.get(b"a")?, None);
+        assert_eq!(eng.get(b"c")?, Some(b"value2".to_vec()));
+        assert!(!eng.keydir.contains_key(b"a".as_slice()));
+        assert_eq!(eng.stats().tombstones, 1);
+
+        // 两个 key 都不存在
+        eng.swap(b"x", b"y")?;
+        assert_eq!(eng.stats().keys, 2);
+
+        // 重新打开之后的索引和交换之后的一致
+        let keydir = eng.keydir.clone();
+        drop(eng);
+        let mut eng = MiniBitcask::new(path.clone())?;
+        assert_eq!(eng.keydir, keydir);
+        assert_eq!(eng.get(b"b")?, Some(b"val1".to_vec()));
+        assert_eq!(eng.get(b"c")?, Some(b"value2".to_vec()));
+
+        path.parent().map(|p| std::fs::remove_dir_all(p));
+        Ok(())
+    }
+
+    // 测试只合并一个范围内的 key
+    #[test]
+    fn test_merge_range() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join("minibitcask-merge-range-test")
+            .join("log");
+        let mut eng = MiniBitcask::new(path.clone())?;
+
+        // a 开头的 key 频繁更新，b 开头的 key 只更新一次
+        for i in 0..10 {
+            for key in [b"a1", b"a2", b"a3"] {
+                eng.set(key, format!("value{}", i).into_bytes())?;
+            }
+        }
+        eng.delete(b"a3")?;
+        eng.set(b"b1", b"old".to_vec())?;
+        eng.set(b"b1", b"new".to_vec())?;
+        eng.set(b"b2", b"value".to_vec())?;
+        eng.delete(b"b2")?;
+
+        let len = eng.log.file.metadata()?.len();
+        eng.merge_range(b"a".to_vec()..b"b".to_vec())?;
+        assert!(eng.log.file.metadata()?.len() < len);
+        assert_eq!(eng.stats().tombstones, 1);
+
+        // 范围内只剩下有效的记录，范围外的记录原样保留
+        let (changes, _) = eng.changes_since(0)?;
+        let keys: Vec<_> = changes.iter().map(|(key, _)| key.as_slice()).collect();
+        assert_eq!(keys, vec![b"a1", b"a2", b"b1", b"b1", b"b2", b"b2"]);
+
+        assert_eq!(eng.get(b"a1")?, Some(b"value9".to_vec()));
+        assert_eq!(eng.get(b"a2")?, Some(b"value9".to_vec()));
+        assert_eq!(eng.get(b"a3")?, None);
+        assert_eq!(eng.get(b"b1")?, Some(b"new".to_vec()));
+        assert_eq!(eng.get(b"b2")?, None);
+
+        // 重新打开之后的数据一致
+        let keydir = eng.keydir.clone();
+        drop(eng);
+        let eng = MiniBitcask::new(path.clone())?;
+        assert_eq!(eng.keydir, keydir);
+        assert_eq!(eng.stats().tombstones, 1);
+
+        path.parent().map(|p| std::fs::remove_dir_all(p));
+        Ok(())
+    }
+
+    // 分段时只重写包含范围内记录的分段
+    #[test]
+    fn test_merge_range_segments() -> Result<()> {
+        let dir = std::env::temp_dir().join("minibitcask-merge-range-segments");
+        let options = SegmentOptions {
+            max_size: 64,
+            ..Default::default()
+        };
+        let mut eng = MiniBitcask::open_segmented(dir.clone(), options.clone())?;
+
+        // 第一个分段中只有范围外的 key，之后的分段中 a 开头的 key 频繁更新，和 b2 的记录混在一起
+        eng.set(b"b1", vec![1; 60])?;
+        for i in 0..10 {
+            for key in [b"a1", b"a2", b"a3"] {
+                eng.set(key, format!("value{}", i).into_bytes())?;
+            }
+        }
+        eng.set(b"b2", b"old".to_vec())?;
+        eng.set(b"a1", b"last".to_vec())?;
+        eng.delete(b"a3")?;
+        eng.set(b"b2", b"new".to_vec())?;
+
+        let segments = eng.list_segments();
+        let first = std::fs::read(&segments[0])?;
+        let len = eng.log.len()?;
+        eng.merge_range(b"a".to_vec()..b"b".to_vec())?;
+
+        // 没有范围内记录的分段保持不变，其他分段只剩下范围外的记录，回收了范围内的空间
+        assert!(eng.log.len()? < len);
+        assert!(eng.list_segments().len() < segments.len());
+        assert_eq!(eng.list_segments()[0], segments[0]);
+        assert_eq!(std::fs::read(&segments[0])?, first);
+        let (changes, _) = eng.changes_since(0)?;
+        let keys: Vec<_> = changes.iter().map(|(key, _)| key.as_slice()).collect();
+        assert_eq!(keys, vec![b"b1", b"b2", b"b2", b"a1", b"a2"]);
+        assert_eq!(eng.log.load_index()?.0, eng.keydir);
+
+        assert_eq!(eng.get(b"a1")?, Some(b"last".to_vec()));
+        assert_eq!(eng.get(b"a2")?, Some(b"value9".to_vec()));
+        assert_eq!(eng.get(b"a3")?, None);
+        assert_eq!(eng.get(b"b1")?, Some(vec![1; 60]));
+        assert_eq!(eng.get(b"b2")?, Some(b"new".to_vec()));
+
+        // 之后的写入正常，重新打开之后的数据一致，删除的 key 不会重新出现
+        eng.set(b"a4", b"value".to_vec())?;
+        let keydir = eng.keydir.clone();
+        drop(eng);
+        let mut eng = MiniBitcask::open_segmented(dir.clone(), options)?;
+        assert_eq!(eng.keydir, keydir);
+        assert_eq!(eng.get(b"a3")?, None);
+        assert_eq!(eng.get(b"a4")?, Some(b"value".to_vec()));
+
+        drop(eng);
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    // 测试记录占用的磁盘空间
+    #[test]
+    fn test_record_size() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join("minibitcask-record-size-test")
+            .join("log");
+        let mut eng = MiniBitcask::new(path.clone())?;
+
+        // 4 字节 key 长度 + 4 字节 value 长度 + 3 字节 key + 5 字节 value
+        eng.set(b"abc", b"hello".to_vec())?;
+        assert_eq!(eng.record_size(b"abc"), Some(16));
+        assert_eq!(eng.log.file.metadata()?.len(), 16);
+
+        eng.set(b"abc", vec![0; 100])?;
+        assert_eq!(eng.record_size(b"abc"), Some(111));
+        eng.set(b"", vec![])?;
+        assert_eq!(eng.record_size(b""), Some(8));
+
+        eng.delete(b"abc")?;
+        assert_eq!(eng.record_size(b"abc"), None);
+        assert_eq!(eng.record_size(b"not exist"), None);
+
+        path.parent().map(|p| std::fs::remove_dir_all(p));
+        Ok(())
+    }
+
+    // 测试按照日志顺序遍历
+    #[test]
+    fn test_scan_log_order() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join("minibitcask-log-order-test")
+            .join("log");
+        let mut eng = MiniBitcask::new(path.clone())?;
+        eng.set(b"c", b"val1".to_vec())?;
+        eng.set(b"a", b"val2".to_vec())?;
+        eng.set(b"b", b"val3".to_vec())?;
+        eng.set(b"d", b"val4".to_vec())?;
+        // 覆盖 c，c 移动到最后；删除 b
+        eng.set(b"c", b"val5".to_vec())?;
+        eng.delete(b"b")?;
+
+        let entries = eng.scan_log_order()?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            entries,
+            vec![
+                (b"a".to_vec(), b"val2".to_vec()),
+                (b"d".to_vec(), b"val4".to_vec()),
+                (b"c".to_vec(), b"val5".to_vec()),
+            ]
+        );
+
+        // 排序的扫描不受影响
+        let keys = eng
+            .scan(..)?
+            .map(|item| item.map(|(key, _)| key))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(keys, vec![b"a".to_vec(), b"c".to_vec(), b"d".to_vec()]);
+
+        path.parent().map(|p| std::fs::remove_dir_all(p));
+        Ok(())
+    }
+
+    // 测试预读
+    #[test]
+    fn test_prefetch() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join("minibitcask-prefetch-test")
+            .join("log");
+        let mut eng = MiniBitcask::new(path.clone())?;
+        for i in 0..100u8 {
+            eng.set(&[i], vec![i; 64])?;
+        }
+        eng.delete(&[10])?;
+        drop(eng);
+
+        let mut eng = MiniBitcask::new(path.clone())?;
+        assert_eq!(eng.prefetch(vec![0]..vec![20])?, 19);
+        assert_eq!(eng.prefetch(..)?, 99);
+        assert_eq!(eng.prefetch(vec![200]..)?, 0);
+
+        assert_eq!(eng.get(&[0])?, Some(vec![0; 64]));
+        assert_eq!(eng.get(&[10])?, None);
+        assert_eq!(eng.get(&[19])?, Some(vec![19; 64]));
+        assert_eq!(eng.get(&[99])?, Some(vec![99; 64]));
+
+        path.parent().map(|p| std::fs::remove_dir_all(p));
+        Ok(())
+    }
+
+    // 测试检查索引和日志的一致性
+    #[test]
+    fn test_audit() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join("minibitcask-audit-test")
+            .join("log");
+        let mut eng = MiniBitcask::new(path.clone())?;
+        for i in 0..50u32 {
+            eng.set(&i.to_be_bytes(), vec![1; i as usize])?;
+        }
+        eng.set(b"", vec![])?;
+        eng.delete(&3u32.to_be_bytes())?;
+        eng.swap(&1u32.to_be_bytes(), &2u32.to_be_bytes())?;
+        eng.merge_range(..)?;
+        eng.set(&5u32.to_be_bytes(), b"value".to_vec())?;
+
+        let report = eng.audit()?;
+        assert_eq!(report.checked, 50);
+        assert!(report.mismatches.is_empty());
+
+        // 错误的位置会被发现
+        let key = 7u32.to_be_bytes();
+        if let Some(entry) = eng.keydir.get_mut(key.as_slice()) {
+            entry.0 += 1;
+        }
+        let report = eng.audit()?;
+        assert_eq!(report.mismatches, vec![key.to_vec()]);
+
+        path.parent().map(|p| std::fs::remove_dir_all(p));
+        Ok(())
+    }
+
+    // 测试将日志回滚到之前的位置
+    #[test]
+    fn test_truncate_to() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join("minibitcask-truncate-test")
+            .join("log");
+        let mut eng = MiniBitcask::new(path.clone())?.with_cache(8);
+        eng.set(b"a", b"val1".to_vec())?;
+        eng.set(b"b", b"val2".to_vec())?;
+        eng.delete(b"b")?;
+        let (_, offset) = eng.changes_since(0)?;
+
+        eng.set(b"a", b"val3".to_vec())?;
+        eng.set(b"b", b"val4".to_vec())?;
+        eng.set(b"c", b"val5".to_vec())?;
+        assert_eq!(eng.get(b"a")?, Some(b"val3".to_vec()));
+
+        // 没有确认、超出末尾、不在记录边界上都会失败
+        assert!(eng.truncate_to(offset, false).is_err());
+        assert!(eng.truncate_to(u64::MAX, true).is_err());
+        assert!(eng.truncate_to(offset + 1, true).is_err());
+        assert_eq!(eng.get(b"c")?, Some(b"val5".to_vec()));
+
+        eng.truncate_to(offset, true)?;
+        assert_eq!(eng.get(b"a")?, Some(b"val1".to_vec()));
+        assert_eq!(eng.get(b"b")?, None);
+        assert_eq!(eng.get(b"c")?, None);
+        assert_eq!(eng.stats().keys, 1);
+        assert_eq!(eng.stats().tombstones, 1);
+        assert_eq!(eng.changes_since(0)?.1, offset);
+
+        // 回滚之后可以继续写入，重新打开之后数据一致
+        eng.set(b"d", b"val6".to_vec())?;
+        drop(eng);
+        let mut eng = MiniBitcask::new(path.clone())?;
+        assert_eq!(eng.get(b"a")?, Some(b"val1".to_vec()));
+        assert_eq!(eng.get(b"c")?, None);
+        assert_eq!(eng.get(b"d")?, Some(b"val6".to_vec()));
+
+        path.parent().map(|p| std::fs::remove_dir_all(p));
+        Ok(())
+    }
+
+    // 测试通过复用的缓冲区读取 value
+    #[test]
+    fn test_with_value() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join("minibitcask-with-value-test")
+            .join("log");
+        let mut eng = MiniBitcask::new(path.clone())?;
+        eng.set(b"large", vec![7; 4096])?;
+        eng.set(b"small", b"value".to_vec())?;
+
+        let sum = eng.with_value(b"large", |v| v.iter().map(|b| *b as u32).sum::<u32>())?;
+        assert_eq!(sum, Some(7 * 4096));
+        let ptr = eng.read_buf.as_ptr();
+        let capacity = eng.read_buf.capacity();
+
+        // 第二次读取复用同一个缓冲区
+        assert_eq!(
+            eng.with_value(b"small", |v| v.to_vec())?,
+            Some(b"value".to_vec())
+        );
+        assert_eq!(eng.read_buf.as_ptr(), ptr);
+        assert_eq!(eng.read_buf.capacity(), capacity);
+
+        assert_eq!(eng.with_value(b"missing", |v| v.len())?, None);
+        eng.delete(b"small")?;
+        assert_eq!(eng.with_value(b"small", |v| v.len())?, None);
+
+        path.parent().map(|p| std::fs::remove_dir_all(p));
+        Ok(())
+    }
+
+    // 测试写缓冲区，sync 之后数据全部写入文件，重新打开之后仍然存在
+    #[test]
+    fn test_write_buffer() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join("minibitcask-write-buffer-test")
+            .join("log");
+        let mut eng = MiniBitcask::new(path.clone())?.with_write_buffer(4096);
+        for i in 0..100u8 {
+            eng.set(&[i], vec![i; 4])?;
+        }
+        eng.delete(&[7])?;
+        // 还在缓冲区中，没有写入文件
+        assert_eq!(std::fs::metadata(&path)?.len(), 0);
+        assert_eq!(eng.stats().keys, 99);
+
+        eng.sync()?;
+        let synced = std::fs::metadata(&path)?.len();
+        assert_eq!(synced, 100 * (8 + 1 + 4) + (8 + 1));
+
+        // 缓冲区满了之后写入文件
+        for i in 0..200u8 {
+            eng.set(&[i], vec![i; 16])?;
+        }
+        assert!(std::fs::metadata(&path)?.len() > synced);
+
+        // 读取时先写入缓冲区中的数据
+        assert_eq!(eng.get(&[199])?, Some(vec![199; 16]));
+        eng.set(&[200], b"last".to_vec())?;
+        assert_eq!(eng.scan(vec![200]..)?.count(), 1);
+        eng.merge()?;
+        eng.set(&[201], b"after merge".to_vec())?;
+        eng.sync()?;
+        drop(eng);
+
+        let mut eng = MiniBitcask::new(path.clone())?;
+        assert_eq!(eng.stats().keys, 202);
+        for i in 0..200u8 {
+            assert_eq!(eng.get(&[i])?, Some(vec![i; 16]));
+        }
+        assert_eq!(eng.get(&[200])?, Some(b"last".to_vec()));
+        assert_eq!(eng.get(&[201])?, Some(b"after merge".to_vec()));
+
+        path.parent().map(|p| std::fs::remove_dir_all(p));
+        Ok(())
+    }
+
+    // 测试日志中的记录总数
+    #[test]
+    fn test_physical_record_count() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join("minibitcask-record-count-test")
+            .join("log");
+        let mut eng = MiniBitcask::new(path.clone())?;
+        assert_eq!(eng.physical_record_count()?, 0);
+
+        for i in 0..10u8 {
+            eng.set(&[i], vec![i; 8])?;
+        }
+        assert_eq!(eng.physical_record_count()?, 10);
+
+        // 覆盖 3 个 key，删除 2 个 key
+        for i in 0..3u8 {
+            eng.set(&[i], vec![0; 4])?;
+        }
+        eng.delete(&[8])?;
+        eng.delete(&[9])?;
+        assert_eq!(eng.stats().keys, 8);
+        assert_eq!(eng.physical_record_count()?, 15);
+
+        // merge 之后只剩下有效的记录
+        eng.merge()?;
+        assert_eq!(eng.physical_record_count()?, 8);
+
+        path.parent().map(|p| std::fs::remove_dir_all(p));
+        Ok(())
+    }
+
+    // 测试按照 value 过滤扫描的结果
+    #[test]
+    fn test_filter_scan() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join("minibitcask-filter-scan-test")
+            .join("log");
+        let mut eng = MiniBitcask::new(path.clone())?;
+        for i in 0..10u8 {
+            eng.set(&[i], vec![i; i as usize])?;
+        }
+        eng.delete(&[8])?;
+
+        let long = eng
+            .filter_scan(.., |v| v.len() >= 6)?
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            long,
+            vec![
+                (vec![6], vec![6; 6]),
+                (vec![7], vec![7; 7]),
+                (vec![9], vec![9; 9]),
+            ]
+        );
+
+        // 只在范围内过滤
+        let even = eng
+            .filter_scan(vec![2]..vec![7], |v| v.len() % 2 == 0)?
+            .map(|item| item.map(|(k, _)| k))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(even, vec![vec![2], vec![4], vec![6]]);
+
+        assert_eq!(eng.filter_scan(.., |_| false)?.count(), 0);
+
+        path.parent().map(|p| std::fs::remove_dir_all(p));
+        Ok(())
+    }
+
+    // 测试有条件不满足时不写入任何数据
+    #[test]
+    fn test_conditional_batch_rejected() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join("minibitcask-conditional-rejected-test")
+            .join("log");
+        let mut eng = MiniBitcask::new(path.clone())?;
+        eng.set(b"a", b"1".to_vec())?;
+        eng.set(b"b", b"2".to_vec())?;
+        let (_, end) = eng.changes_since(0)?;
+
+        let writes = [
+            Op::Set(b"a".to_vec(), b"10".to_vec()),
+            Op::Delete(b"b".to_vec()),
+            Op::Set(b"c".to_vec(), b"30".to_vec()),
+        ];
+        // b 的值不同
+        let conditions = [
+            (b"a".to_vec(), Some(b"1".to_vec())),
+            (b"b".to_vec(), Some(b"3".to_vec())),
+        ];
+        assert!(!eng.conditional_batch(&conditions, &writes)?);
+        // c 应该不存在
+        let conditions = [(b"a".to_vec(), Some(b"1".to_vec())), (b"c".to_vec(), None)];
+        eng.set(b"c", b"3".to_vec())?;
+        assert!(!eng.conditional_batch(&conditions, &writes)?);
+
+        assert_eq!(eng.get(b"a")?, Some(b"1".to_vec()));
+        assert_eq!(eng.get(b"b")?, Some(b"2".to_vec()));
+        assert_eq!(eng.get(b"c")?, Some(b"3".to_vec()));
+        // 除了 c 的写入，日志中没有新的记录
+        assert_eq!(eng.changes_since(end)?.0.len(), 1);
+
+        path.parent().map(|p| std::fs::remove_dir_all(p));
+        Ok(())
+    }
+
+    // 测试所有条件都满足时写入所有数据
+    #[test]
+    fn test_conditional_batch_applied() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join("minibitcask-conditional-applied-test")
+            .join("log");
+        let mut eng = MiniBitcask::new(path.clone())?;
+        eng.set(b"a", b"1".to_vec())?;
+        eng.set(b"b", b"2".to_vec())?;
+
+        let conditions = [
+            (b"a".to_vec(), Some(b"1".to_vec())),
+            (b"b".to_vec(), Some(b"2".to_vec())),
+            (b"c".to_vec(), None),
+        ];
+        let writes = [
+            Op::Set(b"a".to_vec(), b"10".to_vec()),
+            Op::Delete(b"b".to_vec()),
+            Op::Set(b"c".to_vec(), b"30".to_vec()),
+        ];
+        assert!(eng.conditional_batch(&conditions, &writes)?);
+        assert_eq!(eng.get(b"a")?, Some(b"10".to_vec()));
+        assert_eq!(eng.get(b"b")?, None);
+        assert_eq!(eng.get(b"c")?, Some(b"30".to_vec()));
+
+        // 条件已经不满足，重复执行不会写入
+        assert!(!eng.conditional_batch(&conditions, &writes)?);
+        assert!(eng.conditional_batch(&[], &[])?);
+
+        drop(eng);
+        let mut eng = MiniBitcask::new(path.clone())?;
+        assert_eq!(eng.get(b"a")?, Some(b"10".to_vec()));
+        assert_eq!(eng.get(b"b")?, None);
+        assert_eq!(eng.get(b"c")?, Some(b"30".to_vec()));
+
+        path.parent().map(|p| std::fs::remove_dir_all(p));
+        Ok(())
+    }
+
+    // 测试内存索引占用的内存随 key 的数量和长度增长
+    #[test]
+    fn test_keydir_memory_bytes() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join("minibitcask-keydir-memory-test")
+            .join("log");
+        let mut eng = MiniBitcask::new(path.clone())?;
+        assert_eq!(eng.keydir_memory_bytes(), 0);
+
+        for i in 0..100u32 {
+            eng.set(format!("key-{:06}", i).as_bytes(), b"value".to_vec())?;
+        }
+        let first = eng.keydir_memory_bytes();
+        assert!(first >= 100 * 10);
+
+        // key 的数量翻倍，占用的内存也翻倍
+        for i in 100..200u32 {
+            eng.set(format!("key-{:06}", i).as_bytes(), b"value".to_vec())?;
+        }
+        assert_eq!(eng.keydir_memory_bytes(), first * 2);
+
+        // 覆盖已有的 key 不会增加内存占用，value 的大小也不影响
+        eng.set(b"key-000000", vec![0; 1024])?;
+        assert_eq!(eng.keydir_memory_bytes(), first * 2);
+
+        // 每个更长的 key 多出来的字节都会计算在内
+        eng.set(&[b'x'; 110], b"value".to_vec())?;
+        assert_eq!(eng.keydir_memory_bytes(), first * 2 + first / 100 + 100);
+
+        eng.delete(&[b'x'; 110])?;
+        eng.delete(b"key-000000")?;
+        assert_eq!(eng.keydir_memory_bytes(), first * 2 - first / 100);
+
+        path.parent().map(|p| std::fs::remove_dir_all(p));
+        Ok(())
+    }
+
+    // 测试数据的摘要只和有效的数据有关
+    #[test]
+    fn test_digest() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join("minibitcask-digest-test")
+            .join("log");
+        let mut eng = MiniBitcask::new(path.clone())?;
+        let empty = eng.digest()?;
+
+        eng.set(b"a", b"1".to_vec())?;
+        eng.set(b"b", b"2".to_vec())?;
+        eng.set(b"a", b"3".to_vec())?;
+        eng.set(b"c", b"4".to_vec())?;
+        eng.delete(b"c")?;
+        let before = eng.digest()?;
+        assert_ne!(before, empty);
 
-        // 加 exclusive lock 防止并发更新
-        file.try_lock_exclusive()?;
+        // merge 之后日志的布局变了，数据没有变化
+        eng.merge()?;
+        assert_eq!(eng.digest()?, before);
+
+        // 另一个数据库以不同的顺序写入相同的数据
+        let other_path = std::env::temp_dir()
+            .join("minibitcask-digest-test")
+            .join("other");
+        let mut other = MiniBitcask::new(other_path)?;
+        other.set(b"b", b"2".to_vec())?;
+        other.set(b"a", b"3".to_vec())?;
+        assert_eq!(other.digest()?, before);
+
+        // key 和 value 的边界不同
+        other.delete(b"a")?;
+        other.set(b"b", b"2a3".to_vec())?;
+        assert_ne!(other.digest()?, before);
+
+        // 修改数据之后摘要发生变化
+        eng.set(b"b", b"5".to_vec())?;
+        let changed = eng.digest()?;
+        assert_ne!(changed, before);
+        eng.set(b"b", b"2".to_vec())?;
+        assert_eq!(eng.digest()?, before);
+        eng.delete(b"a")?;
+        assert_ne!(eng.digest()?, before);
 
-        Ok(Self { path, file })
+        drop(other);
+        path.parent().map(|p| std::fs::remove_dir_all(p));
+        Ok(())
     }
 
-    // 构建内存索引
-    fn load_index(&mut self) -> Result<KeyDir> {
-        let mut len_buf = [0u8; KEY_VAL_HEADER_LEN as usize];
-        let mut keydir = KeyDir::new();
-        let file_len = self.file.metadata()?.len();
-        let mut r = BufReader::new(&mut self.file);
-        let mut pos: u64 = r.seek(SeekFrom::Start(0))?;
+    // 测试合并两个数据库，两种冲突处理方式
+    #[test]
+    fn test_merge_from() -> Result<()> {
+        let dir = std::env::temp_dir().join("minibitcask-merge-from-test");
+        let open = |name: &str| -> Result<MiniBitcask> {
+            let mut eng = MiniBitcask::new(dir.join(name))?;
+            if name.starts_with("self") {
+                eng.set(b"a", b"self-a".to_vec())?;
+                eng.set(b"b", b"self-b".to_vec())?;
+            } else {
+                eng.set(b"b", b"other-b".to_vec())?;
+                eng.set(b"c", b"other-c".to_vec())?;
+                eng.set(b"d", b"other-d".to_vec())?;
+                eng.delete(b"d")?;
+            }
+            Ok(eng)
+        };
 
-        while pos < file_len {
-            let read_one = || -> Result<(Vec<u8>, u64, Option<u32>)> {
-                // 读取 key 的长度
-                r.read_exact(&mut len_buf)?;
-                let key_len = u32::from_be_bytes(len_buf);
-                // 读取 value 的长度
-                r.read_exact(&mut len_buf)?;
-                let value_lent_or_tombstone = match i32::from_be_bytes(len_buf) {
-                    l if l >= 0 => Some(l as u32),
-                    _ => None,
-                };
+        let mut other = open("other")?;
+        let mut keep = open("self-keep")?;
+        assert_eq!(keep.merge_from(&mut other, ConflictPolicy::KeepSelf)?, 1);
+        assert_eq!(keep.get(b"a")?, Some(b"self-a".to_vec()));
+        assert_eq!(keep.get(b"b")?, Some(b"self-b".to_vec()));
+        assert_eq!(keep.get(b"c")?, Some(b"other-c".to_vec()));
+        assert_eq!(keep.get(b"d")?, None);
+
+        let mut eng = open("self-take")?;
+        assert_eq!(eng.merge_from(&mut other, ConflictPolicy::TakeOther)?, 2);
+        assert_eq!(eng.get(b"a")?, Some(b"self-a".to_vec()));
+        assert_eq!(eng.get(b"b")?, Some(b"other-b".to_vec()));
+        assert_eq!(eng.get(b"c")?, Some(b"other-c".to_vec()));
+        assert_eq!(eng.get(b"d")?, None);
+
+        // 另一个数据库保持不变，合并的数据重新打开之后仍然存在
+        assert_eq!(other.stats().keys, 2);
+        drop(eng);
+        let mut eng = MiniBitcask::new(dir.join("self-take"))?;
+        assert_eq!(eng.stats().keys, 3);
+        assert_eq!(eng.get(b"b")?, Some(b"other-b".to_vec()));
+
+        drop((keep, eng, other));
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    // 测试分多次写入一个很大的 value
+    #[test]
+    fn test_set_streaming() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join("minibitcask-set-streaming-test")
+            .join("log");
+        let mut eng = MiniBitcask::new(path.clone())?;
+        eng.set(b"small", b"1".to_vec())?;
 
-                // value 的位置
-                let value_pos = pos + KEY_VAL_HEADER_LEN as u64 * 2 + key_len as u64;
+        let value: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251) as u8).collect();
+        let mut writer = eng.set_streaming(b"large", value.len() as u32)?;
+        for chunk in value.chunks(64 * 1024) {
+            writer.write_all(chunk)?;
+        }
+        assert_eq!(writer.remaining(), 0);
+        // 超出长度的写入会失败
+        assert!(writer.write_all(b"x").is_err());
+        drop(writer);
+        assert_eq!(eng.get(b"large")?, Some(value.clone()));
+
+        eng.set(b"small", b"2".to_vec())?;
+        let writer = eng.set_streaming(b"empty", 0)?;
+        drop(writer);
+        assert_eq!(eng.get(b"empty")?, Some(vec![]));
+
+        // 没有写完就被 drop，之前的数据不受影响
+        let mut writer = eng.set_streaming(b"large", 100)?;
+        writer.write_all(&[1; 60])?;
+        drop(writer);
+        assert_eq!(eng.get(b"large")?, Some(value.clone()));
+        eng.set(b"after", b"3".to_vec())?;
+
+        drop(eng);
+        let mut eng = MiniBitcask::new(path.clone())?;
+        assert_eq!(eng.get(b"large")?, Some(value.clone()));
+        assert_eq!(eng.get(b"small")?, Some(b"2".to_vec()));
+        assert_eq!(eng.get(b"after")?, Some(b"3".to_vec()));
+
+        // 进程崩溃时留下不完整的记录，通过 repair 清除
+        let mut writer = eng.set_streaming(b"crash", 100)?;
+        writer.write_all(&[2; 60])?;
+        std::mem::forget(writer);
+        drop(eng);
+        let report = MiniBitcask::repair(path.clone())?;
+        assert_eq!(report.dropped, 1);
+        assert_eq!(report.dropped_bytes, 8 + 5 + 60);
+        let mut eng = MiniBitcask::new(path.clone())?;
+        assert_eq!(eng.get(b"crash")?, None);
+        assert_eq!(eng.get(b"large")?, Some(value));
 
-                // 读取 key 的内容
-                let mut key = vec![0; key_len as usize];
-                r.read_exact(&mut key)?;
+        drop(eng);
+        path.parent().map(|p| std::fs::remove_dir_all(p));
+        Ok(())
+    }
 
-                // 跳过 value 的长度
-                if let Some(value_len) = value_lent_or_tombstone {
-                    r.seek_relative(value_len as i64)?;
-                }
+    // 测试按照 value 的大小列出 key
+    #[test]
+    fn test_top_keys_by_size() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join("minibitcask-top-keys-test")
+            .join("log");
+        let mut eng = MiniBitcask::new(path.clone())?;
+        assert_eq!(eng.top_keys_by_size(3), vec![]);
+
+        eng.set(b"a", vec![0; 10])?;
+        eng.set(b"b", vec![0; 300])?;
+        eng.set(b"c", vec![0; 50])?;
+        eng.set(b"d", vec![0; 300])?;
+        eng.set(b"e", vec![])?;
+        eng.set(b"f", vec![0; 1000])?;
+        // 覆盖和删除之后使用最新的大小
+        eng.set(b"a", vec![0; 100])?;
+        eng.delete(b"f")?;
+
+        assert_eq!(
+            eng.top_keys_by_size(3),
+            vec![
+                (b"b".to_vec(), 300),
+                (b"d".to_vec(), 300),
+                (b"a".to_vec(), 100)
+            ]
+        );
+        assert_eq!(eng.top_keys_by_size(1), vec![(b"b".to_vec(), 300)]);
+        assert_eq!(eng.top_keys_by_size(0), vec![]);
+        let all = eng.top_keys_by_size(10);
+        assert_eq!(all.len(), 5);
+        assert_eq!(all[3], (b"c".to_vec(), 50));
+        assert_eq!(all[4], (b"e".to_vec(), 0));
 
-                Ok((key, value_pos, value_lent_or_tombstone))
-            }();
+        path.parent().map(|p| std::fs::remove_dir_all(p));
+        Ok(())
+    }
 
-            match read_one {
-                Ok((key, value_pos, Some(value_len))) => {
-                    keydir.insert(key, (value_pos, value_len));
-                    pos = value_pos + value_len as u64;
-                }
-                Ok((key, value_pos, None)) => {
-                    keydir.remove(&key);
-                    pos = value_pos;
-                }
-                Err(err) => return Err(err.into()),
+    // 测试直接修改日志之后重建内存索引
+    #[test]
+    fn test_reindex() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join("minibitcask-reindex-test")
+            .join("log");
+        let mut eng = MiniBitcask::new(path.clone())?.with_cache(10);
+        eng.set(b"a", b"1".to_vec())?;
+        eng.set(b"b", b"2".to_vec())?;
+        assert_eq!(eng.get(b"a")?, Some(b"1".to_vec()));
+
+        // 绕过索引直接写入日志，索引和日志不一致
+        eng.log.write_entry(b"a", Some(b"10"))?;
+        eng.log.write_entry(b"b", None)?;
+        eng.log.write_entry(b"c", Some(b"30"))?;
+        assert_eq!(eng.get(b"a")?, Some(b"1".to_vec()));
+        assert_eq!(eng.get(b"c")?, None);
+        assert_eq!(eng.stats().keys, 2);
+
+        eng.reindex()?;
+        assert_eq!(eng.get(b"a")?, Some(b"10".to_vec()));
+        assert_eq!(eng.get(b"b")?, None);
+        assert_eq!(eng.get(b"c")?, Some(b"30".to_vec()));
+        assert_eq!(
+            eng.stats(),
+            Stats {
+                keys: 2,
+                tombstones: 1
             }
-        }
+        );
+        assert!(eng.audit()?.mismatches.is_empty());
 
-        Ok(keydir)
-    }
+        // 截断日志之后重建索引
+        let len = eng.log.len()?;
+        eng.set(b"d", b"4".to_vec())?;
+        eng.log.file.set_len(len)?;
+        eng.reindex()?;
+        assert_eq!(eng.get(b"d")?, None);
+        assert_eq!(eng.stats().keys, 2);
 
-    // 根据 value 的位置和长度获取 value 的值
-    fn read_value(&mut self, value_pos: u64, value_len: u32) -> Result<Vec<u8>> {
-        let mut value = vec![0; value_len as usize];
-        self.file.seek(SeekFrom::Start(value_pos))?;
-        self.file.read_exact(&mut value)?;
-        Ok(value)
+        path.parent().map(|p| std::fs::remove_dir_all(p));
+        Ok(())
     }
 
-    // +-------------+-------------+----------------+----------------+
-    // | key len(4)    val len(4)     key(varint)       val(varint)  |
-    // +-------------+-------------+----------------+----------------+
-    fn write_entry(&mut self, key: &[u8], value: Option<&[u8]>) -> Result<(u64, u32)> {
-        let key_len = key.len() as u32;
-        let value_len = value.map_or(0, |v| v.len() as u32);
-        let value_len_or_tomestone = value.map_or(-1, |v| v.len() as i32);
+    // 测试新的实例打开之后，旧实例使用过期的 epoch 写入会被拒绝
+    #[test]
+    fn test_epoch_fencing() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join("minibitcask-epoch-test")
+            .join("log");
+        let mut old = MiniBitcask::new(path.clone())?;
+        let old_epoch = old.epoch();
+        old.set_fenced(b"a", b"1".to_vec(), old_epoch)?;
+
+        // 模拟文件锁失效，新的实例打开数据库
+        old.log.file.unlock()?;
+        let mut new = MiniBitcask::new(path.clone())?;
+        assert_eq!(new.epoch(), old_epoch + 1);
+
+        let err = old.set_fenced(b"a", b"2".to_vec(), old_epoch).unwrap_err();
+        let fenced = err.get_ref().and_then(|e| e.downcast_ref::<FencedOut>());
+        assert_eq!(
+            fenced,
+            Some(&FencedOut {
+                epoch: old_epoch,
+                current: old_epoch + 1
+            })
+        );
+        assert!(old.delete_fenced(b"a", old_epoch).is_err());
+        assert!(new.set_fenced(b"a", b"3".to_vec(), old_epoch).is_err());
 
-        // 总共占据的长度
-        let len = KEY_VAL_HEADER_LEN * 2 + key_len + value_len;
+        new.set_fenced(b"a", b"3".to_vec(), new.epoch())?;
+        new.delete_fenced(b"b", new.epoch())?;
+        assert_eq!(new.get(b"a")?, Some(b"3".to_vec()));
 
-        let offset = self.file.seek(SeekFrom::End(0))?;
-        let mut w = BufWriter::with_capacity(len as usize, &mut self.file);
-        w.write_all(&key_len.to_be_bytes())?;
-        w.write_all(&value_len_or_tomestone.to_be_bytes())?;
-        w.write_all(key)?;
-        if let Some(value) = value {
-            w.write_all(value)?;
-        }
-        w.flush()?;
+        // 重新打开之后 epoch 继续增加
+        drop(old);
+        drop(new);
+        let eng = MiniBitcask::new(path.clone())?;
+        assert_eq!(eng.epoch(), old_epoch + 2);
 
-        Ok((offset, len))
+        drop(eng);
+        path.parent().map(|p| std::fs::remove_dir_all(p));
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::{Log, MiniBitcask, Result};
-    use std::ops::Bound;
+    fn mvcc_key(key: &[u8], version: u64) -> Vec<u8> {
+        let mut encoded = (key.len() as u64).to_be_bytes().to_vec();
+        encoded.extend_from_slice(key);
+        encoded.extend_from_slice(&version.to_be_bytes());
+        encoded
+    }
 
     #[test]
-    fn test_log_read_write() -> Result<()> {
+    fn test_merge_mvcc() -> Result<()> {
         let path = std::env::temp_dir()
-            .join("sqldb-disk-engine-log-test1")
+            .join("minibitcask-merge-mvcc-test")
             .join("log");
+        let mut eng = MiniBitcask::new(path.clone())?;
+        for version in 1..=4 {
+            eng.set(
+                &mvcc_key(b"a", version),
+                format!("a{}", version).into_bytes(),
+            )?;
+        }
+        eng.set(&mvcc_key(b"b", 2), b"b2".to_vec())?;
+        eng.set(b"plain", b"value".to_vec())?;
+
+        // 没有活跃事务时，每个 key 只保留最新的版本
+        assert_eq!(eng.merge_mvcc(1, u64::MAX)?, 3);
+        assert_eq!(eng.get(&mvcc_key(b"a", 4))?, Some(b"a4".to_vec()));
+        assert_eq!(eng.get(&mvcc_key(b"a", 3))?, None);
+        assert_eq!(eng.get(&mvcc_key(b"b", 2))?, Some(b"b2".to_vec()));
+        assert_eq!(eng.get(b"plain")?, Some(b"value".to_vec()));
+        assert_eq!(eng.physical_record_count()?, 3);
+
+        // 活跃事务的版本为 3 时，版本 2 是它能读到的版本，需要保留
+        for version in 5..=6 {
+            eng.set(
+                &mvcc_key(b"b", version),
+                format!("b{}", version).into_bytes(),
+            )?;
+        }
+        eng.set(&mvcc_key(b"b", 1), b"b1".to_vec())?;
+        assert_eq!(eng.merge_mvcc(1, 3)?, 1);
+        assert_eq!(eng.get(&mvcc_key(b"b", 1))?, None);
+        assert_eq!(eng.get(&mvcc_key(b"b", 2))?, Some(b"b2".to_vec()));
+        assert_eq!(eng.get(&mvcc_key(b"b", 5))?, Some(b"b5".to_vec()));
+        assert_eq!(eng.merge_mvcc(1, 3)?, 0);
+        drop(eng);
+
+        // 重新打开后数据不变
+        let mut eng = MiniBitcask::new(path.clone())?;
+        assert_eq!(eng.get(&mvcc_key(b"b", 2))?, Some(b"b2".to_vec()));
+        assert_eq!(eng.get(&mvcc_key(b"a", 3))?, None);
+        assert_eq!(eng.stats().keys, 5);
 
-        let mut log = Log::new(path.clone())?;
-        log.write_entry(b"a", Some(b"val1"))?;
-        log.write_entry(b"b", Some(b"val2"))?;
-        log.write_entry(b"c", Some(b"val3"))?;
+        path.parent().map(|p| std::fs::remove_dir_all(p));
+        Ok(())
+    }
 
-        // rewrite
-        log.write_entry(b"a", Some(b"val5"))?;
-        // delete
-        log.write_entry(b"c", None)?;
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_par_for_each() -> Result<()> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
 
-        let keydir = log.load_index()?;
-        assert_eq!(2, keydir.len());
+        let path = std::env::temp_dir()
+            .join("minibitcask-par-for-each-test")
+            .join("log");
+        let mut eng = MiniBitcask::new(path.clone())?.with_write_buffer(4096);
+        for i in 0..1000 {
+            eng.set(format!("key{}", i).as_bytes(), vec![b'v'; i % 37])?;
+        }
+        eng.delete(b"key7")?;
+
+        let serial: usize = eng.scan(..)?.map(|item| item.unwrap().1.len()).sum();
+        let total = AtomicUsize::new(0);
+        let count = AtomicUsize::new(0);
+        eng.par_for_each(|_, value| {
+            total.fetch_add(value.len(), Ordering::Relaxed);
+            count.fetch_add(1, Ordering::Relaxed);
+        })?;
+        assert_eq!(total.into_inner(), serial);
+        assert_eq!(count.into_inner(), 999);
 
         path.parent().map(|p| std::fs::remove_dir_all(p));
-
         Ok(())
     }
 
     #[test]
-    fn test_log_reopen() -> Result<()> {
+    fn test_set_if_absent() -> Result<()> {
         let path = std::env::temp_dir()
-            .join("sqldb-disk-engine-log-test2")
+            .join("minibitcask-set-if-absent-test")
             .join("log");
+        let mut eng = MiniBitcask::new(path.clone())?;
+        assert!(eng.set_if_absent(b"a", b"1".to_vec())?);
+        assert!(!eng.set_if_absent(b"a", b"2".to_vec())?);
+        assert_eq!(eng.get(b"a")?, Some(b"1".to_vec()));
 
-        let mut log = Log::new(path.clone())?;
-        log.write_entry(b"a", Some(b"val1"))?;
-        log.write_entry(b"b", Some(b"val2"))?;
-        log.write_entry(b"c", Some(b"val3"))?;
-        log.write_entry(b"d", Some(b"val4"))?;
-        log.write_entry(b"d", None)?;
+        // 删除之后可以重新写入
+        eng.delete(b"a")?;
+        assert!(eng.set_if_absent(b"a", b"3".to_vec())?);
+        assert_eq!(eng.get(b"a")?, Some(b"3".to_vec()));
 
-        drop(log);
+        path.parent().map(|p| std::fs::remove_dir_all(p));
+        Ok(())
+    }
 
-        let mut log = Log::new(path.clone())?;
-        let keydir = log.load_index()?;
-        assert_eq!(3, keydir.len());
+    #[test]
+    fn test_tombstoned_keys() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join("minibitcask-tombstoned-keys-test")
+            .join("log");
+        let mut eng = MiniBitcask::new(path.clone())?.with_write_buffer(1024);
+        for key in [b"a", b"b", b"c", b"d"] {
+            eng.set(key, b"value".to_vec())?;
+        }
+        eng.delete(b"c")?;
+        eng.delete(b"a")?;
+        // 删除之后重新写入的 key 不是删除状态
+        eng.delete(b"b")?;
+        eng.set(b"b", b"again".to_vec())?;
+        assert_eq!(eng.tombstoned_keys()?, vec![b"a".to_vec(), b"c".to_vec()]);
 
-        path.parent().map(|p| std::fs::remove_dir_all(p));
+        // merge 之后删除记录被回收
+        eng.merge()?;
+        assert!(eng.tombstoned_keys()?.is_empty());
 
+        path.parent().map(|p| std::fs::remove_dir_all(p));
         Ok(())
     }
 
-    // 测试点读的情况
     #[test]
-    fn test_point_opt() -> Result<()> {
-        let path = std::env::temp_dir().join("minibitcask-test").join("log");
+    fn test_recover_to_timestamp() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join("minibitcask-recover-timestamp-test")
+            .join("log");
+        let mut eng = MiniBitcask::new(path.clone())?.with_write_buffer(1024);
+        eng.set(b"a", b"a1".to_vec())?;
+        eng.mark_timestamp(100)?;
+        eng.set(b"b", b"b1".to_vec())?;
+        eng.set(b"a", b"a2".to_vec())?;
+        eng.mark_timestamp(200)?;
+        eng.delete(b"b")?;
+        eng.set(b"c", b"c1".to_vec())?;
+        eng.mark_timestamp(300)?;
+        eng.set(b"d", b"d1".to_vec())?;
+        assert!(eng.mark_timestamp(250).is_err());
+        drop(eng);
+
+        // 不截断时只在内存中恢复，恢复到 200 和 300 之间，只能看到 200 之前的写入
+        let mut eng = MiniBitcask::recover_to_timestamp(path.clone(), 250, false)?;
+        assert_eq!(eng.get(b"a")?, Some(b"a2".to_vec()));
+        assert_eq!(eng.get(b"b")?, Some(b"b1".to_vec()));
+        assert_eq!(eng.get(b"c")?, None);
+        assert_eq!(eng.get(b"d")?, None);
+        // 只读，写入和会改变日志的操作都被拒绝
+        let err = eng.set(b"e", b"e1".to_vec()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+        assert!(eng.delete(b"a").is_err());
+        assert!(eng.merge().is_err());
+        assert!(eng.mark_timestamp(400).is_err());
+        assert!(eng.reindex().is_err());
+        assert_eq!(eng.get(b"e")?, None);
+        // 可以导出 ts 时的数据
+        let export_path = path.with_extension("export");
+        eng.export(export_path.clone())?;
+        let mut exported = MiniBitcask::new(export_path)?;
+        assert_eq!(exported.get(b"b")?, Some(b"b1".to_vec()));
+        assert_eq!(exported.get(b"d")?, None);
+        drop(exported);
+        drop(eng);
+        let mut eng = MiniBitcask::new(path.clone())?;
+        assert_eq!(eng.get(b"e")?, None);
+        assert_eq!(eng.get(b"d")?, Some(b"d1".to_vec()));
+        assert!(!path.with_extension("bak").exists());
+        drop(eng);
+
+        // 截断之后重新打开，数据不变，原来的日志保存在备份中
+        let eng = MiniBitcask::recover_to_timestamp(path.clone(), 250, true)?;
+        drop(eng);
         let mut eng = MiniBitcask::new(path.clone())?;
+        assert_eq!(eng.stats().keys, 2);
+        assert_eq!(eng.get(b"a")?, Some(b"a2".to_vec()));
+        assert_eq!(eng.get(b"d")?, None);
+        assert!(path.with_extension("bak").exists());
+        drop(eng);
+
+        // 早于所有时间点时返回错误，数据不变
+        assert!(MiniBitcask::recover_to_timestamp(path.clone(), 50, true).is_err());
+        let eng = MiniBitcask::new(path.clone())?;
+        assert_eq!(eng.stats().keys, 2);
+        drop(eng);
 
-        // 测试获取一个不存在的 key
-        assert_eq!(eng.get(b"not exist")?, None);
+        path.parent().map(|p| std::fs::remove_dir_all(p));
+        Ok(())
+    }
 
-        // 获取一个存在的 key
-        eng.set(b"aa", vec![1, 2, 3, 4])?;
-        assert_eq!(eng.get(b"aa")?, Some(vec![1, 2, 3, 4]));
+    // 没有时间点（从来没有记录过或者 merge 之后被清除）时拒绝恢复，不会清空数据
+    #[test]
+    fn test_recover_to_timestamp_without_marks() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join("minibitcask-recover-timestamp-no-marks")
+            .join("log");
+        let mut eng = MiniBitcask::new(path.clone())?;
+        eng.set(b"a", b"a1".to_vec())?;
+        drop(eng);
+        assert!(MiniBitcask::recover_to_timestamp(path.clone(), 1000, true).is_err());
 
-        // 重复 put，将会覆盖前一个值
-        eng.set(b"aa", vec![5, 6, 7, 8])?;
-        assert_eq!(eng.get(b"aa")?, Some(vec![5, 6, 7, 8]));
+        let mut eng = MiniBitcask::new(path.clone())?;
+        eng.mark_timestamp(100)?;
+        eng.set(b"b", b"b1".to_vec())?;
+        eng.merge()?;
+        drop(eng);
+        let err = MiniBitcask::recover_to_timestamp(path.clone(), 1000, true);
+        assert_eq!(
+            err.err().map(|e| e.kind()),
+            Some(std::io::ErrorKind::NotFound)
+        );
 
-        // 删除之后再读取
-        eng.delete(b"aa")?;
-        assert_eq!(eng.get(b"aa")?, None);
+        let eng = MiniBitcask::new(path.clone())?;
+        assert_eq!(eng.stats().keys, 2);
 
-        // key、value 为空的情况
-        assert_eq!(eng.get(b"")?, None);
-        eng.set(b"", vec![])?;
-        assert_eq!(eng.get(b"")?, Some(vec![]));
+        drop(eng);
+        path.parent().map(|p| std::fs::remove_dir_all(p));
+        Ok(())
+    }
 
-        eng.set(b"cc", vec![5, 6, 7, 8])?;
-        assert_eq!(eng.get(b"cc")?, Some(vec![5, 6, 7, 8]));
+    #[test]
+    fn test_list_segments() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join("minibitcask-list-segments")
+            .join("log");
+        let mut eng = MiniBitcask::new(path.clone())?;
+        eng.set(b"a", b"1".to_vec())?;
+        eng.set(b"b", b"2".to_vec())?;
+        assert_eq!(eng.list_segments(), vec![path.clone()]);
+        assert!(eng.list_segments().iter().all(|p| p.exists()));
 
+        drop(eng);
         path.parent().map(|p| std::fs::remove_dir_all(p));
         Ok(())
     }
 
-    // 测试扫描
+    // 索引指向已经被覆盖的记录时，check_duplicates 能发现这个 key
     #[test]
-    fn test_scan() -> Result<()> {
+    fn test_check_duplicates() -> Result<()> {
         let path = std::env::temp_dir()
-            .join("minibitcask-scan-test")
+            .join("minibitcask-check-duplicates")
             .join("log");
         let mut eng = MiniBitcask::new(path.clone())?;
+        eng.set(b"a", b"1".to_vec())?;
+        let stale = eng.keydir[b"a".as_slice()];
+        eng.set(b"a", b"2".to_vec())?;
+        eng.set(b"b", b"1".to_vec())?;
+        eng.set(b"c", b"1".to_vec())?;
+        let deleted = eng.keydir[b"c".as_slice()];
+        eng.delete(b"c")?;
+        assert!(eng.check_duplicates()?.is_empty());
 
-        eng.set(b"nnaes", b"value1".to_vec())?;
-        eng.set(b"amhue", b"value2".to_vec())?;
-        eng.set(b"meeae", b"value3".to_vec())?;
-        eng.set(b"uujeh", b"value4".to_vec())?;
-        eng.set(b"anehe", b"value5".to_vec())?;
+        // 模拟出错的 merge，索引仍然指向旧的记录
+        eng.keydir.insert(b"a".to_vec().into_boxed_slice(), stale);
+        eng.keydir.insert(b"c".to_vec().into_boxed_slice(), deleted);
+        assert_eq!(eng.get(b"a")?, Some(b"1".to_vec()));
+        assert_eq!(eng.check_duplicates()?, vec![b"a".to_vec(), b"c".to_vec()]);
 
-        let start = Bound::Included(b"a".to_vec());
-        let end = Bound::Excluded(b"e".to_vec());
+        eng.reindex()?;
+        assert!(eng.check_duplicates()?.is_empty());
+        assert_eq!(eng.get(b"a")?, Some(b"2".to_vec()));
 
-        let mut iter = eng.scan((start.clone(), end.clone()));
-        let (key1, _) = iter.next().expect("no value founded")?;
-        assert_eq!(key1, b"amhue".to_vec());
+        // 日志中有效的 key 不在索引中
+        eng.keydir.remove(b"b".as_slice());
+        assert_eq!(eng.check_duplicates()?, vec![b"b".to_vec()]);
 
-        let (key2, _) = iter.next().expect("no value founded")?;
-        assert_eq!(key2, b"anehe".to_vec());
-        drop(iter);
+        drop(eng);
+        path.parent().map(|p| std::fs::remove_dir_all(p));
+        Ok(())
+    }
 
-        let start = Bound::Included(b"b".to_vec());
-        let end = Bound::Excluded(b"z".to_vec());
-        let mut iter2 = eng.scan((start, end));
+    // 每个字节和固定的 key 异或的编码方式
+    struct XorCodec(u8);
 
-        let (key3, _) = iter2.next_back().expect("no value founded")?;
-        assert_eq!(key3, b"uujeh".to_vec());
+    impl ValueCodec for XorCodec {
+        fn encode(&self, v: &[u8]) -> Vec<u8> {
+            v.iter().map(|b| b ^ self.0).collect()
+        }
 
-        let (key4, _) = iter2.next_back().expect("no value founded")?;
-        assert_eq!(key4, b"nnaes".to_vec());
+        fn decode(&self, v: &[u8]) -> Result<Vec<u8>> {
+            Ok(self.encode(v))
+        }
+    }
 
-        let (key5, _) = iter2.next_back().expect("no value founded")?;
-        assert_eq!(key5, b"meeae".to_vec());
+    #[test]
+    fn test_value_codec() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join("minibitcask-value-codec")
+            .join("log");
+        let mut eng = MiniBitcask::new(path.clone())?.with_codec(XorCodec(0x5a));
+        eng.set(b"a", b"plaintext-a".to_vec())?;
+        eng.set(b"b", b"plaintext-b".to_vec())?;
+        eng.set(b"b", b"plaintext-b2".to_vec())?;
+        eng.delete(b"a")?;
+        eng.set(b"c", b"plaintext-c".to_vec())?;
+        assert_eq!(eng.get(b"b")?, Some(b"plaintext-b2".to_vec()));
+        assert!(eng.set_streaming(b"d", 4).is_err());
+
+        // 日志中保存的是编码之后的数据
+        eng.sync()?;
+        let raw = std::fs::read(&path)?;
+        assert!(!raw.windows(9).any(|w| w == b"plaintext"));
+
+        // 变更记录、扫描和 merge 都使用解码之后的数据
+        let (changes, _) = eng.changes_since(0)?;
+        assert_eq!(changes[0], (b"a".to_vec(), Some(b"plaintext-a".to_vec())));
+        let scanned = eng.scan(..)?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            scanned,
+            vec![
+                (b"b".to_vec(), b"plaintext-b2".to_vec()),
+                (b"c".to_vec(), b"plaintext-c".to_vec())
+            ]
+        );
+        eng.merge()?;
+        assert!(eng.audit()?.mismatches.is_empty());
+        drop(eng);
 
+        // 使用相同的编码方式重新打开
+        let mut eng = MiniBitcask::new(path.clone())?.with_codec(XorCodec(0x5a));
+        assert_eq!(eng.get(b"a")?, None);
+        assert_eq!(eng.get(b"b")?, Some(b"plaintext-b2".to_vec()));
+        assert_eq!(eng.get(b"c")?, Some(b"plaintext-c".to_vec()));
+        drop(eng);
+
+        // 不设置编码方式时读到的是编码之后的数据
+        let mut eng = MiniBitcask::new(path.clone())?;
+        assert_eq!(eng.get(b"c")?, Some(XorCodec(0x5a).encode(b"plaintext-c")));
+
+        drop(eng);
         path.parent().map(|p| std::fs::remove_dir_all(p));
         Ok(())
     }
 
-    // 测试前缀扫描
     #[test]
-    fn test_scan_prefix() -> Result<()> {
+    fn test_get_prefix() -> Result<()> {
         let path = std::env::temp_dir()
-            .join("minibitcask-scan-prefix-test")
+            .join("minibitcask-get-prefix")
             .join("log");
         let mut eng = MiniBitcask::new(path.clone())?;
+        let long: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        eng.set(b"long", long.clone())?;
+        eng.set(b"short", b"abc".to_vec())?;
+        eng.set(b"after", b"tail".to_vec())?;
+
+        assert_eq!(eng.get_prefix(b"long", 16)?, Some(long[..16].to_vec()));
+        assert_eq!(eng.get_prefix(b"long", 0)?, Some(vec![]));
+        assert_eq!(eng.get_prefix(b"short", 16)?, Some(b"abc".to_vec()));
+        assert_eq!(eng.get_prefix(b"missing", 16)?, None);
+        assert_eq!(eng.get(b"after")?, Some(b"tail".to_vec()));
+        drop(eng);
+
+        // 设置了编码方式时返回解码之后的前缀
+        let mut eng = MiniBitcask::new(path.clone())?.with_codec(XorCodec(0x5a));
+        eng.set(b"encoded", b"plaintext".to_vec())?;
+        assert_eq!(eng.get_prefix(b"encoded", 5)?, Some(b"plain".to_vec()));
+
+        drop(eng);
+        path.parent().map(|p| std::fs::remove_dir_all(p));
+        Ok(())
+    }
 
-        eng.set(b"ccnaes", b"value1".to_vec())?;
-        eng.set(b"camhue", b"value2".to_vec())?;
-        eng.set(b"deeae", b"value3".to_vec())?;
-        eng.set(b"eeujeh", b"value4".to_vec())?;
-        eng.set(b"canehe", b"value5".to_vec())?;
-        eng.set(b"aanehe", b"value6".to_vec())?;
+    // 编码之后长度变化的编码方式：每个字节重复两次
+    struct DoubleCodec;
 
-        let prefix = b"ca";
-        let mut iter = eng.scan_prefix(prefix);
-        let (key1, _) = iter.next().transpose()?.unwrap();
-        assert_eq!(key1, b"camhue".to_vec());
-        let (key2, _) = iter.next().transpose()?.unwrap();
-        assert_eq!(key2, b"canehe".to_vec());
+    impl ValueCodec for DoubleCodec {
+        fn encode(&self, v: &[u8]) -> Vec<u8> {
+            v.iter().flat_map(|b| [*b, *b]).collect()
+        }
 
-        println!("{:?}", path.clone());
-        path.parent().map(|p| std::fs::remove_dir_all(p));
-        Ok(())
+        fn decode(&self, v: &[u8]) -> Result<Vec<u8>> {
+            if !v.len().is_multiple_of(2) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "odd encoded length",
+                ));
+            }
+            Ok(v.iter().step_by(2).copied().collect())
+        }
     }
 
+    // 编码之后长度变化时，写入之后不需要重新打开就能读到正确的值
     #[test]
-    fn test_merge() -> Result<()> {
+    fn test_value_codec_changes_length() -> Result<()> {
         let path = std::env::temp_dir()
-            .join("minibitcask-merge-test")
+            .join("minibitcask-value-codec-length")
             .join("log");
-
-        let mut eng = MiniBitcask::new(path.clone())?;
-
-        eng.set(b"a", b"value1".to_vec())?;
-        eng.set(b"b", b"value2".to_vec())?;
-        eng.set(b"c", b"value3".to_vec())?;
-        eng.delete(b"a")?;
-        eng.delete(b"b")?;
-        eng.delete(b"c")?;
+        let mut eng = MiniBitcask::new(path.clone())?.with_codec(DoubleCodec);
+        eng.set(b"a", b"hello".to_vec())?;
+        eng.set(b"b", b"world!".to_vec())?;
+        assert_eq!(eng.get(b"a")?, Some(b"hello".to_vec()));
+        assert_eq!(eng.record_size(b"a"), Some(8 + 1 + 10));
+
+        eng.swap(b"a", b"b")?;
+        assert_eq!(eng.get(b"a")?, Some(b"world!".to_vec()));
+        assert_eq!(eng.get(b"b")?, Some(b"hello".to_vec()));
+
+        let written = eng.conditional_batch(
+            &[(b"a".to_vec(), Some(b"world!".to_vec()))],
+            &[
+                Op::Set(b"c".to_vec(), b"batch".to_vec()),
+                Op::Delete(b"b".to_vec()),
+            ],
+        )?;
+        assert!(written);
+        assert_eq!(eng.get(b"c")?, Some(b"batch".to_vec()));
+        assert_eq!(eng.get(b"b")?, None);
+        assert!(eng.audit()?.mismatches.is_empty());
+        assert!(eng.check_duplicates()?.is_empty());
 
         eng.merge()?;
+        assert_eq!(eng.get(b"a")?, Some(b"world!".to_vec()));
+        assert_eq!(eng.get(b"c")?, Some(b"batch".to_vec()));
+        drop(eng);
 
-        eng.set(b"a", b"value1".to_vec())?;
-        eng.set(b"b", b"value2".to_vec())?;
-        eng.set(b"c", b"value3".to_vec())?;
+        let mut eng = MiniBitcask::new(path.clone())?.with_codec(DoubleCodec);
+        assert_eq!(eng.get(b"a")?, Some(b"world!".to_vec()));
+        assert_eq!(eng.get(b"c")?, Some(b"batch".to_vec()));
 
-        let val = eng.get(b"a")?;
-        assert_eq!(b"value1".to_vec(), val.unwrap());
+        drop(eng);
+        path.parent().map(|p| std::fs::remove_dir_all(p));
+        Ok(())
+    }
 
-        let val = eng.get(b"b")?;
-        assert_eq!(b"value2".to_vec(), val.unwrap());
+    // 分段保存时，写入超过分段大小之后创建新的分段，文件名符合配置的格式，list_segments 按顺序返回所有分段
+    #[test]
+    fn test_segments() -> Result<()> {
+        let dir = std::env::temp_dir().join("minibitcask-segments");
+        assert_eq!(SegmentOptions::default().file_name(1), "data-000001.log");
+        let options = SegmentOptions {
+            prefix: "seg-".to_string(),
+            suffix: ".data".to_string(),
+            max_size: 64,
+        };
+        let segment = |id: u64| dir.join(format!("seg-{:06}.data", id));
 
-        let val = eng.get(b"c")?;
-        assert_eq!(b"value3".to_vec(), val.unwrap());
+        // 每条记录 8 + 4 + 20 = 32 个字节，每个分段保存两条记录
+        let mut eng = MiniBitcask::open_segmented(dir.clone(), options.clone())?;
+        for i in 0..6u8 {
+            eng.set(format!("key{}", i).as_bytes(), vec![i; 20])?;
+        }
+        assert_eq!(
+            eng.list_segments(),
+            vec![segment(1), segment(2), segment(3)]
+        );
+        eng.set(b"key0", vec![100; 20])?;
+        let segments = eng.list_segments();
+        assert_eq!(
+            segments,
+            vec![segment(1), segment(2), segment(3), segment(4)]
+        );
+        for path in segments.iter() {
+            assert!(path.exists());
+        }
+        assert_eq!(std::fs::metadata(segment(2))?.len(), 64);
+
+        // 跨分段读取
+        assert_eq!(eng.get(b"key0")?, Some(vec![100; 20]));
+        assert_eq!(eng.get(b"key3")?, Some(vec![3; 20]));
+        let all = eng.scan(..)?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(all.len(), 6);
+        let (changes, end) = eng.changes_since(64)?;
+        assert_eq!(changes.len(), 5);
+        assert_eq!(end, 7 * 32);
+
+        // 重新打开之后按序号找到所有的分段，不符合格式的文件被忽略
+        drop(eng);
+        std::fs::write(dir.join("seg-1.data"), b"not a segment")?;
+        let mut eng = MiniBitcask::open_segmented(dir.clone(), options.clone())?;
+        assert_eq!(eng.list_segments(), segments);
+        assert_eq!(eng.get(b"key0")?, Some(vec![100; 20]));
+        assert_eq!(eng.get(b"key5")?, Some(vec![5; 20]));
+
+        // 截断到第二个分段中，之后的分段被删除
+        eng.truncate_to(96, true)?;
+        assert_eq!(eng.list_segments(), vec![segment(1), segment(2)]);
+        assert!(!segment(3).exists());
+        assert_eq!(eng.get(b"key0")?, Some(vec![0; 20]));
+        assert_eq!(eng.get(b"key3")?, None);
+        eng.set(b"key3", vec![3; 20])?;
+        assert_eq!(eng.list_segments(), vec![segment(1), segment(2)]);
+        eng.set(b"key4", vec![4; 20])?;
+        assert_eq!(eng.list_segments().len(), 3);
+
+        // merge 之后只剩下一个新的分段
+        eng.merge()?;
+        assert_eq!(eng.list_segments(), vec![segment(4)]);
+        assert!(!segment(1).exists());
+        drop(eng);
+        let mut eng = MiniBitcask::open_segmented(dir.clone(), options)?;
+        let all = eng.scan(..)?.collect::<Result<Vec<_>>>()?;
+        let expected: Vec<_> = (0..5u8)
+            .map(|i| (format!("key{}", i).into_bytes(), vec![i; 20]))
+            .collect();
+        assert_eq!(all, expected);
+
+        drop(eng);
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
 
-        path.parent().map(|p| std::fs::remove_dir_all(p));
+    // 分段之间不一致：之后的分段中有一条更新的有效记录，索引仍然指向之前分段中的记录
+    #[test]
+    fn test_check_duplicates_segments() -> Result<()> {
+        let dir = std::env::temp_dir().join("minibitcask-check-duplicates-segments");
+        let options = SegmentOptions {
+            max_size: 16,
+            ..Default::default()
+        };
+        // 每条记录都超过分段的大小，每次写入都在一个新的分段中
+        let mut eng = MiniBitcask::open_segmented(dir.clone(), options)?;
+        eng.set(b"a", vec![1; 16])?;
+        eng.set(b"b", vec![1; 16])?;
+        eng.set(b"c", vec![1; 16])?;
+        eng.delete(b"c")?;
+        assert_eq!(eng.list_segments().len(), 4);
+        // 之后的分段中被删除的 key 只在一个分段中有效，不会被报告
+        assert!(eng.check_duplicates()?.is_empty());
+
+        // 模拟出错的 merge：a 在新的分段中写入了一条有效记录，但是没有更新索引
+        // 删除记录只有 9 个字节，新的记录和它在同一个分段中
+        eng.log.write_entry(b"a", Some(&[2; 16]))?;
+        assert_eq!(eng.list_segments().len(), 4);
+        assert_eq!(eng.get(b"a")?, Some(vec![1; 16]));
+        assert_eq!(eng.check_duplicates()?, vec![b"a".to_vec()]);
+
+        // 索引修复之后，a 仍然在两个分段中都有有效记录
+        eng.reindex()?;
+        assert_eq!(eng.get(b"a")?, Some(vec![2; 16]));
+        assert_eq!(eng.check_duplicates()?, vec![b"a".to_vec()]);
+
+        // merge 之后每个 key 只在一个分段中有效
+        eng.merge()?;
+        assert!(eng.check_duplicates()?.is_empty());
+        assert_eq!(eng.get(b"a")?, Some(vec![2; 16]));
+        assert_eq!(eng.get(b"b")?, Some(vec![1; 16]));
+
+        drop(eng);
+        std::fs::remove_dir_all(dir)?;
         Ok(())
     }
 }
@@ -1 +1,2 @@
 pub mod bitcask;
+pub mod manager;
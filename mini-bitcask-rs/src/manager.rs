@@ -0,0 +1,301 @@
+use crate::bitcask::{MiniBitcask, Result};
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::{
+        mpsc::{self, Sender},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+// 管理多个数据库，所有数据库的读写都通过一个共享的线程池执行，IO 的并发数由线程池的大小决定
+// 每个数据库保存在 dir/name/log 中
+pub struct DbManager {
+    dir: PathBuf,
+    dbs: Mutex<HashMap<String, Arc<Mutex<MiniBitcask>>>>,
+    // 正在打开的数据库，打开的过程中不持有 dbs 的锁，加锁顺序是先 dbs 后 opening
+    opening: Mutex<HashSet<String>>,
+    pool: ThreadPool,
+}
+
+impl DbManager {
+    pub fn new(dir: PathBuf, threads: usize) -> Self {
+        Self {
+            dir,
+            dbs: Mutex::new(HashMap::new()),
+            opening: Mutex::new(HashSet::new()),
+            pool: ThreadPool::new(threads),
+        }
+    }
+
+    // 打开一个数据库，已经打开或者正在打开的数据库会返回错误
+    // 先预留名字再释放锁打开，打开的过程中其他数据库的读写不会被阻塞
+    pub fn open(&self, name: &str) -> Result<()> {
+        {
+            let dbs = self.dbs.lock().unwrap();
+            let mut opening = self.opening.lock().unwrap();
+            if dbs.contains_key(name) || !opening.insert(name.to_string()) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AlreadyExists,
+                    format!("database {} is already open", name),
+                ));
+            }
+        }
+
+        let path = self.dir.join(name).join("log");
+        let db = self.pool.run(move || MiniBitcask::new(path));
+        let mut dbs = self.dbs.lock().unwrap();
+        self.opening.lock().unwrap().remove(name);
+        dbs.insert(name.to_string(), Arc::new(Mutex::new(db??)));
+        Ok(())
+    }
+
+    // 关闭一个数据库，关闭时会将数据刷到磁盘
+    pub fn close(&self, name: &str) -> Result<()> {
+        let db = self.dbs.lock().unwrap().remove(name);
+        match db {
+            Some(db) => self.pool.run(move || drop(db)),
+            None => Err(not_open(name)),
+        }
+    }
+
+    // 已经打开的数据库
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.dbs.lock().unwrap().keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    pub fn set(&self, name: &str, key: &[u8], value: Vec<u8>) -> Result<()> {
+        let key = key.to_vec();
+        self.execute(name, move |db| db.set(&key, value))
+    }
+
+    pub fn get(&self, name: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let key = key.to_vec();
+        self.execute(name, move |db| db.get(&key))
+    }
+
+    pub fn delete(&self, name: &str, key: &[u8]) -> Result<()> {
+        let key = key.to_vec();
+        self.execute(name, move |db| db.delete(&key))
+    }
+
+    // 在线程池中对数据库执行一个操作，等待操作完成并返回结果
+    pub fn execute<T, F>(&self, name: &str, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut MiniBitcask) -> Result<T> + Send + 'static,
+    {
+        let db = self.dbs.lock().unwrap().get(name).cloned();
+        let db = db.ok_or_else(|| not_open(name))?;
+        self.pool.run(move || f(&mut db.lock().unwrap()))?
+    }
+}
+
+fn not_open(name: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("database {} is not open", name),
+    )
+}
+
+// 固定大小的线程池，任务通过 channel 分发给空闲的线程
+struct ThreadPool {
+    sender: Option<Mutex<Sender<Job>>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl ThreadPool {
+    fn new(size: usize) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..size.max(1))
+            .map(|_| {
+                let receiver = receiver.clone();
+                std::thread::spawn(move || loop {
+                    // 取到任务之后立即释放锁，其他线程可以继续取任务
+                    let job = receiver.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => job(),
+                        // 线程池被 drop，channel 已经关闭
+                        Err(_) => break,
+                    }
+                })
+            })
+            .collect();
+        Self {
+            sender: Some(Mutex::new(sender)),
+            workers,
+        }
+    }
+
+    // 在线程池中执行任务，等待任务完成并返回结果
+    fn run<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel();
+        let job: Job = Box::new(move || {
+            let _ = tx.send(f());
+        });
+        let worker_stopped = || std::io::Error::other("thread pool worker stopped");
+        if let Some(sender) = self.sender.as_ref() {
+            sender
+                .lock()
+                .unwrap()
+                .send(job)
+                .map_err(|_| worker_stopped())?;
+        }
+        rx.recv().map_err(|_| worker_stopped())
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        // 关闭 channel，线程处理完剩余的任务之后退出
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            if worker.join().is_err() {
+                log::error!("thread pool worker panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DbManager;
+    use crate::bitcask::Result;
+
+    // 测试通过管理器打开多个数据库并读写
+    #[test]
+    fn test_manager() -> Result<()> {
+        let dir = std::env::temp_dir().join("minibitcask-manager-test");
+        let manager = DbManager::new(dir.clone(), 2);
+        manager.open("users")?;
+        manager.open("orders")?;
+        assert!(manager.open("users").is_err());
+        assert_eq!(manager.names(), vec!["orders", "users"]);
+
+        manager.set("users", b"alice", b"1".to_vec())?;
+        manager.set("orders", b"alice", b"order-1".to_vec())?;
+        manager.set("orders", b"bob", b"order-2".to_vec())?;
+        manager.delete("orders", b"bob")?;
+
+        assert_eq!(manager.get("users", b"alice")?, Some(b"1".to_vec()));
+        assert_eq!(manager.get("orders", b"alice")?, Some(b"order-1".to_vec()));
+        assert_eq!(manager.get("orders", b"bob")?, None);
+        assert_eq!(manager.get("users", b"bob")?, None);
+        assert_eq!(manager.execute("orders", |db| Ok(db.stats().keys))?, 1);
+
+        // 关闭之后不能再访问，重新打开之后数据仍然存在
+        manager.close("users")?;
+        assert!(manager.get("users", b"alice").is_err());
+        assert!(manager.close("users").is_err());
+        manager.open("users")?;
+        assert_eq!(manager.get("users", b"alice")?, Some(b"1".to_vec()));
+
+        // 多个线程同时访问
+        std::thread::scope(|s| {
+            let handles: Vec<_> = (0..4u8)
+                .map(|i| {
+                    let manager = &manager;
+                    s.spawn(move || manager.set("users", &[i], vec![i]))
+                })
+                .collect();
+            handles
+                .into_iter()
+                .try_for_each(|handle| handle.join().unwrap())
+        })?;
+        for i in 0..4u8 {
+            assert_eq!(manager.get("users", &[i])?, Some(vec![i]));
+        }
+
+        drop(manager);
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    // 测试扫描的过程中，其他线程写入并 merge，扫描读到的仍然是开始时的数据
+    #[test]
+    fn test_scan_during_merge() -> Result<()> {
+        let dir = std::env::temp_dir().join("minibitcask-manager-scan-test");
+        let manager = DbManager::new(dir.clone(), 2);
+        manager.open("db")?;
+        for i in 0..10u8 {
+            manager.set("db", &[i], vec![i; 16])?;
+        }
+
+        let mut iter = manager.execute("db", |db| db.scan(..))?;
+        let (key, value) = iter.next().unwrap()?;
+        assert_eq!((key, value), (vec![0], vec![0; 16]));
+
+        // 覆盖和删除之后 merge，所有数据的位置都发生了变化
+        std::thread::scope(|s| {
+            s.spawn(|| -> Result<()> {
+                for i in 0..10u8 {
+                    manager.set("db", &[i], vec![i + 100; 8])?;
+                }
+                manager.delete("db", &[5])?;
+                manager.execute("db", |db| db.merge())
+            })
+            .join()
+            .unwrap()
+        })?;
+
+        let rest = iter.collect::<Result<Vec<_>>>()?;
+        let expected: Vec<_> = (1..10u8).map(|i| (vec![i], vec![i; 16])).collect();
+        assert_eq!(rest, expected);
+
+        // 新的扫描读到的是最新的数据
+        let latest = manager.execute("db", |db| db.scan(..)?.collect::<Result<Vec<_>>>())?;
+        assert_eq!(latest.len(), 9);
+        assert_eq!(latest[0], (vec![0], vec![100; 8]));
+
+        drop(manager);
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    // 测试打开数据库的过程中不持有 dbs 的锁，其他数据库仍然可以访问，同名的数据库不能重复打开
+    #[test]
+    fn test_open_without_holding_lock() -> Result<()> {
+        let dir = std::env::temp_dir().join("minibitcask-manager-open-test");
+        let manager = DbManager::new(dir.clone(), 1);
+        manager.open("a")?;
+
+        let (release, blocked) = std::sync::mpsc::channel::<()>();
+        std::thread::scope(|s| -> Result<()> {
+            // 唯一的线程被占用，打开 b 的任务只能在队列中等待
+            let busy = s.spawn(|| {
+                manager.execute("a", move |_| {
+                    let _ = blocked.recv();
+                    Ok(())
+                })
+            });
+            let opening = s.spawn(|| manager.open("b"));
+            while !manager.opening.lock().unwrap().contains("b") {
+                std::thread::yield_now();
+            }
+
+            assert_eq!(manager.names(), vec!["a"]);
+            let err = manager.open("b").unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+
+            release.send(()).unwrap();
+            busy.join().unwrap()?;
+            opening.join().unwrap()
+        })?;
+        assert_eq!(manager.names(), vec!["a", "b"]);
+        assert!(manager.opening.lock().unwrap().is_empty());
+
+        drop(manager);
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
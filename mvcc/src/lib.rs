@@ -0,0 +1,2257 @@
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Display,
+    ops::Bound,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+    },
+};
+
+// 自定义 Result 类型
+pub type Result<T> = std::result::Result<T, MvccError>;
+
+// 自定义错误类型
+#[derive(Debug, PartialEq, Eq)]
+pub enum MvccError {
+    // 写入的 key 和其他事务冲突
+    Serialization,
+    // 条件写入时，key 最新提交的版本和期望的版本不一致
+    VersionMismatch { expected: u64, actual: u64 },
+    // 阻塞写入时和其他事务互相等待，当前事务已经被回滚
+    Deadlock,
+    // 事务已经结束，例如被 MVCC::abort 强制回滚
+    Aborted,
+    // 没有这个版本号的活跃事务
+    TxnNotFound(u64),
+    // 事务写入的 key 数量超过了限制，需要先提交或者拆分成多个事务
+    TransactionTooLarge { limit: usize },
+    // 只读事务不能写入，需要先调用 upgrade 升级为读写事务
+    ReadOnly,
+    // 事务不能合并自己的写入
+    ApplyToSelf,
+}
+
+impl std::error::Error for MvccError {}
+
+impl Display for MvccError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Serialization => write!(f, "serialization error, try again."),
+            Self::VersionMismatch { expected, actual } => write!(
+                f,
+                "version mismatch, expected {} but the latest is {}",
+                expected, actual
+            ),
+            Self::Deadlock => write!(f, "deadlock detected, transaction aborted."),
+            Self::Aborted => write!(f, "transaction is already aborted."),
+            Self::TxnNotFound(version) => write!(f, "no active transaction {}", version),
+            Self::ApplyToSelf => write!(f, "cannot apply a transaction to itself."),
+            Self::TransactionTooLarge { limit } => write!(
+                f,
+                "transaction writes more than {} keys, commit or split it.",
+                limit
+            ),
+            Self::ReadOnly => write!(f, "transaction is read-only, upgrade it first."),
+        }
+    }
+}
+
+// 事务的隔离级别
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IsolationLevel {
+    // 快照隔离，只检测写写冲突
+    #[default]
+    Snapshot,
+    // 可串行化，提交时还会检查读取过的 key 是否被其他已提交的事务修改
+    Serializable,
+    // 读已提交，get 和 get_many 每次读取时重新获取活跃事务列表，能读到开启之后其他事务提交的数据
+    // 写写冲突检测仍然使用开启时的快照
+    ReadCommitted,
+}
+
+// 写写冲突检测的粒度
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConflictGranularity {
+    // 只有写入同一个 key 时才冲突
+    #[default]
+    Exact,
+    // 前 n 个字节相同的 key 之间都会冲突，例如以表名作为前缀时，同一张表的写入互相冲突
+    // 比 n 个字节短的 key 整个作为前缀
+    Prefix(usize),
+}
+
+impl ConflictGranularity {
+    // key 所属的冲突范围，范围相同的 key 之间会冲突
+    fn scope<'a>(&self, key: &'a [u8]) -> &'a [u8] {
+        match self {
+            Self::Exact => key,
+            Self::Prefix(len) => &key[..key.len().min(*len)],
+        }
+    }
+}
+
+// 存储引擎定义，这里使用一个简单的内存 BTreeMap
+pub type KVEngine = BTreeMap<Vec<u8>, Option<Vec<u8>>>;
+
+// 写入的 key，以及写入之前这个 key 在当前事务版本下的值
+type UndoEntry = (Vec<u8>, Option<Option<Vec<u8>>>);
+
+// 提交之后调用的回调函数
+type CommitHook = dyn Fn(&CommitRecord) + Send + Sync;
+
+// 事务状态，同一个 MVCC 下的所有事务共享
+// 每个 MVCC 单独维护，gc 的水位线不会受到其他 MVCC 实例中活跃事务的影响
+#[derive(Default)]
+struct TxnState {
+    // 当前活跃的事务 id，及其已经写入的 key 信息
+    active_txn: Mutex<HashMap<u64, Vec<Vec<u8>>>>,
+    // 已经提交的事务 id，回滚的事务不在其中，加锁时需要在 active_txn 之后
+    committed: Mutex<CommittedVersions>,
+    // 当前活跃的事务 id，及其快照能看到的最小版本号（开启时的活跃事务和自己的版本号中最小的一个）
+    active_snapshot: Mutex<HashMap<u64, u64>>,
+    // 阻塞写入时的等待关系，等待的事务 id -> 被等待的事务 id，每个事务同时只会等待一个事务
+    wait_for: Mutex<HashMap<u64, u64>>,
+    // 事务结束（提交或者回滚）时通知等待的事务，配合 active_txn 的锁使用
+    txn_ended: Condvar,
+    // 通过 on_commit 注册的回调函数
+    commit_hooks: Mutex<Vec<Arc<CommitHook>>>,
+    // 上一次 gc 的水位线，水位线以下的旧版本可能已经被清理，过期读取的快照不能早于它
+    gc_watermark: AtomicU64,
+    // 最近分配的版本号，版本号从 1 开始递增，只在持有 active_txn 的锁时分配
+    last_version: AtomicU64,
+}
+
+impl TxnState {
+    // 获取下一个版本号，调用时需要持有 active_txn 的锁，
+    // 这样分配版本号和读取活跃事务列表是原子的，活跃事务列表中不会出现比自己大的版本号
+    fn acquire_next_version(&self) -> u64 {
+        self.last_version.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    // 当前的活跃事务列表和最近分配的版本号，读已提交的事务每次读取时使用
+    // 不在列表中并且不超过这个版本号的事务都已经结束，回滚的事务写入的数据已经被清除，剩下的都是提交的数据
+    fn current_active_xids(&self) -> (HashSet<u64>, u64) {
+        let active_txn = self.active_txn.lock().unwrap();
+        let active_xid = active_txn.keys().cloned().collect();
+        (active_xid, self.last_version.load(Ordering::SeqCst))
+    }
+
+    // 所有活跃事务的快照能看到的最小版本号，水位线以下的事务都已经结束，调用时需要持有 active_txn 的锁
+    fn watermark(&self) -> u64 {
+        let active_snapshot = self.active_snapshot.lock().unwrap();
+        match active_snapshot.values().min() {
+            Some(xmin) => *xmin,
+            None => self.last_version.load(Ordering::SeqCst) + 1,
+        }
+    }
+}
+
+// 已经提交的事务版本号
+// 大部分事务都会提交，compact 之后 floor 以下只记录没有提交的版本号（回滚的事务等），占用的内存更少
+#[derive(Default)]
+struct CommittedVersions {
+    // 这个版本号以下的事务都已经结束
+    floor: u64,
+    // floor 及以上已经提交的版本号
+    committed: HashSet<u64>,
+    // floor 以下没有提交的版本号
+    not_committed: HashSet<u64>,
+}
+
+impl CommittedVersions {
+    fn contains(&self, version: &u64) -> bool {
+        if *version < self.floor {
+            return !self.not_committed.contains(version);
+        }
+        self.committed.contains(version)
+    }
+
+    fn insert(&mut self, version: u64) {
+        self.committed.insert(version);
+    }
+
+    // 将 floor 提高到 watermark，返回减少的记录数，watermark 以下的事务必须都已经结束
+    fn compact(&mut self, watermark: u64) -> usize {
+        if watermark <= self.floor {
+            return 0;
+        }
+        let before = self.len();
+        for version in self.floor.max(1)..watermark {
+            if !self.committed.remove(&version) {
+                self.not_committed.insert(version);
+            }
+        }
+        self.floor = watermark;
+        before.saturating_sub(self.len())
+    }
+
+    // 记录的版本号数量
+    fn len(&self) -> usize {
+        self.committed.len() + self.not_committed.len()
+    }
+}
+
+// MVCC 事务定义
+pub struct MVCC {
+    // KV 存储引擎
+    kv: Arc<Mutex<KVEngine>>,
+    // 事务状态
+    state: Arc<TxnState>,
+    // 写入的 key 被其他活跃事务修改时，是否等待其结束
+    blocking_writes: bool,
+    // 每个事务最多写入的 key 数量，None 表示不限制
+    max_write_keys: Option<usize>,
+}
+
+impl MVCC {
+    pub fn new(kv: KVEngine) -> Self {
+        Self {
+            kv: Arc::new(Mutex::new(kv)),
+            state: Arc::new(TxnState::default()),
+            blocking_writes: false,
+            max_write_keys: None,
+        }
+    }
+
+    // 开启阻塞写入，写入的 key 被其他活跃事务修改时，等待这个事务结束而不是直接返回冲突
+    // 对方回滚后可以继续写入，对方提交后仍然返回 Serialization
+    // 互相等待的事务会形成死锁，检测到死锁时发起等待的事务会被回滚，并返回 Deadlock
+    pub fn with_blocking_writes(mut self) -> Self {
+        self.blocking_writes = true;
+        self
+    }
+
+    // 限制每个事务写入的 key 数量，同一个 key 重复写入只算一次
+    // 超过限制时 write 返回 TransactionTooLarge，之前的写入保持不变，由调用方决定提交还是回滚
+    pub fn with_max_write_keys(mut self, max_write_keys: usize) -> Self {
+        self.max_write_keys = Some(max_write_keys);
+        self
+    }
+
+    pub fn begin_transaction(&self) -> Transaction {
+        self.begin_with_isolation(IsolationLevel::default())
+    }
+
+    // 使用指定的隔离级别开启事务
+    pub fn begin_with_isolation(&self, isolation: IsolationLevel) -> Transaction {
+        Transaction::begin(
+            self.kv.clone(),
+            self.state.clone(),
+            isolation,
+            self.blocking_writes,
+            self.max_write_keys,
+            0,
+            false,
+        )
+    }
+
+    // 开启只读事务，不会注册到活跃事务列表中，其他事务开启时不需要把它当作活跃事务
+    // 需要写入时调用 Transaction::upgrade 升级为读写事务，升级后仍然使用开启时的快照
+    pub fn begin_read_only(&self) -> Transaction {
+        Transaction::begin(
+            self.kv.clone(),
+            self.state.clone(),
+            IsolationLevel::default(),
+            self.blocking_writes,
+            self.max_write_keys,
+            0,
+            true,
+        )
+    }
+
+    // 开启一个允许读到稍旧数据的事务，快照的版本号最多比当前版本号小 max_versions_behind
+    // 能看到快照版本号及之前所有已经提交的事务的写入，仍然是一致的快照，只是不包括最近提交的事务
+    // 快照的版本号不会早于上一次 gc 的水位线，否则可能读到被 gc 清理了部分版本的数据
+    pub fn begin_stale(&self, max_versions_behind: u64) -> Transaction {
+        Transaction::begin(
+            self.kv.clone(),
+            self.state.clone(),
+            IsolationLevel::default(),
+            self.blocking_writes,
+            self.max_write_keys,
+            max_versions_behind,
+            false,
+        )
+    }
+
+    // 开启一个独立的只读快照，开启时复制所有可见的数据，之后的读取不再访问存储引擎
+    // 快照不会注册为活跃事务，所以不会阻止 gc 清理旧版本，适合长时间运行的分析型查询，代价是需要复制数据
+    pub fn begin_detached(&self) -> DetachedSnapshot {
+        let txn = self.begin_transaction();
+        let data = txn.export_all();
+        // 只读的事务，没有需要提交的数据
+        txn.rollback();
+        DetachedSnapshot { data }
+    }
+
+    // 注册提交的回调函数，每个事务提交成功之后按照注册的顺序调用，可以注册多个
+    // 回调在提交的线程中同步执行，调用时事务已经从活跃事务列表中移除，对之后开启的事务可见
+    // 调用时不持有任何锁，回调中可以开启新的事务
+    pub fn on_commit(&self, hook: impl Fn(&CommitRecord) + Send + Sync + 'static) {
+        self.state.commit_hooks.lock().unwrap().push(Arc::new(hook));
+    }
+
+    // 强制回滚一个活跃的事务，例如结束卡住的事务，清除它写入的数据
+    // 只读事务没有写入，只移除它的快照；事务对象之后调用 commit 或者写入都会返回 Aborted
+    pub fn abort(&self, version: u64) -> Result<()> {
+        if rollback_txn(&self.kv, &self.state, version) {
+            Ok(())
+        } else {
+            Err(MvccError::TxnNotFound(version))
+        }
+    }
+
+    // 判断一个版本号的事务是否已经提交，活跃、回滚或者不存在的事务都返回 false
+    pub fn is_committed(&self, version: u64) -> bool {
+        self.state.committed.lock().unwrap().contains(&version)
+    }
+
+    // 不开启事务，读取 key 最新提交的值，跳过活跃事务的写入，key 不存在或者已经被删除时返回 None
+    // 每次调用读到的都是调用时最新提交的数据，多次调用之间没有一致性快照，需要一致性时应该使用事务
+    pub fn get_committed(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let kvengine = self.kv.lock().unwrap();
+        let active_txn = self.state.active_txn.lock().unwrap();
+        // 同一个 key 的所有版本在引擎中是相邻的，从最新的版本开始查找
+        let (start, end) = (
+            Key {
+                raw_key: key.to_vec(),
+                version: 0,
+            },
+            Key {
+                raw_key: key.to_vec(),
+                version: u64::MAX,
+            },
+        );
+        kvengine
+            .range(start.encode()..=end.encode())
+            .rev()
+            .find(|(k, _)| !active_txn.contains_key(&decode_key(k).version))
+            .and_then(|(_, v)| v.clone())
+    }
+
+    // 每个 key 在存储引擎中保存的版本数，包括删除和未提交的版本，用于找出产生版本最多、最需要 gc 的 key
+    pub fn version_stats(&self) -> HashMap<Vec<u8>, usize> {
+        let kvengine = self.kv.lock().unwrap();
+        let mut stats = HashMap::new();
+        for enc_key in kvengine.keys() {
+            *stats.entry(decode_key(enc_key).raw_key).or_default() += 1;
+        }
+        stats
+    }
+
+    // 清理不会再被读到的旧版本数据，返回清理的版本数
+    // 水位线是所有活跃事务的快照能看到的最小版本号，水位线以下的事务都已经结束，
+    // 每个 key 只需要保留水位线以下最新的一个版本，更旧的版本不会再被任何事务读到
+    pub fn gc(&self) -> usize {
+        let watermark = {
+            let _active_txn = self.state.active_txn.lock().unwrap();
+            let watermark = self.state.watermark();
+            self.state
+                .gc_watermark
+                .fetch_max(watermark, Ordering::SeqCst);
+            watermark
+        };
+
+        let mut kvengine = self.kv.lock().unwrap();
+        let mut versions: HashMap<Vec<u8>, Vec<u64>> = HashMap::new();
+        for enc_key in kvengine.keys() {
+            let key_version = decode_key(enc_key);
+            if key_version.version < watermark {
+                versions
+                    .entry(key_version.raw_key)
+                    .or_default()
+                    .push(key_version.version);
+            }
+        }
+
+        let mut removed = 0;
+        for (raw_key, mut versions) in versions {
+            versions.sort();
+            versions.pop();
+            for version in versions {
+                let enc_key = Key {
+                    raw_key: raw_key.clone(),
+                    version,
+                };
+                kvengine.remove(&enc_key.encode());
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    // 压缩事务的元数据，返回减少的记录数，和 gc 不同，不会清理引擎中的数据
+    // 水位线以下的事务都已经结束，不会再参与冲突检测，只需要记录其中没有提交的版本号，
+    // 大部分事务都会提交时，已提交版本号的记录不会随着提交的事务数量一直增长，is_committed 的结果不变
+    pub fn compact_metadata(&self) -> usize {
+        let _active_txn = self.state.active_txn.lock().unwrap();
+        let watermark = self.state.watermark();
+        self.state.committed.lock().unwrap().compact(watermark)
+    }
+}
+
+// 独立的只读快照，数据在开启时复制，不受之后的写入和 gc 影响
+pub struct DetachedSnapshot {
+    data: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl DetachedSnapshot {
+    // 读取数据
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.data.get(key).cloned()
+    }
+
+    // 快照中数据的个数
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+// 提交的事务信息，传给提交的回调函数
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommitRecord {
+    // 事务的版本号
+    pub version: u64,
+    // 写入（包括删除）的 key，已经排序并去重
+    pub keys: Vec<Vec<u8>>,
+}
+
+// 事务的保存点，通过 Transaction::savepoint 创建，记录创建时的写入和读取的数量
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Savepoint {
+    writes: usize,
+    reads: usize,
+}
+
+// 事务快照的信息，用于排查数据的可见性问题
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotInfo {
+    // 事务的版本号，比它大的版本不可见
+    pub version: u64,
+    // 事务开启时的其他活跃事务，它们的修改不可见
+    pub active_xids: HashSet<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Key {
+    raw_key: Vec<u8>,
+    version: u64,
+}
+
+// key 的编码方式，使用大端序编码，编码后的 key 按照 raw_key、version 的顺序排列
+// 默认的小端序编码在版本号超过 255 之后，同一个 key 的不同版本就不再按照版本号排序了
+fn key_codec() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_big_endian()
+        .with_fixint_encoding()
+}
+
+impl Key {
+    fn encode(&self) -> Vec<u8> {
+        key_codec().serialize(self).unwrap()
+    }
+}
+
+fn decode_key(b: &[u8]) -> Key {
+    key_codec().deserialize(b).unwrap()
+}
+
+// MVCC 事务
+pub struct Transaction {
+    // 底层 KV 存储引擎
+    kv: Arc<Mutex<KVEngine>>,
+    // 事务状态
+    state: Arc<TxnState>,
+    // 事务版本号
+    version: u64,
+    // 快照的版本号，这个版本号之前已经提交的数据可见，过期读取时比 version 小，否则和 version 相同
+    read_version: u64,
+    // 事务启动时的活跃事务列表
+    active_xid: HashSet<u64>,
+    // 隔离级别
+    isolation: IsolationLevel,
+    // 可串行化隔离级别下通过 get 读取过的 key，以及第一次读取的顺序，用于回滚到保存点时移除之后的读取
+    read_set: Mutex<HashMap<Vec<u8>, usize>>,
+    // 每次写入的记录，按照写入的顺序排列，用于回滚到保存点
+    undo: Mutex<Vec<UndoEntry>>,
+    // 事务自己写入的 key 最新的值（None 表示删除），读取时先查找这里，不需要遍历引擎
+    local: Mutex<HashMap<Vec<u8>, Option<Vec<u8>>>>,
+    // 是否阻塞写入
+    blocking_writes: bool,
+    // 最多写入的 key 数量
+    max_write_keys: Option<usize>,
+    // 是否是只读事务，只读事务会记录读取过的 key，升级时检查是否被其他事务修改
+    read_only: bool,
+    // 写写冲突检测的粒度
+    granularity: ConflictGranularity,
+}
+
+impl Transaction {
+    // 开启事务
+    fn begin(
+        kv: Arc<Mutex<KVEngine>>,
+        state: Arc<TxnState>,
+        isolation: IsolationLevel,
+        blocking_writes: bool,
+        max_write_keys: Option<usize>,
+        staleness: u64,
+        read_only: bool,
+    ) -> Self {
+        let mut active_txn = state.active_txn.lock().unwrap();
+        // 获取事务版本号
+        let version = state.acquire_next_version();
+        // 这个 map 的 key 就是当前所有活跃的事务
+        let active_xid: HashSet<u64> = active_txn.keys().cloned().collect();
+        // gc 水位线以下只保留了每个 key 最新的版本，所以快照最早只能是水位线的前一个版本
+        let gc_floor = state.gc_watermark.load(Ordering::SeqCst).saturating_sub(1);
+        let read_version = version.saturating_sub(staleness).max(gc_floor);
+
+        // 添加到当前活跃事务 id 列表中，只读事务没有写入，不需要添加
+        if !read_only {
+            active_txn.insert(version, vec![]);
+        }
+        let xmin = active_xid
+            .iter()
+            .min()
+            .map_or(read_version, |xid| read_version.min(*xid));
+        state.active_snapshot.lock().unwrap().insert(version, xmin);
+        drop(active_txn);
+
+        // 返回结果
+        Self {
+            kv,
+            state,
+            version,
+            read_version,
+            active_xid,
+            isolation,
+            read_set: Mutex::new(HashMap::new()),
+            undo: Mutex::new(Vec::new()),
+            local: Mutex::new(HashMap::new()),
+            blocking_writes,
+            max_write_keys,
+            read_only,
+            granularity: ConflictGranularity::default(),
+        }
+    }
+
+    // 将只读事务升级为读写事务，之后可以写入，仍然使用开启时的快照读取
+    // 读取过的 key 在开启之后被其他事务修改并提交时返回 Serialization，事务仍然是只读的
+    // 升级时分配一个新的版本号用于写入，之前开启的事务看不到它的写入，之后开启的事务会把它当作活跃事务
+    pub fn upgrade(&mut self) -> Result<()> {
+        if !self.read_only {
+            return Ok(());
+        }
+        let kvengine = self.kv.lock().unwrap();
+        let mut active_txn = self.state.active_txn.lock().unwrap();
+        let mut active_snapshot = self.state.active_snapshot.lock().unwrap();
+        let xmin = match active_snapshot.get(&self.version) {
+            Some(xmin) => *xmin,
+            None => return Err(MvccError::Aborted),
+        };
+        self.check_read_set(&kvengine, &self.state.committed.lock().unwrap())?;
+
+        let version = self.state.acquire_next_version();
+        active_txn.insert(version, vec![]);
+        active_snapshot.remove(&self.version);
+        active_snapshot.insert(version, xmin);
+        self.version = version;
+        self.read_only = false;
+        self.read_set.lock().unwrap().clear();
+        Ok(())
+    }
+
+    // 写入数据
+    pub fn set(&self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        self.write(key, Some(value))
+    }
+
+    // 删除数据
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
+        self.write(key, None)
+    }
+
+    // 条件写入，只有 key 最新提交的版本等于 expected_version 时才会写入，否则返回 VersionMismatch
+    // 版本号可以通过 get_with_version 获取，key 没有任何已提交的记录时版本号为 0
+    // 当前事务自己写入过的 key 和 get_with_version 一致，版本号是当前事务的版本号
+    pub fn set_if_version(&self, key: &[u8], value: Vec<u8>, expected_version: u64) -> Result<()> {
+        let mut kvengine = self.kv.lock().unwrap();
+        let actual = {
+            let active_txn = self.state.active_txn.lock().unwrap();
+            let (start, end) = (
+                Key {
+                    raw_key: key.to_vec(),
+                    version: 0,
+                },
+                Key {
+                    raw_key: key.to_vec(),
+                    version: u64::MAX,
+                },
+            );
+            kvengine
+                .range(start.encode()..=end.encode())
+                .rev()
+                .map(|(k, _)| decode_key(k).version)
+                .find(|v| *v == self.version || !active_txn.contains_key(v))
+                .unwrap_or(0)
+        };
+        if actual != expected_version {
+            return Err(MvccError::VersionMismatch {
+                expected: expected_version,
+                actual,
+            });
+        }
+        self.write_locked(&mut kvengine, key, Some(value))
+    }
+
+    fn write(&self, key: &[u8], value: Option<Vec<u8>>) -> Result<()> {
+        if self.read_only {
+            return Err(MvccError::ReadOnly);
+        }
+        loop {
+            let mut kvengine = self.kv.lock().unwrap();
+            if self.blocking_writes {
+                // 冲突的写入来自活跃事务时，释放引擎的锁等待它结束，然后重新检查
+                if let Some(holder) = self.find_conflict(&kvengine, key) {
+                    if self.state.active_txn.lock().unwrap().contains_key(&holder) {
+                        drop(kvengine);
+                        if let Err(err) = self.wait_for(holder) {
+                            self.rollback();
+                            return Err(err);
+                        }
+                        continue;
+                    }
+                }
+            }
+            return self.write_locked(&mut kvengine, key, value);
+        }
+    }
+
+    // 等待另一个事务结束，等待之前先在等待关系中检测死锁
+    // 从被等待的事务开始沿着等待关系查找，如果回到了当前事务，说明形成了环
+    fn wait_for(&self, holder: u64) -> Result<()> {
+        let mut active_txn = self.state.active_txn.lock().unwrap();
+        {
+            let mut wait_for = self.state.wait_for.lock().unwrap();
+            let mut next = Some(holder);
+            while let Some(xid) = next {
+                if xid == self.version {
+                    return Err(MvccError::Deadlock);
+                }
+                next = wait_for.get(&xid).copied();
+            }
+            wait_for.insert(self.version, holder);
+        }
+
+        while active_txn.contains_key(&holder) {
+            active_txn = self.state.txn_ended.wait(active_txn).unwrap();
+        }
+        self.state.wait_for.lock().unwrap().remove(&self.version);
+        Ok(())
+    }
+
+    fn write_locked(
+        &self,
+        kvengine: &mut KVEngine,
+        key: &[u8],
+        value: Option<Vec<u8>>,
+    ) -> Result<()> {
+        if self.read_only {
+            return Err(MvccError::ReadOnly);
+        }
+        self.check_conflict(kvengine, key)?;
+
+        // 写入 TxnWrite
+        let mut active_txn = self.state.active_txn.lock().unwrap();
+        match active_txn.get_mut(&self.version) {
+            // 每个 key 只记录一次，写入新的 key 时检查写入的 key 数量是否超过限制
+            Some(keys) => {
+                if !keys.iter().any(|k| k.eq(key)) {
+                    if let Some(limit) = self.max_write_keys {
+                        if keys.len() >= limit {
+                            return Err(MvccError::TransactionTooLarge { limit });
+                        }
+                    }
+                    keys.push(key.to_vec())
+                }
+            }
+            None => return Err(MvccError::Aborted),
+        }
+
+        // 写入数据
+        let enc_key = Key {
+            raw_key: key.to_vec(),
+            version: self.version,
+        };
+        self.local
+            .lock()
+            .unwrap()
+            .insert(key.to_vec(), value.clone());
+        let prev = kvengine.insert(enc_key.encode(), value);
+        self.undo.lock().unwrap().push((key.to_vec(), prev));
+        Ok(())
+    }
+
+    // 判断当前写入的 key 是否和其他的事务冲突
+    fn check_conflict(&self, kvengine: &KVEngine, key: &[u8]) -> Result<()> {
+        match self.find_conflict(kvengine, key) {
+            Some(_) => Err(MvccError::Serialization),
+            None => Ok(()),
+        }
+    }
+
+    // 设置写写冲突检测的粒度，只影响当前事务的写入，默认只检查同一个 key
+    // 需要按照前缀检测冲突时，写入这些 key 的事务都应该使用相同的粒度
+    pub fn with_conflict_granularity(mut self, granularity: ConflictGranularity) -> Self {
+        self.granularity = granularity;
+        self
+    }
+
+    // 查找和当前写入冲突的版本号，也就是 key 最新的一个对当前事务不可见的版本
+    // key 是按照 key-version 排序的，所以只需要判断最近的一个 key 即可
+    // 按照前缀检测时，前缀相同的 key 在引擎中不一定相邻，需要检查所有的 key
+    fn find_conflict(&self, kvengine: &KVEngine, key: &[u8]) -> Option<u64> {
+        if self.granularity != ConflictGranularity::Exact {
+            let scope = self.granularity.scope(key);
+            return kvengine
+                .keys()
+                .map(|k| decode_key(k))
+                .find(|k| {
+                    self.granularity.scope(&k.raw_key) == scope && !self.is_visible(k.version)
+                })
+                .map(|k| k.version);
+        }
+        for (enc_key, _) in kvengine.iter().rev() {
+            let key_version = decode_key(enc_key);
+            if key_version.raw_key.eq(key) {
+                if !self.is_visible(key_version.version) {
+                    return Some(key_version.version);
+                }
+                break;
+            }
+        }
+        None
+    }
+
+    // 将另一个未提交事务的写入合并到当前事务中，就像是当前事务自己写入的一样
+    // 合并的数据同样需要经过当前事务的冲突检测，有冲突时两个事务的数据都保持不变
+    // 合并成功后另一个事务的写入记录被清空，它仍然需要调用 commit 或者 rollback 结束
+    pub fn apply_from(&self, other: &Transaction) -> Result<()> {
+        if self.read_only {
+            return Err(MvccError::ReadOnly);
+        }
+        if other.version == self.version {
+            return Err(MvccError::ApplyToSelf);
+        }
+        let mut kvengine = self.kv.lock().unwrap();
+        let mut active_txn = self.state.active_txn.lock().unwrap();
+        let own = match active_txn.get(&self.version) {
+            Some(own) => own,
+            None => return Err(MvccError::Aborted),
+        };
+        let mut keys = active_txn.get(&other.version).cloned().unwrap_or_default();
+        keys.sort();
+        keys.dedup();
+
+        // 合并的 key 同样计入当前事务写入的 key 数量
+        if let Some(limit) = self.max_write_keys {
+            let added = keys.iter().filter(|k| !own.contains(k)).count();
+            if own.len() + added > limit {
+                return Err(MvccError::TransactionTooLarge { limit });
+            }
+        }
+
+        // 先取出另一个事务写入的数据，再按照当前事务进行冲突检测
+        let mut writes = Vec::new();
+        for key in keys {
+            let enc_key = Key {
+                raw_key: key.clone(),
+                version: other.version,
+            };
+            if let Some(value) = kvengine.remove(&enc_key.encode()) {
+                writes.push((key, value));
+            }
+        }
+
+        let conflict = writes
+            .iter()
+            .try_for_each(|(key, _)| self.check_conflict(&kvengine, key));
+        if let Err(err) = conflict {
+            // 有冲突，恢复另一个事务的数据
+            for (key, value) in writes {
+                let enc_key = Key {
+                    raw_key: key,
+                    version: other.version,
+                };
+                kvengine.insert(enc_key.encode(), value);
+            }
+            return Err(err);
+        }
+
+        // 以当前事务的版本号写入数据
+        for (key, value) in writes {
+            let keys = active_txn.entry(self.version).or_default();
+            if !keys.contains(&key) {
+                keys.push(key.clone());
+            }
+            let enc_key = Key {
+                raw_key: key.clone(),
+                version: self.version,
+            };
+            self.local
+                .lock()
+                .unwrap()
+                .insert(key.clone(), value.clone());
+            let prev = kvengine.insert(enc_key.encode(), value);
+            self.undo.lock().unwrap().push((key, prev));
+        }
+        if let Some(keys) = active_txn.get_mut(&other.version) {
+            keys.clear();
+        }
+        other.local.lock().unwrap().clear();
+        other.undo.lock().unwrap().clear();
+        Ok(())
+    }
+
+    // 创建保存点，之后可以通过 rollback_to 撤销保存点之后的写入和读取，事务仍然是活跃的
+    pub fn savepoint(&self) -> Savepoint {
+        Savepoint {
+            writes: self.undo.lock().unwrap().len(),
+            reads: self.read_set.lock().unwrap().len(),
+        }
+    }
+
+    // 回滚到保存点，按照相反的顺序撤销保存点之后的写入，恢复每个 key 在保存点时的值
+    // 保存点之后第一次读取的 key 也会从读取记录中移除，提交时不会再因为这些 key 被修改而失败
+    pub fn rollback_to(&self, savepoint: &Savepoint) -> Result<()> {
+        let mut kvengine = self.kv.lock().unwrap();
+        let mut active_txn = self.state.active_txn.lock().unwrap();
+        let keys = match active_txn.get_mut(&self.version) {
+            Some(keys) => keys,
+            None => return Err(MvccError::Aborted),
+        };
+        let mut undo = self.undo.lock().unwrap();
+        let mut local = self.local.lock().unwrap();
+        while undo.len() > savepoint.writes {
+            let (key, prev) = undo.pop().unwrap();
+            let enc_key = Key {
+                raw_key: key.clone(),
+                version: self.version,
+            };
+            // 保存点之前没有写入过的 key 从写入记录中移除
+            if prev.is_none() {
+                keys.retain(|k| *k != key);
+            }
+            match prev {
+                Some(value) => {
+                    local.insert(key, value.clone());
+                    kvengine.insert(enc_key.encode(), value)
+                }
+                None => {
+                    local.remove(&key);
+                    kvengine.remove(&enc_key.encode())
+                }
+            };
+        }
+        self.read_set
+            .lock()
+            .unwrap()
+            .retain(|_, order| *order < savepoint.reads);
+        Ok(())
+    }
+
+    // 记录读取过的 key 及其第一次读取的顺序
+    fn record_reads<'k>(&self, keys: impl Iterator<Item = &'k [u8]>) {
+        let mut read_set = self.read_set.lock().unwrap();
+        for key in keys {
+            let order = read_set.len();
+            read_set.entry(key.to_vec()).or_insert(order);
+        }
+    }
+
+    // 读取数据，从最后一条数据进行遍历，找到第一条可见的数据
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.get_with_version(key).map(|(value, _)| value)
+    }
+
+    // 读取数据，同时返回读到的数据的版本号，可以用于 set_if_version
+    pub fn get_with_version(&self, key: &[u8]) -> Option<(Vec<u8>, u64)> {
+        if self.isolation == IsolationLevel::Serializable || self.read_only {
+            self.record_reads(std::iter::once(key));
+        }
+        // 自己写入过的 key 直接返回写入的值
+        if let Some(value) = self.local.lock().unwrap().get(key) {
+            return value.clone().map(|v| (v, self.version));
+        }
+        // 先获取活跃事务列表再读取数据，之后开启的事务的版本号一定比快照的版本号大
+        let (active_xid, read_version) = self.read_snapshot();
+        let kvengine = self.kv.lock().unwrap();
+        for (k, v) in kvengine.iter().rev() {
+            let key_version = decode_key(k);
+            if key_version.raw_key.eq(key)
+                && self.is_visible_in(key_version.version, &active_xid, read_version)
+            {
+                return v.clone().map(|v| (v, key_version.version));
+            }
+        }
+        None
+    }
+
+    // 批量读取数据，只加锁一次，所有的 key 在同一个快照中读取，返回的结果和 keys 的顺序一致
+    pub fn get_many(&self, keys: &[Vec<u8>]) -> Vec<Option<Vec<u8>>> {
+        if self.isolation == IsolationLevel::Serializable || self.read_only {
+            self.record_reads(keys.iter().map(|k| k.as_slice()));
+        }
+        let wanted: HashSet<&[u8]> = keys.iter().map(|k| k.as_slice()).collect();
+        let mut found: HashMap<Vec<u8>, Option<Vec<u8>>> = HashMap::new();
+        let (active_xid, read_version) = self.read_snapshot();
+        let kvengine = self.kv.lock().unwrap();
+        // 同一个 key 的版本按照从旧到新的顺序排列，最后一个可见的版本就是读到的值
+        for (k, v) in kvengine.iter() {
+            let key_version = decode_key(k);
+            if wanted.contains(key_version.raw_key.as_slice())
+                && self.is_visible_in(key_version.version, &active_xid, read_version)
+            {
+                found.insert(key_version.raw_key, v.clone());
+            }
+        }
+        keys.iter()
+            .map(|key| found.get(key).cloned().flatten())
+            .collect()
+    }
+
+    // 打印出所有可见的数据
+    pub fn print_all(&self) {
+        for (k, v) in self.export_all().iter() {
+            print!(
+                "{}={} ",
+                String::from_utf8_lossy(k),
+                String::from_utf8_lossy(v)
+            );
+        }
+        println!();
+    }
+
+    // 导出所有可见的数据，不包括已经删除的数据，用于备份
+    // 在同一次引擎加锁期间读取，得到的是事务开启时的一致性快照，不受其他事务后续写入的影响
+    pub fn export_all(&self) -> BTreeMap<Vec<u8>, Vec<u8>> {
+        let mut records = BTreeMap::new();
+        let kvengine = self.kv.lock().unwrap();
+        for (k, v) in kvengine.iter() {
+            let key_version = decode_key(k);
+            if self.is_visible(key_version.version) {
+                records.insert(key_version.raw_key, v.clone());
+            }
+        }
+        records
+            .into_iter()
+            .filter_map(|(k, v)| v.map(|v| (k, v)))
+            .collect()
+    }
+
+    // 遍历所有可见的数据，和 export_all 不同，每次调用 next 时才读取下一个 key，不会复制所有的数据
+    // 只在 next 期间持有引擎的锁，两次 next 之间其他事务可以继续读写
+    // 按照存储引擎中的顺序返回，编码后的 key 先按长度再按内容排序，和 export_all 的顺序不一定相同
+    pub fn scan(&self) -> ScanIterator<'_> {
+        ScanIterator {
+            txn: self,
+            cursor: Bound::Unbounded,
+            done: false,
+        }
+    }
+
+    // 检查提交是否会成功，但不会真正提交，也不会释放任何资源
+    // 可以在执行代价较高的提交之前调用，提前发现冲突并重试
+    pub fn validate(&self) -> Result<()> {
+        let kvengine = self.kv.lock().unwrap();
+        let _active_txn = self.state.active_txn.lock().unwrap();
+        self.check_read_set(&kvengine, &self.state.committed.lock().unwrap())
+    }
+
+    // 提交时的检查，只对可串行化的事务生效，只读事务升级时也会检查
+    // 读取过的 key 如果存在当前事务不可见、并且已经提交的版本，说明读到的数据已经过期，提交会失败
+    // 只记录 get 读取的 key，export_all 等扫描操作不会被检查
+    fn check_read_set(&self, kvengine: &KVEngine, committed: &CommittedVersions) -> Result<()> {
+        if self.isolation != IsolationLevel::Serializable && !self.read_only {
+            return Ok(());
+        }
+        let read_set = self.read_set.lock().unwrap();
+        for enc_key in kvengine.keys() {
+            let key_version = decode_key(enc_key);
+            if read_set.contains_key(&key_version.raw_key)
+                && !self.is_visible(key_version.version)
+                && committed.contains(&key_version.version)
+            {
+                return Err(MvccError::Serialization);
+            }
+        }
+        Ok(())
+    }
+
+    // 提交事务，可串行化的事务检查失败时返回错误，事务仍然是活跃的，需要调用 rollback 结束
+    // 事务已经结束（例如被强制回滚）时返回 Aborted
+    pub fn commit(&self) -> Result<()> {
+        if self.read_only {
+            // 只读事务没有写入，只需要移除快照
+            let active = self
+                .state
+                .active_snapshot
+                .lock()
+                .unwrap()
+                .remove(&self.version)
+                .is_some();
+            if !active {
+                return Err(MvccError::Aborted);
+            }
+            self.state.committed.lock().unwrap().insert(self.version);
+            return Ok(());
+        }
+        let kvengine = self.kv.lock().unwrap();
+        let mut active_txn = self.state.active_txn.lock().unwrap();
+        if !active_txn.contains_key(&self.version) {
+            return Err(MvccError::Aborted);
+        }
+        let mut committed = self.state.committed.lock().unwrap();
+        self.check_read_set(&kvengine, &committed)?;
+        drop(kvengine);
+
+        // 清除活跃事务列表中的数据，和记录提交在同一次加锁中完成，不会出现既不活跃也没有提交的中间状态
+        let mut keys = active_txn.remove(&self.version).unwrap_or_default();
+        committed.insert(self.version);
+        drop(committed);
+        self.state
+            .active_snapshot
+            .lock()
+            .unwrap()
+            .remove(&self.version);
+        self.state.txn_ended.notify_all();
+        drop(active_txn);
+
+        // 调用提交的回调函数
+        let hooks = self.state.commit_hooks.lock().unwrap().clone();
+        if !hooks.is_empty() {
+            keys.sort();
+            keys.dedup();
+            let record = CommitRecord {
+                version: self.version,
+                keys,
+            };
+            for hook in hooks {
+                hook(&record);
+            }
+        }
+        Ok(())
+    }
+
+    // 整理事务的写入记录，按照 key 排序
+    // 写入时每个 key 已经只记录一次，同一个事务多次写入同一个 key 时引擎中只保留最后一次写入的值（包括删除）
+    pub fn squash(&self) {
+        let mut active_txn = self.state.active_txn.lock().unwrap();
+        if let Some(keys) = active_txn.get_mut(&self.version) {
+            keys.sort();
+            keys.dedup();
+        }
+    }
+
+    // 合并写入记录后提交事务
+    pub fn commit_squashed(&self) -> Result<()> {
+        self.squash();
+        self.commit()
+    }
+
+    // 回滚事务
+    pub fn rollback(&self) {
+        rollback_txn(&self.kv, &self.state, self.version);
+    }
+
+    // 获取事务快照的信息，可以用来解释为什么读到了某个版本的数据
+    pub fn snapshot_info(&self) -> SnapshotInfo {
+        SnapshotInfo {
+            version: self.version,
+            active_xids: self.active_xid.clone(),
+        }
+    }
+
+    // 判断一个版本的数据对当前事务是否可见
+    // 1. 当前事务自己的修改可见
+    // 2. 如果是另一个活跃事务的修改，则不可见
+    // 3. 如果版本号比快照的版本号大，则不可见
+    fn is_visible(&self, version: u64) -> bool {
+        self.is_visible_in(version, &self.active_xid, self.read_version)
+    }
+
+    // 使用指定的活跃事务列表和快照版本号判断是否可见
+    fn is_visible_in(&self, version: u64, active_xid: &HashSet<u64>, read_version: u64) -> bool {
+        if version == self.version {
+            return true;
+        }
+        if active_xid.contains(&version) {
+            return false;
+        }
+        version <= read_version
+    }
+
+    // 读取时使用的活跃事务列表和快照版本号，读已提交的事务每次重新获取，其他隔离级别使用开启时的快照
+    fn read_snapshot(&self) -> (Cow<'_, HashSet<u64>>, u64) {
+        match self.isolation {
+            IsolationLevel::ReadCommitted => {
+                let (active_xid, read_version) = self.state.current_active_xids();
+                (Cow::Owned(active_xid), read_version)
+            }
+            _ => (Cow::Borrowed(&self.active_xid), self.read_version),
+        }
+    }
+}
+
+// 事务中可见数据的迭代器，通过 Transaction::scan 创建
+// 同一个 key 的所有版本在引擎中是相邻的，每次读取一组版本，返回其中最新的可见值
+pub struct ScanIterator<'a> {
+    txn: &'a Transaction,
+    // 下一次从引擎中的哪个位置开始读取
+    cursor: Bound<Vec<u8>>,
+    done: bool,
+}
+
+impl Iterator for ScanIterator<'_> {
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let kvengine = self.txn.kv.lock().unwrap();
+        // 当前这一组的 raw key，及其最新的可见值，不可见或者已经删除时为 None
+        let mut group: Option<(Vec<u8>, Option<Vec<u8>>)> = None;
+        for (k, v) in kvengine.range((self.cursor.clone(), Bound::Unbounded)) {
+            let key_version = decode_key(k);
+            let visible = self.txn.is_visible(key_version.version);
+            match group.as_mut() {
+                Some((raw_key, value)) if raw_key == &key_version.raw_key => {
+                    if visible {
+                        *value = v.clone();
+                    }
+                }
+                _ => {
+                    // 遇到了下一个 key，上一个 key 有可见的值时返回，下次从这里继续读取
+                    if let Some((raw_key, Some(value))) = group.take() {
+                        self.cursor = Bound::Included(k.clone());
+                        return Some((raw_key, value));
+                    }
+                    let value = if visible { v.clone() } else { None };
+                    group = Some((key_version.raw_key, value));
+                }
+            }
+        }
+        self.done = true;
+        group.and_then(|(raw_key, value)| value.map(|value| (raw_key, value)))
+    }
+}
+
+// 回滚一个事务，清除它写入的数据以及活跃事务列表中的记录，返回事务是否是活跃的
+// 事务是活跃的（包括只读事务）时返回 true
+fn rollback_txn(kv: &Mutex<KVEngine>, state: &TxnState, version: u64) -> bool {
+    // 清除写入的数据，和写入时一样先锁引擎再锁活跃事务列表，避免并发时互相等待
+    let mut kvengine = kv.lock().unwrap();
+    let mut active_txn = state.active_txn.lock().unwrap();
+    if let Some(keys) = active_txn.get(&version) {
+        // 已经不在引擎中的 key 直接跳过
+        for k in keys {
+            let enc_key = Key {
+                raw_key: k.to_vec(),
+                version,
+            };
+            kvengine.remove(&enc_key.encode());
+        }
+    }
+
+    // 清除活跃事务列表中的数据
+    let active = active_txn.remove(&version).is_some();
+    let snapshot = state
+        .active_snapshot
+        .lock()
+        .unwrap()
+        .remove(&version)
+        .is_some();
+    state.txn_ended.notify_all();
+    active || snapshot
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        CommitRecord, ConflictGranularity, IsolationLevel, KVEngine, MvccError, Result, MVCC,
+    };
+    use std::{
+        collections::{BTreeMap, HashSet},
+        sync::{atomic::Ordering, Arc, Mutex},
+    };
+
+    // 同一个事务中先写入、删除、再写入，提交后可见的是最后一次写入的值
+    #[test]
+    fn test_commit_squashed() -> Result<()> {
+        let mvcc = MVCC::new(KVEngine::new());
+
+        let tx1 = mvcc.begin_transaction();
+        tx1.set(b"a", b"a1".to_vec())?;
+        tx1.delete(b"a")?;
+        tx1.set(b"a", b"a3".to_vec())?;
+        tx1.commit_squashed()?;
+
+        let tx2 = mvcc.begin_transaction();
+        assert_eq!(tx2.get(b"a"), Some(b"a3".to_vec()));
+        tx2.commit()?;
+
+        // 只保留了一个版本的数据
+        assert_eq!(mvcc.kv.lock().unwrap().len(), 1);
+        Ok(())
+    }
+
+    // 合并之后回滚，重复写入的 key 只会被清除一次
+    #[test]
+    fn test_squash_rollback() -> Result<()> {
+        let mvcc = MVCC::new(KVEngine::new());
+
+        let tx1 = mvcc.begin_transaction();
+        tx1.set(b"a", b"a1".to_vec())?;
+        tx1.delete(b"a")?;
+        tx1.set(b"a", b"a3".to_vec())?;
+        tx1.squash();
+        tx1.rollback();
+
+        let tx2 = mvcc.begin_transaction();
+        assert_eq!(tx2.get(b"a"), None);
+        tx2.commit()?;
+        assert!(mvcc.kv.lock().unwrap().is_empty());
+        Ok(())
+    }
+
+    // 不调用 squash，重复写入的 key 在写入记录中也只有一条
+    #[test]
+    fn test_write_dedup() -> Result<()> {
+        let mvcc = MVCC::new(KVEngine::new());
+
+        let tx1 = mvcc.begin_transaction();
+        tx1.set(b"b", b"b1".to_vec())?;
+        tx1.set(b"a", b"a1".to_vec())?;
+        tx1.delete(b"a")?;
+        tx1.set(b"a", b"a3".to_vec())?;
+        let sp = tx1.savepoint();
+        tx1.set(b"b", b"b2".to_vec())?;
+        assert_eq!(
+            mvcc.state.active_txn.lock().unwrap()[&tx1.version],
+            vec![b"b".to_vec(), b"a".to_vec()]
+        );
+
+        // 回滚到保存点之后，保存点之前写入过的 key 仍然保留
+        tx1.rollback_to(&sp)?;
+        assert_eq!(mvcc.state.active_txn.lock().unwrap()[&tx1.version].len(), 2);
+        tx1.rollback();
+        assert!(mvcc.kv.lock().unwrap().is_empty());
+        Ok(())
+    }
+
+    // 将辅助事务的写入合并到父事务中，一起提交
+    #[test]
+    fn test_apply_from() -> Result<()> {
+        let mvcc = MVCC::new(KVEngine::new());
+        let tx0 = mvcc.begin_transaction();
+        tx0.set(b"a", b"a1".to_vec())?;
+        tx0.commit()?;
+
+        let parent = mvcc.begin_transaction();
+        parent.set(b"b", b"b1".to_vec())?;
+
+        let helper = mvcc.begin_transaction();
+        helper.set(b"a", b"a2".to_vec())?;
+        helper.set(b"c", b"c1".to_vec())?;
+
+        parent.apply_from(&helper)?;
+        helper.commit()?;
+
+        // 父事务能看到合并进来的数据，提交之前其他事务看不到
+        assert_eq!(parent.get(b"a"), Some(b"a2".to_vec()));
+        let tx1 = mvcc.begin_transaction();
+        assert_eq!(tx1.get(b"c"), None);
+        tx1.commit()?;
+
+        parent.commit()?;
+        let tx2 = mvcc.begin_transaction();
+        assert_eq!(tx2.get(b"a"), Some(b"a2".to_vec()));
+        assert_eq!(tx2.get(b"b"), Some(b"b1".to_vec()));
+        assert_eq!(tx2.get(b"c"), Some(b"c1".to_vec()));
+        tx2.commit()?;
+        Ok(())
+    }
+
+    // 合并时和其他事务冲突
+    #[test]
+    fn test_apply_from_conflict() -> Result<()> {
+        let mvcc = MVCC::new(KVEngine::new());
+
+        let parent = mvcc.begin_transaction();
+        let other = mvcc.begin_transaction();
+        other.set(b"a", b"a1".to_vec())?;
+
+        let helper = mvcc.begin_transaction();
+        helper.set(b"b", b"b1".to_vec())?;
+        helper.set(b"a", b"a2".to_vec()).unwrap_err();
+        other.commit()?;
+
+        // other 在 parent 开启之后提交，parent 不能写入 a
+        let helper2 = mvcc.begin_transaction();
+        helper2.set(b"a", b"a3".to_vec())?;
+        assert_eq!(parent.apply_from(&helper2), Err(MvccError::Serialization));
+
+        // 冲突时 helper2 的数据保持不变
+        assert_eq!(helper2.get(b"a"), Some(b"a3".to_vec()));
+        assert_eq!(parent.get(b"a"), None);
+        helper.rollback();
+        helper2.rollback();
+        parent.rollback();
+        Ok(())
+    }
+
+    // 不能合并自己，合并的 key 计入数量限制，合并后另一个事务回滚到保存点不会影响合并的数据
+    #[test]
+    fn test_apply_from_checks() -> Result<()> {
+        let mvcc = MVCC::new(KVEngine::new()).with_max_write_keys(2);
+
+        let parent = mvcc.begin_transaction();
+        parent.set(b"a", b"a1".to_vec())?;
+        assert_eq!(parent.apply_from(&parent), Err(MvccError::ApplyToSelf));
+        assert_eq!(parent.get(b"a"), Some(b"a1".to_vec()));
+
+        // 合并之后写入的 key 超过限制，两个事务的数据都保持不变
+        let helper = mvcc.begin_transaction();
+        helper.set(b"b", b"b1".to_vec())?;
+        helper.set(b"c", b"c1".to_vec())?;
+        assert_eq!(
+            parent.apply_from(&helper),
+            Err(MvccError::TransactionTooLarge { limit: 2 })
+        );
+        assert_eq!(helper.get(b"b"), Some(b"b1".to_vec()));
+        helper.rollback();
+
+        let helper2 = mvcc.begin_transaction();
+        helper2.set(b"b", b"b1".to_vec())?;
+        let sp = helper2.savepoint();
+        helper2.set(b"b", b"b2".to_vec())?;
+        parent.apply_from(&helper2)?;
+        helper2.rollback_to(&sp)?;
+        helper2.rollback();
+        assert_eq!(parent.get(b"b"), Some(b"b2".to_vec()));
+
+        parent.rollback();
+        assert!(mvcc.kv.lock().unwrap().is_empty());
+        Ok(())
+    }
+
+    // 独立快照不会阻止 gc，并且在 gc 之后仍然能读到一致的数据
+    #[test]
+    fn test_detached_snapshot_gc() -> Result<()> {
+        let mvcc = MVCC::new(KVEngine::new());
+        let tx0 = mvcc.begin_transaction();
+        tx0.set(b"a", b"a1".to_vec())?;
+        tx0.set(b"b", b"b1".to_vec())?;
+        tx0.commit()?;
+
+        let detached = mvcc.begin_detached();
+        let reader = mvcc.begin_transaction();
+
+        for value in [b"a2", b"a3"] {
+            let tx = mvcc.begin_transaction();
+            tx.set(b"a", value.to_vec())?;
+            tx.delete(b"b")?;
+            tx.commit()?;
+        }
+
+        // 普通的读事务会阻止 gc 清理它需要的版本
+        mvcc.gc();
+        assert_eq!(reader.get(b"a"), Some(b"a1".to_vec()));
+        assert_eq!(reader.get(b"b"), Some(b"b1".to_vec()));
+        reader.commit()?;
+
+        // 读事务结束之后，旧版本被清理，独立快照仍然能读到开启时的数据
+        assert!(mvcc.gc() > 0);
+        assert_eq!(detached.get(b"a"), Some(b"a1".to_vec()));
+        assert_eq!(detached.get(b"b"), Some(b"b1".to_vec()));
+        assert_eq!(detached.len(), 2);
+
+        let tx = mvcc.begin_transaction();
+        assert_eq!(tx.get(b"a"), Some(b"a3".to_vec()));
+        assert_eq!(tx.get(b"b"), None);
+        tx.commit()?;
+        Ok(())
+    }
+
+    // 导出的数据是事务开启时的一致性快照
+    #[test]
+    fn test_export_all() -> Result<()> {
+        let mvcc = MVCC::new(KVEngine::new());
+        let tx0 = mvcc.begin_transaction();
+        tx0.set(b"a", b"a1".to_vec())?;
+        tx0.set(b"b", b"b1".to_vec())?;
+        tx0.set(b"c", b"c1".to_vec())?;
+        tx0.delete(b"c")?;
+        tx0.commit()?;
+
+        // 未提交事务的写入不可见
+        let pending = mvcc.begin_transaction();
+        pending.set(b"d", b"d1".to_vec())?;
+
+        let snapshot = mvcc.begin_transaction();
+        let expected = BTreeMap::from([
+            (b"a".to_vec(), b"a1".to_vec()),
+            (b"b".to_vec(), b"b1".to_vec()),
+        ]);
+        assert_eq!(snapshot.export_all(), expected);
+
+        pending.commit()?;
+        let tx = mvcc.begin_transaction();
+        tx.set(b"a", b"a2".to_vec())?;
+        tx.delete(b"b")?;
+        tx.set(b"e", b"e1".to_vec())?;
+        tx.commit()?;
+
+        assert_eq!(snapshot.export_all(), expected);
+        snapshot.commit()?;
+
+        let tx = mvcc.begin_transaction();
+        assert_eq!(
+            tx.export_all(),
+            BTreeMap::from([
+                (b"a".to_vec(), b"a2".to_vec()),
+                (b"d".to_vec(), b"d1".to_vec()),
+                (b"e".to_vec(), b"e1".to_vec()),
+            ])
+        );
+        tx.commit()?;
+        Ok(())
+    }
+
+    // validate 的结果和之后 commit 的结果一致
+    #[test]
+    fn test_validate() -> Result<()> {
+        let mvcc = MVCC::new(KVEngine::new());
+        let tx0 = mvcc.begin_transaction();
+        tx0.set(b"a", b"a1".to_vec())?;
+        tx0.set(b"b", b"b1".to_vec())?;
+        tx0.commit()?;
+
+        // 读写不同的 key，没有冲突
+        let tx1 = mvcc.begin_with_isolation(IsolationLevel::Serializable);
+        let tx2 = mvcc.begin_with_isolation(IsolationLevel::Serializable);
+        assert_eq!(tx1.get(b"a"), Some(b"a1".to_vec()));
+        tx1.set(b"c", b"c1".to_vec())?;
+        tx2.set(b"d", b"d1".to_vec())?;
+        tx2.commit()?;
+        assert_eq!(tx1.validate(), Ok(()));
+        assert_eq!(tx1.validate(), Ok(()));
+        assert_eq!(tx1.commit(), Ok(()));
+
+        // 写偏斜：tx3 读 a 写 b，tx4 读 b 写 a
+        let tx3 = mvcc.begin_with_isolation(IsolationLevel::Serializable);
+        let tx4 = mvcc.begin_with_isolation(IsolationLevel::Serializable);
+        tx3.get(b"a");
+        tx3.set(b"b", b"b3".to_vec())?;
+        tx4.get(b"b");
+        tx4.set(b"a", b"a4".to_vec())?;
+
+        // tx4 还没有提交，tx3 读到的数据仍然有效
+        assert_eq!(tx3.validate(), Ok(()));
+        assert_eq!(tx3.commit(), Ok(()));
+
+        // tx3 已经提交，tx4 读到的 b 已经过期，validate 不会结束事务
+        assert_eq!(tx4.validate(), Err(MvccError::Serialization));
+        assert_eq!(tx4.validate(), Err(MvccError::Serialization));
+        assert_eq!(tx4.commit(), Err(MvccError::Serialization));
+        tx4.rollback();
+
+        // 快照隔离不检查读取的 key
+        let tx5 = mvcc.begin_transaction();
+        tx5.get(b"a");
+        let tx6 = mvcc.begin_transaction();
+        tx6.set(b"a", b"a6".to_vec())?;
+        tx6.commit()?;
+        assert_eq!(tx5.validate(), Ok(()));
+        assert_eq!(tx5.commit(), Ok(()));
+
+        let tx = mvcc.begin_transaction();
+        assert_eq!(tx.get(b"a"), Some(b"a6".to_vec()));
+        assert_eq!(tx.get(b"b"), Some(b"b3".to_vec()));
+        tx.commit()?;
+        Ok(())
+    }
+
+    // 条件写入，过期的版本号会被拒绝
+    #[test]
+    fn test_set_if_version() -> Result<()> {
+        let mvcc = MVCC::new(KVEngine::new());
+
+        // 没有任何记录的 key 版本号为 0
+        let tx0 = mvcc.begin_transaction();
+        tx0.set_if_version(b"a", b"a1".to_vec(), 0)?;
+        tx0.commit()?;
+
+        let tx1 = mvcc.begin_transaction();
+        let (value, version) = tx1.get_with_version(b"a").unwrap();
+        assert_eq!(value, b"a1".to_vec());
+        tx1.commit()?;
+
+        // 其他事务修改了 a
+        let tx2 = mvcc.begin_transaction();
+        tx2.set(b"a", b"a2".to_vec())?;
+        tx2.commit()?;
+
+        let tx3 = mvcc.begin_transaction();
+        assert!(matches!(
+            tx3.set_if_version(b"a", b"a3".to_vec(), version),
+            Err(MvccError::VersionMismatch { expected, .. }) if expected == version
+        ));
+        assert_eq!(tx3.get(b"a"), Some(b"a2".to_vec()));
+
+        // 使用最新的版本号写入
+        let (_, latest) = tx3.get_with_version(b"a").unwrap();
+        assert!(latest > version);
+        tx3.set_if_version(b"a", b"a3".to_vec(), latest)?;
+        tx3.commit()?;
+
+        let tx = mvcc.begin_transaction();
+        assert_eq!(tx.get(b"a"), Some(b"a3".to_vec()));
+        assert_eq!(tx.get_with_version(b"b"), None);
+        tx.commit()?;
+
+        // 自己写入过的 key，get_with_version 返回的版本号可以直接用于 set_if_version
+        let tx4 = mvcc.begin_transaction();
+        tx4.set(b"a", b"a4".to_vec())?;
+        let (_, own) = tx4.get_with_version(b"a").unwrap();
+        tx4.set_if_version(b"a", b"a5".to_vec(), own)?;
+        // 其他活跃事务的写入不影响版本号，前缀相同的其他 key 也不影响
+        let tx5 = mvcc.begin_transaction();
+        tx5.set(b"ab", b"ab1".to_vec())?;
+        assert!(matches!(
+            tx5.set_if_version(b"a", b"a6".to_vec(), own),
+            Err(MvccError::VersionMismatch { actual, .. }) if actual > latest && actual < own
+        ));
+        tx4.commit()?;
+        tx5.rollback();
+
+        let tx = mvcc.begin_transaction();
+        assert_eq!(tx.get(b"a"), Some(b"a5".to_vec()));
+        tx.commit()?;
+        Ok(())
+    }
+
+    // 批量读取的结果和逐个读取一致
+    #[test]
+    fn test_get_many() -> Result<()> {
+        let mvcc = MVCC::new(KVEngine::new());
+        let tx0 = mvcc.begin_transaction();
+        tx0.set(b"a", b"a1".to_vec())?;
+        tx0.set(b"b", b"b1".to_vec())?;
+        tx0.set(b"c", b"c1".to_vec())?;
+        tx0.commit()?;
+
+        let tx1 = mvcc.begin_transaction();
+        tx1.set(b"a", b"a2".to_vec())?;
+        tx1.delete(b"b")?;
+        tx1.commit()?;
+
+        // 未提交的写入不可见
+        let pending = mvcc.begin_transaction();
+        pending.set(b"c", b"c3".to_vec())?;
+
+        let tx = mvcc.begin_transaction();
+        tx.set(b"d", b"d1".to_vec())?;
+        let keys = vec![
+            b"c".to_vec(),
+            b"a".to_vec(),
+            b"b".to_vec(),
+            b"x".to_vec(),
+            b"d".to_vec(),
+            b"a".to_vec(),
+        ];
+        let values = tx.get_many(&keys);
+        let expected: Vec<_> = keys.iter().map(|key| tx.get(key)).collect();
+        assert_eq!(values, expected);
+        assert_eq!(
+            values,
+            vec![
+                Some(b"c1".to_vec()),
+                Some(b"a2".to_vec()),
+                None,
+                None,
+                Some(b"d1".to_vec()),
+                Some(b"a2".to_vec()),
+            ]
+        );
+        assert!(tx.get_many(&[]).is_empty());
+        tx.commit()?;
+        pending.rollback();
+        Ok(())
+    }
+
+    // 阻塞写入时两个事务互相等待，其中一个被回滚，另一个继续执行
+    #[test]
+    fn test_deadlock() -> Result<()> {
+        let mvcc = MVCC::new(KVEngine::new()).with_blocking_writes();
+        let tx1 = mvcc.begin_transaction();
+        let tx2 = mvcc.begin_transaction();
+        tx1.set(b"a", b"a1".to_vec())?;
+        tx2.set(b"b", b"b2".to_vec())?;
+
+        std::thread::scope(|s| {
+            // tx1 等待 tx2 释放 b
+            let waiter = s.spawn(|| tx1.set(b"b", b"b1".to_vec()));
+            while !mvcc
+                .state
+                .wait_for
+                .lock()
+                .unwrap()
+                .contains_key(&tx1.version)
+            {
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+
+            // tx2 再等待 tx1 释放 a，形成死锁，tx2 被回滚
+            assert_eq!(tx2.set(b"a", b"a2".to_vec()), Err(MvccError::Deadlock));
+            waiter.join().unwrap()
+        })?;
+        tx1.commit()?;
+        assert!(mvcc.state.wait_for.lock().unwrap().is_empty());
+
+        let tx3 = mvcc.begin_transaction();
+        assert_eq!(tx3.get(b"a"), Some(b"a1".to_vec()));
+        assert_eq!(tx3.get(b"b"), Some(b"b1".to_vec()));
+        tx3.commit()?;
+
+        // 等待的事务提交之后，仍然是写写冲突
+        let tx4 = mvcc.begin_transaction();
+        let tx5 = mvcc.begin_transaction();
+        tx4.set(b"a", b"a4".to_vec())?;
+        std::thread::scope(|s| {
+            let waiter = s.spawn(|| tx5.set(b"a", b"a5".to_vec()));
+            while mvcc.state.wait_for.lock().unwrap().is_empty() {
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+            tx4.commit()?;
+            assert_eq!(waiter.join().unwrap(), Err(MvccError::Serialization));
+            Ok(())
+        })
+    }
+
+    // 快照信息中包含事务的版本号和开启时的活跃事务
+    #[test]
+    fn test_snapshot_info() -> Result<()> {
+        let mvcc = MVCC::new(KVEngine::new());
+        let tx1 = mvcc.begin_transaction();
+        let tx2 = mvcc.begin_transaction();
+        let tx3 = mvcc.begin_transaction();
+        tx2.commit()?;
+
+        let tx4 = mvcc.begin_transaction();
+        let info = tx4.snapshot_info();
+        assert_eq!(info.version, tx4.version);
+        assert!(info.version > tx3.version);
+        assert_eq!(info.active_xids, HashSet::from([tx1.version, tx3.version]));
+
+        // 快照信息在事务开启时就确定了，不受其他事务结束的影响
+        tx1.commit()?;
+        assert_eq!(tx4.snapshot_info(), info);
+        assert!(tx1.snapshot_info().active_xids.is_empty());
+
+        tx3.commit()?;
+        tx4.commit()?;
+        Ok(())
+    }
+
+    // 从外部强制回滚事务，事务之后的提交和写入都会失败
+    #[test]
+    fn test_abort() -> Result<()> {
+        let mvcc = MVCC::new(KVEngine::new());
+        let tx0 = mvcc.begin_transaction();
+        tx0.set(b"a", b"a0".to_vec())?;
+        tx0.commit()?;
+
+        let tx1 = mvcc.begin_transaction();
+        tx1.set(b"a", b"a1".to_vec())?;
+        tx1.set(b"b", b"b1".to_vec())?;
+        mvcc.abort(tx1.version)?;
+
+        assert_eq!(tx1.commit(), Err(MvccError::Aborted));
+        assert_eq!(tx1.set(b"c", b"c1".to_vec()), Err(MvccError::Aborted));
+        assert_eq!(
+            mvcc.abort(tx1.version),
+            Err(MvccError::TxnNotFound(tx1.version))
+        );
+        tx1.rollback();
+
+        // 写入的数据已经被清除，其他事务可以写入同样的 key
+        let tx2 = mvcc.begin_transaction();
+        assert_eq!(tx2.get(b"a"), Some(b"a0".to_vec()));
+        assert_eq!(tx2.get(b"b"), None);
+        tx2.set(b"a", b"a2".to_vec())?;
+        tx2.commit()?;
+        assert_eq!(mvcc.kv.lock().unwrap().len(), 2);
+        assert!(mvcc.state.active_txn.lock().unwrap().is_empty());
+
+        // 同一个 key 写入两次之后回滚或者强制回滚
+        let tx3 = mvcc.begin_transaction();
+        tx3.set(b"a", b"a3".to_vec())?;
+        tx3.set(b"a", b"a4".to_vec())?;
+        tx3.delete(b"a")?;
+        tx3.rollback();
+        let tx4 = mvcc.begin_transaction();
+        tx4.set(b"b", b"b4".to_vec())?;
+        tx4.set(b"b", b"b5".to_vec())?;
+        mvcc.abort(tx4.version)?;
+        assert_eq!(mvcc.kv.lock().unwrap().len(), 2);
+
+        // 只读事务被强制回滚时移除快照，之后提交返回 Aborted
+        let tx5 = mvcc.begin_read_only();
+        mvcc.abort(tx5.version)?;
+        assert!(mvcc.state.active_snapshot.lock().unwrap().is_empty());
+        assert_eq!(tx5.commit(), Err(MvccError::Aborted));
+        assert_eq!(
+            mvcc.abort(tx5.version),
+            Err(MvccError::TxnNotFound(tx5.version))
+        );
+        Ok(())
+    }
+
+    // 提交之后调用注册的回调函数
+    #[test]
+    fn test_on_commit() -> Result<()> {
+        let mvcc = MVCC::new(KVEngine::new());
+        let records = Arc::new(Mutex::new(Vec::new()));
+        let commits = Arc::new(Mutex::new(0));
+        {
+            let records = records.clone();
+            mvcc.on_commit(move |record| records.lock().unwrap().push(record.clone()));
+            let commits = commits.clone();
+            mvcc.on_commit(move |_| *commits.lock().unwrap() += 1);
+        }
+
+        let tx1 = mvcc.begin_transaction();
+        tx1.set(b"b", b"b1".to_vec())?;
+        tx1.set(b"a", b"a1".to_vec())?;
+        tx1.set(b"b", b"b2".to_vec())?;
+        tx1.delete(b"c")?;
+        tx1.commit()?;
+
+        // 回滚和只读的事务
+        let tx2 = mvcc.begin_transaction();
+        tx2.set(b"d", b"d1".to_vec())?;
+        tx2.rollback();
+        let tx3 = mvcc.begin_transaction();
+        tx3.get(b"a");
+        tx3.commit()?;
+        // 提交失败不会调用回调
+        assert!(tx2.commit().is_err());
+
+        assert_eq!(
+            *records.lock().unwrap(),
+            vec![
+                CommitRecord {
+                    version: tx1.version,
+                    keys: vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()],
+                },
+                CommitRecord {
+                    version: tx3.version,
+                    keys: vec![],
+                },
+            ]
+        );
+        assert_eq!(*commits.lock().unwrap(), 2);
+        Ok(())
+    }
+
+    // 写入的 key 数量超过限制时返回错误，之前的写入保持不变，直到回滚
+    #[test]
+    fn test_max_write_keys() -> Result<()> {
+        let mvcc = MVCC::new(KVEngine::new()).with_max_write_keys(2);
+
+        let tx1 = mvcc.begin_transaction();
+        tx1.set(b"a", b"a1".to_vec())?;
+        tx1.delete(b"b")?;
+        assert_eq!(
+            tx1.set(b"c", b"c1".to_vec()),
+            Err(MvccError::TransactionTooLarge { limit: 2 })
+        );
+        assert_eq!(tx1.get(b"a"), Some(b"a1".to_vec()));
+        assert_eq!(mvcc.kv.lock().unwrap().len(), 2);
+        tx1.rollback();
+
+        let tx2 = mvcc.begin_transaction();
+        assert_eq!(tx2.get(b"a"), None);
+        assert!(mvcc.kv.lock().unwrap().is_empty());
+
+        // 重复写入已有的 key 不受限制，每个事务单独计算
+        tx2.set(b"a", b"a1".to_vec())?;
+        tx2.set(b"a", b"a2".to_vec())?;
+        tx2.set(b"b", b"b1".to_vec())?;
+        tx2.set(b"a", b"a3".to_vec())?;
+        tx2.commit_squashed()?;
+        let tx3 = mvcc.begin_transaction();
+        tx3.set(b"c", b"c1".to_vec())?;
+        assert_eq!(tx3.get(b"a"), Some(b"a3".to_vec()));
+        tx3.commit()?;
+        Ok(())
+    }
+
+    // 逐个读取可见的数据，结果和 export_all 相同，两次读取之间不会持有引擎的锁
+    #[test]
+    fn test_scan() -> Result<()> {
+        let mvcc = MVCC::new(KVEngine::new());
+        let tx0 = mvcc.begin_transaction();
+        for key in [b"a".as_slice(), b"b", b"c", b"dd", b"eee"] {
+            tx0.set(key, key.to_vec())?;
+        }
+        tx0.commit()?;
+
+        let tx1 = mvcc.begin_transaction();
+        tx1.set(b"a", b"a1".to_vec())?;
+        tx1.delete(b"c")?;
+        tx1.set(b"f", b"f1".to_vec())?;
+        tx1.commit()?;
+
+        // tx2 开启时 tx3 仍然活跃，tx3 的写入对 tx2 不可见
+        let tx3 = mvcc.begin_transaction();
+        tx3.set(b"b", b"b3".to_vec())?;
+        tx3.delete(b"dd")?;
+        let tx2 = mvcc.begin_transaction();
+        tx2.set(b"g", b"g2".to_vec())?;
+        tx2.delete(b"eee")?;
+
+        let expected = tx2.export_all();
+        assert_eq!(tx2.scan().collect::<BTreeMap<_, _>>(), expected);
+        assert_eq!(expected.len(), 5);
+
+        // 读取一部分之后，其他事务仍然可以写入，写入对当前的遍历不可见
+        let mut iter = tx2.scan();
+        let first = iter.next().unwrap();
+        tx3.commit()?;
+        let tx4 = mvcc.begin_transaction();
+        tx4.set(b"h", b"h4".to_vec())?;
+        tx4.delete(b"f")?;
+        tx4.commit()?;
+        let mut scanned: BTreeMap<_, _> = iter.collect();
+        scanned.insert(first.0, first.1);
+        assert_eq!(scanned, expected);
+
+        // tx2 没有提交，新的事务看不到它的写入
+        assert_eq!(mvcc.begin_transaction().scan().count(), 4);
+        let empty = MVCC::new(KVEngine::new());
+        assert_eq!(empty.begin_transaction().scan().next(), None);
+        Ok(())
+    }
+
+    // 不开启事务读取最新提交的数据
+    #[test]
+    fn test_get_committed() -> Result<()> {
+        let mvcc = MVCC::new(KVEngine::new());
+        assert_eq!(mvcc.get_committed(b"a"), None);
+
+        let tx1 = mvcc.begin_transaction();
+        tx1.set(b"a", b"a1".to_vec())?;
+        tx1.set(b"b", b"b1".to_vec())?;
+        // 没有提交之前读不到
+        assert_eq!(mvcc.get_committed(b"a"), None);
+        tx1.commit()?;
+        assert_eq!(mvcc.get_committed(b"a"), Some(b"a1".to_vec()));
+
+        // 活跃事务的写入和删除都被跳过
+        let tx2 = mvcc.begin_transaction();
+        tx2.set(b"a", b"a2".to_vec())?;
+        tx2.delete(b"b")?;
+        tx2.set(b"c", b"c2".to_vec())?;
+        assert_eq!(mvcc.get_committed(b"a"), Some(b"a1".to_vec()));
+        assert_eq!(mvcc.get_committed(b"b"), Some(b"b1".to_vec()));
+        assert_eq!(mvcc.get_committed(b"c"), None);
+
+        // 比 tx2 后开启但先提交的事务
+        let tx3 = mvcc.begin_transaction();
+        tx3.set(b"d", b"d3".to_vec())?;
+        tx3.commit()?;
+        assert_eq!(mvcc.get_committed(b"d"), Some(b"d3".to_vec()));
+
+        tx2.commit()?;
+        assert_eq!(mvcc.get_committed(b"a"), Some(b"a2".to_vec()));
+        assert_eq!(mvcc.get_committed(b"b"), None);
+        assert_eq!(mvcc.get_committed(b"c"), Some(b"c2".to_vec()));
+
+        // 回滚的写入不可见
+        let tx4 = mvcc.begin_transaction();
+        tx4.set(b"a", b"a4".to_vec())?;
+        tx4.rollback();
+        assert_eq!(mvcc.get_committed(b"a"), Some(b"a2".to_vec()));
+        Ok(())
+    }
+
+    // 过期读取，读到的是之前的版本
+    #[test]
+    fn test_begin_stale() -> Result<()> {
+        let mvcc = MVCC::new(KVEngine::new());
+        let mut versions = vec![];
+        for value in [b"a1", b"a2", b"a3"] {
+            let tx = mvcc.begin_transaction();
+            tx.set(b"a", value.to_vec())?;
+            tx.set(value, value.to_vec())?;
+            tx.commit()?;
+            versions.push(tx.version);
+        }
+
+        // 快照的版本号落在第二个和第三个事务之间
+        let behind = mvcc.state.last_version.load(Ordering::SeqCst) + 1 - versions[1];
+        let stale = mvcc.begin_stale(behind);
+        assert_eq!(stale.get(b"a"), Some(b"a2".to_vec()));
+        assert_eq!(stale.get(b"a1"), Some(b"a1".to_vec()));
+        assert_eq!(stale.get(b"a3"), None);
+
+        // 自己的写入可见，最近提交的写入会被认为是冲突
+        stale.set(b"b", b"b1".to_vec())?;
+        assert_eq!(stale.get(b"b"), Some(b"b1".to_vec()));
+        assert_eq!(
+            stale.set(b"a", b"a4".to_vec()),
+            Err(MvccError::Serialization)
+        );
+        stale.commit()?;
+
+        assert_eq!(mvcc.begin_stale(0).get(b"a"), Some(b"a3".to_vec()));
+
+        // gc 之后旧版本被清理，快照不会早于 gc 的水位线
+        mvcc.gc();
+        let stale = mvcc.begin_stale(behind);
+        assert_eq!(stale.get(b"a"), Some(b"a3".to_vec()));
+        assert_eq!(stale.get(b"b"), Some(b"b1".to_vec()));
+        Ok(())
+    }
+
+    // 只读事务升级后可以写入并提交，升级之前开启的事务看不到它的写入
+    #[test]
+    fn test_upgrade_read_only() -> Result<()> {
+        let mvcc = MVCC::new(KVEngine::new());
+        let tx0 = mvcc.begin_transaction();
+        tx0.set(b"a", b"a1".to_vec())?;
+        tx0.commit()?;
+
+        let mut tx1 = mvcc.begin_read_only();
+        assert_eq!(tx1.get(b"a"), Some(b"a1".to_vec()));
+        assert_eq!(tx1.set(b"a", b"a2".to_vec()), Err(MvccError::ReadOnly));
+        let tx2 = mvcc.begin_transaction();
+
+        tx1.upgrade()?;
+        tx1.set(b"a", b"a2".to_vec())?;
+        assert_eq!(tx1.get(b"a"), Some(b"a2".to_vec()));
+        let tx3 = mvcc.begin_transaction();
+        tx1.commit()?;
+
+        // tx2 在升级之前开启，tx3 开启时 tx1 还是活跃事务，都看不到它的写入
+        assert_eq!(tx2.get(b"a"), Some(b"a1".to_vec()));
+        assert_eq!(tx3.get(b"a"), Some(b"a1".to_vec()));
+        tx2.commit()?;
+        tx3.commit()?;
+        assert_eq!(mvcc.get_committed(b"a"), Some(b"a2".to_vec()));
+        Ok(())
+    }
+
+    // 读取过的 key 在开启之后被其他事务修改并提交，不能升级
+    #[test]
+    fn test_upgrade_conflict() -> Result<()> {
+        let mvcc = MVCC::new(KVEngine::new());
+        let mut tx1 = mvcc.begin_read_only();
+        assert_eq!(tx1.get(b"a"), None);
+
+        let tx2 = mvcc.begin_transaction();
+        tx2.set(b"a", b"a2".to_vec())?;
+        tx2.commit()?;
+
+        assert_eq!(tx1.upgrade(), Err(MvccError::Serialization));
+        assert_eq!(tx1.set(b"b", b"b1".to_vec()), Err(MvccError::ReadOnly));
+        tx1.commit()?;
+        assert_eq!(tx1.commit(), Err(MvccError::Aborted));
+        Ok(())
+    }
+
+    // 事务提交之后 is_committed 才返回 true，回滚的事务始终是 false
+    #[test]
+    fn test_is_committed() -> Result<()> {
+        let mvcc = MVCC::new(KVEngine::new());
+        let tx1 = mvcc.begin_transaction();
+        let v1 = tx1.snapshot_info().version;
+        tx1.set(b"a", b"a1".to_vec())?;
+        assert!(!mvcc.is_committed(v1));
+        tx1.commit()?;
+        assert!(mvcc.is_committed(v1));
+
+        let tx2 = mvcc.begin_transaction();
+        let v2 = tx2.snapshot_info().version;
+        tx2.set(b"a", b"a2".to_vec())?;
+        tx2.rollback();
+        assert!(!mvcc.is_committed(v2));
+        assert!(!mvcc.is_committed(u64::MAX));
+        Ok(())
+    }
+
+    // 回滚到保存点，撤销之后的写入，之后的读取也不再参与提交时的检查
+    #[test]
+    fn test_savepoint_read_set() -> Result<()> {
+        let mvcc = MVCC::new(KVEngine::new());
+        let tx1 = mvcc.begin_with_isolation(IsolationLevel::Serializable);
+        tx1.set(b"a", b"a1".to_vec())?;
+        let sp = tx1.savepoint();
+        assert_eq!(tx1.get(b"b"), None);
+        tx1.set(b"a", b"a2".to_vec())?;
+        tx1.set(b"c", b"c1".to_vec())?;
+        tx1.rollback_to(&sp)?;
+        assert_eq!(tx1.get(b"a"), Some(b"a1".to_vec()));
+        assert_eq!(tx1.get(b"c"), None);
+
+        // 保存点之后读取的 b 被其他事务修改，不影响提交
+        let tx2 = mvcc.begin_transaction();
+        tx2.set(b"b", b"b2".to_vec())?;
+        tx2.commit()?;
+        tx1.commit()?;
+        assert_eq!(mvcc.get_committed(b"a"), Some(b"a1".to_vec()));
+        assert_eq!(mvcc.get_committed(b"c"), None);
+
+        // 保存点之前读取的 key 仍然会被检查
+        let tx3 = mvcc.begin_with_isolation(IsolationLevel::Serializable);
+        tx3.get(b"a");
+        let sp = tx3.savepoint();
+        tx3.get(b"a");
+        tx3.rollback_to(&sp)?;
+        let tx4 = mvcc.begin_transaction();
+        tx4.set(b"a", b"a4".to_vec())?;
+        tx4.commit()?;
+        assert_eq!(tx3.commit(), Err(MvccError::Serialization));
+        tx3.rollback();
+        Ok(())
+    }
+
+    #[test]
+    fn test_version_stats() -> Result<()> {
+        let mvcc = MVCC::new(KVEngine::new());
+        for i in 0..10 {
+            let tx = mvcc.begin_transaction();
+            tx.set(b"hot", format!("v{}", i).into_bytes())?;
+            if i == 0 {
+                tx.set(b"cold", b"v0".to_vec())?;
+            }
+            tx.commit()?;
+        }
+        let tx = mvcc.begin_transaction();
+        tx.delete(b"cold")?;
+        tx.commit()?;
+
+        let stats = mvcc.version_stats();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[b"hot".as_slice()], 10);
+        assert_eq!(stats[b"cold".as_slice()], 2);
+
+        // gc 之后每个 key 只剩最新的版本
+        mvcc.gc();
+        let stats = mvcc.version_stats();
+        assert_eq!(stats[b"hot".as_slice()], 1);
+        assert_eq!(stats[b"cold".as_slice()], 1);
+        Ok(())
+    }
+
+    // 按照前缀检测冲突时，写入同一个前缀下不同的 key 也会冲突
+    #[test]
+    fn test_conflict_granularity() -> Result<()> {
+        let mvcc = MVCC::new(KVEngine::new());
+        let prefix = ConflictGranularity::Prefix(3);
+        let tx1 = mvcc.begin_transaction().with_conflict_granularity(prefix);
+        let tx2 = mvcc.begin_transaction().with_conflict_granularity(prefix);
+        tx1.set(b"t1:a", b"a".to_vec())?;
+        assert_eq!(
+            tx2.set(b"t1:b", b"b".to_vec()),
+            Err(MvccError::Serialization)
+        );
+        tx2.set(b"t2:a", b"a".to_vec())?;
+        // 自己写入的 key 不冲突
+        tx1.set(b"t1:c", b"c".to_vec())?;
+        tx1.commit()?;
+        tx2.commit()?;
+
+        // 默认只检查同一个 key
+        let tx3 = mvcc.begin_transaction();
+        let tx4 = mvcc.begin_transaction();
+        tx3.set(b"t1:a", b"a3".to_vec())?;
+        tx4.set(b"t1:b", b"b4".to_vec())?;
+        tx3.commit()?;
+        tx4.commit()?;
+        assert_eq!(mvcc.get_committed(b"t1:b"), Some(b"b4".to_vec()));
+        Ok(())
+    }
+
+    // 读取自己写入过的 key 时直接返回写入的值，删除之后读取不到
+    #[test]
+    fn test_read_own_writes() -> Result<()> {
+        let mvcc = MVCC::new(KVEngine::new());
+        let tx0 = mvcc.begin_transaction();
+        tx0.set(b"a", b"a0".to_vec())?;
+        tx0.set(b"b", b"b0".to_vec())?;
+        tx0.commit()?;
+
+        let tx1 = mvcc.begin_transaction();
+        tx1.set(b"a", b"a1".to_vec())?;
+        tx1.delete(b"b")?;
+        for _ in 0..100 {
+            assert_eq!(tx1.get(b"a"), Some(b"a1".to_vec()));
+            assert_eq!(tx1.get(b"b"), None);
+        }
+        assert_eq!(
+            tx1.get_with_version(b"a").map(|(_, v)| v),
+            Some(tx1.version)
+        );
+
+        // 回滚到保存点之后读到保存点时的值
+        let sp = tx1.savepoint();
+        tx1.set(b"a", b"a2".to_vec())?;
+        tx1.set(b"c", b"c1".to_vec())?;
+        tx1.rollback_to(&sp)?;
+        assert_eq!(tx1.get(b"a"), Some(b"a1".to_vec()));
+        assert_eq!(tx1.get(b"c"), None);
+
+        // 合并到其他事务之后，原来的事务读取不到这些写入
+        let tx2 = mvcc.begin_transaction();
+        tx2.apply_from(&tx1)?;
+        assert_eq!(tx2.get(b"a"), Some(b"a1".to_vec()));
+        assert_eq!(tx2.get(b"b"), None);
+        assert_eq!(tx1.get(b"a"), Some(b"a0".to_vec()));
+        tx1.commit()?;
+        tx2.commit()?;
+        assert_eq!(mvcc.get_committed(b"a"), Some(b"a1".to_vec()));
+        assert_eq!(mvcc.get_committed(b"b"), None);
+        Ok(())
+    }
+
+    // 并发开启事务，活跃事务列表中只会有比自己小的版本号
+    #[test]
+    fn test_concurrent_begin() -> Result<()> {
+        let mvcc = Arc::new(MVCC::new(KVEngine::new()));
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let mvcc = mvcc.clone();
+                std::thread::spawn(move || -> Result<Vec<u64>> {
+                    let mut versions = vec![];
+                    for j in 0..200 {
+                        let tx = mvcc.begin_transaction();
+                        assert!(tx.active_xid.iter().all(|xid| *xid < tx.version));
+                        versions.push(tx.version);
+                        if (i + j) % 2 == 0 {
+                            tx.set(format!("k{}", i).as_bytes(), vec![j as u8])?;
+                            tx.commit()?;
+                        } else {
+                            tx.rollback();
+                        }
+                    }
+                    Ok(versions)
+                })
+            })
+            .collect();
+        let mut versions = vec![];
+        for handle in handles {
+            versions.extend(handle.join().unwrap()?);
+        }
+        versions.sort();
+        assert_eq!(versions, (1..=1600).collect::<Vec<u64>>());
+
+        // 每个 MVCC 实例的版本号单独分配
+        let other = MVCC::new(KVEngine::new());
+        assert_eq!(other.begin_transaction().version, 1);
+        Ok(())
+    }
+
+    // 压缩元数据之后，已提交版本号的记录减少，is_committed 和冲突检测的结果不变
+    #[test]
+    fn test_compact_metadata() -> Result<()> {
+        let mvcc = MVCC::new(KVEngine::new());
+        let mut versions = vec![];
+        for i in 0..100 {
+            let tx = mvcc.begin_transaction();
+            tx.set(b"a", vec![i])?;
+            if i % 10 == 0 {
+                tx.rollback();
+            } else {
+                tx.commit()?;
+            }
+            versions.push((tx.version, i % 10 != 0));
+        }
+
+        // 活跃的事务之后提交的版本号需要保留
+        let active = mvcc.begin_with_isolation(IsolationLevel::Serializable);
+        assert_eq!(active.get(b"a"), Some(vec![99]));
+        let tx = mvcc.begin_transaction();
+        tx.set(b"a", vec![100])?;
+        tx.commit()?;
+        versions.push((tx.version, true));
+
+        let before = mvcc.state.committed.lock().unwrap().len();
+        assert_eq!(before, 91);
+        assert_eq!(mvcc.compact_metadata(), 80);
+        assert_eq!(mvcc.state.committed.lock().unwrap().len(), 11);
+        assert_eq!(mvcc.compact_metadata(), 0);
+        for (version, committed) in versions {
+            assert_eq!(mvcc.is_committed(version), committed);
+        }
+        assert!(!mvcc.is_committed(active.version));
+        assert!(!mvcc.is_committed(u64::MAX));
+
+        // 活跃的事务仍然能发现读取的 key 被修改
+        assert_eq!(active.commit(), Err(MvccError::Serialization));
+        active.rollback();
+        mvcc.compact_metadata();
+        assert_eq!(mvcc.state.committed.lock().unwrap().len(), 11);
+        assert!(!mvcc.is_committed(active.version));
+        Ok(())
+    }
+
+    // 读已提交的事务能读到开启之后其他事务提交的数据，读不到未提交和回滚的数据
+    #[test]
+    fn test_read_committed() -> Result<()> {
+        let mvcc = MVCC::new(KVEngine::new());
+        let tx0 = mvcc.begin_transaction();
+        tx0.set(b"a", b"a0".to_vec())?;
+        tx0.commit()?;
+
+        let rc = mvcc.begin_with_isolation(IsolationLevel::ReadCommitted);
+        let si = mvcc.begin_transaction();
+        assert_eq!(rc.get(b"a"), Some(b"a0".to_vec()));
+
+        let tx1 = mvcc.begin_transaction();
+        tx1.set(b"a", b"a1".to_vec())?;
+        tx1.set(b"b", b"b1".to_vec())?;
+        assert_eq!(rc.get(b"a"), Some(b"a0".to_vec()));
+        tx1.commit()?;
+        assert_eq!(rc.get(b"a"), Some(b"a1".to_vec()));
+        assert_eq!(
+            rc.get_many(&[b"a".to_vec(), b"b".to_vec()]),
+            vec![Some(b"a1".to_vec()), Some(b"b1".to_vec())]
+        );
+        // 快照隔离的事务仍然读到开启时的数据
+        assert_eq!(si.get(b"a"), Some(b"a0".to_vec()));
+
+        let tx2 = mvcc.begin_transaction();
+        tx2.set(b"a", b"a2".to_vec())?;
+        tx2.rollback();
+        assert_eq!(rc.get(b"a"), Some(b"a1".to_vec()));
+
+        // 自己的写入可见
+        rc.set(b"c", b"c1".to_vec())?;
+        assert_eq!(rc.get(b"c"), Some(b"c1".to_vec()));
+        rc.commit()?;
+        si.commit()?;
+        Ok(())
+    }
+}